@@ -11,19 +11,33 @@ const LS_SELECTION_KEY = 'characterViewerSelection';
 const LS_COLLAPSE_KEY = 'characterViewerListCollapsed';
 const LS_THEME_KEY = 'characterViewerTheme';
 const LS_INFO_BAR_KEY = 'characterViewerInfoBarItems';
+const LS_CHAT_AUTHOR_KEY = 'characterViewerChatAuthor';
+// Viewers opened with ?group=raid1 share chat and pinned notes with everyone else in that group.
+const VIEWER_GROUP = new URLSearchParams(window.location.search).get('group') || 'default';
 
 let allCharacterData = {};
+let allCharacterMeta = {};
+let serverClockOffsetMs = 0;
+let chatMessages = [];
+let viewers = [];
+let pinnedNotes = {};
+let activeAlerts = {}; // Character name -> alerts fired by the server's rules that have not cleared yet
+// The !sanc indicator shows while the server's rule of this name has an active alert for the character.
+const NO_SANC_RULE = 'no_sanctuary';
 let orderedSelectedNames = [];
 let webSocket = null;
 let cardElements = [];
 let reconnectTimer = null;
+let heartbeatWatchdogTimer = null;
+let lastServerMessageAt = 0;
 let activeInfoBarItems = [];
 let knownKeysForModal = new Set();
 
 let characterListElement, cardGridElement, cardTemplate, globalConnectionStatusIndicator,
     listPanelElement, listPanelHeader, collapseToggle,
     settingsToggleButton,
-    settingsModal, infoBarKeysListElement, themeToggleModalButton;
+    settingsModal, infoBarKeysListElement, themeToggleModalButton,
+    chatLogElement, chatFormElement, chatInputElement, chatAuthorInput, viewerListElement;
 
 function initializeCssAndJsConfigs() {
     const rootStyle = getComputedStyle(document.documentElement);
@@ -147,19 +161,45 @@ function loadInfoBarSettings() {
 
 function connectWebSocket() {
     clearTimeout(reconnectTimer);
-    const wsUri = `ws://${SERVER_HOST}:${SERVER_PORT}/ws`;
+    // The chat name doubles as the name other viewers in the group see us under.
+    const viewerName = localStorage.getItem(LS_CHAT_AUTHOR_KEY);
+    const wsUri = `ws://${SERVER_HOST}:${SERVER_PORT}/ws?format=v2&group=${encodeURIComponent(VIEWER_GROUP)}`
+        + (viewerName ? `&name=${encodeURIComponent(viewerName)}` : '');
     updateGlobalConnectionStatus('connecting');
     if (webSocket && webSocket.readyState !== WebSocket.CLOSED) webSocket.close();
     webSocket = new WebSocket(wsUri);
-    webSocket.onopen = () => { updateGlobalConnectionStatus('connected'); clearTimeout(reconnectTimer); };
+    webSocket.onopen = () => { updateGlobalConnectionStatus('connected'); clearTimeout(reconnectTimer); lastServerMessageAt = Date.now(); };
     webSocket.onmessage = (event) => {
+        lastServerMessageAt = Date.now();
         try {
             const data = JSON.parse(event.data);
             let dataChanged = false;
-            if (data?.updates || data?.deletions) {
+            if (data?.type === 'heartbeat') {
+                armHeartbeatWatchdog(data.interval_ms);
+                if (!globalConnectionStatusIndicator?.classList.contains('connected')) updateGlobalConnectionStatus('connected');
+                return;
+            }
+            if (data?.server_time) serverClockOffsetMs = Date.now() - data.server_time * 1000;
+            if (data?.type === 'snapshot') {
+                // Only servers with chat, presence and alerts send v2 snapshots, so this is where the
+                // UI for them is switched on. Older servers ignore ?format=v2 and send plain maps.
+                document.body.classList.add('server-features');
+                allCharacterData = data.characters || {};
+                allCharacterMeta = data.meta || {};
+                chatMessages = data.annotations?.chat || [];
+                pinnedNotes = data.annotations?.notes || {};
+                viewers = data.viewers || [];
+                activeAlerts = {};
+                (data.alerts || []).forEach(addActiveAlert);
+                renderChatLog();
+                renderViewerList();
+                dataChanged = true;
+            } else if (data?.type === 'delta' || data?.updates || data?.deletions) {
                 Object.entries(data.updates || {}).forEach(([name, charData]) => { allCharacterData[name] = charData; dataChanged = true; });
-                (data.deletions || []).forEach(name => { if (allCharacterData[name]) { delete allCharacterData[name]; dataChanged = true; }});
-            } else if (typeof data === 'object' && data !== null) { allCharacterData = data; dataChanged = true;
+                Object.entries(data.meta || {}).forEach(([name, charMeta]) => { allCharacterMeta[name] = charMeta; });
+                (data.deletions || []).forEach(name => { delete allCharacterMeta[name]; if (allCharacterData[name]) { delete allCharacterData[name]; dataChanged = true; }});
+            } else if (data?.type === 'event') { dataChanged = handleServerEvent(data);
+            } else if (typeof data === 'object' && data !== null && !data.type) { allCharacterData = data; dataChanged = true;
             } else console.warn("Unexpected data format:", data);
 
             if (dataChanged) {
@@ -175,17 +215,169 @@ function connectWebSocket() {
     };
     webSocket.onerror = (error) => console.error("WebSocket Error:", error);
     webSocket.onclose = (event) => {
+        clearInterval(heartbeatWatchdogTimer);
+        heartbeatWatchdogTimer = null;
         updateGlobalConnectionStatus('disconnected');
         webSocket = null;
         requestAnimationFrame(() => { updateActiveCards(); updateCharacterListConnectionIndicators(false); });
         clearTimeout(reconnectTimer);
-        if (!event.wasClean || event.code === 1006) {
+        if (!event.wasClean || event.code === 1006 || event.code === 4000) {
             console.log(`Attempting reconnect in ${RECONNECT_DELAY_MS / 1000} seconds...`);
             reconnectTimer = setTimeout(connectWebSocket, RECONNECT_DELAY_MS);
         } else { console.log("Clean disconnect. Not attempting auto-reconnect."); }
     };
 }
 
+// Returns true when card contents need re-rendering.
+function handleServerEvent(event) {
+    switch (event.event) {
+        case 'chat':
+            chatMessages.push(event.message);
+            chatMessages = chatMessages.slice(-200);
+            renderChatLog();
+            return false;
+        case 'note_pinned': {
+            const notes = pinnedNotes[event.note.character] || [];
+            if (!notes.some(n => n.id === event.note.id)) notes.push(event.note);
+            pinnedNotes[event.note.character] = notes;
+            return true;
+        }
+        case 'note_unpinned':
+            pinnedNotes[event.character] = (pinnedNotes[event.character] || []).filter(n => n.id !== event.id);
+            return true;
+        case 'viewer_joined':
+            if (!viewers.some(v => v.id === event.viewer.id)) viewers.push(event.viewer);
+            renderViewerList();
+            return false;
+        case 'viewer_left':
+            viewers = viewers.filter(v => v.id !== event.id);
+            renderViewerList();
+            return false;
+        case 'alert':
+            appendChatLine(null, event.alert.message, `chat-alert alert-${event.alert.severity}`);
+            if (!event.alert.active) return false;
+            addActiveAlert(event.alert);
+            return true;
+        case 'alert_cleared':
+            activeAlerts[event.character] = (activeAlerts[event.character] || []).filter(a => a.id !== event.id);
+            return true;
+        case 'fight_ended': {
+            const f = event.fight;
+            appendChatLine(null, `${f.character} vs ${f.opponent}: ${f.outcome.replace('_', ' ')} after ${Math.round(f.duration_seconds)}s, ${f.hp_lost} HP lost`, 'chat-alert');
+            return false;
+        }
+        case 'affect_expiring':
+            appendChatLine(null, `${event.character}: ${event.affect} ends in ${event.remaining_seconds}s`, 'chat-alert');
+            return false;
+        case 'affect_expired':
+            appendChatLine(null, `${event.character}: ${event.affect} has worn off`, 'chat-alert');
+            return false;
+        case 'plugin':
+            appendChatLine(null, `${event.character}: ${event.message || event.name}`, 'chat-alert');
+            return false;
+        case 'error':
+            appendChatLine(null, event.message, 'chat-error');
+            return false;
+        default:
+            return false;
+    }
+}
+
+function sendViewerMessage(message) {
+    if (!webSocket || webSocket.readyState !== WebSocket.OPEN) return false;
+    const author = chatAuthorInput?.value.trim() || localStorage.getItem(LS_CHAT_AUTHOR_KEY) || undefined;
+    webSocket.send(JSON.stringify({ ...message, author }));
+    return true;
+}
+
+function renderViewerList() {
+    if (!viewerListElement) return;
+    const names = viewers.filter(v => v.name).map(v => v.name);
+    const anonymous = viewers.length - names.length;
+    if (anonymous > 0) names.push(`${anonymous} anonymous`);
+    viewerListElement.textContent = names.length ? `Watching: ${names.join(', ')}` : '';
+    viewerListElement.title = viewers.map(v => `${v.name || 'anonymous'} (${v.transport}, idle ${v.idle_seconds}s)`).join('\n');
+}
+
+function renderChatLog() {
+    if (!chatLogElement) return;
+    chatLogElement.innerHTML = '';
+    chatMessages.forEach(message => appendChatLine(message.author, message.text));
+}
+
+function appendChatLine(author, text, className = null) {
+    if (!chatLogElement) return;
+    const li = document.createElement('li');
+    if (className) li.className = className;
+    if (author) {
+        const authorSpan = document.createElement('span');
+        authorSpan.className = 'chat-author';
+        authorSpan.textContent = `${author}: `;
+        li.appendChild(authorSpan);
+    }
+    li.appendChild(document.createTextNode(text));
+    chatLogElement.appendChild(li);
+    chatLogElement.scrollTop = chatLogElement.scrollHeight;
+}
+
+function addActiveAlert(alert) {
+    const alerts = activeAlerts[alert.character] || [];
+    if (!alerts.some(a => a.id === alert.id)) alerts.push(alert);
+    activeAlerts[alert.character] = alerts;
+}
+
+function renderAlerts(cardElement, charName) {
+    const alertsList = cardElement._elements?.alertsList;
+    if (!alertsList) return;
+    alertsList.innerHTML = '';
+    (activeAlerts[charName] || []).forEach(alert => {
+        const li = document.createElement('li');
+        li.className = `alert-${alert.severity}`;
+        li.textContent = alert.message;
+        li.title = alert.rule;
+        alertsList.appendChild(li);
+    });
+}
+
+function renderNotes(cardElement, charName) {
+    const notesList = cardElement._elements?.notesList;
+    if (!notesList) return;
+    notesList.innerHTML = '';
+    (pinnedNotes[charName] || []).forEach(note => {
+        const li = document.createElement('li');
+        const text = document.createElement('span');
+        text.textContent = `${note.text} (${note.author})`;
+        const unpin = document.createElement('button');
+        unpin.textContent = '×';
+        unpin.title = 'Unpin';
+        unpin.addEventListener('click', (e) => { sendViewerMessage({ type: 'unpin', character: charName, id: note.id }); e.stopPropagation(); });
+        li.appendChild(text);
+        li.appendChild(unpin);
+        notesList.appendChild(li);
+    });
+}
+
+// Once the server has announced a heartbeat interval, treat three missed beats as a dead connection
+// and force a reconnect instead of waiting for the browser to notice the half-open socket.
+function armHeartbeatWatchdog(intervalMs) {
+    if (heartbeatWatchdogTimer || !intervalMs) return;
+    const deadlineMs = intervalMs * 3;
+    heartbeatWatchdogTimer = setInterval(() => {
+        if (!webSocket || Date.now() - lastServerMessageAt <= deadlineMs) return;
+        console.warn(`No message from server in ${deadlineMs} ms. Reconnecting...`);
+        clearInterval(heartbeatWatchdogTimer);
+        heartbeatWatchdogTimer = null;
+        const staleSocket = webSocket;
+        staleSocket.onclose = null;
+        staleSocket.close();
+        webSocket = null;
+        updateGlobalConnectionStatus('disconnected');
+        requestAnimationFrame(() => { updateActiveCards(); updateCharacterListConnectionIndicators(false); });
+        clearTimeout(reconnectTimer);
+        reconnectTimer = setTimeout(connectWebSocket, RECONNECT_DELAY_MS);
+    }, intervalMs);
+}
+
 function updateGlobalConnectionStatus(statusClass) {
     if (!globalConnectionStatusIndicator) return;
     globalConnectionStatusIndicator.classList.remove('connected', 'disconnected', 'connecting');
@@ -211,6 +403,11 @@ function updateCharacterListConnectionIndicators(useCharacterData) {
         if (useCharacterData && globalConnectionStatusIndicator.classList.contains('connected')) {
             isConnected = allCharacterData[charName]?.CONNECTED === "YES";
             title = isConnected ? 'Connected' : 'Disconnected (Character)';
+            const lastUpdate = allCharacterMeta[charName]?.last_update;
+            if (lastUpdate) {
+                const ageSeconds = Math.max(0, Math.round((Date.now() - serverClockOffsetMs) / 1000 - lastUpdate));
+                title += ` - last updated ${ageSeconds}s ago`;
+            }
         } else { title = globalConnectionStatusIndicator.classList.contains('connecting') ? 'Connecting...' : 'Disconnected (Main)'; }
         indicator.classList.toggle('connected', isConnected);
         indicator.classList.toggle('disconnected', !isConnected);
@@ -318,6 +515,13 @@ function createCardElement() {
     const cardClone = cardTemplate.content.firstElementChild.cloneNode(true);
     const expandButton = cardClone.querySelector('.expand-button');
     if (expandButton) expandButton.addEventListener('click', (e) => { toggleExpand(cardClone); e.stopPropagation(); });
+    const pinButton = cardClone.querySelector('.pin-note-button');
+    if (pinButton) pinButton.addEventListener('click', (e) => {
+        e.stopPropagation();
+        const charName = cardClone.dataset.charname;
+        const text = charName && prompt(`Pin a note to ${charName}:`);
+        if (text?.trim()) sendViewerMessage({ type: 'pin', character: charName, text: text.trim() });
+    });
     cardClone._elements = {
         nameText: cardClone.querySelector('.char-name-text'),
         lagBarFg: cardClone.querySelector('.char-lag .lag-bar-fg'),
@@ -352,6 +556,8 @@ function createCardElement() {
         cardConnectionIndicator: cardClone.querySelector('.card-connection-indicator'),
         expandButton: expandButton,
         cardContent: cardClone.querySelector('.card-content'),
+        notesList: cardClone.querySelector('.notes-list'),
+        alertsList: cardClone.querySelector('.alerts-list'),
     };
     return cardClone;
 }
@@ -396,6 +602,8 @@ function clearCard(cardElement) {
      if (els.affectsText) els.affectsText.textContent = "";
      if (els.expandedSection) els.expandedSection.style.display = 'none';
      if (els.expandedText) els.expandedText.textContent = "";
+     if (els.notesList) els.notesList.innerHTML = '';
+     if (els.alertsList) els.alertsList.innerHTML = '';
 }
 
 function updateCardData(cardElement, charName, data) {
@@ -562,14 +770,17 @@ function updateCardData(cardElement, charName, data) {
     }
   });
   if (els.favorStyleLine) els.favorStyleLine.style.display = favorStyleLineVisible ? 'flex' : 'none';
-  const { affectsStr, hasSanctuary } = parseAffects(data.AFFECTS);
+  const affectsStr = parseAffects(data.AFFECTS);
   if (els.affectsSection && els.affectsText) {
       if (affectsStr) { els.affectsText.textContent = affectsStr; els.affectsSection.style.display = 'block';
       } else { els.affectsSection.style.display = 'none'; els.affectsText.textContent = ''; }
   }
   if(els.blindnessIndicator) els.blindnessIndicator.style.display = (SHOW_BLINDNESS_INDICATOR && isVisuallyBlind) ? 'inline-block' : 'none';
-  if(els.noSancIndicator) els.noSancIndicator.style.display = (SHOW_NO_SANC_INDICATOR && !hasSanctuary && affectsStr) ? 'inline-block' : 'none';
+  const noSanctuary = (activeAlerts[charName] || []).some(alert => alert.rule === NO_SANC_RULE);
+  if(els.noSancIndicator) els.noSancIndicator.style.display = (SHOW_NO_SANC_INDICATOR && noSanctuary) ? 'inline-block' : 'none';
   if(els.expandButton) els.expandButton.textContent = isExpanded ? "-" : "+";
+  renderAlerts(cardElement, charName);
+  renderNotes(cardElement, charName);
   if (els.expandedSection && els.expandedText) {
       if (isExpanded) { els.expandedText.textContent = formatFullData(data); els.expandedSection.style.display = 'block';
       } else { els.expandedSection.style.display = 'none'; }
@@ -590,7 +801,7 @@ function updateBar(barFgElement, textElement, current, max, type, barElement = n
 }
 
 function parseAffects(affectsData) {
-    let parsedAffects = [], affectsStr = "", hasSanctuary = false, couldParse = false;
+    let parsedAffects = [], affectsStr = "", couldParse = false;
     if (typeof affectsData === 'object' && affectsData !== null) {
         Object.entries(affectsData).forEach(([key, value]) => parsedAffects.push({ name: key, value: String(value) }));
         if (parsedAffects.length > 0) couldParse = true;
@@ -613,13 +824,7 @@ function parseAffects(affectsData) {
         });
         affectsStr = parsedAffects.map(item => `${item.name}${(item.value && item.value !== "0") ? (': ' + item.value) : ''}`).join('\n');
     }
-    const checkStrLower = (affectsStr || "").toLowerCase();
-    hasSanctuary = Array.from(SANCTUARY_AFFECTS).some(sanc => checkStrLower.includes(sanc));
-    if (!hasSanctuary && couldParse) {
-        const affectNamesLower = new Set(parsedAffects.map(a => a.name.toLowerCase()));
-        hasSanctuary = Array.from(SANCTUARY_AFFECTS).some(sanc => affectNamesLower.has(sanc));
-    }
-    return { affectsStr: affectsStr || "", hasSanctuary };
+    return affectsStr || "";
 }
 
 function formatFullData(data) {
@@ -649,7 +854,7 @@ function formatFullData(data) {
                 if (typeof value === 'string') return (value.match(/[a-z]+/g) || []).join(', ') || "(None)";
                 if (typeof value === 'object' && value !== null) return Object.keys(value).filter(dir => value[dir] != null).join(', ') || "(None)";
             }
-            if (key === 'AFFECTS' && typeof value === 'object' && value !== null) return parseAffects(value).affectsStr || "(None)";
+            if (key === 'AFFECTS' && typeof value === 'object' && value !== null) return parseAffects(value) || "(None)";
             if (value === null || value === undefined) return "(Not Set)";
             if (value === "") return "(Empty)";
             if (typeof value === 'object') return JSON.stringify(value);
@@ -730,6 +935,11 @@ document.addEventListener('DOMContentLoaded', () => {
     settingsModal = document.getElementById('settings-modal');
     infoBarKeysListElement = document.getElementById('info-bar-keys-list');
     themeToggleModalButton = document.getElementById('theme-toggle-modal-button');
+    chatLogElement = document.getElementById('chat-log');
+    chatFormElement = document.getElementById('chat-form');
+    chatInputElement = document.getElementById('chat-input');
+    chatAuthorInput = document.getElementById('chat-author-input');
+    viewerListElement = document.getElementById('viewer-list');
 
     initializeCssAndJsConfigs();
     loadInfoBarSettings();
@@ -809,5 +1019,18 @@ document.addEventListener('DOMContentLoaded', () => {
     }
     updateCardAssignments();
     if (characterListElement) characterListElement.addEventListener('click', handleCharacterSelect);
+    if (chatAuthorInput) {
+        try { chatAuthorInput.value = localStorage.getItem(LS_CHAT_AUTHOR_KEY) || ''; } catch (e) { console.error("Failed to load chat name:", e); }
+        chatAuthorInput.addEventListener('change', () => {
+            try { localStorage.setItem(LS_CHAT_AUTHOR_KEY, chatAuthorInput.value.trim()); } catch (e) { console.error("Failed to save chat name:", e); }
+        });
+    }
+    if (chatFormElement && chatInputElement) {
+        chatFormElement.addEventListener('submit', (event) => {
+            event.preventDefault();
+            const text = chatInputElement.value.trim();
+            if (text && sendViewerMessage({ type: 'chat', text })) chatInputElement.value = '';
+        });
+    }
     connectWebSocket();
 });
\ No newline at end of file
@@ -0,0 +1,89 @@
+// --- Broadcast Encoding Benchmarks ---
+// Compares encoding a delta once per subscriber, as broadcasts did before frames were shared, with
+// encoding it once and handing every subscriber the same frame. The server is a binary crate, so
+// this file is compiled into it as a module when the `bench` feature is on. `#[bench]` needs a
+// nightly toolchain:
+//
+//   cargo +nightly bench --features bench
+
+extern crate test;
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use serde_json::json;
+use test::{black_box, Bencher};
+
+use crate::protocol::{encode_delta, Encoding, MessageFormat};
+use crate::subscriber_queue::QueuedDelta;
+use crate::{CharacterDataMap, DeltaUpdate};
+
+const CHARACTERS: usize = 30;
+
+fn character(name: &str) -> CharacterDataMap {
+    let mut data = CharacterDataMap::new();
+    data.insert("CHARACTER_NAME".to_string(), json!(name));
+    data.insert("HEALTH".to_string(), json!("812"));
+    data.insert("HEALTH_MAX".to_string(), json!("1024"));
+    data.insert("OPPONENT_NAME".to_string(), json!("a cave troll"));
+    data.insert("AFFECTS".to_string(), json!("{sanctuary}{24}{haste}{12}{bless}{6}{armor}{30}{stone skin}{18}".repeat(4)));
+    for i in 0..20 {
+        data.insert(format!("KEY_{}", i), json!(i.to_string()));
+    }
+    data
+}
+
+fn delta() -> DeltaUpdate {
+    let updates = (0..CHARACTERS).map(|i| (format!("Char{}", i), character(&format!("Char{}", i)))).collect();
+    DeltaUpdate { updates, deletions: vec!["Gone".to_string()], meta: HashMap::new() }
+}
+
+fn per_subscriber(b: &mut Bencher, subscribers: usize) {
+    let delta = delta();
+    b.iter(|| {
+        for _ in 0..subscribers {
+            black_box(encode_delta(&delta, 1, SystemTime::now(), MessageFormat::Legacy, Encoding::Json).unwrap());
+        }
+    });
+}
+
+fn shared(b: &mut Bencher, subscribers: usize) {
+    let delta = delta();
+    b.iter(|| {
+        // The clone stands in for the delta broadcast_pending builds; it only makes this side slower.
+        let queued = QueuedDelta::new(1, delta.clone());
+        for _ in 0..subscribers {
+            black_box(queued.frame(MessageFormat::Legacy, Encoding::Json, None));
+        }
+    });
+}
+
+#[bench]
+fn per_subscriber_1(b: &mut Bencher) {
+    per_subscriber(b, 1);
+}
+
+#[bench]
+fn shared_1(b: &mut Bencher) {
+    shared(b, 1);
+}
+
+#[bench]
+fn per_subscriber_100(b: &mut Bencher) {
+    per_subscriber(b, 100);
+}
+
+#[bench]
+fn shared_100(b: &mut Bencher) {
+    shared(b, 100);
+}
+
+#[bench]
+fn per_subscriber_1000(b: &mut Bencher) {
+    per_subscriber(b, 1000);
+}
+
+#[bench]
+fn shared_1000(b: &mut Bencher) {
+    shared(b, 1000);
+}
@@ -0,0 +1,57 @@
+// Example plugin: renames the keys an older client script sends and announces level-ups to
+// viewers. See the plugin ABI in rust_server/src/plugins.rs.
+
+use serde_json::{json, Map, Value};
+
+const RENAMED: [(&str, &str); 2] = [("HP", "HEALTH"), ("MAXHP", "HEALTH_MAX")];
+
+#[link(wasm_import_module = "env")]
+extern "C" {
+    fn log(ptr: *const u8, len: usize);
+}
+
+fn log_message(message: &str) {
+    unsafe { log(message.as_ptr(), message.len()) }
+}
+
+#[no_mangle]
+pub extern "C" fn alloc(len: usize) -> *mut u8 {
+    let mut buffer = Vec::<u8>::with_capacity(len);
+    let ptr = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dealloc(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn process(ptr: *mut u8, len: usize) -> u64 {
+    let input = Vec::from_raw_parts(ptr, len, len);
+    let Ok(Value::Object(input)) = serde_json::from_slice::<Value>(&input) else {
+        log_message("input is not a JSON object");
+        return 0;
+    };
+    let Some(Value::Object(mut data)) = input.get("data").cloned() else { return 0 };
+
+    for (from, to) in RENAMED {
+        if let Some(value) = data.remove(from) {
+            data.insert(to.to_string(), value);
+        }
+    }
+
+    let mut events = Vec::new();
+    let level = |map: &Map<String, Value>| map.get("LEVEL").and_then(|v| v.to_string().trim_matches('"').parse::<u32>().ok());
+    if let (Some(level), Some(before)) = (level(&data), input.get("previous").and_then(Value::as_object).and_then(level)) {
+        if level > before {
+            events.push(json!({ "name": "level_up", "message": format!("reached level {}", level) }));
+        }
+    }
+
+    let output = json!({ "data": data, "events": events }).to_string().into_bytes().into_boxed_slice();
+    let len = output.len() as u64;
+    let ptr = Box::into_raw(output) as *mut u8 as u64;
+    (ptr << 32) | len
+}
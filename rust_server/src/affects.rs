@@ -0,0 +1,315 @@
+// --- Affect Countdowns ---
+// MUD clients send AFFECTS as a nested table of affect name -> remaining duration, e.g.
+// `{sanctuary}{120}{bless}{45}`. Instead of relying on the client to re-send it every tick, the
+// server anchors each duration to the time it was received and counts down itself: the stored
+// AFFECTS value is rewritten with the corrected remaining times every AFFECT_REFRESH_SECONDS,
+// viewers get `affect_expiring` once an affect has AFFECT_EXPIRING_SECONDS or less left and
+// `affect_expired` when it runs out or the client stops reporting it. Whatever the client sends
+// next always wins over the countdown. Non-numeric and zero durations (permanent affects) are
+// passed through unchanged.
+
+use std::collections::HashMap;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, SystemTime};
+
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::conditions::affect_entries;
+use crate::state_store::StateStore;
+use crate::viewer_events::{ViewerEvent, ViewerEvents};
+use crate::CharacterDataMap;
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct AffectConfig {
+    pub key: String,                // Empty disables countdowns
+    pub seconds_per_unit: f64,      // For MUDs that report durations in ticks or game hours
+    pub expiring_seconds: u64,
+    pub refresh_interval: Duration, // How often corrected times are written back to AFFECTS
+}
+
+#[derive(Debug)]
+enum Remaining {
+    Timed { expires_at: SystemTime, warned: bool },
+    Fixed(String), // Sent as-is by the client
+}
+
+#[derive(Debug)]
+struct Affect {
+    name: String,
+    remaining: Remaining,
+}
+
+#[derive(Debug)]
+struct CharacterAffects {
+    affects: Vec<Affect>, // In the client's order
+    refreshed_at: SystemTime,
+    shown: String, // The AFFECTS value currently stored
+}
+
+#[derive(Debug)]
+pub struct AffectTracker {
+    config: AffectConfig,
+    characters: StdMutex<HashMap<String, CharacterAffects>>,
+    last_tick: StdMutex<Option<SystemTime>>,
+    events: ViewerEvents,
+}
+
+impl AffectTracker {
+    pub fn new(config: AffectConfig, events: ViewerEvents) -> Self {
+        Self { config, characters: StdMutex::new(HashMap::new()), last_tick: StdMutex::new(None), events }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.key.is_empty()
+    }
+
+    /// Takes the durations from a client update as the new truth, announcing affects it no longer reports.
+    pub fn observe(&self, character: &str, data: &CharacterDataMap, now: SystemTime) {
+        if !self.is_enabled() {
+            return;
+        }
+        let value = data.get(&self.config.key);
+        let entries = value.and_then(affect_entries).unwrap_or_default();
+        let mut characters = self.characters.lock().unwrap();
+        let previous = characters.remove(character).map(|c| c.affects).unwrap_or_default();
+
+        let affects: Vec<Affect> = entries.into_iter().map(|(name, value)| {
+            let remaining = match value.parse::<f64>() {
+                Ok(units) if units > 0.0 && units.is_finite() => {
+                    let seconds = units * self.config.seconds_per_unit;
+                    // A warning already sent stays sent unless the affect was renewed past the threshold.
+                    let warned = seconds <= self.config.expiring_seconds as f64 && previous.iter()
+                        .any(|p| p.name == name && matches!(p.remaining, Remaining::Timed { warned: true, .. }));
+                    Remaining::Timed { expires_at: now + Duration::from_secs_f64(seconds), warned }
+                }
+                _ => Remaining::Fixed(value),
+            };
+            Affect { name, remaining }
+        }).collect();
+
+        for ended in previous.iter().filter(|p| !affects.iter().any(|a| a.name == p.name)) {
+            debug!("Affect '{}' of '{}' no longer reported.", ended.name, character);
+            self.events.publish_all(ViewerEvent::AffectExpired { character: character.to_string(), affect: ended.name.clone() });
+        }
+
+        if !affects.is_empty() {
+            let shown = value.and_then(Value::as_str).unwrap_or_default().to_string();
+            characters.insert(character.to_string(), CharacterAffects { affects, refreshed_at: now, shown });
+        }
+    }
+
+    /// Counts down every tracked affect, at most once per second. Called from broadcast_loop so
+    /// corrected times go out with the same broadcast.
+    pub fn tick(&self, store: &StateStore, now: SystemTime) {
+        if !self.is_enabled() {
+            return;
+        }
+        {
+            let mut last_tick = self.last_tick.lock().unwrap();
+            if last_tick.is_some_and(|last| now.duration_since(last).is_ok_and(|age| age < TICK_INTERVAL)) {
+                return;
+            }
+            *last_tick = Some(now);
+        }
+
+        let mut characters = self.characters.lock().unwrap();
+        characters.retain(|character, tracked| {
+            let mut expired = false;
+            tracked.affects.retain_mut(|affect| {
+                let Remaining::Timed { expires_at, warned } = &mut affect.remaining else { return true };
+                let Ok(left) = expires_at.duration_since(now) else {
+                    info!("Affect '{}' of '{}' expired.", affect.name, character);
+                    self.events.publish_all(ViewerEvent::AffectExpired { character: character.clone(), affect: affect.name.clone() });
+                    expired = true;
+                    return false;
+                };
+                if !*warned && left.as_secs() <= self.config.expiring_seconds {
+                    *warned = true;
+                    self.events.publish_all(ViewerEvent::AffectExpiring {
+                        character: character.clone(),
+                        affect: affect.name.clone(),
+                        remaining_seconds: left.as_secs_f64().ceil() as u64,
+                    });
+                }
+                true
+            });
+
+            let refresh_due = now.duration_since(tracked.refreshed_at).is_ok_and(|age| age >= self.config.refresh_interval);
+            if expired || refresh_due {
+                tracked.refreshed_at = now;
+                let value = self.render(&tracked.affects, now);
+                if value != tracked.shown {
+                    store.set_key(character, &self.config.key, Value::String(value.clone()));
+                    tracked.shown = value;
+                }
+            }
+            !tracked.affects.is_empty()
+        });
+    }
+
+    /// Stops counting down for a character that disconnected or was pruned.
+    pub fn remove_character(&self, character: &str) {
+        self.characters.lock().unwrap().remove(character);
+    }
+
+    /// The `{name}{remaining}` table in the client's units, rounded up.
+    fn render(&self, affects: &[Affect], now: SystemTime) -> String {
+        affects.iter().map(|affect| {
+            let value = match &affect.remaining {
+                Remaining::Timed { expires_at, .. } => {
+                    let seconds = expires_at.duration_since(now).map_or(0.0, |d| d.as_secs_f64());
+                    ((seconds / self.config.seconds_per_unit).ceil() as u64).to_string()
+                }
+                Remaining::Fixed(value) => value.clone(),
+            };
+            format!("{{{}}}{{{}}}", affect.name, value)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tokio::sync::broadcast::Receiver;
+
+    use crate::viewer_events::GroupEvent;
+
+    struct Fixture {
+        tracker: AffectTracker,
+        store: StateStore,
+        events: Receiver<Arc<GroupEvent>>,
+        start: SystemTime,
+    }
+
+    impl Fixture {
+        fn new(seconds_per_unit: f64) -> Self {
+            let events = ViewerEvents::new();
+            let receiver = events.subscribe();
+            let config = AffectConfig {
+                key: "AFFECTS".to_string(),
+                seconds_per_unit,
+                expiring_seconds: 10,
+                refresh_interval: Duration::from_secs(5),
+            };
+            let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let store = StateStore::new();
+            store.upsert("Thoric", CharacterDataMap::new(), start, "test".to_string());
+            Self { tracker: AffectTracker::new(config, events), store, events: receiver, start }
+        }
+
+        fn at(&self, seconds: u64) -> SystemTime {
+            self.start + Duration::from_secs(seconds)
+        }
+
+        /// A client update with the given AFFECTS value at `seconds`.
+        fn update(&self, seconds: u64, affects: &str) {
+            let data = CharacterDataMap::from([("AFFECTS".to_string(), json!(affects))]);
+            self.store.upsert("Thoric", data.clone(), self.at(seconds), "test".to_string());
+            self.tracker.observe("Thoric", &data, self.at(seconds));
+        }
+
+        fn tick(&self, seconds: u64) {
+            self.tracker.tick(&self.store, self.at(seconds));
+        }
+
+        fn shown(&self) -> Value {
+            self.store.get("Thoric").unwrap().data["AFFECTS"].clone()
+        }
+
+        fn events(&mut self) -> Vec<String> {
+            std::iter::from_fn(|| self.events.try_recv().ok())
+                .map(|e| match &e.event {
+                    ViewerEvent::AffectExpiring { affect, remaining_seconds, .. } => format!("expiring {} {}", affect, remaining_seconds),
+                    ViewerEvent::AffectExpired { affect, .. } => format!("expired {}", affect),
+                    other => panic!("unexpected event {:?}", other),
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn countdowns_continue_between_client_updates() {
+        let mut fixture = Fixture::new(1.0);
+        fixture.update(0, "{sanctuary}{120}{bless}{0}{fly}{permanent}");
+        // Nothing is written back before the refresh interval.
+        fixture.tick(1);
+        assert_eq!(fixture.shown(), json!("{sanctuary}{120}{bless}{0}{fly}{permanent}"));
+        fixture.tick(30);
+        assert_eq!(fixture.shown(), json!("{sanctuary}{90}{bless}{0}{fly}{permanent}"));
+        fixture.tick(60);
+        assert_eq!(fixture.shown(), json!("{sanctuary}{60}{bless}{0}{fly}{permanent}"));
+        // Ticks closer than a second apart are skipped.
+        fixture.tick(60);
+        assert!(fixture.events().is_empty());
+
+        // The client's next update wins over the countdown.
+        fixture.update(61, "{sanctuary}{100}");
+        fixture.tick(70);
+        assert_eq!(fixture.shown(), json!("{sanctuary}{91}"));
+    }
+
+    #[test]
+    fn durations_are_converted_with_seconds_per_unit() {
+        let fixture = Fixture::new(60.0);
+        fixture.update(0, "{haste}{3}");
+        fixture.tick(61);
+        // 119s left is 2 units, rounded up.
+        assert_eq!(fixture.shown(), json!("{haste}{2}"));
+    }
+
+    #[test]
+    fn expiring_warnings_are_sent_once_until_renewed() {
+        let mut fixture = Fixture::new(1.0);
+        fixture.update(0, "{bless}{15}");
+        fixture.tick(4);
+        assert!(fixture.events().is_empty());
+        fixture.tick(5);
+        assert_eq!(fixture.events(), vec!["expiring bless 10"]);
+        fixture.tick(6);
+        assert!(fixture.events().is_empty());
+
+        // A client update within the threshold does not warn again...
+        fixture.update(7, "{bless}{8}");
+        fixture.tick(8);
+        assert!(fixture.events().is_empty());
+        // ...but one renewing the affect past it re-arms the warning.
+        fixture.update(9, "{bless}{60}");
+        fixture.tick(10);
+        assert!(fixture.events().is_empty());
+        fixture.tick(59);
+        assert_eq!(fixture.events(), vec!["expiring bless 10"]);
+    }
+
+    #[test]
+    fn affects_expire_on_timeout_or_when_no_longer_reported() {
+        let mut fixture = Fixture::new(1.0);
+        fixture.update(0, "{bless}{3}{armor}{100}{fly}{0}");
+        fixture.tick(1);
+        assert_eq!(fixture.events(), vec!["expiring bless 2"]);
+        fixture.tick(4);
+        assert_eq!(fixture.events(), vec!["expired bless"]);
+        // An expiry is written back right away.
+        assert_eq!(fixture.shown(), json!("{armor}{96}{fly}{0}"));
+
+        fixture.update(5, "{fly}{0}");
+        assert_eq!(fixture.events(), vec!["expired armor"]);
+        fixture.update(6, "");
+        assert_eq!(fixture.events(), vec!["expired fly"]);
+    }
+
+    #[test]
+    fn removed_characters_stop_counting_down() {
+        let mut fixture = Fixture::new(1.0);
+        fixture.update(0, "{bless}{3}");
+        fixture.tracker.remove_character("Thoric");
+        fixture.tick(10);
+        assert!(fixture.events().is_empty());
+        assert_eq!(fixture.shown(), json!("{bless}{3}"));
+    }
+}
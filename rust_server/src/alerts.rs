@@ -0,0 +1,552 @@
+// --- Alert Rules ---
+// Alerts that used to be hardcoded in the viewer are defined once on the server in a TOML file
+// (ALERT_RULES_FILE) and shared by every viewer:
+//
+//   [[rule]]
+//   name = "low_health"
+//   when = "HEALTH / HEALTH_MAX < 0.3"
+//   clear = "HEALTH / HEALTH_MAX > 0.5"   # optional hysteresis, defaults to `when` turning false
+//   severity = "critical"                 # info, warning or critical
+//   message = "{name} is at {HEALTH}/{HEALTH_MAX}"
+//   cooldown_seconds = 60                 # minimum time between two firings per character
+//   characters = ["Thoric"]               # optional, all characters if absent
+//
+// Rules are evaluated per character on every update. A rule fires when its condition turns true
+// and stays active until it clears; `changed`/`became` rules fire on every matching update and are
+// never active. Firings and clears go to v2 viewers as `alert`/`alert_cleared` events, active
+// alerts are part of the snapshot, and GET /api/alerts returns the recent history.
+//
+// The rules file is read again on a configuration reload. Alerts of rules that keep their name
+// stay active; those of removed rules, or of characters a rule no longer covers, are cleared.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::conditions::{Condition, ConditionError};
+use crate::viewer_events::{ViewerEvent, ViewerEvents};
+use crate::webhooks::{WebhookEvent, Webhooks};
+use crate::{system_time_serde, CharacterDataMap, SharedState};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleDefinition {
+    name: String,
+    when: String,
+    clear: Option<String>,
+    #[serde(default)]
+    severity: Severity,
+    message: Option<String>,
+    #[serde(default)]
+    cooldown_seconds: u64,
+    #[serde(default)]
+    characters: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    when: Condition,
+    clear: Option<Condition>,
+    momentary: bool,
+    severity: Severity,
+    message: String,
+    cooldown: Duration,
+    characters: HashSet<String>, // Empty means every character
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RuleError {
+    #[error("failed to read '{path}': {error}")]
+    Io { path: String, error: std::io::Error },
+    #[error("invalid rules file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("rule '{rule}': invalid {field} condition: {error}")]
+    Condition { rule: String, field: &'static str, error: ConditionError },
+    #[error("rule '{0}': `changed`/`became` rules fire once per update and cannot have a clear condition")]
+    MomentaryClear(String),
+    #[error("duplicate rule name '{0}'")]
+    Duplicate(String),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AlertRecord {
+    pub id: u64,
+    pub rule: String,
+    pub character: String,
+    pub severity: Severity,
+    pub message: String,
+    pub active: bool, // Always false for `changed`/`became` rules
+    #[serde(with = "system_time_serde")]
+    pub fired_at: SystemTime,
+    #[serde(serialize_with = "system_time_serde::serialize_option")]
+    pub cleared_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    active: Option<AlertRecord>,
+    last_fired: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+struct EngineState {
+    rules: HashMap<(String, String), RuleState>, // (character, rule name)
+    history: VecDeque<AlertRecord>,
+}
+
+impl EngineState {
+    fn mark_cleared(&mut self, id: u64, now: SystemTime) {
+        if let Some(record) = self.history.iter_mut().find(|r| r.id == id) {
+            record.active = false;
+            record.cleared_at = Some(now);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AlertEngine {
+    rules: RwLock<Arc<Vec<Rule>>>,
+    history_len: AtomicUsize,
+    next_id: AtomicU64,
+    state: StdMutex<EngineState>,
+    events: ViewerEvents,
+    webhooks: Webhooks,
+}
+
+impl AlertEngine {
+    /// Loads the rules file, or creates an engine without rules if `path` is empty.
+    pub fn load(path: &str, history_len: usize, events: ViewerEvents, webhooks: Webhooks) -> Result<Self, RuleError> {
+        Ok(Self {
+            rules: RwLock::new(Arc::new(read_rules(path)?)),
+            history_len: AtomicUsize::new(history_len),
+            next_id: AtomicU64::new(1),
+            state: StdMutex::new(EngineState::default()),
+            events,
+            webhooks,
+        })
+    }
+
+    /// Replaces the rules with those in `path`, or with none if it is empty. On error the
+    /// current rules stay.
+    pub fn reload(&self, path: &str, history_len: usize) -> Result<(), RuleError> {
+        let rules = read_rules(path)?;
+        let mut state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+        let mut cleared = Vec::new();
+        state.rules.retain(|(character, name), rule_state| {
+            let covered = rules.iter().any(|rule| &rule.name == name && (rule.characters.is_empty() || rule.characters.contains(character)));
+            if !covered {
+                cleared.extend(rule_state.active.take());
+            }
+            covered
+        });
+        for alert in cleared {
+            info!("Alert '{}' cleared for '{}' (id {}): rule removed.", alert.rule, alert.character, alert.id);
+            state.mark_cleared(alert.id, now);
+            self.publish_cleared(alert.id, &alert.rule, &alert.character);
+        }
+        self.history_len.store(history_len, Ordering::Relaxed);
+        while state.history.len() > history_len {
+            state.history.pop_front();
+        }
+        *self.rules.write().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    fn rules(&self) -> Arc<Vec<Rule>> {
+        Arc::clone(&self.rules.read().unwrap())
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules().len()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules().is_empty()
+    }
+
+    /// Evaluates every rule for one character update, publishing fired and cleared alerts.
+    pub fn evaluate(&self, character: &str, previous: Option<&CharacterDataMap>, current: &CharacterDataMap, now: SystemTime) {
+        let rules = self.rules();
+        let history_len = self.history_len.load(Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        for rule in rules.iter() {
+            if !rule.characters.is_empty() && !rule.characters.contains(character) {
+                continue;
+            }
+            let rule_state = state.rules.entry((character.to_string(), rule.name.clone())).or_default();
+
+            if let Some(active) = &rule_state.active {
+                let cleared = match &rule.clear {
+                    Some(clear) => clear.evaluate(previous, current) == Some(true),
+                    None => rule.when.evaluate(previous, current) == Some(false),
+                };
+                if cleared {
+                    let id = active.id;
+                    rule_state.active = None;
+                    info!("Alert '{}' cleared for '{}' (id {}).", rule.name, character, id);
+                    state.mark_cleared(id, now);
+                    self.publish_cleared(id, &rule.name, character);
+                }
+                continue;
+            }
+
+            if rule.when.evaluate(previous, current) != Some(true) {
+                continue;
+            }
+            // Still true once the cooldown has passed means it fires on the first update after.
+            // A clock that went backwards counts as still cooling down.
+            if rule_state.last_fired.is_some_and(|last| now.duration_since(last).map_or(true, |elapsed| elapsed < rule.cooldown)) {
+                debug!("Alert '{}' for '{}' suppressed by cooldown.", rule.name, character);
+                continue;
+            }
+            let record = AlertRecord {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                rule: rule.name.clone(),
+                character: character.to_string(),
+                severity: rule.severity,
+                message: render_message(&rule.message, &rule.name, character, current),
+                active: !rule.momentary,
+                fired_at: now,
+                cleared_at: None,
+            };
+            rule_state.last_fired = Some(now);
+            if record.active {
+                rule_state.active = Some(record.clone());
+            }
+            info!("Alert '{}' fired for '{}' (id {}): {}", rule.name, character, record.id, record.message);
+            state.history.push_back(record.clone());
+            while state.history.len() > history_len {
+                state.history.pop_front();
+            }
+            self.webhooks.notify(WebhookEvent::AlertFired { alert: record.clone() });
+            self.events.publish_all(ViewerEvent::Alert { alert: record });
+        }
+    }
+
+    /// Currently active alerts, oldest first, optionally limited to some characters.
+    pub fn active(&self, filter: Option<&HashSet<String>>) -> Vec<AlertRecord> {
+        let state = self.state.lock().unwrap();
+        let mut active: Vec<AlertRecord> = state.rules.values()
+            .filter_map(|s| s.active.clone())
+            .filter(|a| filter.is_none_or(|names| names.contains(&a.character)))
+            .collect();
+        active.sort_by_key(|a| a.id);
+        active
+    }
+
+    /// Forgets a pruned character's rule state, clearing its active alerts. History is kept.
+    pub fn remove_character(&self, character: &str, now: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        let mut cleared = Vec::new();
+        state.rules.retain(|(name, _), rule_state| {
+            if name != character {
+                return true;
+            }
+            cleared.extend(rule_state.active.take());
+            false
+        });
+        for alert in cleared {
+            state.mark_cleared(alert.id, now);
+            self.publish_cleared(alert.id, &alert.rule, &alert.character);
+        }
+    }
+
+    fn publish_cleared(&self, id: u64, rule: &str, character: &str) {
+        self.webhooks.notify(WebhookEvent::AlertCleared { id, rule: rule.to_string(), character: character.to_string() });
+        self.events.publish_all(ViewerEvent::AlertCleared { id, rule: rule.to_string(), character: character.to_string() });
+    }
+
+    fn history(&self, character: Option<&str>, limit: usize) -> Vec<AlertRecord> {
+        let state = self.state.lock().unwrap();
+        state.history.iter().rev()
+            .filter(|r| character.is_none_or(|name| r.character == name))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+fn read_rules(path: &str) -> Result<Vec<Rule>, RuleError> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    let text = std::fs::read_to_string(path).map_err(|error| RuleError::Io { path: path.to_string(), error })?;
+    compile(toml::from_str::<RulesFile>(&text)?)
+}
+
+fn compile(file: RulesFile) -> Result<Vec<Rule>, RuleError> {
+    let mut names = HashSet::new();
+    file.rules.into_iter().map(|definition| {
+        if !names.insert(definition.name.clone()) {
+            return Err(RuleError::Duplicate(definition.name));
+        }
+        let parse = |field: &'static str, source: &str| Condition::parse(source)
+            .map_err(|error| RuleError::Condition { rule: definition.name.clone(), field, error });
+        let when = parse("when", &definition.when)?;
+        let clear = definition.clear.as_deref().map(|clear| parse("clear", clear)).transpose()?;
+        let momentary = when.is_momentary();
+        if momentary && clear.is_some() {
+            return Err(RuleError::MomentaryClear(definition.name));
+        }
+        Ok(Rule {
+            message: definition.message.unwrap_or_else(|| format!("{{name}}: {}", definition.name)),
+            name: definition.name,
+            when,
+            clear,
+            momentary,
+            severity: definition.severity,
+            cooldown: Duration::from_secs(definition.cooldown_seconds),
+            characters: definition.characters.into_iter().collect(),
+        })
+    }).collect()
+}
+
+/// Replaces `{name}` with the character, `{rule}` with the rule name and `{KEY}` with the
+/// character's current value for KEY (`?` if it has none).
+fn render_message(template: &str, rule: &str, character: &str, data: &CharacterDataMap) -> String {
+    let mut message = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else { break };
+        message.push_str(&rest[..start]);
+        match &rest[start + 1..end] {
+            "name" => message.push_str(character),
+            "rule" => message.push_str(rule),
+            key => match data.get(key) {
+                Some(Value::String(s)) => message.push_str(s),
+                Some(value) => message.push_str(&value.to_string()),
+                None => message.push('?'),
+            },
+        }
+        rest = &rest[end + 1..];
+    }
+    message.push_str(rest);
+    message
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlertsQuery {
+    character: Option<String>,
+    limit: Option<usize>,
+}
+
+// GET /api/alerts
+pub async fn list_alerts(State(state): State<SharedState>, Query(query): Query<AlertsQuery>) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(state.alerts.history_len.load(Ordering::Relaxed));
+    Json(state.alerts.history(query.character.as_deref(), limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::sync::broadcast::Receiver;
+    use uuid::Uuid;
+
+    use crate::viewer_events::GroupEvent;
+
+    fn engine(rules: &str, history_len: usize) -> (AlertEngine, Receiver<Arc<GroupEvent>>) {
+        let events = ViewerEvents::new();
+        let receiver = events.subscribe();
+        let engine = AlertEngine::load("", history_len, events, Webhooks::default()).unwrap();
+        *engine.rules.write().unwrap() = Arc::new(compile(toml::from_str(rules).unwrap()).unwrap());
+        (engine, receiver)
+    }
+
+    fn data(values: &[(&str, &str)]) -> CharacterDataMap {
+        values.iter().map(|(k, v)| (k.to_string(), json!(v))).collect()
+    }
+
+    fn health(value: &str) -> CharacterDataMap {
+        data(&[("HEALTH", value)])
+    }
+
+    /// (event name, rule) of every event published since the last call.
+    fn received(receiver: &mut Receiver<Arc<GroupEvent>>) -> Vec<(&'static str, String)> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|e| match &e.event {
+                ViewerEvent::Alert { alert } => ("alert", alert.rule.clone()),
+                ViewerEvent::AlertCleared { rule, .. } => ("alert_cleared", rule.clone()),
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    /// Feeds `updates` to the engine as consecutive updates for Thoric, `seconds` apart.
+    fn feed(engine: &AlertEngine, updates: &[CharacterDataMap], start: SystemTime, seconds: u64) {
+        let mut previous: Option<&CharacterDataMap> = None;
+        for (i, current) in updates.iter().enumerate() {
+            engine.evaluate("Thoric", previous, current, start + Duration::from_secs(i as u64 * seconds));
+            previous = Some(current);
+        }
+    }
+
+    const HYSTERESIS: &str = r#"
+        [[rule]]
+        name = "low_health"
+        when = "HEALTH < 30"
+        clear = "HEALTH > 50"
+    "#;
+
+    #[test]
+    fn hysteresis_keeps_alerts_active_until_the_clear_condition() {
+        let (engine, mut events) = engine(HYSTERESIS, 10);
+        feed(&engine, &[health("20"), health("10"), health("40")], SystemTime::now(), 1);
+        assert_eq!(received(&mut events), vec![("alert", "low_health".to_string())]);
+        assert_eq!(engine.active(None).len(), 1);
+
+        engine.evaluate("Thoric", Some(&health("40")), &health("60"), SystemTime::now());
+        assert_eq!(received(&mut events), vec![("alert_cleared", "low_health".to_string())]);
+        assert!(engine.active(None).is_empty());
+        let history = engine.history(None, 10);
+        assert!(!history[0].active && history[0].cleared_at.is_some());
+    }
+
+    #[test]
+    fn cleared_alerts_rearm() {
+        let (engine, mut events) = engine(HYSTERESIS, 10);
+        feed(&engine, &[health("20"), health("60"), health("20")], SystemTime::now(), 1);
+        let fired: Vec<&str> = received(&mut events).iter().map(|(event, _)| *event).collect();
+        assert_eq!(fired, vec!["alert", "alert_cleared", "alert"]);
+        assert_eq!(engine.active(None).len(), 1);
+    }
+
+    #[test]
+    fn without_a_clear_condition_alerts_clear_when_the_rule_turns_false() {
+        let (engine, mut events) = engine("[[rule]]\nname = \"low\"\nwhen = \"HEALTH < 30\"", 10);
+        // A missing key neither fires nor clears.
+        feed(&engine, &[health("20"), data(&[]), health("40")], SystemTime::now(), 1);
+        let fired: Vec<&str> = received(&mut events).iter().map(|(event, _)| *event).collect();
+        assert_eq!(fired, vec!["alert", "alert_cleared"]);
+    }
+
+    #[test]
+    fn cooldown_suppresses_refiring() {
+        let (engine, mut events) = engine("[[rule]]\nname = \"low\"\nwhen = \"HEALTH < 30\"\ncooldown_seconds = 60", 10);
+        let start = SystemTime::now();
+        // Fires at 0s and clears at 20s; 40s is still within the cooldown, 60s is not.
+        feed(&engine, &[health("20"), health("40"), health("20"), health("20")], start, 20);
+        let fired: Vec<&str> = received(&mut events).iter().map(|(event, _)| *event).collect();
+        assert_eq!(fired, vec!["alert", "alert_cleared", "alert"]);
+        assert_eq!(engine.active(None)[0].fired_at, start + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn momentary_rules_fire_on_every_match_and_are_never_active() {
+        let rules = r#"
+            [[rule]]
+            name = "new_opponent"
+            when = "OPPONENT_NAME changed"
+            message = "{name} is fighting {OPPONENT_NAME}"
+
+            [[rule]]
+            name = "disconnected"
+            when = "CONNECTED became NO"
+        "#;
+        let (engine, mut events) = engine(rules, 10);
+        let updates = [
+            data(&[("OPPONENT_NAME", "a rat"), ("CONNECTED", "YES")]),
+            data(&[("OPPONENT_NAME", "a troll"), ("CONNECTED", "YES")]),
+            data(&[("OPPONENT_NAME", "a troll"), ("CONNECTED", "NO")]),
+            data(&[("OPPONENT_NAME", "a bat"), ("CONNECTED", "NO")]),
+        ];
+        feed(&engine, &updates, SystemTime::now(), 1);
+
+        let rules: Vec<String> = received(&mut events).into_iter().map(|(_, rule)| rule).collect();
+        assert_eq!(rules, vec!["new_opponent", "disconnected", "new_opponent"]);
+        assert!(engine.active(None).is_empty());
+        let history = engine.history(Some("Thoric"), 10);
+        assert_eq!(history[0].message, "Thoric is fighting a bat");
+        assert!(history.iter().all(|r| !r.active && r.cleared_at.is_none()));
+    }
+
+    #[test]
+    fn history_is_bounded_and_newest_first() {
+        let (engine, _events) = engine("[[rule]]\nname = \"opponent\"\nwhen = \"OPPONENT_NAME changed\"\nmessage = \"{OPPONENT_NAME}\"", 2);
+        let updates: Vec<CharacterDataMap> = ["a", "b", "c", "d"].iter().map(|o| data(&[("OPPONENT_NAME", o)])).collect();
+        feed(&engine, &updates, SystemTime::now(), 1);
+        let messages: Vec<String> = engine.history(None, 10).into_iter().map(|r| r.message).collect();
+        assert_eq!(messages, vec!["d", "c"]);
+        assert_eq!(engine.history(None, 1).len(), 1);
+        assert!(engine.history(Some("Alice"), 10).is_empty());
+    }
+
+    #[test]
+    fn reload_clears_alerts_of_removed_or_narrowed_rules() {
+        let rules = r#"
+            [[rule]]
+            name = "low_health"
+            when = "HEALTH < 30"
+
+            [[rule]]
+            name = "low_mana"
+            when = "MANA < 30"
+        "#;
+        let (engine, mut events) = engine(rules, 10);
+        let low = data(&[("HEALTH", "10"), ("MANA", "10")]);
+        engine.evaluate("Thoric", None, &low, SystemTime::now());
+        engine.evaluate("Alice", None, &low, SystemTime::now());
+        received(&mut events);
+
+        let path = std::env::temp_dir().join(format!("alert_rules_{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, "[[rule]]\nname = \"low_health\"\nwhen = \"HEALTH < 30\"\ncharacters = [\"Thoric\"]\n").unwrap();
+        let reloaded = engine.reload(path.to_str().unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        reloaded.unwrap();
+
+        let cleared = received(&mut events);
+        assert_eq!(cleared.len(), 3);
+        assert!(cleared.iter().all(|(event, _)| *event == "alert_cleared"));
+        let active: Vec<(String, String)> = engine.active(None).into_iter().map(|a| (a.character, a.rule)).collect();
+        assert_eq!(active, vec![("Thoric".to_string(), "low_health".to_string())]);
+        assert_eq!(engine.history(None, 10).len(), 1);
+        // The kept alert does not fire again.
+        engine.evaluate("Thoric", Some(&low), &low, SystemTime::now());
+        assert!(received(&mut events).is_empty());
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let error = |rules: &str| compile(toml::from_str(rules).unwrap()).unwrap_err().to_string();
+        assert_eq!(error("[[rule]]\nname = \"a\"\nwhen = \"HEALTH <\"\n[[rule]]\nname = \"a\"\nwhen = \"HEALTH < 1\""),
+            format!("rule 'a': invalid when condition: {}", Condition::parse("HEALTH <").unwrap_err()));
+        assert_eq!(error("[[rule]]\nname = \"a\"\nwhen = \"HEALTH < 1\"\n[[rule]]\nname = \"a\"\nwhen = \"HEALTH < 2\""), "duplicate rule name 'a'");
+        assert_eq!(error("[[rule]]\nname = \"a\"\nwhen = \"HEALTH changed\"\nclear = \"HEALTH > 1\""),
+            "rule 'a': `changed`/`became` rules fire once per update and cannot have a clear condition");
+        assert!(toml::from_str::<RulesFile>("[[rule]]\nname = \"a\"\nwhen = \"HEALTH < 1\"\nsevere = \"info\"").is_err());
+    }
+
+    #[test]
+    fn messages_substitute_name_rule_and_keys() {
+        let data = CharacterDataMap::from([("HEALTH".to_string(), json!(20)), ("CLASS".to_string(), json!("Mage"))]);
+        assert_eq!(render_message("{name} ({CLASS}) at {HEALTH}, {rule}: {MANA}", "low", "Thoric", &data), "Thoric (Mage) at 20, low: ?");
+        assert_eq!(render_message("no keys", "low", "Thoric", &data), "no keys");
+        assert_eq!(render_message("{name} {unclosed", "low", "Thoric", &data), "Thoric {unclosed");
+    }
+}
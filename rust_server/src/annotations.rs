@@ -0,0 +1,274 @@
+// --- Viewer Chat & Pinned Notes ---
+// Viewers on /ws can talk to each other and pin short notes to a character card. Everything is
+// scoped to a group chosen with `?group=` (default "default"), so several raids can share a
+// server. Each group keeps a bounded chat history and a bounded number of notes per character,
+// which new v2 viewers receive in their snapshot. Live changes go out as viewer events (see
+// viewer_events.rs).
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::system_time_serde;
+use crate::viewer_events::{ViewerEvent, ViewerEvents};
+
+pub const DEFAULT_GROUP: &str = "default";
+const MAX_GROUP_LEN: usize = 32;
+const MAX_AUTHOR_LEN: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct AnnotationConfig {
+    pub chat_history: usize,           // Chat messages kept per group
+    pub notes_per_character: usize,    // Pinned notes kept per character and group; the oldest is unpinned first
+    pub max_text_len: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChatMessage {
+    pub id: u64,
+    pub author: String,
+    pub text: String,
+    #[serde(with = "system_time_serde")]
+    pub time: SystemTime,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PinnedNote {
+    pub id: u64,
+    pub character: String,
+    pub author: String,
+    pub text: String,
+    #[serde(with = "system_time_serde")]
+    pub time: SystemTime,
+}
+
+/// What a group's viewers see in their snapshot.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GroupAnnotations {
+    pub chat: VecDeque<ChatMessage>,
+    pub notes: HashMap<String, Vec<PinnedNote>>,
+}
+
+/// Text messages viewers send over /ws.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ViewerMessage {
+    Chat { text: String, author: Option<String> },
+    Pin { character: String, text: String, author: Option<String> },
+    Unpin { character: String, id: u64 },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AnnotationError {
+    #[error("message is empty")]
+    Empty,
+    #[error("message is longer than {0} characters")]
+    TooLong(usize),
+    #[error("unknown character '{0}'")]
+    UnknownCharacter(String),
+    #[error("note {0} not found")]
+    UnknownNote(u64),
+}
+
+#[derive(Debug)]
+pub struct AnnotationStore {
+    config: AnnotationConfig,
+    next_id: AtomicU64,
+    groups: DashMap<String, GroupAnnotations>,
+    events: ViewerEvents,
+}
+
+impl AnnotationStore {
+    pub fn new(config: AnnotationConfig, events: ViewerEvents) -> Self {
+        Self { config, next_id: AtomicU64::new(1), groups: DashMap::new(), events }
+    }
+
+    pub fn snapshot(&self, group: &str) -> Option<GroupAnnotations> {
+        self.groups.get(group).map(|annotations| annotations.clone())
+    }
+
+    /// Applies a viewer message. `character_exists` is only consulted for pins.
+    pub fn apply(
+        &self,
+        group: &str,
+        message: ViewerMessage,
+        character_exists: impl FnOnce(&str) -> bool,
+        now: SystemTime,
+    ) -> Result<(), AnnotationError> {
+        match message {
+            ViewerMessage::Chat { text, author } => {
+                let message = ChatMessage { id: self.next_id(), author: author_name(author), text: self.validate(&text)?, time: now };
+                let mut annotations = self.groups.entry(group.to_string()).or_default();
+                annotations.chat.push_back(message.clone());
+                while annotations.chat.len() > self.config.chat_history {
+                    annotations.chat.pop_front();
+                }
+                drop(annotations);
+                self.events.publish(group, ViewerEvent::Chat { message });
+            }
+            ViewerMessage::Pin { character, text, author } => {
+                let text = self.validate(&text)?;
+                if !character_exists(&character) {
+                    return Err(AnnotationError::UnknownCharacter(character));
+                }
+                let note = PinnedNote { id: self.next_id(), character: character.clone(), author: author_name(author), text, time: now };
+                let mut annotations = self.groups.entry(group.to_string()).or_default();
+                let notes = annotations.notes.entry(character.clone()).or_default();
+                notes.push(note.clone());
+                let overflow = notes.len().saturating_sub(self.config.notes_per_character);
+                let unpinned: Vec<u64> = notes.drain(..overflow).map(|n| n.id).collect();
+                drop(annotations);
+                for id in unpinned {
+                    self.events.publish(group, ViewerEvent::NoteUnpinned { character: character.clone(), id });
+                }
+                self.events.publish(group, ViewerEvent::NotePinned { note });
+            }
+            ViewerMessage::Unpin { character, id } => {
+                let mut annotations = self.groups.get_mut(group).ok_or(AnnotationError::UnknownNote(id))?;
+                let notes = annotations.notes.get_mut(&character).ok_or(AnnotationError::UnknownNote(id))?;
+                let index = notes.iter().position(|n| n.id == id).ok_or(AnnotationError::UnknownNote(id))?;
+                notes.remove(index);
+                if notes.is_empty() {
+                    annotations.notes.remove(&character);
+                }
+                drop(annotations);
+                self.events.publish(group, ViewerEvent::NoteUnpinned { character, id });
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the notes of a pruned character in every group.
+    pub fn remove_character(&self, character: &str) {
+        for mut annotations in self.groups.iter_mut() {
+            annotations.notes.remove(character);
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn validate(&self, text: &str) -> Result<String, AnnotationError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(AnnotationError::Empty);
+        }
+        if text.chars().count() > self.config.max_text_len {
+            return Err(AnnotationError::TooLong(self.config.max_text_len));
+        }
+        Ok(text.to_string())
+    }
+}
+
+fn author_name(author: Option<String>) -> String {
+    author.map(|a| a.trim().chars().take(MAX_AUTHOR_LEN).collect::<String>())
+        .filter(|a| !a.is_empty())
+        .unwrap_or_else(|| "viewer".to_string())
+}
+
+/// Normalizes the `?group=` value; anything unusable falls back to the default group.
+pub fn group_name(requested: Option<&str>) -> String {
+    requested.map(str::trim)
+        .filter(|g| !g.is_empty() && g.len() <= MAX_GROUP_LEN)
+        .filter(|g| g.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(DEFAULT_GROUP)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use tokio::sync::broadcast::Receiver;
+
+    use crate::viewer_events::GroupEvent;
+
+    fn store() -> (AnnotationStore, Receiver<Arc<GroupEvent>>) {
+        let events = ViewerEvents::new();
+        let receiver = events.subscribe();
+        (AnnotationStore::new(AnnotationConfig { chat_history: 3, notes_per_character: 2, max_text_len: 10 }, events), receiver)
+    }
+
+    fn chat(text: &str) -> ViewerMessage {
+        ViewerMessage::Chat { text: text.to_string(), author: None }
+    }
+
+    fn pin(character: &str, text: &str) -> ViewerMessage {
+        ViewerMessage::Pin { character: character.to_string(), text: text.to_string(), author: Some("  Bob  ".to_string()) }
+    }
+
+    fn received(receiver: &mut Receiver<Arc<GroupEvent>>) -> Vec<ViewerEvent> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).map(|e| e.event.clone()).collect()
+    }
+
+    #[test]
+    fn chat_history_is_bounded_per_group() {
+        let (store, _events) = store();
+        let now = SystemTime::now();
+        for text in ["one", "two", "three", "four"] {
+            store.apply("raid", chat(text), |_| true, now).unwrap();
+        }
+        store.apply("other", chat("hello"), |_| true, now).unwrap();
+
+        let texts: Vec<String> = store.snapshot("raid").unwrap().chat.into_iter().map(|m| m.text).collect();
+        assert_eq!(texts, vec!["two", "three", "four"]);
+        assert_eq!(store.snapshot("other").unwrap().chat.len(), 1);
+        assert!(store.snapshot("nobody").is_none());
+    }
+
+    #[test]
+    fn pinning_past_the_limit_unpins_the_oldest_note() {
+        let (store, mut events) = store();
+        let now = SystemTime::now();
+        for text in ["a", "b", "c"] {
+            store.apply("raid", pin("Thoric", text), |_| true, now).unwrap();
+        }
+
+        let notes = &store.snapshot("raid").unwrap().notes["Thoric"];
+        assert_eq!(notes.iter().map(|n| n.text.as_str()).collect::<Vec<_>>(), vec!["b", "c"]);
+        assert_eq!(notes[0].author, "Bob");
+        let events = received(&mut events);
+        assert!(matches!(&events[2], ViewerEvent::NoteUnpinned { character, id } if character == "Thoric" && *id == 1));
+        assert!(matches!(&events[3], ViewerEvent::NotePinned { note } if note.text == "c"));
+    }
+
+    #[test]
+    fn invalid_messages_change_nothing() {
+        let (store, mut events) = store();
+        let now = SystemTime::now();
+        assert!(matches!(store.apply("raid", chat("   "), |_| true, now), Err(AnnotationError::Empty)));
+        assert!(matches!(store.apply("raid", chat("eleven char"), |_| true, now), Err(AnnotationError::TooLong(10))));
+        assert!(matches!(store.apply("raid", pin("Nobody", "hi"), |_| false, now), Err(AnnotationError::UnknownCharacter(_))));
+        let unpin = ViewerMessage::Unpin { character: "Thoric".to_string(), id: 7 };
+        assert!(matches!(store.apply("raid", unpin, |_| true, now), Err(AnnotationError::UnknownNote(7))));
+        assert!(store.snapshot("raid").is_none());
+        assert!(received(&mut events).is_empty());
+    }
+
+    #[test]
+    fn unpinning_and_pruning_remove_notes() {
+        let (store, _events) = store();
+        let now = SystemTime::now();
+        store.apply("raid", pin("Thoric", "a"), |_| true, now).unwrap();
+        store.apply("raid", pin("Alice", "b"), |_| true, now).unwrap();
+        store.apply("other", pin("Alice", "c"), |_| true, now).unwrap();
+
+        store.apply("raid", ViewerMessage::Unpin { character: "Thoric".to_string(), id: 1 }, |_| true, now).unwrap();
+        store.remove_character("Alice");
+        assert!(store.snapshot("raid").unwrap().notes.is_empty());
+        assert!(store.snapshot("other").unwrap().notes.is_empty());
+    }
+
+    #[test]
+    fn unusable_group_names_fall_back_to_the_default() {
+        assert_eq!(group_name(Some(" raid-1 ")), "raid-1");
+        assert_eq!(group_name(Some("raid one")), DEFAULT_GROUP);
+        assert_eq!(group_name(Some(&"x".repeat(MAX_GROUP_LEN + 1))), DEFAULT_GROUP);
+        assert_eq!(group_name(None), DEFAULT_GROUP);
+    }
+}
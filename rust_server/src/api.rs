@@ -0,0 +1,163 @@
+// --- REST Read API ---
+// Read-only JSON endpoints for consumers that just want to poll current state (bots, MUD-side
+// scripts) without holding a WebSocket open. Single-character and single-key responses carry a
+// content-hash ETag so unchanged values can be answered with 304 Not Modified. Character data is
+// serialized in key order, so the same data always gives the same body and ETag.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::time::SystemTime;
+
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, error};
+
+use crate::{system_time_serde, CharacterMeta, SharedState};
+
+#[derive(Debug, Serialize)]
+struct CharacterSummary {
+    name: String,
+    connected: bool,
+    #[serde(with = "system_time_serde")]
+    last_update: SystemTime,
+    last_update_age_seconds: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct CharacterDetail<'a> {
+    name: &'a str,
+    data: BTreeMap<&'a String, &'a Value>, // Sorted, unlike the stored HashMap
+    #[serde(with = "system_time_serde")]
+    timestamp: SystemTime,
+    meta: CharacterMeta,
+}
+
+fn age_seconds(timestamp: SystemTime) -> u64 {
+    SystemTime::now().duration_since(timestamp).map_or(0, |age| age.as_secs())
+}
+
+/// Strong ETag over the exact JSON body that is sent.
+fn etag_for(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// RFC 9110 If-None-Match: a list of (possibly weak) tags, or `*`.
+fn if_none_match_hits(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Serializes `body` and answers with 304 if the client already has this exact representation.
+fn conditional_json<T: Serialize>(headers: &HeaderMap, body: &T) -> Response {
+    let json = match serde_json::to_string(body) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize API response: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let etag = etag_for(&json);
+    let etag_header = match HeaderValue::from_str(&etag) {
+        Ok(value) => value,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if if_none_match_hits(headers, &etag) {
+        debug!("API: If-None-Match hit for ETag {}", etag);
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag_header)]).into_response();
+    }
+    (
+        StatusCode::OK,
+        [(header::ETAG, etag_header), (header::CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+        json,
+    ).into_response()
+}
+
+// GET /api/characters
+pub async fn list_characters(State(state): State<SharedState>) -> impl IntoResponse {
+    let characters: Vec<CharacterSummary> = state.store.summaries()
+        .into_iter()
+        .map(|(name, connected, last_update)| CharacterSummary {
+            name,
+            connected,
+            last_update,
+            last_update_age_seconds: age_seconds(last_update),
+        })
+        .collect();
+    Json(characters)
+}
+
+// GET /api/characters/:name
+pub async fn get_character(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(info) = state.store.get(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    conditional_json(&headers, &CharacterDetail { name: &name, data: info.data.iter().collect(), timestamp: info.timestamp, meta: info.meta() })
+}
+
+// GET /api/characters/:name/:key
+pub async fn get_character_key(
+    State(state): State<SharedState>,
+    Path((name, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(info) = state.store.get(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some(value) = info.data.get(&key) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // The bare value keeps polling from MUD client scripts trivial to parse.
+    conditional_json::<Value>(&headers, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::CharacterDataMap;
+
+    fn etag_of(response: &Response) -> String {
+        response.headers()[header::ETAG].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn same_character_data_gives_the_same_etag() {
+        let timestamp = SystemTime::UNIX_EPOCH;
+        let meta = CharacterMeta { last_update: timestamp, first_seen: timestamp, source: "test".to_string(), connected: true };
+        // Every HashMap has its own random iteration order, so a few of them are bound to differ.
+        let etags: Vec<String> = (0..16).map(|_| {
+            let data: CharacterDataMap = (0..32).map(|i| (format!("KEY_{}", i), json!(i.to_string()))).collect();
+            let detail = CharacterDetail { name: "Alice", data: data.iter().collect(), timestamp, meta: meta.clone() };
+            etag_of(&conditional_json(&HeaderMap::new(), &detail))
+        }).collect();
+        assert!(etags.iter().all(|etag| *etag == etags[0]), "{:?}", etags);
+    }
+
+    #[test]
+    fn if_none_match_answers_not_modified() {
+        let value = json!("95");
+        let etag = etag_of(&conditional_json(&HeaderMap::new(), &value));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(&format!("\"other\", W/{}", etag)).unwrap());
+        assert_eq!(conditional_json(&headers, &value).status(), StatusCode::NOT_MODIFIED);
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert_eq!(conditional_json(&headers, &value).status(), StatusCode::OK);
+    }
+}
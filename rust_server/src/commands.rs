@@ -0,0 +1,418 @@
+// --- Viewer -> MUD Client Command Channel ---
+// Viewers queue commands for a character with POST /api/characters/:name/commands. The MUD client
+// receives them in the response body of its next POST /update, in the same `{key}{value}` format
+// it sends: `{COMMAND_ID}{12}{COMMAND}{cast heal Thoric}` per command. Delivery is at most once;
+// the client confirms execution by sending `{COMMAND_ACK}{12,13}` with a later update.
+//
+// A character only accepts commands matching its allowlist: `*`/`?` glob patterns separated by
+// `|`, taken from the COMMAND_ALLOWLIST key the character's own client sends, or from the
+// COMMAND_ALLOWLIST setting if it sends none. With neither, commands are refused.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::config::Live;
+use crate::{system_time_serde, CharacterDataMap, SharedState};
+
+pub const ALLOWLIST_KEY: &str = "COMMAND_ALLOWLIST";
+pub const ACK_KEY: &str = "COMMAND_ACK";
+const MAX_COMMAND_LEN: usize = 200;
+
+#[derive(Clone, Debug)]
+pub struct CommandConfig {
+    pub default_ttl: Duration,
+    pub max_ttl: Duration,
+    pub queue_capacity: usize,  // Pending commands per character
+    pub history: usize,         // Finished commands kept per character for GET
+    pub default_allowlist: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandStatus {
+    Pending,
+    Delivered,
+    Acknowledged,
+    Expired,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandRecord {
+    pub id: u64,
+    pub command: String,
+    pub issued_by: String,
+    pub status: CommandStatus,
+    #[serde(with = "system_time_serde")]
+    pub issued_at: SystemTime,
+    #[serde(with = "system_time_serde")]
+    pub expires_at: SystemTime,
+    #[serde(with = "system_time_serde")]
+    pub updated_at: SystemTime, // Time of the last status change
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("unknown character")]
+    UnknownCharacter,
+    #[error("invalid command: {0}")]
+    Invalid(&'static str),
+    #[error("command is not in the character's allowlist")]
+    NotAllowed,
+    #[error("too many pending commands for this character")]
+    QueueFull,
+}
+
+impl IntoResponse for CommandError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            CommandError::UnknownCharacter => StatusCode::NOT_FOUND,
+            CommandError::Invalid(_) => StatusCode::BAD_REQUEST,
+            CommandError::NotAllowed => StatusCode::FORBIDDEN,
+            CommandError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Pending commands and recent history per character, oldest first.
+#[derive(Debug)]
+pub struct CommandStore {
+    config: Live<CommandConfig>,
+    next_id: AtomicU64,
+    queues: DashMap<String, VecDeque<CommandRecord>>,
+}
+
+impl CommandStore {
+    pub fn new(config: CommandConfig) -> Self {
+        Self { config: Live::new(config), next_id: AtomicU64::new(1), queues: DashMap::new() }
+    }
+
+    /// Applies reloaded settings. Queued commands keep the expiry they were issued with.
+    pub fn set_config(&self, config: CommandConfig) {
+        info!("Command Channel Config: {:?}", config);
+        self.config.set(config);
+    }
+
+    pub fn enqueue(
+        &self,
+        character: &str,
+        data: &CharacterDataMap,
+        command: &str,
+        issued_by: String,
+        ttl: Option<Duration>,
+        now: SystemTime,
+    ) -> Result<CommandRecord, CommandError> {
+        let config = self.config.get();
+        let command = command.trim();
+        validate(command)?;
+        let allowlist = match data.get(ALLOWLIST_KEY) {
+            Some(Value::String(patterns)) => patterns.as_str(),
+            _ => config.default_allowlist.as_str(),
+        };
+        if !allowlist.split('|').map(str::trim).any(|pattern| !pattern.is_empty() && glob_match(pattern, command)) {
+            return Err(CommandError::NotAllowed);
+        }
+
+        let mut queue = self.queues.entry(character.to_string()).or_default();
+        expire(&mut queue, now);
+        if queue.iter().filter(|c| c.status == CommandStatus::Pending).count() >= config.queue_capacity {
+            return Err(CommandError::QueueFull);
+        }
+        let ttl = ttl.unwrap_or(config.default_ttl).min(config.max_ttl);
+        let record = CommandRecord {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            command: command.to_string(),
+            issued_by,
+            status: CommandStatus::Pending,
+            issued_at: now,
+            expires_at: now + ttl,
+            updated_at: now,
+        };
+        queue.push_back(record.clone());
+        trim_history(&mut queue, config.history);
+        Ok(record)
+    }
+
+    /// Marks every unexpired pending command as delivered and returns them in issue order.
+    pub fn take_pending(&self, character: &str, now: SystemTime) -> Vec<CommandRecord> {
+        let Some(mut queue) = self.queues.get_mut(character) else { return Vec::new() };
+        expire(&mut queue, now);
+        let delivered: Vec<CommandRecord> = queue.iter_mut()
+            .filter(|c| c.status == CommandStatus::Pending)
+            .map(|c| {
+                c.status = CommandStatus::Delivered;
+                c.updated_at = now;
+                c.clone()
+            })
+            .collect();
+        trim_history(&mut queue, self.config.get().history);
+        delivered
+    }
+
+    /// Marks delivered commands as executed. Returns how many ids matched.
+    pub fn acknowledge(&self, character: &str, ids: &[u64], now: SystemTime) -> usize {
+        let Some(mut queue) = self.queues.get_mut(character) else { return 0 };
+        let mut acknowledged = 0;
+        for record in queue.iter_mut().filter(|c| c.status == CommandStatus::Delivered && ids.contains(&c.id)) {
+            record.status = CommandStatus::Acknowledged;
+            record.updated_at = now;
+            acknowledged += 1;
+        }
+        acknowledged
+    }
+
+    pub fn list(&self, character: &str, now: SystemTime) -> Vec<CommandRecord> {
+        let Some(mut queue) = self.queues.get_mut(character) else { return Vec::new() };
+        expire(&mut queue, now);
+        queue.iter().cloned().collect()
+    }
+
+    pub fn remove(&self, character: &str) {
+        self.queues.remove(character);
+    }
+}
+
+fn expire(queue: &mut VecDeque<CommandRecord>, now: SystemTime) {
+    for record in queue.iter_mut().filter(|c| c.status == CommandStatus::Pending && c.expires_at <= now) {
+        record.status = CommandStatus::Expired;
+        record.updated_at = now;
+    }
+}
+
+/// Drops the oldest finished commands beyond `history`. Pending commands are never dropped.
+fn trim_history(queue: &mut VecDeque<CommandRecord>, history: usize) {
+    let mut finished = queue.iter().filter(|c| c.status != CommandStatus::Pending).count();
+    while finished > history {
+        let Some(index) = queue.iter().position(|c| c.status != CommandStatus::Pending) else { break };
+        queue.remove(index);
+        finished -= 1;
+    }
+}
+
+/// Rejects anything a MUD client would interpret as more than one plain command.
+fn validate(command: &str) -> Result<(), CommandError> {
+    if command.is_empty() {
+        return Err(CommandError::Invalid("empty command"));
+    }
+    if command.len() > MAX_COMMAND_LEN {
+        return Err(CommandError::Invalid("command too long"));
+    }
+    // `;` separates commands in zMUD, CMud and TinTin++, braces would break the response format,
+    // and a leading `#` is a client-side script command.
+    if command.chars().any(|c| c.is_control() || matches!(c, ';' | '{' | '}')) || command.starts_with('#') {
+        return Err(CommandError::Invalid("command contains reserved characters"));
+    }
+    Ok(())
+}
+
+/// Case-insensitive glob match where `*` matches any run of characters and `?` exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Parses the ids from a `{COMMAND_ACK}` value: a single number or a comma separated list.
+pub fn ack_ids(value: &Value) -> Vec<u64> {
+    match value {
+        Value::Number(n) => n.as_u64().into_iter().collect(),
+        Value::String(s) => s.split(|c: char| c == ',' || c.is_whitespace()).filter_map(|id| id.trim().parse().ok()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The `/update` response body carrying delivered commands.
+pub fn response_body(commands: &[CommandRecord]) -> String {
+    commands.iter().map(|c| format!("{{COMMAND_ID}}{{{}}}{{COMMAND}}{{{}}}", c.id, c.command)).collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewCommand {
+    command: String,
+    ttl_seconds: Option<u64>,
+    issued_by: Option<String>,
+}
+
+// POST /api/characters/:name/commands
+pub async fn post_command(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    Json(request): Json<NewCommand>,
+) -> Result<impl IntoResponse, CommandError> {
+    let info = state.store.get(&name).ok_or(CommandError::UnknownCharacter)?;
+    let issued_by = request.issued_by.unwrap_or_else(|| "viewer".to_string());
+    let record = state.commands.enqueue(
+        &name,
+        &info.data,
+        &request.command,
+        issued_by,
+        request.ttl_seconds.map(Duration::from_secs),
+        SystemTime::now(),
+    ).inspect_err(|e| debug!("Rejected command for '{}': {}", name, e))?;
+    info!("Queued command {} for '{}' from {}: {}", record.id, name, record.issued_by, record.command);
+    Ok((StatusCode::ACCEPTED, Json(record)))
+}
+
+// GET /api/characters/:name/commands
+pub async fn list_commands(State(state): State<SharedState>, Path(name): Path<String>) -> impl IntoResponse {
+    Json(state.commands.list(&name, SystemTime::now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn store(default_allowlist: &str) -> CommandStore {
+        CommandStore::new(CommandConfig {
+            default_ttl: Duration::from_secs(30),
+            max_ttl: Duration::from_secs(60),
+            queue_capacity: 2,
+            history: 2,
+            default_allowlist: default_allowlist.to_string(),
+        })
+    }
+
+    fn allowing(patterns: &str) -> CharacterDataMap {
+        CharacterDataMap::from([(ALLOWLIST_KEY.to_string(), json!(patterns))])
+    }
+
+    fn statuses(store: &CommandStore, character: &str, now: SystemTime) -> Vec<CommandStatus> {
+        store.list(character, now).into_iter().map(|c| c.status).collect()
+    }
+
+    #[test]
+    fn globs_match_case_insensitively() {
+        assert!(glob_match("cast *", "CAST heal Thoric"));
+        assert!(glob_match("c?st *", "cast heal"));
+        assert!(glob_match("*heal*", "cast heal Thoric"));
+        assert!(glob_match("flee", "flee"));
+        assert!(!glob_match("flee", "flee north"));
+        assert!(!glob_match("cast *", "recast heal"));
+    }
+
+    #[test]
+    fn the_character_allowlist_overrides_the_default() {
+        let store = store("flee");
+        let now = SystemTime::now();
+        let enqueue = |data: &CharacterDataMap, command: &str| store.enqueue("Thoric", data, command, "viewer".to_string(), None, now);
+
+        assert!(enqueue(&CharacterDataMap::new(), "flee").is_ok());
+        assert!(matches!(enqueue(&CharacterDataMap::new(), "cast heal"), Err(CommandError::NotAllowed)));
+        assert!(enqueue(&allowing("cast *| quaff *"), "quaff red").is_ok());
+        assert!(matches!(enqueue(&allowing("cast *|quaff *"), "flee"), Err(CommandError::NotAllowed)));
+    }
+
+    #[test]
+    fn without_any_allowlist_everything_is_refused() {
+        let store = store("");
+        let result = store.enqueue("Thoric", &allowing(" | "), "flee", "viewer".to_string(), None, SystemTime::now());
+        assert!(matches!(result, Err(CommandError::NotAllowed)));
+    }
+
+    #[test]
+    fn reserved_characters_are_rejected() {
+        let store = store("*");
+        for command in ["", "   ", "flee;quit", "say {hi}", "#alias x", "say\nquit", &"a".repeat(MAX_COMMAND_LEN + 1)] {
+            let result = store.enqueue("Thoric", &CharacterDataMap::new(), command, "viewer".to_string(), None, SystemTime::now());
+            assert!(matches!(result, Err(CommandError::Invalid(_))), "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn commands_expire_before_delivery() {
+        let store = store("*");
+        let now = SystemTime::now();
+        let data = CharacterDataMap::new();
+        let short = store.enqueue("Thoric", &data, "flee", "viewer".to_string(), Some(Duration::from_secs(5)), now).unwrap();
+        // TTLs are capped at max_ttl.
+        let long = store.enqueue("Thoric", &data, "rest", "viewer".to_string(), Some(Duration::from_secs(600)), now).unwrap();
+        assert_eq!(long.expires_at, now + Duration::from_secs(60));
+
+        let delivered = store.take_pending("Thoric", now + Duration::from_secs(10));
+        assert_eq!(delivered.iter().map(|c| c.id).collect::<Vec<_>>(), vec![long.id]);
+        let listed = store.list("Thoric", now + Duration::from_secs(10));
+        assert_eq!(listed.iter().find(|c| c.id == short.id).map(|c| c.status), Some(CommandStatus::Expired));
+    }
+
+    #[test]
+    fn queues_are_per_character() {
+        let store = store("*");
+        let now = SystemTime::now();
+        let data = CharacterDataMap::new();
+        for command in ["flee", "rest"] {
+            store.enqueue("Thoric", &data, command, "viewer".to_string(), None, now).unwrap();
+        }
+        // Thoric's queue is full, Alice's is not.
+        assert!(matches!(store.enqueue("Thoric", &data, "sleep", "viewer".to_string(), None, now), Err(CommandError::QueueFull)));
+        let alice = store.enqueue("Alice", &data, "flee", "viewer".to_string(), None, now).unwrap();
+
+        assert_eq!(store.take_pending("Alice", now).iter().map(|c| c.id).collect::<Vec<_>>(), vec![alice.id]);
+        assert_eq!(statuses(&store, "Thoric", now), vec![CommandStatus::Pending, CommandStatus::Pending]);
+        store.remove("Thoric");
+        assert!(store.list("Thoric", now).is_empty());
+        assert_eq!(statuses(&store, "Alice", now), vec![CommandStatus::Delivered]);
+    }
+
+    #[test]
+    fn only_delivered_commands_are_acknowledged() {
+        let store = store("*");
+        let now = SystemTime::now();
+        let data = CharacterDataMap::new();
+        let first = store.enqueue("Thoric", &data, "flee", "viewer".to_string(), None, now).unwrap();
+        store.take_pending("Thoric", now);
+        let second = store.enqueue("Thoric", &data, "rest", "viewer".to_string(), None, now).unwrap();
+
+        assert_eq!(store.acknowledge("Thoric", &[first.id, second.id, 999], now), 1);
+        assert_eq!(store.acknowledge("Alice", &[first.id], now), 0);
+        assert_eq!(statuses(&store, "Thoric", now), vec![CommandStatus::Acknowledged, CommandStatus::Pending]);
+    }
+
+    #[test]
+    fn history_keeps_pending_commands_and_the_latest_finished_ones() {
+        let store = store("*");
+        let now = SystemTime::now();
+        let data = CharacterDataMap::new();
+        for command in ["a", "b", "c", "d"] {
+            store.enqueue("Thoric", &data, command, "viewer".to_string(), None, now).unwrap();
+            store.take_pending("Thoric", now);
+        }
+        store.enqueue("Thoric", &data, "e", "viewer".to_string(), None, now).unwrap();
+        let listed: Vec<String> = store.list("Thoric", now).into_iter().map(|c| c.command).collect();
+        assert_eq!(listed, vec!["c", "d", "e"]);
+    }
+
+    #[test]
+    fn ack_ids_accept_numbers_and_lists() {
+        assert_eq!(ack_ids(&json!(12)), vec![12]);
+        assert_eq!(ack_ids(&json!("12, 13 x 14")), vec![12, 13, 14]);
+        assert_eq!(ack_ids(&json!(null)), Vec::<u64>::new());
+    }
+}
@@ -0,0 +1,274 @@
+// --- Computed Keys ---
+// Operator-defined keys computed on every update, so MUD-specific logic lives in a config file
+// instead of the server or script.js. Definitions come from a TOML file (COMPUTED_KEYS_FILE) and
+// use the expression language from conditions.rs:
+//
+//   [[key]]
+//   name = "TANK_RATIO"
+//   expr = "round(HEALTH / OPPONENT_HEALTH, 2)"
+//
+// Keys are computed in file order after the derived metrics, so an expression can use derived
+// keys and the keys defined above it. A computed key replaces a client key of the same name and is
+// left out when an input is missing. The file is compiled at startup, where errors are fatal, and
+// recompiled whenever it changes; a broken edit is logged and the previous definitions stay active.
+
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{error, info};
+
+use crate::conditions::{ConditionError, Expr, Operand};
+use crate::{derived, CharacterDataMap};
+
+// Keys the server itself maintains.
+const RESERVED_KEYS: [&str; 2] = ["CHARACTER_NAME", "CONNECTED"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default, rename = "key")]
+    keys: Vec<KeyDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyDefinition {
+    name: String,
+    expr: String,
+}
+
+#[derive(Debug)]
+struct ComputedKey {
+    name: String,
+    expr: Expr,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ComputedKeyError {
+    #[error("failed to read '{path}': {error}")]
+    Io { path: String, error: std::io::Error },
+    #[error("invalid computed keys file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("key '{key}': {error}")]
+    Expression { key: String, error: ConditionError },
+    #[error("duplicate key name '{0}'")]
+    Duplicate(String),
+    #[error("key name '{0}' is empty or reserved")]
+    Reserved(String),
+}
+
+#[derive(Debug, Default)]
+pub struct ComputedKeys {
+    path: String,
+    keys: RwLock<Arc<Vec<ComputedKey>>>,
+    modified: RwLock<Option<SystemTime>>, // Of the file the current keys were compiled from
+}
+
+impl ComputedKeys {
+    /// Compiles the keys file, or creates an empty set if `path` is empty.
+    pub fn load(path: &str) -> Result<Self, ComputedKeyError> {
+        let computed = Self { path: path.to_string(), ..Self::default() };
+        if !path.is_empty() {
+            computed.reload()?;
+        }
+        Ok(computed)
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    /// Adds every computed key to `data`, given the character's previous data for `prev()`.
+    pub fn apply(&self, data: &mut CharacterDataMap, previous: Option<&CharacterDataMap>) {
+        let keys = Arc::clone(&self.keys.read().unwrap());
+        for key in keys.iter() {
+            match key.expr.evaluate(previous, data) {
+                Some(Operand::Number(n)) if n.is_finite() => data.insert(key.name.clone(), derived::json_number(n)),
+                Some(Operand::Text(text)) => data.insert(key.name.clone(), Value::String(text)),
+                _ => data.remove(&key.name),
+            };
+        }
+    }
+
+    /// Recompiles the file if it changed since it was last loaded. Returns whether it did.
+    pub fn reload_if_changed(&self) -> Result<bool, ComputedKeyError> {
+        if self.path.is_empty() {
+            return Ok(false);
+        }
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified())
+            .map_err(|error| ComputedKeyError::Io { path: self.path.clone(), error })?;
+        if *self.modified.read().unwrap() == Some(modified) {
+            return Ok(false);
+        }
+        self.reload().map(|()| true)
+    }
+
+    fn reload(&self) -> Result<(), ComputedKeyError> {
+        let io_error = |error| ComputedKeyError::Io { path: self.path.clone(), error };
+        // Taken before reading, so an edit during the read is picked up by the next check.
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).map_err(io_error)?;
+        let text = std::fs::read_to_string(&self.path).map_err(io_error)?;
+        let keys = toml::from_str::<KeysFile>(&text).map_err(ComputedKeyError::from).and_then(compile);
+        // A failed reload is not retried until the file changes again.
+        *self.modified.write().unwrap() = Some(modified);
+        *self.keys.write().unwrap() = Arc::new(keys?);
+        Ok(())
+    }
+}
+
+fn compile(file: KeysFile) -> Result<Vec<ComputedKey>, ComputedKeyError> {
+    let mut names = HashSet::new();
+    file.keys.into_iter().map(|definition| {
+        let name = definition.name.trim().to_string();
+        if RESERVED_KEYS.contains(&name.as_str()) || name.starts_with(derived::PREFIX) || name.is_empty() {
+            return Err(ComputedKeyError::Reserved(name));
+        }
+        if !names.insert(name.clone()) {
+            return Err(ComputedKeyError::Duplicate(name));
+        }
+        let expr = Expr::parse(&definition.expr).map_err(|error| ComputedKeyError::Expression { key: name.clone(), error })?;
+        Ok(ComputedKey { name, expr })
+    }).collect()
+}
+
+/// Checks the keys file for changes every `interval`.
+pub async fn reload_loop(computed: Arc<ComputedKeys>, interval: Duration) {
+    if computed.path.is_empty() {
+        return;
+    }
+    info!("Watching '{}' for computed key changes every {:?}", computed.path, interval);
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match computed.reload_if_changed() {
+            Ok(true) => info!("Reloaded {} computed key(s) from '{}'.", computed.key_count(), computed.path),
+            Ok(false) => {}
+            Err(e) => error!("Failed to reload computed keys, keeping the previous ones: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    /// A keys file in the temp directory, removed again on drop.
+    struct KeysFileOnDisk(std::path::PathBuf);
+
+    impl KeysFileOnDisk {
+        fn new(text: &str) -> Self {
+            let file = Self(std::env::temp_dir().join(format!("computed_keys_{}.toml", Uuid::new_v4())));
+            file.write(text, SystemTime::now());
+            file
+        }
+
+        /// Replaces the contents, with an explicit modification time so the change is always seen.
+        fn write(&self, text: &str, modified: SystemTime) {
+            std::fs::write(&self.0, text).unwrap();
+            std::fs::File::options().write(true).open(&self.0).unwrap().set_modified(modified).unwrap();
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for KeysFileOnDisk {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn compile_error(text: &str) -> String {
+        toml::from_str::<KeysFile>(text).map_err(ComputedKeyError::from).and_then(compile).unwrap_err().to_string()
+    }
+
+    fn data(values: &[(&str, Value)]) -> CharacterDataMap {
+        values.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected_with_the_key_name() {
+        assert_eq!(compile_error("[[key]]\nname = \"RATIO\"\nexpr = \"HEALTH /\""), "key 'RATIO': expected a key, number or string, found end of input");
+        assert_eq!(compile_error("[[key]]\nname = \"RATIO\"\nexpr = \"nope(HEALTH)\""), "key 'RATIO': unknown function 'nope'");
+        assert_eq!(compile_error("[[key]]\nname = \"A\"\nexpr = \"1\"\n[[key]]\nname = \" A \"\nexpr = \"2\""), "duplicate key name 'A'");
+        assert_eq!(compile_error("[[key]]\nname = \"CONNECTED\"\nexpr = \"1\""), "key name 'CONNECTED' is empty or reserved");
+        assert_eq!(compile_error("[[key]]\nname = \"DERIVED_X\"\nexpr = \"1\""), "key name 'DERIVED_X' is empty or reserved");
+        assert!(compile_error("[[key]]\nname = \"A\"\nexpression = \"1\"").starts_with("invalid computed keys file: "));
+    }
+
+    #[test]
+    fn keys_are_computed_in_file_order() {
+        let file = KeysFileOnDisk::new(r#"
+            [[key]]
+            name = "HP_LEFT"
+            expr = "HEALTH_MAX - HEALTH"
+
+            [[key]]
+            name = "HALF_LEFT"
+            expr = "HP_LEFT / 2"
+
+            [[key]]
+            name = "TANK_RATIO"
+            expr = "round(HEALTH / OPPONENT_HEALTH, 2)"
+        "#);
+        let computed = ComputedKeys::load(file.path()).unwrap();
+        assert_eq!(computed.key_count(), 3);
+
+        let mut update = data(&[("HEALTH", json!("75")), ("HEALTH_MAX", json!(100)), ("TANK_RATIO", json!("client value"))]);
+        computed.apply(&mut update, None);
+        assert_eq!(update["HP_LEFT"], json!(25));
+        assert_eq!(update["HALF_LEFT"], json!(12.5));
+        // Missing inputs leave the key out, even if the client sent it.
+        assert!(!update.contains_key("TANK_RATIO"));
+    }
+
+    #[test]
+    fn prev_reads_the_previous_update() {
+        let file = KeysFileOnDisk::new("[[key]]\nname = \"HEALTH_CHANGE\"\nexpr = \"HEALTH - prev(HEALTH)\"");
+        let computed = ComputedKeys::load(file.path()).unwrap();
+
+        let mut first = data(&[("HEALTH", json!(100))]);
+        computed.apply(&mut first, None);
+        assert!(!first.contains_key("HEALTH_CHANGE"));
+
+        let mut second = data(&[("HEALTH", json!(60))]);
+        computed.apply(&mut second, Some(&first));
+        assert_eq!(second["HEALTH_CHANGE"], json!(-40));
+    }
+
+    #[test]
+    fn changed_files_replace_the_keys_and_broken_edits_keep_them() {
+        let file = KeysFileOnDisk::new("[[key]]\nname = \"DOUBLE\"\nexpr = \"HEALTH * 2\"");
+        let computed = ComputedKeys::load(file.path()).unwrap();
+        assert!(!computed.reload_if_changed().unwrap());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        file.write("[[key]]\nname = \"TRIPLE\"\nexpr = \"HEALTH * 3\"", later);
+        assert!(computed.reload_if_changed().unwrap());
+        let mut update = data(&[("HEALTH", json!(2))]);
+        computed.apply(&mut update, None);
+        assert_eq!((update.get("DOUBLE"), update.get("TRIPLE")), (None, Some(&json!(6))));
+
+        file.write("[[key]]\nname = \"BROKEN\"\nexpr = \"HEALTH *\"", later + Duration::from_secs(10));
+        assert!(computed.reload_if_changed().is_err());
+        // The failed edit is not retried until the file changes again.
+        assert!(!computed.reload_if_changed().unwrap());
+        let mut update = data(&[("HEALTH", json!(2))]);
+        computed.apply(&mut update, None);
+        assert_eq!(update.get("TRIPLE"), Some(&json!(6)));
+    }
+
+    #[test]
+    fn no_file_means_no_keys() {
+        let computed = ComputedKeys::load("").unwrap();
+        assert_eq!(computed.key_count(), 0);
+        assert!(!computed.reload_if_changed().unwrap());
+    }
+}
@@ -0,0 +1,665 @@
+// --- Rule Conditions ---
+// The small expression and condition language used by alert rules and computed keys, evaluated
+// against a character's previous and current data on every update:
+//
+//   HEALTH / HEALTH_MAX < 0.3          arithmetic (+ - * /, parentheses) and < <= > >= == !=
+//   CLASS == 'Vampire'                 text compares case-insensitively
+//   OPPONENT_NAME changed              the value differs from the previous update
+//   CONNECTED became NO                the value is NO now and was not before
+//   affect 'sanctuary', 'nadur dion' missing     none of the affects is present (or `present`)
+//   not ..., ... and ..., ... or ...   `and` binds tighter than `or`; `&&` and `||` work too
+//   A < 1 and (B changed or C < 2)     parentheses group conditions as well as arithmetic
+//   HEALTH - prev(HEALTH) < -100       functions, see FUNCTIONS; prev(KEY) is the previous value
+//   if(OPPONENT_NAME != '', 'fighting', 'idle')   the value depends on a condition
+//
+// Evaluation is three-valued: a condition that refers to a missing or non-numeric key is unknown
+// rather than false, so a partial update neither fires nor clears an alert. Likewise an
+// expression with a missing input has no value.
+
+use serde_json::Value;
+
+use crate::CharacterDataMap;
+
+const AFFECTS_KEY: &str = "AFFECTS";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    Abs,
+    Round,
+    Floor,
+    Ceil,
+    Min,
+    Max,
+    Upper,
+    Lower,
+    Trim,
+    Len,
+    Concat,
+    Replace,
+    Coalesce,
+}
+
+/// Name, function and the number of arguments it takes (minimum, maximum).
+const FUNCTIONS: [(&str, Function, usize, usize); 13] = [
+    ("abs", Function::Abs, 1, 1),
+    ("round", Function::Round, 1, 2), // round(x, digits)
+    ("floor", Function::Floor, 1, 1),
+    ("ceil", Function::Ceil, 1, 1),
+    ("min", Function::Min, 1, usize::MAX),
+    ("max", Function::Max, 1, usize::MAX),
+    ("upper", Function::Upper, 1, 1),
+    ("lower", Function::Lower, 1, 1),
+    ("trim", Function::Trim, 1, 1),
+    ("len", Function::Len, 1, 1),
+    ("concat", Function::Concat, 1, usize::MAX),
+    ("replace", Function::Replace, 3, 3), // replace(text, from, to)
+    ("coalesce", Function::Coalesce, 1, usize::MAX), // The first argument that has a value
+];
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Key(String),
+    Previous(String),
+    Number(f64),
+    Text(String),
+    Neg(Box<Expr>),
+    Arith(Box<Expr>, ArithOp, Box<Expr>),
+    Call(Function, Vec<Expr>),
+    If(Box<Condition>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+pub enum Condition {
+    Compare(Expr, CompareOp, Expr),
+    Changed(String),
+    Became(String, Expr),
+    Affect { names: Vec<String>, present: bool },
+    Not(Box<Condition>),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConditionError {
+    #[error("unexpected character '{0}'")]
+    UnexpectedChar(char),
+    #[error("unterminated string")]
+    UnterminatedString,
+    #[error("expected {expected}, found {found}")]
+    Expected { expected: &'static str, found: String },
+    #[error("unknown function '{0}'")]
+    UnknownFunction(String),
+    #[error("{function}() takes {expected} argument(s), found {found}")]
+    Arity { function: String, expected: String, found: usize },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Number(f64),
+    Text(String),
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, ConditionError> {
+        let mut parser = Parser { tokens: tokenize(source)?, pos: 0 };
+        let condition = parser.or()?;
+        match parser.peek() {
+            None => Ok(condition),
+            Some(token) => Err(ConditionError::Expected { expected: "end of condition", found: token.describe() }),
+        }
+    }
+
+    /// True for conditions about a single update (`changed`, `became`) rather than a lasting state.
+    pub fn is_momentary(&self) -> bool {
+        match self {
+            Condition::Changed(_) | Condition::Became(..) => true,
+            Condition::Compare(..) | Condition::Affect { .. } => false,
+            Condition::Not(inner) => inner.is_momentary(),
+            Condition::And(all) | Condition::Or(all) => all.iter().any(Condition::is_momentary),
+        }
+    }
+
+    /// `None` when the answer depends on data the character did not send.
+    pub fn evaluate(&self, previous: Option<&CharacterDataMap>, current: &CharacterDataMap) -> Option<bool> {
+        match self {
+            Condition::Compare(left, op, right) => compare(&left.evaluate(previous, current)?, *op, &right.evaluate(previous, current)?),
+            Condition::Changed(key) => Some(previous.is_some_and(|previous| previous.get(key) != current.get(key))),
+            Condition::Became(key, value) => {
+                let Some(previous) = previous else { return Some(false) };
+                let target = value.evaluate(Some(previous), current)?;
+                let is_target = |data: &CharacterDataMap| data.get(key).and_then(operand).is_some_and(|v| compare(&v, CompareOp::Eq, &target) == Some(true));
+                Some(is_target(current) && !is_target(previous))
+            }
+            Condition::Affect { names, present } => {
+                let affects = current.get(AFFECTS_KEY)?;
+                Some(names.iter().any(|name| has_affect(affects, name)) == *present)
+            }
+            Condition::Not(inner) => inner.evaluate(previous, current).map(|result| !result),
+            Condition::And(all) => {
+                let results: Vec<Option<bool>> = all.iter().map(|c| c.evaluate(previous, current)).collect();
+                if results.contains(&Some(false)) { Some(false) } else if results.contains(&None) { None } else { Some(true) }
+            }
+            Condition::Or(any) => {
+                let results: Vec<Option<bool>> = any.iter().map(|c| c.evaluate(previous, current)).collect();
+                if results.contains(&Some(true)) { Some(true) } else if results.contains(&None) { None } else { Some(false) }
+            }
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, ConditionError> {
+        let mut parser = Parser { tokens: tokenize(source)?, pos: 0 };
+        let expr = parser.expr()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(ConditionError::Expected { expected: "end of expression", found: token.describe() }),
+        }
+    }
+
+    /// `None` when the value depends on data the character did not send.
+    pub fn evaluate(&self, previous: Option<&CharacterDataMap>, data: &CharacterDataMap) -> Option<Operand> {
+        match self {
+            Expr::Key(key) => data.get(key).and_then(operand),
+            Expr::Previous(key) => previous?.get(key).and_then(operand),
+            Expr::Number(n) => Some(Operand::Number(*n)),
+            Expr::Text(t) => Some(Operand::Text(t.clone())),
+            Expr::Neg(inner) => match inner.evaluate(previous, data)? {
+                Operand::Number(n) => Some(Operand::Number(-n)),
+                Operand::Text(_) => None,
+            },
+            Expr::Arith(left, op, right) => {
+                let (Operand::Number(l), Operand::Number(r)) = (left.evaluate(previous, data)?, right.evaluate(previous, data)?) else { return None };
+                let result = match op {
+                    ArithOp::Add => l + r,
+                    ArithOp::Sub => l - r,
+                    ArithOp::Mul => l * r,
+                    ArithOp::Div if r == 0.0 => return None,
+                    ArithOp::Div => l / r,
+                };
+                Some(Operand::Number(result))
+            }
+            Expr::Call(function, args) => call(*function, args, previous, data),
+            Expr::If(condition, then, otherwise) => match condition.evaluate(previous, data)? {
+                true => then.evaluate(previous, data),
+                false => otherwise.evaluate(previous, data),
+            },
+        }
+    }
+}
+
+fn call(function: Function, args: &[Expr], previous: Option<&CharacterDataMap>, data: &CharacterDataMap) -> Option<Operand> {
+    if function == Function::Coalesce {
+        return args.iter().find_map(|arg| arg.evaluate(previous, data));
+    }
+    let values = args.iter().map(|arg| arg.evaluate(previous, data)).collect::<Option<Vec<Operand>>>()?;
+    let number = |i: usize| match values.get(i) {
+        Some(Operand::Number(n)) => Some(*n),
+        _ => None,
+    };
+    let numbers = || (0..values.len()).map(number).collect::<Option<Vec<f64>>>();
+    let text = |i: usize| values.get(i).map(Operand::to_text);
+    let result = match function {
+        Function::Abs => Operand::Number(number(0)?.abs()),
+        Function::Round => {
+            let digits = if values.len() > 1 { number(1)? } else { 0.0 };
+            let scale = 10f64.powi(digits as i32);
+            Operand::Number((number(0)? * scale).round() / scale)
+        }
+        Function::Floor => Operand::Number(number(0)?.floor()),
+        Function::Ceil => Operand::Number(number(0)?.ceil()),
+        Function::Min => Operand::Number(numbers()?.into_iter().fold(f64::INFINITY, f64::min)),
+        Function::Max => Operand::Number(numbers()?.into_iter().fold(f64::NEG_INFINITY, f64::max)),
+        Function::Upper => Operand::Text(text(0)?.to_uppercase()),
+        Function::Lower => Operand::Text(text(0)?.to_lowercase()),
+        Function::Trim => Operand::Text(text(0)?.trim().to_string()),
+        Function::Len => Operand::Number(text(0)?.chars().count() as f64),
+        Function::Concat => Operand::Text(values.iter().map(Operand::to_text).collect()),
+        Function::Replace => Operand::Text(text(0)?.replace(&text(1)?, &text(2)?)),
+        Function::Coalesce => unreachable!("handled above"),
+    };
+    Some(result)
+}
+
+impl Operand {
+    pub fn to_text(&self) -> String {
+        match self {
+            Operand::Number(n) => n.to_string(),
+            Operand::Text(t) => t.clone(),
+        }
+    }
+}
+
+/// MUD clients send most numbers as strings, so anything that parses as a number is one.
+fn operand(value: &Value) -> Option<Operand> {
+    match value {
+        Value::Number(n) => n.as_f64().map(Operand::Number),
+        Value::String(s) => Some(s.trim().parse::<f64>().map_or_else(|_| Operand::Text(s.clone()), Operand::Number)),
+        Value::Bool(b) => Some(Operand::Text(b.to_string())),
+        _ => None,
+    }
+}
+
+fn compare(left: &Operand, op: CompareOp, right: &Operand) -> Option<bool> {
+    match (left, right) {
+        (Operand::Number(l), Operand::Number(r)) => Some(match op {
+            CompareOp::Lt => l < r,
+            CompareOp::Le => l <= r,
+            CompareOp::Gt => l > r,
+            CompareOp::Ge => l >= r,
+            CompareOp::Eq => l == r,
+            CompareOp::Ne => l != r,
+        }),
+        _ => {
+            let text = |o: &Operand| match o {
+                Operand::Number(n) => n.to_string(),
+                Operand::Text(t) => t.trim().to_lowercase(),
+            };
+            match op {
+                CompareOp::Eq => Some(text(left) == text(right)),
+                CompareOp::Ne => Some(text(left) != text(right)),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn has_affect(affects: &Value, name: &str) -> bool {
+    match affect_entries(affects) {
+        Some(entries) => entries.iter().any(|(affect, _)| affect.eq_ignore_ascii_case(name)),
+        None => affects.as_str().is_some_and(|text| text.to_lowercase().contains(name)),
+    }
+}
+
+/// Splits an AFFECTS value sent as a nested `{name}{value}{name}{value}` table into its entries.
+/// `None` if it is plain text or not a string at all.
+pub fn affect_entries(affects: &Value) -> Option<Vec<(String, String)>> {
+    let text = affects.as_str()?.trim();
+    if !text.starts_with('{') {
+        return None;
+    }
+    let mut fields = text.split('}').map(|part| part.trim_start()).filter(|part| !part.is_empty());
+    let mut entries = Vec::new();
+    while let Some(name) = fields.next() {
+        let name = name.strip_prefix('{')?.trim();
+        let value = fields.next().and_then(|v| v.strip_prefix('{')).unwrap_or("").trim();
+        if !name.is_empty() {
+            entries.push((name.to_string(), value.to_string()));
+        }
+    }
+    (!entries.is_empty()).then_some(entries)
+}
+
+// --- Tokenizer ---
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Text(String),
+    Symbol(&'static str),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Ident(name) => format!("'{}'", name),
+            Token::Number(n) => n.to_string(),
+            Token::Text(t) => format!("'{}'", t),
+            Token::Symbol(s) => format!("'{}'", s),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 16] = ["&&", "||", "<=", ">=", "==", "!=", "<", ">", "=", "+", "-", "*", "/", "(", ")", ","];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ConditionError> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(**s)) {
+            // A lone `=` means the same as `==`, `&&` and `||` the same as `and` and `or`.
+            tokens.push(match *symbol {
+                "=" => Token::Symbol("=="),
+                "&&" => Token::Ident("and".to_string()),
+                "||" => Token::Ident("or".to_string()),
+                _ => Token::Symbol(symbol),
+            });
+            rest = &rest[symbol.len()..];
+        } else if c == '\'' || c == '"' {
+            let end = rest[1..].find(c).ok_or(ConditionError::UnterminatedString)?;
+            tokens.push(Token::Text(rest[1..=end].to_string()));
+            rest = &rest[end + 2..];
+        } else if c.is_ascii_digit() || c == '.' {
+            let end = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+            let number = rest[..end].parse().map_err(|_| ConditionError::UnexpectedChar(c))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(ConditionError::UnexpectedChar(c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// --- Parser ---
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn found(&self) -> String {
+        self.peek().map_or_else(|| "end of input".to_string(), Token::describe)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let matched = matches!(self.peek(), Some(Token::Ident(word)) if word == keyword);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn eat_symbol(&mut self, symbol: &'static str) -> bool {
+        let matched = self.peek() == Some(&Token::Symbol(symbol));
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn or(&mut self) -> Result<Condition, ConditionError> {
+        let mut any = vec![self.and()?];
+        while self.eat_keyword("or") {
+            any.push(self.and()?);
+        }
+        Ok(if any.len() == 1 { any.remove(0) } else { Condition::Or(any) })
+    }
+
+    fn and(&mut self) -> Result<Condition, ConditionError> {
+        let mut all = vec![self.not()?];
+        while self.eat_keyword("and") {
+            all.push(self.not()?);
+        }
+        Ok(if all.len() == 1 { all.remove(0) } else { Condition::And(all) })
+    }
+
+    fn not(&mut self) -> Result<Condition, ConditionError> {
+        if self.eat_keyword("not") {
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Condition, ConditionError> {
+        if self.eat_keyword("affect") {
+            let mut names = Vec::new();
+            loop {
+                match self.peek().cloned() {
+                    Some(Token::Text(name)) => names.push(name.trim().to_lowercase()),
+                    _ => return Err(ConditionError::Expected { expected: "a quoted affect name", found: self.found() }),
+                }
+                self.pos += 1;
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+            let present = if self.eat_keyword("present") {
+                true
+            } else if self.eat_keyword("missing") {
+                false
+            } else {
+                return Err(ConditionError::Expected { expected: "'present' or 'missing'", found: self.found() });
+            };
+            return Ok(Condition::Affect { names, present });
+        }
+
+        if let Some(Token::Ident(key)) = self.peek().cloned() {
+            if let Some(Token::Ident(keyword)) = self.tokens.get(self.pos + 1) {
+                match keyword.as_str() {
+                    "changed" => {
+                        self.pos += 2;
+                        return Ok(Condition::Changed(key));
+                    }
+                    "became" => {
+                        self.pos += 2;
+                        let value = match self.peek().cloned() {
+                            // Bare words are values here, so `CONNECTED became NO` needs no quotes.
+                            Some(Token::Ident(word)) => Expr::Text(word),
+                            Some(Token::Text(text)) => Expr::Text(text),
+                            Some(Token::Number(n)) => Expr::Number(n),
+                            _ => return Err(ConditionError::Expected { expected: "a value after 'became'", found: self.found() }),
+                        };
+                        self.pos += 1;
+                        return Ok(Condition::Became(key, value));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // `(` opens either a group of conditions or an arithmetic group as in `(HEALTH + 5) * 2 < 10`.
+        // A group of conditions is tried first; an operator after its `)` shows it was arithmetic.
+        if self.peek() == Some(&Token::Symbol("(")) {
+            let start = self.pos;
+            self.pos += 1;
+            let grouped = self.or().and_then(|inner| self.expect_symbol(")", "')'").map(|_| inner));
+            match grouped {
+                Ok(inner) if !matches!(self.peek(), Some(Token::Symbol(s)) if *s != ")" && *s != ",") => return Ok(inner),
+                Ok(_) => self.pos = start,
+                Err(error) => {
+                    // Report whichever reading got further, which is where the mistake most likely is.
+                    let reached = self.pos;
+                    self.pos = start;
+                    return self.comparison().map_err(|other| if reached > self.pos { error } else { other });
+                }
+            }
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Condition, ConditionError> {
+        let left = self.expr()?;
+        let op = match self.next() {
+            Some(Token::Symbol("<")) => CompareOp::Lt,
+            Some(Token::Symbol("<=")) => CompareOp::Le,
+            Some(Token::Symbol(">")) => CompareOp::Gt,
+            Some(Token::Symbol(">=")) => CompareOp::Ge,
+            Some(Token::Symbol("==")) => CompareOp::Eq,
+            Some(Token::Symbol("!=")) => CompareOp::Ne,
+            _ => {
+                self.pos -= 1;
+                return Err(ConditionError::Expected { expected: "a comparison", found: self.found() });
+            }
+        };
+        Ok(Condition::Compare(left, op, self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, ConditionError> {
+        let mut left = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => ArithOp::Add,
+                Some(Token::Symbol("-")) => ArithOp::Sub,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expr::Arith(Box::new(left), op, Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ConditionError> {
+        let mut left = self.factor()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => ArithOp::Mul,
+                Some(Token::Symbol("/")) => ArithOp::Div,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expr::Arith(Box::new(left), op, Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, ConditionError> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Text(t)) => Ok(Expr::Text(t)),
+            Some(Token::Ident(word)) if self.eat_symbol("(") => self.call(word),
+            Some(Token::Ident(word)) if !is_keyword(&word) => Ok(Expr::Key(word)),
+            Some(Token::Symbol("-")) => Ok(Expr::Neg(Box::new(self.factor()?))),
+            Some(Token::Symbol("(")) => {
+                let inner = self.expr()?;
+                if !self.eat_symbol(")") {
+                    return Err(ConditionError::Expected { expected: "')'", found: self.found() });
+                }
+                Ok(inner)
+            }
+            _ => {
+                self.pos -= 1;
+                Err(ConditionError::Expected { expected: "a key, number or string", found: self.found() })
+            }
+        }
+    }
+}
+
+impl Parser {
+    /// Arguments of a function call whose name and `(` were just consumed.
+    fn call(&mut self, name: String) -> Result<Expr, ConditionError> {
+        let expr = match name.as_str() {
+            "prev" => match self.next() {
+                Some(Token::Ident(key)) if !is_keyword(&key) => Expr::Previous(key),
+                _ => {
+                    self.pos -= 1;
+                    return Err(ConditionError::Expected { expected: "a key in prev()", found: self.found() });
+                }
+            },
+            "if" => {
+                let condition = self.or()?;
+                self.expect_symbol(",", "','")?;
+                let then = self.expr()?;
+                self.expect_symbol(",", "','")?;
+                Expr::If(Box::new(condition), Box::new(then), Box::new(self.expr()?))
+            }
+            _ => {
+                let (_, function, min, max) = *FUNCTIONS.iter().find(|(n, ..)| *n == name)
+                    .ok_or_else(|| ConditionError::UnknownFunction(name.clone()))?;
+                let mut args = vec![self.expr()?];
+                while self.eat_symbol(",") {
+                    args.push(self.expr()?);
+                }
+                if args.len() < min || args.len() > max {
+                    let expected = if max == usize::MAX {
+                        format!("at least {}", min)
+                    } else if min == max {
+                        min.to_string()
+                    } else {
+                        format!("{} to {}", min, max)
+                    };
+                    return Err(ConditionError::Arity { function: name, expected, found: args.len() });
+                }
+                Expr::Call(function, args)
+            }
+        };
+        self.expect_symbol(")", "')'")?;
+        Ok(expr)
+    }
+
+    fn expect_symbol(&mut self, symbol: &'static str, expected: &'static str) -> Result<(), ConditionError> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(ConditionError::Expected { expected, found: self.found() })
+        }
+    }
+}
+
+fn is_keyword(word: &str) -> bool {
+    matches!(word, "and" | "or" | "not" | "affect" | "changed" | "became" | "present" | "missing")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn data(pairs: &[(&str, Value)]) -> CharacterDataMap {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+    }
+
+    fn holds(source: &str, previous: &CharacterDataMap, current: &CharacterDataMap) -> Option<bool> {
+        Condition::parse(source).unwrap().evaluate(Some(previous), current)
+    }
+
+    #[test]
+    fn parentheses_group_conditions() {
+        let previous = data(&[("HEALTH", json!(50)), ("OPPONENT_NAME", json!("orc")), ("CONNECTED", json!("YES"))]);
+        let current = data(&[("HEALTH", json!(50)), ("OPPONENT_NAME", json!("troll")), ("CONNECTED", json!("YES"))]);
+        assert_eq!(holds("HEALTH < 100 and (OPPONENT_NAME changed or CONNECTED became NO)", &previous, &current), Some(true));
+        assert_eq!(holds("HEALTH > 100 and (OPPONENT_NAME changed or CONNECTED became NO)", &previous, &current), Some(false));
+        // Without the parentheses `and` binds first.
+        assert_eq!(holds("HEALTH > 100 and OPPONENT_NAME changed or CONNECTED became YES", &previous, &current), Some(false));
+        assert_eq!(holds("HEALTH > 100 and (OPPONENT_NAME changed or CONNECTED == YES)", &previous, &current), Some(false));
+        assert_eq!(holds("not (HEALTH > 100 or ((CONNECTED == 'NO')))", &previous, &current), Some(true));
+    }
+
+    #[test]
+    fn symbolic_and_or_match_the_keywords() {
+        let current = data(&[("A", json!(1)), ("B", json!(2)), ("C", json!(3))]);
+        assert_eq!(holds("A == 1 && (B == 5 || C == 3)", &current, &current), Some(true));
+        assert_eq!(holds("A == 2 || B == 2 && C == 4", &current, &current), Some(false));
+    }
+
+    #[test]
+    fn parentheses_still_group_arithmetic() {
+        let current = data(&[("HEALTH", json!(2)), ("HEALTH_MAX", json!(10))]);
+        assert_eq!(holds("(HEALTH + 3) * 2 == 10", &current, &current), Some(true));
+        assert_eq!(holds("((HEALTH)) / HEALTH_MAX < 0.3", &current, &current), Some(true));
+        assert_eq!(holds("(HEALTH < 3) and (HEALTH_MAX - HEALTH) / 2 == 4", &current, &current), Some(true));
+        assert_eq!(holds("if((HEALTH < 3 or HEALTH_MAX < 3), 'low', 'ok') == 'low'", &current, &current), Some(true));
+    }
+
+    #[test]
+    fn malformed_groups_are_rejected() {
+        let error = |source: &str| Condition::parse(source).unwrap_err().to_string();
+        assert_eq!(error("HEALTH < 10 and (A changed or B < 2"), "expected ')', found end of input");
+        assert_eq!(error("HEALTH < 10 and (A changed or B chnaged)"), "expected a comparison, found 'chnaged'");
+        assert_eq!(error("(HEALTH < 10) + 1 > 2"), "expected ')', found '<'");
+        assert_eq!(error("HEALTH < 10 & A changed"), "unexpected character '&'");
+    }
+}
@@ -0,0 +1,735 @@
+// --- Configuration ---
+// All settings in one typed struct, read from an optional TOML or YAML file (CONFIG_FILE or
+// `--config <path>`) with environment variables on top:
+//
+//   defaults  <  config file  <  environment (including .env)
+//
+// Every setting has a file key, e.g. `[rate_limit] rps`, and the environment variable it always
+// had, e.g. RATE_LIMIT_RPS. Unknown file keys, values that do not parse and values outside their
+// range stop the server at startup with a list of every problem, rather than falling back to
+// defaults. `--print-config` prints the effective configuration as TOML and exits.
+//
+// The file (and the alert rules file it names) is reloaded on SIGHUP and when it changes on disk.
+// Settings that can change live take effect at once: the data, connection and tombstone
+// timeouts in [server], the rate limits, [priority], [commands], [metrics] and [alerts]. Changes
+// to anything else are logged as needing a restart. An invalid file is logged and the running
+// configuration stays active.
+
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn, Level};
+
+use crate::commands::CommandConfig;
+use crate::derived::Metric;
+use crate::export::ExportConfig;
+use crate::metrics::MetricsConfig;
+use crate::mqtt;
+use crate::priority::PriorityConfig;
+use crate::protocol::MessageFormat;
+use crate::{RateLimiterConfig, SharedState};
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read '{path}': {error}")]
+    Io { path: String, error: std::io::Error },
+    #[error("'{0}' is neither a .toml nor a .yaml/.yml file")]
+    Format(String),
+    #[error("invalid config file '{path}': {error}")]
+    Toml { path: String, error: toml::de::Error },
+    #[error("invalid config file '{path}': {error}")]
+    Yaml { path: String, error: serde_yaml::Error },
+    #[error("invalid configuration:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+}
+
+/// A value hot reload may replace. Readers keep the `Arc` they got until they are done with it.
+#[derive(Debug)]
+pub struct Live<T>(RwLock<Arc<T>>);
+
+impl<T> Live<T> {
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(Arc::new(value)))
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// A setting type that can be read from an environment variable.
+trait Setting: Sized {
+    fn parse(text: &str) -> Result<Self, String>;
+}
+
+impl Setting for String {
+    fn parse(text: &str) -> Result<Self, String> {
+        Ok(text.to_string())
+    }
+}
+
+macro_rules! parsed_settings {
+    ($($ty:ty),*) => {
+        $(impl Setting for $ty {
+            fn parse(text: &str) -> Result<Self, String> {
+                text.trim().parse().map_err(|e: <$ty as FromStr>::Err| e.to_string())
+            }
+        })*
+    };
+}
+
+parsed_settings!(bool, u8, u16, u32, u64, usize, f64);
+
+/// Declares the config sections: per field its type, default and environment variable.
+macro_rules! config_sections {
+    ($($section:ident: $ty:ident { $($field:ident: $fty:ty = $default:expr, $env:literal;)* })*) => {
+        $(
+            #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+            #[serde(default, deny_unknown_fields)]
+            pub struct $ty {
+                $(pub $field: $fty,)*
+            }
+
+            impl Default for $ty {
+                fn default() -> Self {
+                    Self { $($field: $default.to_owned(),)* }
+                }
+            }
+
+            impl $ty {
+                fn apply_env(&mut self, problems: &mut Vec<String>) {
+                    $(override_from_env(&mut self.$field, concat!(stringify!($section), ".", stringify!($field)), $env, problems);)*
+                }
+            }
+        )*
+
+        #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct Config {
+            $(pub $section: $ty,)*
+        }
+
+        impl Config {
+            fn apply_env(&mut self, problems: &mut Vec<String>) {
+                $(self.$section.apply_env(problems);)*
+            }
+
+            /// Names of the sections that differ between two configs.
+            fn changed_sections(&self, other: &Config) -> Vec<&'static str> {
+                let mut changed = Vec::new();
+                $(if self.$section != other.$section { changed.push(stringify!($section)); })*
+                changed
+            }
+        }
+
+        // (file key, environment variable) of every setting, for error messages.
+        const SETTINGS: &[(&str, &str)] = &[
+            $($((concat!(stringify!($section), ".", stringify!($field)), $env),)*)*
+        ];
+    };
+}
+
+config_sections! {
+    server: ServerSection {
+        http_host: String = "0.0.0.0", "HTTP_HOST";
+        http_port: u16 = 8080, "HTTP_PORT";
+        static_dir_path: String = "static", "STATIC_DIR_PATH";
+        log_level: String = "INFO", "LOG_LEVEL";
+        message_format: String = "legacy", "MESSAGE_FORMAT";
+        prune_interval_seconds: u64 = 60, "PRUNE_INTERVAL_SECONDS";
+        data_timeout_minutes: u64 = 30, "DATA_TIMEOUT_MINUTES";
+        broadcast_interval_seconds: f64 = 0.2, "BROADCAST_INTERVAL_SECONDS";
+        connection_timeout_seconds: u64 = 5, "CONNECTION_TIMEOUT_SECONDS";
+        tombstone_retention_seconds: u64 = 300, "TOMBSTONE_RETENTION_SECONDS";
+        config_reload_seconds: u64 = 5, "CONFIG_RELOAD_SECONDS";
+    }
+    rate_limit: RateLimitSection {
+        rps: f64 = 5.0, "RATE_LIMIT_RPS";
+        burst_capacity: f64 = 15.0, "RATE_LIMIT_BURST_CAPACITY";
+        violation_threshold: u32 = 20, "RATE_LIMIT_VIOLATION_THRESHOLD";
+        ban_duration_seconds: u64 = 300, "RATE_LIMIT_BAN_DURATION_SECONDS";
+        cleanup_interval_seconds: u64 = 600, "RATE_LIMIT_CLEANUP_INTERVAL_SECONDS";
+    }
+    websocket: WebSocketSection {
+        ping_interval_seconds: u64 = 20, "WS_PING_INTERVAL_SECONDS";
+        pong_timeout_seconds: u64 = 60, "WS_PONG_TIMEOUT_SECONDS";
+        heartbeat_interval_seconds: u64 = 10, "WS_HEARTBEAT_INTERVAL_SECONDS";
+        queue_capacity: usize = 32, "WS_QUEUE_CAPACITY";
+        deflate: bool = true, "WS_DEFLATE";
+        deflate_level: u32 = 6, "WS_DEFLATE_LEVEL";
+        deflate_window_bits: u8 = 15, "WS_DEFLATE_WINDOW_BITS";
+        deflate_threshold_bytes: usize = 1024, "WS_DEFLATE_THRESHOLD_BYTES";
+    }
+    priority: PrioritySection {
+        keys: String = "", "PRIORITY_KEYS";
+        drop_thresholds: String = "", "PRIORITY_DROP_THRESHOLDS";
+        min_flush_spacing_ms: u64 = 100, "PRIORITY_MIN_FLUSH_SPACING_MS";
+    }
+    commands: CommandsSection {
+        ttl_seconds: u64 = 30, "COMMAND_TTL_SECONDS";
+        max_ttl_seconds: u64 = 300, "COMMAND_MAX_TTL_SECONDS";
+        queue_capacity: usize = 8, "COMMAND_QUEUE_CAPACITY";
+        history: usize = 20, "COMMAND_HISTORY";
+        allowlist: String = "", "COMMAND_ALLOWLIST";
+    }
+    annotations: AnnotationsSection {
+        chat_history: usize = 50, "CHAT_HISTORY";
+        notes_per_character: usize = 5, "NOTES_PER_CHARACTER";
+        max_length: usize = 280, "ANNOTATION_MAX_LENGTH";
+    }
+    derived: DerivedSection {
+        metrics: String = "all", "DERIVED_METRICS";
+        xp_key: String = "EXPERIENCE", "DERIVED_XP_KEY";
+        rate_window_seconds: u64 = 10, "DERIVED_RATE_WINDOW_SECONDS";
+        xp_window_seconds: u64 = 900, "DERIVED_XP_WINDOW_SECONDS";
+    }
+    computed_keys: ComputedKeysSection {
+        file: String = "", "COMPUTED_KEYS_FILE";
+        reload_seconds: u64 = 5, "COMPUTED_KEYS_RELOAD_SECONDS";
+    }
+    scripting: ScriptingSection {
+        dir: String = "", "SCRIPTS_DIR";
+        reload_seconds: u64 = 5, "SCRIPTS_RELOAD_SECONDS";
+        timeout_ms: u64 = 50, "SCRIPT_TIMEOUT_MS";
+        max_operations: u64 = 1_000_000, "SCRIPT_MAX_OPERATIONS";
+        max_string_size: usize = 65536, "SCRIPT_MAX_STRING_SIZE";
+        max_collection_size: usize = 10000, "SCRIPT_MAX_COLLECTION_SIZE";
+    }
+    plugins: PluginsSection {
+        dir: String = "", "PLUGINS_DIR";
+        fuel: u64 = 10_000_000, "PLUGIN_FUEL";
+        max_memory_mb: usize = 16, "PLUGIN_MAX_MEMORY_MB";
+    }
+    metrics: MetricsSection {
+        character_keys: String = "", "METRICS_CHARACTER_KEYS";
+        max_character_series: usize = 1000, "METRICS_MAX_CHARACTER_SERIES";
+    }
+    export: ExportSection {
+        sink: String = "", "EXPORT_SINK";
+        address: String = "", "EXPORT_ADDRESS";
+        influx_token: String = "", "EXPORT_INFLUX_TOKEN";
+        prefix: String = "mud", "EXPORT_PREFIX";
+        keys: String = "*", "EXPORT_KEYS";
+        interval_seconds: u64 = 10, "EXPORT_INTERVAL_SECONDS";
+        batch_size: usize = 500, "EXPORT_BATCH_SIZE";
+        buffer_lines: usize = 100_000, "EXPORT_BUFFER_LINES";
+        max_backoff_seconds: u64 = 300, "EXPORT_MAX_BACKOFF_SECONDS";
+    }
+    mqtt: MqttSection {
+        host: String = "", "MQTT_HOST";
+        port: u16 = 1883, "MQTT_PORT";
+        client_id: String = "mud-data-server", "MQTT_CLIENT_ID";
+        username: String = "", "MQTT_USERNAME";
+        password: String = "", "MQTT_PASSWORD";
+        topic_prefix: String = "mud", "MQTT_TOPIC_PREFIX";
+        qos: u8 = 0, "MQTT_QOS";
+        retain: bool = true, "MQTT_RETAIN";
+        ingest_topic: String = "", "MQTT_INGEST_TOPIC";
+        queue_capacity: usize = 1000, "MQTT_QUEUE_CAPACITY";
+    }
+    fights: FightsSection {
+        history: usize = 20, "FIGHT_HISTORY";
+        curve_points: usize = 100, "FIGHT_CURVE_POINTS";
+    }
+    affects: AffectsSection {
+        key: String = "AFFECTS", "AFFECT_KEY";
+        seconds_per_unit: f64 = 1.0, "AFFECT_SECONDS_PER_UNIT";
+        expiring_seconds: u64 = 30, "AFFECT_EXPIRING_SECONDS";
+        refresh_seconds: u64 = 5, "AFFECT_REFRESH_SECONDS";
+    }
+    alerts: AlertsSection {
+        rules_file: String = "", "ALERT_RULES_FILE";
+        history: usize = 200, "ALERT_HISTORY";
+    }
+    webhooks: WebhooksSection {
+        file: String = "", "WEBHOOKS_FILE";
+        max_attempts: u32 = 5, "WEBHOOK_MAX_ATTEMPTS";
+        initial_backoff_ms: u64 = 500, "WEBHOOK_INITIAL_BACKOFF_MS";
+        max_backoff_seconds: u64 = 60, "WEBHOOK_MAX_BACKOFF_SECONDS";
+        timeout_seconds: u64 = 5, "WEBHOOK_TIMEOUT_SECONDS";
+        queue_capacity: usize = 256, "WEBHOOK_QUEUE_CAPACITY";
+        dead_letter_file: String = "webhook_dead_letters.jsonl", "WEBHOOK_DEAD_LETTER_FILE";
+    }
+}
+
+fn override_from_env<T: Setting>(value: &mut T, key: &str, name: &str, problems: &mut Vec<String>) {
+    match env::var(name) {
+        Ok(text) => match T::parse(&text) {
+            Ok(parsed) => *value = parsed,
+            Err(e) => problems.push(format!("{} ({}) has invalid value '{}': {}", key, name, text, e)),
+        },
+        Err(env::VarError::NotPresent) => {}
+        Err(env::VarError::NotUnicode(_)) => problems.push(format!("{} ({}) is not valid UTF-8", key, name)),
+    }
+}
+
+/// "rate_limit.rps (RATE_LIMIT_RPS)"
+fn describe(key: &str) -> String {
+    match SETTINGS.iter().find(|(setting, _)| *setting == key) {
+        Some((_, env)) => format!("{} ({})", key, env),
+        None => key.to_string(),
+    }
+}
+
+impl Config {
+    /// Reads the file, if any, applies the environment on top and validates the result.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        let mut problems = Vec::new();
+        config.apply_env(&mut problems);
+        config.validate(&mut problems);
+        if problems.is_empty() { Ok(config) } else { Err(ConfigError::Invalid(problems)) }
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let display = path.display().to_string();
+        let text = std::fs::read_to_string(path).map_err(|error| ConfigError::Io { path: display.clone(), error })?;
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("toml") => toml::from_str(&text).map_err(|error| ConfigError::Toml { path: display, error }),
+            // An empty YAML document is null rather than an empty mapping.
+            Some("yaml" | "yml") if text.trim().is_empty() => Ok(Self::default()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(|error| ConfigError::Yaml { path: display, error }),
+            _ => Err(ConfigError::Format(display)),
+        }
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        let mut check = |ok: bool, key: &str, requirement: &str| {
+            if !ok {
+                problems.push(format!("{} {}", describe(key), requirement));
+            }
+        };
+        let positive = "must be greater than 0";
+
+        let server = &self.server;
+        check(Level::from_str(&server.log_level).is_ok(), "server.log_level", "must be one of TRACE, DEBUG, INFO, WARN, ERROR");
+        check(MessageFormat::from_str(&server.message_format).is_ok(), "server.message_format", "must be legacy or v2");
+        check(format!("{}:{}", server.http_host, server.http_port).parse::<SocketAddr>().is_ok(), "server.http_host", "must be an IP address");
+        check(server.prune_interval_seconds > 0, "server.prune_interval_seconds", positive);
+        check(server.data_timeout_minutes > 0, "server.data_timeout_minutes", positive);
+        check(server.broadcast_interval_seconds.is_finite() && server.broadcast_interval_seconds > 0.0, "server.broadcast_interval_seconds", positive);
+        check(server.connection_timeout_seconds > 0, "server.connection_timeout_seconds", positive);
+        check(server.config_reload_seconds > 0, "server.config_reload_seconds", positive);
+
+        let rate_limit = &self.rate_limit;
+        check(rate_limit.rps.is_finite() && rate_limit.rps > 0.0, "rate_limit.rps", positive);
+        check(rate_limit.burst_capacity.is_finite() && rate_limit.burst_capacity >= 1.0, "rate_limit.burst_capacity", "must be at least 1");
+        check(rate_limit.violation_threshold > 0, "rate_limit.violation_threshold", positive);
+        check(rate_limit.cleanup_interval_seconds > 0, "rate_limit.cleanup_interval_seconds", positive);
+
+        let websocket = &self.websocket;
+        check(websocket.ping_interval_seconds > 0, "websocket.ping_interval_seconds", positive);
+        check(websocket.queue_capacity > 0, "websocket.queue_capacity", positive);
+        check(websocket.deflate_level <= 9, "websocket.deflate_level", "must be between 0 and 9");
+        check((9..=15).contains(&websocket.deflate_window_bits), "websocket.deflate_window_bits", "must be between 9 and 15");
+
+        for entry in self.priority.drop_thresholds.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let valid = entry.split_once(':')
+                .is_some_and(|(key, pct)| !key.trim().is_empty() && pct.trim().parse::<f64>().is_ok_and(|pct| pct > 0.0));
+            check(valid, "priority.drop_thresholds", &format!("has invalid entry '{}', expected KEY:PERCENT", entry));
+        }
+
+        let commands = &self.commands;
+        check(commands.queue_capacity > 0, "commands.queue_capacity", positive);
+        check(commands.max_ttl_seconds >= commands.ttl_seconds, "commands.max_ttl_seconds", "must be at least commands.ttl_seconds");
+
+        check(self.annotations.notes_per_character > 0, "annotations.notes_per_character", positive);
+        check(self.annotations.max_length > 0, "annotations.max_length", positive);
+
+        let derived = self.derived.metrics.trim();
+        if !derived.eq_ignore_ascii_case("all") {
+            for name in derived.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                let known = Metric::ALL.iter().any(|metric| metric.name().eq_ignore_ascii_case(name));
+                check(known, "derived.metrics", &format!("has unknown metric '{}', known: {}", name, Metric::ALL.map(Metric::name).join(", ")));
+            }
+        }
+
+        check(self.computed_keys.reload_seconds > 0, "computed_keys.reload_seconds", positive);
+
+        let scripting = &self.scripting;
+        check(scripting.reload_seconds > 0, "scripting.reload_seconds", positive);
+        check(scripting.timeout_ms > 0, "scripting.timeout_ms", positive);
+        check(scripting.max_operations > 0, "scripting.max_operations", positive);
+        check(scripting.max_string_size > 0, "scripting.max_string_size", positive);
+        check(scripting.max_collection_size > 0, "scripting.max_collection_size", positive);
+
+        check(self.plugins.fuel > 0, "plugins.fuel", positive);
+        check(self.plugins.max_memory_mb > 0, "plugins.max_memory_mb", positive);
+
+        let export = &self.export;
+        if let Err(e) = ExportConfig::sink_from_env_value(&export.sink, &export.address) {
+            check(false, "export.sink", &format!("is invalid: {}", e));
+        }
+        check(export.interval_seconds > 0, "export.interval_seconds", positive);
+        check(export.batch_size > 0, "export.batch_size", positive);
+        check(export.buffer_lines >= export.batch_size, "export.buffer_lines", "must be at least export.batch_size");
+
+        check(self.mqtt.qos <= 2, "mqtt.qos", "must be 0, 1 or 2");
+        if let Some(problem) = mqtt::ingest_topic_problem(&self.mqtt.topic_prefix, &self.mqtt.ingest_topic) {
+            check(false, "mqtt.ingest_topic", problem);
+        }
+        check(self.mqtt.queue_capacity > 0, "mqtt.queue_capacity", positive);
+
+        check(self.fights.curve_points >= 2, "fights.curve_points", "must be at least 2");
+
+        let affects = &self.affects;
+        check(affects.seconds_per_unit.is_finite() && affects.seconds_per_unit > 0.0, "affects.seconds_per_unit", positive);
+        check(affects.refresh_seconds > 0, "affects.refresh_seconds", positive);
+
+        let webhooks = &self.webhooks;
+        check(webhooks.max_attempts > 0, "webhooks.max_attempts", positive);
+        check(webhooks.timeout_seconds > 0, "webhooks.timeout_seconds", positive);
+        check(webhooks.queue_capacity > 0, "webhooks.queue_capacity", positive);
+    }
+
+    /// The effective configuration as TOML, with secrets left out.
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        for secret in [&mut config.export.influx_token, &mut config.mqtt.password] {
+            if !secret.is_empty() {
+                *secret = REDACTED.to_string();
+            }
+        }
+        let text = toml::to_string(&config).expect("the config serializes to TOML");
+        format!("# Effective configuration: defaults, config file and environment combined.\n\n{}", text)
+    }
+
+    /// A copy of `self` with every setting that can change live taken from `live`.
+    fn with_live_settings_of(&self, live: &Config) -> Config {
+        let mut config = self.clone();
+        config.server.data_timeout_minutes = live.server.data_timeout_minutes;
+        config.server.connection_timeout_seconds = live.server.connection_timeout_seconds;
+        config.server.tombstone_retention_seconds = live.server.tombstone_retention_seconds;
+        let cleanup_interval_seconds = config.rate_limit.cleanup_interval_seconds;
+        config.rate_limit = RateLimitSection { cleanup_interval_seconds, ..live.rate_limit.clone() };
+        config.priority = live.priority.clone();
+        config.commands = live.commands.clone();
+        config.metrics = live.metrics.clone();
+        config.alerts = live.alerts.clone();
+        config
+    }
+
+    pub fn data_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.data_timeout_minutes * 60)
+    }
+
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.connection_timeout_seconds)
+    }
+
+    pub fn tombstone_retention(&self) -> Duration {
+        Duration::from_secs(self.server.tombstone_retention_seconds)
+    }
+}
+
+impl RateLimitSection {
+    pub fn limiter_config(&self) -> RateLimiterConfig {
+        RateLimiterConfig {
+            rps: self.rps,
+            burst_capacity: self.burst_capacity,
+            violation_threshold: self.violation_threshold,
+            ban_duration: Duration::from_secs(self.ban_duration_seconds),
+            cleanup_interval: Duration::from_secs(self.cleanup_interval_seconds),
+        }
+    }
+}
+
+impl PrioritySection {
+    pub fn priority_config(&self) -> PriorityConfig {
+        PriorityConfig::parse(&self.keys, &self.drop_thresholds, Duration::from_millis(self.min_flush_spacing_ms))
+    }
+}
+
+impl CommandsSection {
+    pub fn command_config(&self) -> CommandConfig {
+        CommandConfig {
+            default_ttl: Duration::from_secs(self.ttl_seconds),
+            max_ttl: Duration::from_secs(self.max_ttl_seconds),
+            queue_capacity: self.queue_capacity,
+            history: self.history,
+            default_allowlist: self.allowlist.clone(),
+        }
+    }
+}
+
+impl MetricsSection {
+    pub fn metrics_config(&self) -> MetricsConfig {
+        MetricsConfig::parse(&self.character_keys, self.max_character_series)
+    }
+}
+
+/// The config file from `--config <path>` or CONFIG_FILE, if any.
+pub fn file_path(args: &[String]) -> Option<PathBuf> {
+    let from_args = args.iter().position(|arg| arg == "--config").and_then(|i| args.get(i + 1));
+    from_args.cloned().or_else(|| env::var("CONFIG_FILE").ok()).filter(|path| !path.trim().is_empty()).map(PathBuf::from)
+}
+
+// Modification times of the config file and the alert rules file.
+type Fingerprint = (Option<SystemTime>, Option<SystemTime>);
+
+fn fingerprint(path: Option<&Path>, config: &Config) -> Fingerprint {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let rules_file = Some(config.alerts.rules_file.as_str()).filter(|file| !file.is_empty());
+    (path.and_then(modified), rules_file.and_then(|file| modified(Path::new(file))))
+}
+
+/// Reloads the configuration on SIGHUP, and when the config or alert rules file changes.
+pub async fn reload_loop(state: SharedState, path: Option<PathBuf>, interval: Duration) {
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            warn!("Failed to install SIGHUP handler, configuration is only reloaded on file changes: {}", e);
+            None
+        }
+    };
+    info!("Watching {:?} for configuration changes every {:?}", path, interval);
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    let mut last = fingerprint(path.as_deref(), &state.config.get());
+    loop {
+        #[cfg(unix)]
+        let hangup_received = async {
+            match hangup.as_mut() {
+                Some(hangup) => { hangup.recv().await; }
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<()>();
+
+        // Taken before reading, so an edit during the reload is picked up by the next check.
+        let current = tokio::select! {
+            _ = hangup_received => {
+                info!("Received SIGHUP, reloading the configuration.");
+                fingerprint(path.as_deref(), &state.config.get())
+            }
+            _ = interval.tick() => {
+                let current = fingerprint(path.as_deref(), &state.config.get());
+                if current == last {
+                    continue;
+                }
+                info!("Configuration files changed, reloading.");
+                current
+            }
+        };
+        let rules_file = state.config.get().alerts.rules_file.clone();
+        reload(&state, path.as_deref());
+        let config = state.config.get();
+        // A new alert rules file is watched from now on.
+        last = if config.alerts.rules_file == rules_file { current } else { fingerprint(path.as_deref(), &config) };
+    }
+}
+
+fn reload(state: &SharedState, path: Option<&Path>) {
+    let new = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to reload the configuration, keeping the current one: {}", e);
+            return;
+        }
+    };
+    let old = state.config.get();
+
+    let restart_required = old.changed_sections(&new.with_live_settings_of(&old));
+    if !restart_required.is_empty() {
+        warn!("Configuration changes in [{}] take effect after a restart.", restart_required.join("], ["));
+    }
+    if new.rate_limit != old.rate_limit {
+        state.rate_limiter.set_config(new.rate_limit.limiter_config());
+        state.character_rate_limiter.set_config(new.rate_limit.limiter_config());
+    }
+    if new.priority != old.priority {
+        state.priority.set(new.priority.priority_config());
+    }
+    if new.commands != old.commands {
+        state.commands.set_config(new.commands.command_config());
+    }
+    if new.metrics != old.metrics {
+        state.metrics.set(new.metrics.metrics_config());
+    }
+    // The rules file itself may have changed, so it is always read again.
+    match state.alerts.reload(&new.alerts.rules_file, new.alerts.history) {
+        Ok(()) => info!("Alert Rules: {} rule(s) from '{}', history {}", state.alerts.rule_count(), new.alerts.rules_file, new.alerts.history),
+        Err(e) => error!("Failed to reload alert rules, keeping the previous ones: {}", e),
+    }
+
+    // Settings that need a restart keep their running values, so the warning repeats on every
+    // reload until the server is restarted.
+    let applied = old.with_live_settings_of(&new);
+    let live_changes = applied.changed_sections(&old);
+    info!("Configuration reloaded. Applied changes in: {}", if live_changes.is_empty() { "none".to_string() } else { live_changes.join(", ") });
+    state.config.set(applied);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// A config file in the temp directory, removed again on drop.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(extension: &str, text: &str) -> Self {
+            let file = Self(env::temp_dir().join(format!("config_{}.{}", Uuid::new_v4(), extension)));
+            file.write(text);
+            file
+        }
+
+        fn write(&self, text: &str) {
+            std::fs::write(&self.0, text).unwrap();
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn problems(config: &Config) -> Vec<String> {
+        let mut problems = Vec::new();
+        config.validate(&mut problems);
+        problems
+    }
+
+    #[test]
+    fn files_set_their_sections_and_unknown_keys_are_rejected() {
+        let toml = ConfigFile::new("toml", "[server]\nhttp_port = 9000\n\n[rate_limit]\nrps = 2.5\n");
+        let config = Config::from_file(&toml.0).unwrap();
+        assert_eq!((config.server.http_port, config.rate_limit.rps), (9000, 2.5));
+        assert_eq!(config.websocket, WebSocketSection::default());
+        let yaml = ConfigFile::new("yml", "server:\n  http_port: 9000\nrate_limit:\n  rps: 2.5\n");
+        assert_eq!(Config::from_file(&yaml.0).unwrap(), config);
+
+        toml.write("[server]\nhttp_prot = 9000\n");
+        let error = Config::from_file(&toml.0).unwrap_err();
+        assert!(matches!(error, ConfigError::Toml { .. }));
+        assert!(error.to_string().contains("unknown field `http_prot`"), "{}", error);
+        toml.write("[sever]\nhttp_port = 9000\n");
+        assert!(Config::from_file(&toml.0).unwrap_err().to_string().contains("unknown field `sever`"));
+        yaml.write("server:\n  http_prot: 9000\n");
+        assert!(matches!(Config::from_file(&yaml.0), Err(ConfigError::Yaml { .. })));
+
+        let ini = ConfigFile::new("ini", "");
+        assert!(matches!(Config::from_file(&ini.0), Err(ConfigError::Format(_))));
+    }
+
+    #[test]
+    fn invalid_environment_values_name_the_setting_and_the_variable() {
+        // A variable of its own, so tests loading the config in parallel do not see it.
+        let name = "CONFIG_TEST_RATE_LIMIT_RPS";
+        let (mut rps, mut problems) = (5.0, Vec::new());
+        env::set_var(name, "fast");
+        override_from_env(&mut rps, "rate_limit.rps", name, &mut problems);
+        assert_eq!(problems, ["rate_limit.rps (CONFIG_TEST_RATE_LIMIT_RPS) has invalid value 'fast': invalid float literal"]);
+        assert_eq!(rps, 5.0);
+
+        env::set_var(name, " 2.5 ");
+        problems.clear();
+        override_from_env(&mut rps, "rate_limit.rps", name, &mut problems);
+        env::remove_var(name);
+        assert!(problems.is_empty());
+        assert_eq!(rps, 2.5);
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        assert!(problems(&Config::default()).is_empty());
+
+        let mut config = Config::default();
+        config.priority.drop_thresholds = "HEALTH:10, MANA, MOVES:-5".to_string();
+        config.derived.metrics = "HEALTH_PCT, NOPE".to_string();
+        config.rate_limit.rps = 0.0;
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 4);
+        assert_eq!(problems[0], "rate_limit.rps (RATE_LIMIT_RPS) must be greater than 0");
+        assert_eq!(problems[1], "priority.drop_thresholds (PRIORITY_DROP_THRESHOLDS) has invalid entry 'MANA', expected KEY:PERCENT");
+        assert_eq!(problems[2], "priority.drop_thresholds (PRIORITY_DROP_THRESHOLDS) has invalid entry 'MOVES:-5', expected KEY:PERCENT");
+        assert!(problems[3].starts_with("derived.metrics (DERIVED_METRICS) has unknown metric 'NOPE', known: "), "{}", problems[3]);
+
+        let toml = ConfigFile::new("toml", "[derived]\nmetrics = \"NOPE\"\n");
+        let error = Config::load(Some(&toml.0)).unwrap_err();
+        assert!(matches!(&error, ConfigError::Invalid(problems) if problems.len() == 1), "{}", error);
+        assert!(error.to_string().starts_with("invalid configuration:\n  - derived.metrics (DERIVED_METRICS)"));
+    }
+
+    #[test]
+    fn printed_config_redacts_secrets() {
+        let mut config = Config::default();
+        config.export.influx_token = "influx-secret".to_string();
+        config.mqtt.password = "mqtt-secret".to_string();
+        let printed = config.to_toml();
+        assert!(!printed.contains("secret"), "{}", printed);
+
+        let parsed: Config = toml::from_str(&printed).unwrap();
+        assert_eq!((parsed.export.influx_token.as_str(), parsed.mqtt.password.as_str()), (REDACTED, REDACTED));
+        config.export.influx_token = REDACTED.to_string();
+        config.mqtt.password = REDACTED.to_string();
+        assert_eq!(parsed, config);
+
+        // Unset secrets stay empty, so the output shows that none is configured.
+        let parsed: Config = toml::from_str(&Config::default().to_toml()).unwrap();
+        assert_eq!(parsed, Config::default());
+    }
+
+    #[test]
+    fn only_live_sections_change_without_a_restart() {
+        let old = Config::default();
+        let mut new = Config::default();
+        new.server.http_port = 9000;
+        new.server.data_timeout_minutes = 5;
+        new.rate_limit.rps = 1.0;
+        new.rate_limit.cleanup_interval_seconds = 60;
+        new.priority.keys = "HEALTH".to_string();
+        new.websocket.queue_capacity = 8;
+
+        assert_eq!(old.changed_sections(&new), ["server", "rate_limit", "websocket", "priority"]);
+        // What a reload warns about: the port, the cleanup interval and the WebSocket queue.
+        assert_eq!(old.changed_sections(&new.with_live_settings_of(&old)), ["server", "rate_limit", "websocket"]);
+
+        let applied = old.with_live_settings_of(&new);
+        assert_eq!((applied.server.http_port, applied.server.data_timeout_minutes), (8080, 5));
+        assert_eq!((applied.rate_limit.rps, applied.rate_limit.cleanup_interval_seconds), (1.0, 600));
+        assert_eq!(applied.priority.keys, "HEALTH");
+        assert_eq!(applied.websocket, old.websocket);
+    }
+
+    #[tokio::test]
+    async fn reloads_apply_live_settings_to_the_running_state() {
+        let toml = ConfigFile::new("toml", "");
+        let config = Config::load(Some(&toml.0)).unwrap();
+        let (state, _) = crate::build_state(&config, MessageFormat::Legacy).unwrap();
+        let state = Arc::new(state);
+        assert!(!state.priority.get().is_enabled());
+
+        toml.write("[server]\nhttp_port = 9000\n\n[rate_limit]\nrps = 1.5\n\n[priority]\nkeys = \"HEALTH\"\n");
+        reload(&state, Some(&toml.0));
+        let applied = state.config.get();
+        assert_eq!(applied.server.http_port, config.server.http_port);
+        assert_eq!(applied.rate_limit.rps, 1.5);
+        assert_eq!(state.rate_limiter.config.get().rps, 1.5);
+        assert_eq!(state.character_rate_limiter.config.get().rps, 1.5);
+        assert!(state.priority.get().is_enabled());
+
+        // An invalid file keeps the running configuration.
+        toml.write("[priority]\nkeys = 5\n");
+        reload(&state, Some(&toml.0));
+        assert!(Arc::ptr_eq(&applied, &state.config.get()));
+    }
+}
@@ -0,0 +1,299 @@
+// --- Derived Metrics ---
+// Values every viewer would otherwise compute itself, added to each update before it is stored:
+// percentages from raw keys and rates over time from the previous update. Derived keys start with
+// `DERIVED_` (e.g. DERIVED_HEALTH_PCT); keys with that prefix sent by MUD clients are dropped, so
+// the two can never collide. Alert rules and viewers see derived keys like any other key.
+//
+// Rates are exponential moving averages, so a single slow or fast update does not make them jump.
+// They restart from zero when a character reconnects after being marked disconnected.
+
+use std::time::{Duration, SystemTime};
+
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::numbers::as_number;
+use crate::{CharacterDataMap, CharacterInfo};
+
+pub const PREFIX: &str = "DERIVED_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    HealthPct,
+    ManaPct,
+    MovementPct,
+    DamageTakenPerSecond,
+    ManaRegenPerSecond,
+    XpPerHour,
+    SecondsSinceCombat,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 7] = [
+        Metric::HealthPct,
+        Metric::ManaPct,
+        Metric::MovementPct,
+        Metric::DamageTakenPerSecond,
+        Metric::ManaRegenPerSecond,
+        Metric::XpPerHour,
+        Metric::SecondsSinceCombat,
+    ];
+
+    /// The metric's name without the prefix, as used in DERIVED_METRICS.
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::HealthPct => "HEALTH_PCT",
+            Metric::ManaPct => "MANA_PCT",
+            Metric::MovementPct => "MOVEMENT_PCT",
+            Metric::DamageTakenPerSecond => "DAMAGE_TAKEN_PER_SECOND",
+            Metric::ManaRegenPerSecond => "MANA_REGEN_PER_SECOND",
+            Metric::XpPerHour => "XP_PER_HOUR",
+            Metric::SecondsSinceCombat => "SECONDS_SINCE_COMBAT",
+        }
+    }
+
+    fn key(self) -> String {
+        format!("{}{}", PREFIX, self.name())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DerivedConfig {
+    pub metrics: Vec<Metric>,
+    pub xp_key: String,
+    pub rate_window: Duration, // Smoothing time constant for damage and mana rates
+    pub xp_window: Duration,   // Smoothing time constant for XP per hour
+}
+
+impl DerivedConfig {
+    /// Parses the `metrics` list ("HEALTH_PCT,XP_PER_HOUR", "all" or empty for none).
+    pub fn parse(metrics: &str, xp_key: &str, rate_window: Duration, xp_window: Duration) -> Self {
+        let metrics = if metrics.trim().eq_ignore_ascii_case("all") {
+            Metric::ALL.to_vec()
+        } else {
+            metrics.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .filter_map(|name| {
+                    let metric = Metric::ALL.into_iter().find(|m| m.name().eq_ignore_ascii_case(name));
+                    if metric.is_none() {
+                        warn!("Ignoring unknown DERIVED_METRICS entry '{}'. Known metrics: {}.", name,
+                            Metric::ALL.map(Metric::name).join(", "));
+                    }
+                    metric
+                })
+                .collect()
+        };
+        Self { metrics, xp_key: xp_key.trim().to_string(), rate_window, xp_window }
+    }
+
+    /// Drops client keys in the derived namespace, then adds the enabled metrics to `data`.
+    pub fn apply(&self, data: &mut CharacterDataMap, previous: Option<&CharacterInfo>, now: SystemTime) {
+        let before = data.len();
+        data.retain(|key, _| !key.starts_with(PREFIX));
+        if data.len() != before {
+            debug!("Dropped {} client key(s) with the reserved '{}' prefix.", before - data.len(), PREFIX);
+        }
+        if self.metrics.is_empty() {
+            return;
+        }
+
+        // A negative elapsed time means the clock moved back; such an update is treated like the first one.
+        let update = Update {
+            previous: previous.map(|p| &p.data),
+            elapsed: previous.and_then(|p| now.duration_since(p.timestamp).ok()).map(|d| d.as_secs_f64()),
+            same_session: previous.is_some_and(CharacterInfo::is_connected),
+        };
+
+        for metric in &self.metrics {
+            let value = match metric {
+                Metric::HealthPct => percentage(data, "HEALTH", "HEALTH_MAX"),
+                Metric::ManaPct => percentage(data, "MANA", "MANA_MAX"),
+                Metric::MovementPct => percentage(data, "MOVEMENT", "MOVEMENT_MAX"),
+                Metric::DamageTakenPerSecond => update.rate(*metric, data, "HEALTH", -1.0, self.rate_window, 1.0),
+                Metric::ManaRegenPerSecond => update.rate(*metric, data, "MANA", 1.0, self.rate_window, 1.0),
+                Metric::XpPerHour => update.rate(*metric, data, &self.xp_key, 1.0, self.xp_window, 3600.0).map(f64::round),
+                Metric::SecondsSinceCombat => update.seconds_since_combat(data),
+            };
+            if let Some(value) = value.filter(|v| v.is_finite()) {
+                data.insert(metric.key(), json_number(value));
+            }
+        }
+    }
+}
+
+/// What is known about the character's previous update.
+struct Update<'a> {
+    previous: Option<&'a CharacterDataMap>,
+    elapsed: Option<f64>, // Seconds since the previous update
+    same_session: bool,   // The previous update was not followed by a disconnect
+}
+
+impl Update<'_> {
+    /// Smoothed rate of increase (`direction` 1.0) or decrease (-1.0) of `key`, per `unit` seconds.
+    /// Changes in the other direction count as zero, so e.g. healing does not offset damage taken.
+    fn rate(&self, metric: Metric, data: &CharacterDataMap, key: &str, direction: f64, window: Duration, unit: f64) -> Option<f64> {
+        let current = as_number(data.get(key)?)?;
+        let (Some(previous), Some(elapsed), true) = (self.previous, self.elapsed, self.same_session) else {
+            return Some(0.0);
+        };
+        let last_rate = previous.get(&metric.key()).and_then(as_number).unwrap_or(0.0);
+        let Some(last) = previous.get(key).and_then(as_number) else {
+            return Some(last_rate);
+        };
+        if elapsed <= 0.0 {
+            return Some(last_rate);
+        }
+        let instant = ((current - last) * direction).max(0.0) / elapsed * unit;
+        let alpha = 1.0 - (-elapsed / window.as_secs_f64().max(1.0)).exp();
+        Some(round1(last_rate + alpha * (instant - last_rate)))
+    }
+
+    /// Zero while the character has an opponent, counting up afterwards. Absent until its first fight.
+    fn seconds_since_combat(&self, data: &CharacterDataMap) -> Option<f64> {
+        let fighting = data.get("OPPONENT_NAME").and_then(Value::as_str).is_some_and(|name| !name.trim().is_empty());
+        if fighting {
+            return Some(0.0);
+        }
+        let last = self.previous?.get(&Metric::SecondsSinceCombat.key()).and_then(as_number)?;
+        Some(round1(last + self.elapsed.unwrap_or(0.0)))
+    }
+}
+
+fn percentage(data: &CharacterDataMap, key: &str, max_key: &str) -> Option<f64> {
+    let max = as_number(data.get(max_key)?)?;
+    let value = as_number(data.get(key)?)?;
+    (max > 0.0).then(|| (value / max * 100.0).round())
+}
+
+/// Whole numbers are sent as integers, so viewers show `30` rather than `30.0`.
+pub fn json_number(value: f64) -> Value {
+    if value.fract() == 0.0 && value.abs() < 9_007_199_254_740_992.0 {
+        Value::from(value as i64)
+    } else {
+        Value::from(value)
+    }
+}
+
+fn round1(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> DerivedConfig {
+        DerivedConfig::parse("all", "EXP", Duration::from_secs(10), Duration::from_secs(60))
+    }
+
+    fn data(values: &[(&str, Value)]) -> CharacterDataMap {
+        let mut data: CharacterDataMap = values.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        data.insert("CONNECTED".to_string(), json!("YES"));
+        data
+    }
+
+    /// Runs `updates` through `config` as consecutive updates at the given offsets in seconds,
+    /// returning what was stored for each.
+    fn run(config: &DerivedConfig, updates: &[(f64, CharacterDataMap)]) -> Vec<CharacterDataMap> {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut previous: Option<CharacterInfo> = None;
+        let mut stored = Vec::new();
+        for (offset, update) in updates {
+            let now = start + Duration::from_secs_f64(*offset);
+            let mut update = update.clone();
+            config.apply(&mut update, previous.as_ref(), now);
+            previous = Some(CharacterInfo { data: update.clone(), timestamp: now, first_seen: start, source: "test".to_string() });
+            stored.push(update);
+        }
+        stored
+    }
+
+    fn metric(data: &CharacterDataMap, metric: Metric) -> Option<&Value> {
+        data.get(&metric.key())
+    }
+
+    #[test]
+    fn parses_metric_names() {
+        let config = DerivedConfig::parse(" health_pct, NOPE ,XP_PER_HOUR,", " EXP ", Duration::ZERO, Duration::ZERO);
+        assert_eq!(config.metrics, vec![Metric::HealthPct, Metric::XpPerHour]);
+        assert_eq!(config.xp_key, "EXP");
+        assert_eq!(DerivedConfig::parse("ALL", "", Duration::ZERO, Duration::ZERO).metrics, Metric::ALL.to_vec());
+    }
+
+    #[test]
+    fn percentages_need_a_positive_max() {
+        let stored = run(&config(), &[
+            (0.0, data(&[("HEALTH", json!("50")), ("HEALTH_MAX", json!(200)), ("MANA", json!(10)), ("MANA_MAX", json!(0))])),
+            (1.0, data(&[("HEALTH", json!(-20)), ("HEALTH_MAX", json!("200")), ("MOVEMENT", json!(10))])),
+            (2.0, data(&[("HEALTH", json!("dead")), ("HEALTH_MAX", json!(200))])),
+        ]);
+        assert_eq!(metric(&stored[0], Metric::HealthPct), Some(&json!(25)));
+        assert_eq!(metric(&stored[0], Metric::ManaPct), None);
+        assert_eq!(metric(&stored[1], Metric::HealthPct), Some(&json!(-10)));
+        assert_eq!(metric(&stored[1], Metric::MovementPct), None);
+        assert_eq!(metric(&stored[2], Metric::HealthPct), None);
+    }
+
+    #[test]
+    fn damage_taken_is_a_moving_average_of_health_lost() {
+        let stored = run(&config(), &[
+            (0.0, data(&[("HEALTH", json!(100))])),
+            // 20 HP in 2s is 10/s; with a 10s window the average moves 1 - e^-0.2 of the way there.
+            (2.0, data(&[("HEALTH", json!(80))])),
+            (4.0, data(&[("HEALTH", json!(80))])),
+            // Healing counts as no damage rather than negative damage.
+            (6.0, data(&[("HEALTH", json!(100))])),
+        ]);
+        let rates: Vec<Option<&Value>> = stored.iter().map(|d| metric(d, Metric::DamageTakenPerSecond)).collect();
+        assert_eq!(rates, vec![Some(&json!(0)), Some(&json!(1.8)), Some(&json!(1.5)), Some(&json!(1.2))]);
+    }
+
+    #[test]
+    fn mana_regen_and_xp_per_hour_use_their_own_windows() {
+        let stored = run(&config(), &[
+            (0.0, data(&[("MANA", json!(10)), ("EXP", json!("1000"))])),
+            (5.0, data(&[("MANA", json!(30)), ("EXP", json!("1000"))])),
+            (65.0, data(&[("MANA", json!(30)), ("EXP", json!("1100"))])),
+        ]);
+        // 4 mana/s over 5s of a 10s window.
+        assert_eq!(metric(&stored[1], Metric::ManaRegenPerSecond), Some(&json!(1.6)));
+        // 100 XP in 60s is 6000/h; one full 60s window moves the average 1 - e^-1 of the way.
+        assert_eq!(metric(&stored[2], Metric::XpPerHour), Some(&json!(3793)));
+    }
+
+    #[test]
+    fn rates_restart_after_a_disconnect() {
+        let mut disconnected = data(&[("HEALTH", json!(50))]);
+        disconnected.insert("CONNECTED".to_string(), json!("NO"));
+        let stored = run(&config(), &[
+            (0.0, data(&[("HEALTH", json!(100))])),
+            (2.0, data(&[("HEALTH", json!(50))])),
+            (3.0, disconnected),
+            (60.0, data(&[("HEALTH", json!(10))])),
+        ]);
+        assert_ne!(metric(&stored[2], Metric::DamageTakenPerSecond), Some(&json!(0)));
+        assert_eq!(metric(&stored[3], Metric::DamageTakenPerSecond), Some(&json!(0)));
+    }
+
+    #[test]
+    fn seconds_since_combat_counts_from_the_last_opponent() {
+        let stored = run(&config(), &[
+            (0.0, data(&[("OPPONENT_NAME", json!(""))])),
+            (1.0, data(&[("OPPONENT_NAME", json!("a cave troll"))])),
+            (4.0, data(&[("OPPONENT_NAME", json!(" "))])),
+            (6.5, data(&[])),
+        ]);
+        let seconds: Vec<Option<&Value>> = stored.iter().map(|d| metric(d, Metric::SecondsSinceCombat)).collect();
+        assert_eq!(seconds, vec![None, Some(&json!(0)), Some(&json!(3)), Some(&json!(5.5))]);
+    }
+
+    #[test]
+    fn client_keys_in_the_derived_namespace_are_dropped() {
+        let config = DerivedConfig::parse("", "", Duration::ZERO, Duration::ZERO);
+        let stored = run(&config, &[(0.0, data(&[("DERIVED_HEALTH_PCT", json!(100)), ("HEALTH", json!(5))]))]);
+        assert!(!stored[0].contains_key("DERIVED_HEALTH_PCT"));
+        assert!(stored[0].contains_key("HEALTH"));
+    }
+}
@@ -0,0 +1,481 @@
+// --- Metrics Export ---
+// Pushes character values and server stats to a time-series sink for long-term graphs, as an
+// alternative to scraping /metrics. Every EXPORT_INTERVAL_SECONDS the numeric keys of connected
+// characters (EXPORT_KEYS) and a few server stats are sampled and rendered for the sink:
+//
+//   influx-http  InfluxDB line protocol, POSTed to the EXPORT_ADDRESS URL
+//   influx-udp   InfluxDB line protocol over UDP
+//   graphite     Graphite plaintext over TCP
+//   statsd       StatsD gauges over UDP
+//
+// Lines are buffered and sent in batches of EXPORT_BATCH_SIZE. When a send fails, the unsent lines
+// stay buffered (up to EXPORT_BUFFER_LINES, dropping the oldest) and sending is retried with a
+// doubling backoff up to EXPORT_MAX_BACKOFF_SECONDS, while sampling goes on.
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::numbers::as_number;
+use crate::{metrics, SharedState};
+
+// Keeps a UDP packet within a typical MTU.
+const MAX_DATAGRAM_BYTES: usize = 1400;
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sink {
+    InfluxHttp,
+    InfluxUdp,
+    Graphite,
+    Statsd,
+}
+
+impl Sink {
+    const ALL: [(&'static str, Sink); 4] =
+        [("influx-http", Sink::InfluxHttp), ("influx-udp", Sink::InfluxUdp), ("graphite", Sink::Graphite), ("statsd", Sink::Statsd)];
+}
+
+#[derive(Clone, Debug)]
+pub struct ExportConfig {
+    pub sink: Option<Sink>,    // None disables exporting
+    pub address: String,       // URL for influx-http, host:port otherwise
+    pub influx_token: String,  // Sent as `Authorization: Token ...` by influx-http
+    pub prefix: String,
+    pub keys: HashSet<String>, // Empty for every numeric key
+    pub interval: Duration,
+    pub batch_size: usize,
+    pub buffer_lines: usize,
+    pub max_backoff: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("unknown EXPORT_SINK '{0}', expected one of influx-http, influx-udp, graphite, statsd")]
+    UnknownSink(String),
+    #[error("EXPORT_ADDRESS is required for EXPORT_SINK '{0}'")]
+    MissingAddress(String),
+    #[error("HTTP client: {0}")]
+    Client(#[from] reqwest::Error),
+    #[error("UDP socket: {0}")]
+    Socket(#[from] std::io::Error),
+}
+
+impl ExportConfig {
+    /// Parses `EXPORT_SINK`, where empty disables exporting.
+    pub fn sink_from_env_value(sink: &str, address: &str) -> Result<Option<Sink>, ExportError> {
+        let sink = sink.trim();
+        if sink.is_empty() {
+            return Ok(None);
+        }
+        let found = Sink::ALL.iter().find(|(name, _)| name.eq_ignore_ascii_case(sink)).map(|(_, sink)| *sink);
+        let found = found.ok_or_else(|| ExportError::UnknownSink(sink.to_string()))?;
+        if address.trim().is_empty() {
+            return Err(ExportError::MissingAddress(sink.to_string()));
+        }
+        Ok(Some(found))
+    }
+
+    /// Parses `EXPORT_KEYS`: "HEALTH,MANA", or "*" for every numeric key.
+    pub fn keys_from_env_value(keys: &str) -> HashSet<String> {
+        match keys.trim() {
+            "*" => HashSet::new(),
+            keys => keys.split(',').map(str::trim).filter(|k| !k.is_empty()).map(String::from).collect(),
+        }
+    }
+}
+
+enum Transport {
+    Http { client: reqwest::Client, url: String, token: String },
+    Udp { socket: UdpSocket, address: String },
+    Tcp { address: String },
+}
+
+pub struct Exporter {
+    config: ExportConfig,
+    sink: Sink,
+    transport: Transport,
+    buffer: VecDeque<String>,
+    backoff: Option<(Duration, Instant)>, // Current backoff and when sending may be retried
+}
+
+impl Exporter {
+    /// None if exporting is disabled.
+    pub async fn new(config: ExportConfig) -> Result<Option<Self>, ExportError> {
+        let Some(sink) = config.sink else { return Ok(None) };
+        let address = config.address.trim().to_string();
+        let transport = match sink {
+            Sink::InfluxHttp => Transport::Http {
+                client: reqwest::Client::builder().timeout(SEND_TIMEOUT).build()?,
+                url: address,
+                token: config.influx_token.clone(),
+            },
+            Sink::InfluxUdp | Sink::Statsd => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                Transport::Udp { socket, address }
+            }
+            Sink::Graphite => Transport::Tcp { address },
+        };
+        Ok(Some(Self { config, sink, transport, buffer: VecDeque::new(), backoff: None }))
+    }
+
+    fn sample(&mut self, state: &SharedState, now: SystemTime) {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let summaries = state.store.summaries();
+        let mut lines = Vec::new();
+        for (character, connected, _) in &summaries {
+            if !connected {
+                continue;
+            }
+            let mut fields: Vec<(String, f64)> = state.store.inspect(character, |info| {
+                info.into_iter()
+                    .flat_map(|info| &info.data)
+                    .filter(|(key, _)| self.config.keys.is_empty() || self.config.keys.contains(*key))
+                    .filter_map(|(key, value)| Some((key.clone(), as_number(value)?)))
+                    .collect()
+            });
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            self.render(&mut lines, Some(character), &fields, timestamp);
+        }
+
+        let connected = summaries.iter().filter(|(_, connected, _)| *connected).count();
+        let server = [
+            ("characters", summaries.len() as f64),
+            ("characters_connected", connected as f64),
+            ("subscribers", state.subscribers.len() as f64),
+            ("ingest_requests_total", metrics::total(&metrics::INGEST_REQUESTS) as f64),
+            ("parse_errors_total", metrics::total(&metrics::PARSE_ERRORS) as f64),
+            ("pruned_characters_total", metrics::PRUNED_CHARACTERS.get() as f64),
+            ("rate_limit_throttled_total", metrics::RATE_LIMIT_THROTTLED.get() as f64),
+            ("rate_limit_bans_total", metrics::RATE_LIMIT_BANS.get() as f64),
+        ].map(|(name, value)| (name.to_string(), value));
+        self.render(&mut lines, None, &server, timestamp);
+
+        self.buffer.extend(lines);
+        let overflow = self.buffer.len().saturating_sub(self.config.buffer_lines);
+        if overflow > 0 {
+            self.buffer.drain(..overflow);
+            warn!("Export buffer full ({} lines), dropped the {} oldest.", self.config.buffer_lines, overflow);
+        }
+    }
+
+    /// Appends the lines for one character (None for the server stats) in the sink's format.
+    fn render(&self, lines: &mut Vec<String>, character: Option<&str>, fields: &[(String, f64)], timestamp: Duration) {
+        if fields.is_empty() {
+            return;
+        }
+        let prefix = &self.config.prefix;
+        match self.sink {
+            Sink::InfluxHttp | Sink::InfluxUdp => {
+                let series = match character {
+                    Some(name) => format!("{}_character,character={}", influx_measurement(prefix), influx_escape(name)),
+                    None => format!("{}_server", influx_measurement(prefix)),
+                };
+                // Influx fields are chunked so one line stays within a datagram.
+                let mut line = String::new();
+                for (key, value) in fields {
+                    let field = format!("{}={}", influx_escape(key), value);
+                    if !line.is_empty() && series.len() + line.len() + field.len() + 32 > MAX_DATAGRAM_BYTES {
+                        lines.push(format!("{} {} {}", series, line, timestamp.as_nanos()));
+                        line.clear();
+                    }
+                    if !line.is_empty() {
+                        line.push(',');
+                    }
+                    line.push_str(&field);
+                }
+                lines.push(format!("{} {} {}", series, line, timestamp.as_nanos()));
+            }
+            Sink::Graphite | Sink::Statsd => {
+                let path = match character {
+                    Some(name) => format!("{}.character.{}", path_component(prefix), path_component(name)),
+                    None => format!("{}.server", path_component(prefix)),
+                };
+                for (key, value) in fields {
+                    let metric = format!("{}.{}", path, path_component(key));
+                    match self.sink {
+                        Sink::Graphite => lines.push(format!("{} {} {}", metric, value, timestamp.as_secs())),
+                        _ => {
+                            // A signed StatsD gauge is a relative change, so negative values reset to zero first.
+                            if *value < 0.0 {
+                                lines.push(format!("{}:0|g", metric));
+                            }
+                            lines.push(format!("{}:{}|g", metric, value));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends buffered lines batch by batch until the buffer is empty or a send fails.
+    async fn flush(&mut self) {
+        if self.backoff.is_some_and(|(_, retry_at)| Instant::now() < retry_at) {
+            return;
+        }
+        let mut sent = 0;
+        while !self.buffer.is_empty() {
+            let count = self.buffer.len().min(self.config.batch_size);
+            let batch: Vec<&str> = self.buffer.iter().take(count).map(String::as_str).collect();
+            match tokio::time::timeout(SEND_TIMEOUT, send(&self.transport, &batch)).await.unwrap_or_else(|_| Err("timed out".to_string())) {
+                Ok(()) => {
+                    self.buffer.drain(..count);
+                    sent += count;
+                    if self.backoff.take().is_some() {
+                        info!("Metrics export to '{}' recovered.", self.config.address);
+                    }
+                }
+                Err(reason) => {
+                    let backoff = self.backoff.map_or(self.config.interval, |(last, _)| (last * 2).min(self.config.max_backoff));
+                    warn!("Metrics export to '{}' failed: {}. {} line(s) buffered, retrying in {:?}.",
+                        self.config.address, reason, self.buffer.len(), backoff);
+                    self.backoff = Some((backoff, Instant::now() + backoff));
+                    break;
+                }
+            }
+        }
+        if sent > 0 {
+            debug!("Exported {} line(s) to '{}'.", sent, self.config.address);
+        }
+    }
+}
+
+async fn send(transport: &Transport, batch: &[&str]) -> Result<(), String> {
+    match transport {
+        Transport::Http { client, url, token } => {
+            let mut request = client.post(url).body(batch.join("\n"));
+            if !token.is_empty() {
+                request = request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token));
+            }
+            let response = request.send().await.map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("HTTP {}", response.status()));
+            }
+        }
+        Transport::Udp { socket, address } => {
+            let mut datagram = String::new();
+            for line in batch {
+                if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM_BYTES {
+                    socket.send_to(datagram.as_bytes(), address.as_str()).await.map_err(|e| e.to_string())?;
+                    datagram.clear();
+                }
+                datagram.push_str(line);
+                datagram.push('\n');
+            }
+            socket.send_to(datagram.as_bytes(), address.as_str()).await.map_err(|e| e.to_string())?;
+        }
+        Transport::Tcp { address } => {
+            let mut stream = TcpStream::connect(address.as_str()).await.map_err(|e| e.to_string())?;
+            let mut payload = batch.join("\n");
+            payload.push('\n');
+            stream.write_all(payload.as_bytes()).await.map_err(|e| e.to_string())?;
+            stream.shutdown().await.map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Samples and flushes every `config.interval`.
+pub async fn export_loop(state: SharedState, mut exporter: Exporter) {
+    info!("Exporting metrics to {:?} sink '{}' every {:?}", exporter.sink, exporter.config.address, exporter.config.interval);
+    let mut interval = tokio::time::interval(exporter.config.interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        exporter.sample(&state, SystemTime::now());
+        exporter.flush().await;
+    }
+}
+
+/// Tag values and field keys escape commas, spaces and equals signs.
+fn influx_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace(',', "\\,").replace(' ', "\\ ").replace('=', "\\=")
+}
+
+/// Measurement names only escape commas and spaces; a backslash before anything else is kept literally.
+fn influx_measurement(text: &str) -> String {
+    text.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+/// Graphite and StatsD paths use dots as separators, so anything else unusual becomes `_`.
+fn path_component(text: &str) -> String {
+    text.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use crate::config::Config;
+    use crate::protocol::MessageFormat;
+
+    const TIMESTAMP: Duration = Duration::from_secs(1_700_000_000);
+
+    async fn exporter(sink: Sink, address: &str) -> Exporter {
+        let config = ExportConfig {
+            sink: Some(sink),
+            address: address.to_string(),
+            influx_token: String::new(),
+            prefix: "mud".to_string(),
+            keys: HashSet::new(),
+            interval: Duration::from_millis(10),
+            batch_size: 2,
+            buffer_lines: 100,
+            max_backoff: Duration::from_millis(40),
+        };
+        Exporter::new(config).await.unwrap().unwrap()
+    }
+
+    fn fields(pairs: &[(&str, f64)]) -> Vec<(String, f64)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), *value)).collect()
+    }
+
+    fn rendered(exporter: &Exporter, character: Option<&str>, pairs: &[(&str, f64)]) -> Vec<String> {
+        let mut lines = Vec::new();
+        exporter.render(&mut lines, character, &fields(pairs), TIMESTAMP);
+        lines
+    }
+
+    #[tokio::test]
+    async fn influx_line_protocol() {
+        let influx = exporter(Sink::InfluxUdp, "127.0.0.1:8089").await;
+        assert_eq!(
+            rendered(&influx, Some("Thoric"), &[("HEALTH", 120.0), ("MANA", 80.5)]),
+            ["mud_character,character=Thoric HEALTH=120,MANA=80.5 1700000000000000000"],
+        );
+        assert_eq!(rendered(&influx, None, &[("characters", 2.0)]), ["mud_server characters=2 1700000000000000000"]);
+        assert!(rendered(&influx, Some("Thoric"), &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn graphite_and_statsd_lines() {
+        let graphite = exporter(Sink::Graphite, "127.0.0.1:2003").await;
+        assert_eq!(
+            rendered(&graphite, Some("Thoric"), &[("HEALTH", 120.0), ("MANA", 80.5)]),
+            ["mud.character.Thoric.HEALTH 120 1700000000", "mud.character.Thoric.MANA 80.5 1700000000"],
+        );
+        assert_eq!(rendered(&graphite, None, &[("subscribers", 3.0)]), ["mud.server.subscribers 3 1700000000"]);
+
+        let statsd = exporter(Sink::Statsd, "127.0.0.1:8125").await;
+        assert_eq!(rendered(&statsd, Some("Thoric"), &[("HEALTH", -5.0)]), ["mud.character.Thoric.HEALTH:0|g", "mud.character.Thoric.HEALTH:-5|g"]);
+        assert_eq!(rendered(&statsd, Some("Thoric"), &[("HEALTH", 5.0)]), ["mud.character.Thoric.HEALTH:5|g"]);
+    }
+
+    #[tokio::test]
+    async fn names_with_spaces_and_commas_are_escaped() {
+        let mut influx = exporter(Sink::InfluxHttp, "http://127.0.0.1:8086/write").await;
+        influx.config.prefix = "mud stats,eu".to_string();
+        assert_eq!(
+            rendered(&influx, Some("Sir Thoric, the=Bold"), &[("HP NOW", 1.0)]),
+            [r"mud\ stats\,eu_character,character=Sir\ Thoric\,\ the\=Bold HP\ NOW=1 1700000000000000000"],
+        );
+
+        let mut graphite = exporter(Sink::Graphite, "127.0.0.1:2003").await;
+        graphite.config.prefix = "mud.eu".to_string();
+        assert_eq!(
+            rendered(&graphite, Some("Sir Thoric, the.Bold"), &[("HP NOW", 1.0)]),
+            ["mud_eu.character.Sir_Thoric__the_Bold.HP_NOW 1 1700000000"],
+        );
+    }
+
+    #[tokio::test]
+    async fn long_influx_lines_are_split_to_fit_a_datagram() {
+        let influx = exporter(Sink::InfluxUdp, "127.0.0.1:8089").await;
+        let keys: Vec<String> = (0..200).map(|i| format!("KEY_{:03}", i)).collect();
+        let pairs: Vec<(&str, f64)> = keys.iter().map(|key| (key.as_str(), 12345.5)).collect();
+        let lines = rendered(&influx, Some("Thoric"), &pairs);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.len() <= MAX_DATAGRAM_BYTES && line.starts_with("mud_character,character=Thoric ")));
+        assert_eq!(lines.iter().map(|line| line.matches('=').count() - 1).sum::<usize>(), 200);
+    }
+
+    /// Accepts Graphite connections and returns what each one sent.
+    async fn graphite_receiver(listener: TcpListener, connections: usize) -> Vec<String> {
+        let mut received = Vec::new();
+        for _ in 0..connections {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut text = String::new();
+            stream.read_to_string(&mut text).await.unwrap();
+            received.push(text);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn flush_sends_batches_and_keeps_lines_after_a_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut graphite = exporter(Sink::Graphite, &address).await;
+        graphite.buffer.extend((1..=5).map(|i| format!("mud.server.line {} 1700000000", i)));
+
+        let receiver = tokio::spawn(graphite_receiver(listener, 3));
+        graphite.flush().await;
+        assert!(graphite.buffer.is_empty());
+        assert_eq!(receiver.await.unwrap(), [
+            "mud.server.line 1 1700000000\nmud.server.line 2 1700000000\n",
+            "mud.server.line 3 1700000000\nmud.server.line 4 1700000000\n",
+            "mud.server.line 5 1700000000\n",
+        ]);
+
+        // Nothing listens on the address any more: the lines stay buffered and a backoff starts.
+        graphite.buffer.push_back("mud.server.line 6 1700000000".to_string());
+        graphite.flush().await;
+        assert_eq!(graphite.buffer.len(), 1);
+        assert!(graphite.backoff.is_some());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        graphite.transport = Transport::Tcp { address: listener.local_addr().unwrap().to_string() };
+        let receiver = tokio::spawn(graphite_receiver(listener, 1));
+        graphite.flush().await;
+        assert_eq!(graphite.buffer.len(), 1, "sent before the backoff elapsed");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        graphite.flush().await;
+        assert!(graphite.buffer.is_empty());
+        assert!(graphite.backoff.is_none());
+        assert_eq!(receiver.await.unwrap(), ["mud.server.line 6 1700000000\n"]);
+    }
+
+    #[tokio::test]
+    async fn statsd_batches_share_datagrams() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut statsd = exporter(Sink::Statsd, &socket.local_addr().unwrap().to_string()).await;
+        statsd.buffer.extend(["mud.server.a:1|g", "mud.server.b:2|g", "mud.server.c:3|g"].map(String::from));
+        statsd.flush().await;
+
+        let mut datagrams = Vec::new();
+        for _ in 0..2 {
+            let mut buffer = [0; MAX_DATAGRAM_BYTES];
+            let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buffer)).await.unwrap().unwrap();
+            datagrams.push(String::from_utf8_lossy(&buffer[..len]).into_owned());
+        }
+        assert_eq!(datagrams, ["mud.server.a:1|g\nmud.server.b:2|g\n", "mud.server.c:3|g\n"]);
+    }
+
+    #[tokio::test]
+    async fn sample_exports_connected_characters_and_drops_the_oldest_lines() {
+        let (state, _) = crate::build_state(&Config::default(), MessageFormat::Legacy).unwrap();
+        let state = Arc::new(state);
+        crate::ingest_update(&state, "{CHARACTER_NAME}{Thoric}{HEALTH}{120}{CLASS}{Warrior}", "test".to_string(), None).await.unwrap();
+        crate::ingest_update(&state, "{CHARACTER_NAME}{Zed}{HEALTH}{30}", "test".to_string(), None).await.unwrap();
+        let mut statsd = exporter(Sink::Statsd, "127.0.0.1:8125").await;
+        statsd.config.keys = ["HEALTH".to_string()].into();
+        let now = UNIX_EPOCH + TIMESTAMP;
+
+        statsd.sample(&state, now);
+        assert_eq!(statsd.buffer.iter().take(2).collect::<Vec<_>>(), ["mud.character.Thoric.HEALTH:120|g", "mud.character.Zed.HEALTH:30|g"]);
+        assert!(statsd.buffer.contains(&"mud.server.characters_connected:2|g".to_string()));
+
+        statsd.config.buffer_lines = 4;
+        statsd.sample(&state, now);
+        assert_eq!(statsd.buffer.len(), 4);
+        assert!(statsd.buffer.iter().all(|line| line.starts_with("mud.server.")));
+    }
+}
@@ -0,0 +1,388 @@
+// --- Combat Sessions ---
+// Detects fights from the OPPONENT_NAME / OPPONENT_HEALTH keys the MUD client scripts already send:
+// a fight starts when OPPONENT_NAME becomes non-empty and ends when it is cleared, changes to
+// another opponent or the character disconnects. Each finished fight becomes a record with its
+// duration, health lost, mana spent, the opponent's health over time and an outcome. Recent fights
+// are kept per character for GET /api/characters/:name/fights and announced to v2 viewers as
+// `fight_ended` events.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::time::SystemTime;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::numbers::as_number;
+use crate::viewer_events::{ViewerEvent, ViewerEvents};
+use crate::{system_time_serde, CharacterDataMap, SharedState};
+
+// An opponent last seen at or below this share of its highest observed health counts as defeated.
+const DEFEATED_BELOW: f64 = 0.1;
+
+#[derive(Clone, Debug)]
+pub struct FightConfig {
+    pub history: usize,      // Finished fights kept per character, 0 disables fight tracking
+    pub curve_points: usize, // Opponent health samples kept per fight
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Won,          // The opponent's health was (nearly) gone when the fight ended
+    Died,         // The character's health reached zero
+    Fled,         // The fight ended with the opponent still standing
+    Disconnected, // The character stopped sending updates mid-fight
+    Unknown,      // No OPPONENT_HEALTH was sent
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthSample {
+    pub seconds: f64, // Since the start of the fight
+    pub health: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FightRecord {
+    pub id: u64,
+    pub character: String,
+    pub opponent: String,
+    #[serde(with = "system_time_serde")]
+    pub started_at: SystemTime,
+    #[serde(with = "system_time_serde")]
+    pub ended_at: SystemTime,
+    pub duration_seconds: f64,
+    pub hp_lost: f64,     // Sum of health drops; healing during the fight does not offset it
+    pub mana_spent: f64,  // Sum of mana drops
+    pub opponent_health: Vec<HealthSample>,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug)]
+struct Fight {
+    opponent: String,
+    started_at: SystemTime,
+    health: Option<f64>,
+    mana: Option<f64>,
+    lowest_health: Option<f64>,
+    hp_lost: f64,
+    mana_spent: f64,
+    opponent_health: Vec<HealthSample>,
+    highest_opponent_health: Option<f64>,
+}
+
+impl Fight {
+    /// Starts from the previous update's health and mana, so losses in the first update count.
+    fn start(opponent: String, previous: Option<&CharacterDataMap>, now: SystemTime) -> Self {
+        Self {
+            opponent,
+            started_at: now,
+            health: previous.and_then(|p| p.get("HEALTH").and_then(as_number)),
+            mana: previous.and_then(|p| p.get("MANA").and_then(as_number)),
+            lowest_health: None,
+            hp_lost: 0.0,
+            mana_spent: 0.0,
+            opponent_health: Vec::new(),
+            highest_opponent_health: None,
+        }
+    }
+
+    fn observe(&mut self, data: &CharacterDataMap, now: SystemTime, curve_points: usize) {
+        if let Some(health) = data.get("HEALTH").and_then(as_number) {
+            self.hp_lost += self.health.map_or(0.0, |last| (last - health).max(0.0));
+            self.health = Some(health);
+            self.lowest_health = Some(self.lowest_health.map_or(health, |lowest| lowest.min(health)));
+        }
+        if let Some(mana) = data.get("MANA").and_then(as_number) {
+            self.mana_spent += self.mana.map_or(0.0, |last| (last - mana).max(0.0));
+            self.mana = Some(mana);
+        }
+        let Some(health) = data.get("OPPONENT_HEALTH").and_then(as_number) else { return };
+        self.highest_opponent_health = Some(self.highest_opponent_health.map_or(health, |highest| highest.max(health)));
+        if self.opponent_health.last().is_some_and(|sample| sample.health == health) {
+            return;
+        }
+        // Halving the resolution when full keeps the shape of long fights within the limit.
+        if self.opponent_health.len() >= curve_points.max(2) {
+            let mut index = 0;
+            self.opponent_health.retain(|_| { index += 1; index % 2 == 1 });
+        }
+        let seconds = now.duration_since(self.started_at).map_or(0.0, |d| d.as_secs_f64());
+        self.opponent_health.push(HealthSample { seconds: (seconds * 10.0).round() / 10.0, health });
+    }
+
+    fn outcome(&self) -> Outcome {
+        if self.lowest_health.is_some_and(|health| health <= 0.0) {
+            return Outcome::Died;
+        }
+        match (self.opponent_health.last(), self.highest_opponent_health) {
+            (Some(last), Some(highest)) if last.health <= highest * DEFEATED_BELOW => Outcome::Won,
+            (Some(_), _) => Outcome::Fled,
+            (None, _) => Outcome::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CharacterFights {
+    current: Option<Fight>,
+    finished: VecDeque<FightRecord>, // Oldest first
+}
+
+#[derive(Debug)]
+pub struct FightTracker {
+    config: FightConfig,
+    next_id: AtomicU64,
+    characters: StdMutex<HashMap<String, CharacterFights>>,
+    events: ViewerEvents,
+}
+
+impl FightTracker {
+    pub fn new(config: FightConfig, events: ViewerEvents) -> Self {
+        Self { config, next_id: AtomicU64::new(1), characters: StdMutex::new(HashMap::new()), events }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.history > 0
+    }
+
+    /// Follows one character update, ending and starting fights as the opponent changes.
+    pub fn observe(&self, character: &str, previous: Option<&CharacterDataMap>, current: &CharacterDataMap, now: SystemTime) {
+        if !self.is_enabled() {
+            return;
+        }
+        let opponent = current.get("OPPONENT_NAME").and_then(Value::as_str).map(str::trim).filter(|name| !name.is_empty());
+        let mut characters = self.characters.lock().unwrap();
+        let fights = characters.entry(character.to_string()).or_default();
+
+        if let Some(mut fight) = fights.current.take() {
+            // After a switch, this update's OPPONENT_HEALTH and losses belong to the new opponent.
+            if opponent.is_none_or(|name| name == fight.opponent) {
+                fight.observe(current, now, self.config.curve_points);
+            }
+            if opponent == Some(fight.opponent.as_str()) {
+                fights.current = Some(fight);
+                return;
+            }
+            let outcome = fight.outcome();
+            self.finish(character, fights, fight, outcome, now);
+        }
+
+        if let Some(opponent) = opponent {
+            debug!("Fight started: '{}' vs '{}'.", character, opponent);
+            let mut fight = Fight::start(opponent.to_string(), previous, now);
+            fight.observe(current, now, self.config.curve_points);
+            fights.current = Some(fight);
+        }
+    }
+
+    /// Ends a character's fight, if any, because the character stopped sending updates.
+    pub fn disconnected(&self, character: &str, now: SystemTime) {
+        let mut characters = self.characters.lock().unwrap();
+        let Some(fights) = characters.get_mut(character) else { return };
+        if let Some(fight) = fights.current.take() {
+            self.finish(character, fights, fight, Outcome::Disconnected, now);
+        }
+    }
+
+    /// Forgets a pruned character's fights.
+    pub fn remove_character(&self, character: &str) {
+        self.characters.lock().unwrap().remove(character);
+    }
+
+    /// Finished fights of one character, newest first.
+    fn recent(&self, character: &str) -> Vec<FightRecord> {
+        let characters = self.characters.lock().unwrap();
+        characters.get(character).map_or_else(Vec::new, |fights| fights.finished.iter().rev().cloned().collect())
+    }
+
+    fn finish(&self, character: &str, fights: &mut CharacterFights, fight: Fight, outcome: Outcome, now: SystemTime) {
+        let duration = now.duration_since(fight.started_at).map_or(0.0, |d| d.as_secs_f64());
+        let record = FightRecord {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            character: character.to_string(),
+            opponent: fight.opponent,
+            started_at: fight.started_at,
+            ended_at: now,
+            duration_seconds: (duration * 10.0).round() / 10.0,
+            hp_lost: fight.hp_lost,
+            mana_spent: fight.mana_spent,
+            opponent_health: fight.opponent_health,
+            outcome,
+        };
+        info!("Fight ended: '{}' vs '{}' after {:.1}s, {:?}, {} HP lost.", character, record.opponent, record.duration_seconds, outcome, record.hp_lost);
+        fights.finished.push_back(record.clone());
+        while fights.finished.len() > self.config.history {
+            fights.finished.pop_front();
+        }
+        self.events.publish_all(ViewerEvent::FightEnded { fight: record });
+    }
+}
+
+// GET /api/characters/:name/fights
+pub async fn list_fights(State(state): State<SharedState>, Path(name): Path<String>) -> Response {
+    if !state.store.inspect(&name, |info| info.is_some()) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(state.fights.recent(&name)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::json;
+    use tokio::sync::broadcast::Receiver;
+
+    use crate::viewer_events::GroupEvent;
+
+    fn tracker(history: usize, curve_points: usize) -> (FightTracker, Receiver<Arc<GroupEvent>>) {
+        let events = ViewerEvents::new();
+        let receiver = events.subscribe();
+        (FightTracker::new(FightConfig { history, curve_points }, events), receiver)
+    }
+
+    fn start() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn data(values: &[(&str, Value)]) -> CharacterDataMap {
+        values.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+    }
+
+    /// An update fighting `opponent` (none if empty) at `opponent_health` (none if negative).
+    fn fighting(opponent: &str, opponent_health: i64, health: i64, mana: i64) -> CharacterDataMap {
+        let mut update = data(&[("OPPONENT_NAME", json!(opponent)), ("HEALTH", json!(health.to_string())), ("MANA", json!(mana))]);
+        if opponent_health >= 0 {
+            update.insert("OPPONENT_HEALTH".to_string(), json!(opponent_health.to_string()));
+        }
+        update
+    }
+
+    /// Feeds Thoric's updates at the given offsets in seconds.
+    fn feed(tracker: &FightTracker, updates: &[(u64, CharacterDataMap)]) {
+        let mut previous: Option<&CharacterDataMap> = None;
+        for (offset, update) in updates {
+            tracker.observe("Thoric", previous, update, start() + Duration::from_secs(*offset));
+            previous = Some(update);
+        }
+    }
+
+    fn ended(receiver: &mut Receiver<Arc<GroupEvent>>) -> Vec<FightRecord> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|e| match &e.event {
+                ViewerEvent::FightEnded { fight } => fight.clone(),
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    /// The outcome of a single fight against a rat that ends with the given last update.
+    fn outcome_of(last: CharacterDataMap) -> Outcome {
+        let (tracker, mut events) = tracker(5, 10);
+        feed(&tracker, &[(0, fighting("a rat", 100, 100, 0)), (1, last), (2, fighting("", -1, 100, 0))]);
+        ended(&mut events)[0].outcome
+    }
+
+    #[test]
+    fn a_fight_runs_from_the_first_opponent_until_it_is_cleared() {
+        let (tracker, mut events) = tracker(5, 10);
+        feed(&tracker, &[
+            (0, fighting("", -1, 100, 50)),
+            (1, fighting("a rat", 100, 90, 40)),
+            (3, fighting("a rat", 50, 95, 45)),
+            (5, fighting("a rat", 5, 80, 30)),
+        ]);
+        assert!(tracker.recent("Thoric").is_empty());
+        assert!(ended(&mut events).is_empty());
+
+        feed(&tracker, &[(6, fighting("", -1, 80, 30))]);
+        let fight = &ended(&mut events)[0];
+        assert_eq!((fight.character.as_str(), fight.opponent.as_str()), ("Thoric", "a rat"));
+        assert_eq!((fight.started_at, fight.ended_at, fight.duration_seconds), (start() + Duration::from_secs(1), start() + Duration::from_secs(6), 5.0));
+        // Losses count from the update before the fight; healing and regeneration do not offset them.
+        assert_eq!((fight.hp_lost, fight.mana_spent), (25.0, 25.0));
+        let curve: Vec<(f64, f64)> = fight.opponent_health.iter().map(|s| (s.seconds, s.health)).collect();
+        assert_eq!(curve, vec![(0.0, 100.0), (2.0, 50.0), (4.0, 5.0)]);
+        assert_eq!(fight.outcome, Outcome::Won);
+        assert_eq!(tracker.recent("Thoric").len(), 1);
+    }
+
+    #[test]
+    fn switching_opponents_splits_the_fight() {
+        let (tracker, mut events) = tracker(5, 10);
+        feed(&tracker, &[
+            (0, fighting("a rat", 100, 100, 0)),
+            (2, fighting("a rat", 0, 100, 0)),
+            (3, fighting("a troll", 100, 90, 0)),
+        ]);
+        let rat = ended(&mut events);
+        assert_eq!(rat.len(), 1);
+        assert_eq!((rat[0].opponent.as_str(), rat[0].duration_seconds, rat[0].hp_lost, rat[0].outcome), ("a rat", 3.0, 0.0, Outcome::Won));
+
+        tracker.disconnected("Thoric", start() + Duration::from_secs(10));
+        let troll = &ended(&mut events)[0];
+        assert_eq!((troll.opponent.as_str(), troll.started_at, troll.hp_lost), ("a troll", start() + Duration::from_secs(3), 10.0));
+        assert_eq!(troll.opponent_health[0].health, 100.0);
+        assert_eq!(troll.outcome, Outcome::Disconnected);
+    }
+
+    #[test]
+    fn outcomes_depend_on_the_last_opponent_health() {
+        // At most DEFEATED_BELOW of the highest health seen counts as a win.
+        assert_eq!(outcome_of(fighting("a rat", 10, 100, 0)), Outcome::Won);
+        assert_eq!(outcome_of(fighting("a rat", 11, 100, 0)), Outcome::Fled);
+        assert_eq!(outcome_of(fighting("a rat", 0, 0, 0)), Outcome::Died);
+        assert_eq!(outcome_of(fighting("a rat", 100, -5, 0)), Outcome::Died);
+
+        let (tracker, mut events) = tracker(5, 10);
+        feed(&tracker, &[(0, fighting("a rat", -1, 100, 0)), (1, fighting("", -1, 100, 0))]);
+        assert_eq!(ended(&mut events)[0].outcome, Outcome::Unknown);
+    }
+
+    #[test]
+    fn disconnecting_outside_a_fight_does_nothing() {
+        let (tracker, mut events) = tracker(5, 10);
+        tracker.disconnected("Thoric", start());
+        feed(&tracker, &[(0, fighting("", -1, 100, 0))]);
+        tracker.disconnected("Thoric", start());
+        assert!(ended(&mut events).is_empty());
+    }
+
+    #[test]
+    fn history_and_curves_are_bounded() {
+        let (tracker, _events) = tracker(2, 4);
+        let mut updates = Vec::new();
+        for (i, opponent) in ["a rat", "a bat", "a troll"].into_iter().enumerate() {
+            let offset = i as u64 * 10;
+            for step in 0..6 {
+                updates.push((offset + step, fighting(opponent, 100 - step as i64 * 10, 100, 0)));
+            }
+            updates.push((offset + 6, fighting("", -1, 100, 0)));
+        }
+        feed(&tracker, &updates);
+
+        let recent = tracker.recent("Thoric");
+        assert_eq!(recent.iter().map(|f| f.opponent.as_str()).collect::<Vec<_>>(), vec!["a troll", "a bat"]);
+        let curve = &recent[0].opponent_health;
+        assert!(curve.len() <= 4, "{:?}", curve);
+        assert_eq!(curve.first().map(|s| s.health), Some(100.0));
+        assert_eq!(curve.last().map(|s| s.health), Some(50.0));
+    }
+
+    #[test]
+    fn nothing_is_tracked_without_history() {
+        let (tracker, mut events) = tracker(0, 10);
+        feed(&tracker, &[(0, fighting("a rat", 100, 100, 0)), (1, fighting("", -1, 100, 0))]);
+        assert!(ended(&mut events).is_empty());
+        assert!(tracker.recent("Thoric").is_empty());
+    }
+}
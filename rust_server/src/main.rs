@@ -1,14 +1,13 @@
+#![cfg_attr(feature = "bench", feature(test))]
+
 use std::time::{Duration, SystemTime}; // SystemTime is in std::time
 use headers::UserAgent; // UserAgent comes directly from the headers crate
 use tracing::trace; // Explicitly import the trace macro
-use std::str::FromStr; // For Level::from_str and get_env_var parsing
+use std::str::FromStr; // For Level::from_str
 use axum::extract::connect_info::ConnectInfo; // To get peer address
 
 use axum::{
-    extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
-    },
+    extract::{Query, State},
     http::{StatusCode, header, HeaderMap, Request}, // Added Request for middleware
     response::{Html, IntoResponse, Response},
     routing::{get, post},
@@ -28,7 +27,7 @@ use std::{
 use tokio::{
     fs::File,
     io::AsyncReadExt,
-    sync::{broadcast, Mutex}, // Tokio Mutex for AppState
+    sync::{broadcast, Notify},
     time::{self, Instant},
 };
 use tower_http::{
@@ -39,30 +38,65 @@ use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 use std::env;
 use dotenv::dotenv;
-use once_cell::sync::Lazy;
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+mod affects;
+mod alerts;
+mod annotations;
+mod api;
+mod commands;
+mod computed;
+mod conditions;
+mod config;
+mod derived;
+mod export;
+mod fights;
+mod metrics;
+mod mqtt;
+mod numbers;
+mod plugins;
+mod presence;
+mod priority;
+mod protocol;
+mod scripting;
+mod sse;
+mod state_store;
+mod subscriber_queue;
+mod viewer_events;
+mod webhooks;
+mod websocket;
+#[cfg(all(test, feature = "bench"))]
+#[path = "../benches/broadcast.rs"]
+mod broadcast_bench;
+use alerts::AlertEngine;
+use annotations::{AnnotationConfig, AnnotationStore, ViewerMessage};
+use commands::CommandStore;
+use affects::{AffectConfig, AffectTracker};
+use computed::ComputedKeys;
+use config::{Config, Live};
+use derived::DerivedConfig;
+use export::{ExportConfig, Exporter};
+use fights::{FightConfig, FightTracker};
+use metrics::MetricsConfig;
+use mqtt::{MqttBridge, MqttConfig};
+use plugins::{PluginConfig, Plugins};
+use priority::PriorityConfig;
+use protocol::{EncodeError, EncodedSnapshot, Encoding, Frame, MessageFormat};
+use scripting::{ScriptConfig, Scripts};
+use state_store::StateStore;
+use presence::ViewerSummary;
+use subscriber_queue::{QueuedDelta, SubscriberQueue};
+use viewer_events::{GroupSnapshot, ViewerEvent, ViewerEvents};
+use webhooks::{WebhookConfig, WebhookEvent, Webhooks};
+use websocket::{CompressionStats, DeflateConfig, WebSocket, WebSocketUpgrade};
 
 // For Rate Limiting
 use tower::{Layer, Service};
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::sync::Mutex as StdMutex; // Using std::sync::Mutex for per-IP state in RateLimiter
-
-// --- Configuration ---
-fn get_env_var<T: std::str::FromStr>(name: &str, default: T) -> T {
-  env::var(name)
-      .ok()
-      .and_then(|val| val.parse().ok())
-      .unwrap_or(default)
-}
-
-fn get_env_var_string(name: &str, default: &str) -> String {
-  env::var(name).unwrap_or_else(|_| default.to_string())
-}
-
-static STATIC_DIR_PATH_CONFIG: Lazy<String> = Lazy::new(|| {
-    get_env_var_string("STATIC_DIR_PATH", "static")
-});
+use std::sync::Mutex as StdMutex; // Using std::sync::Mutex for per-key state in RateLimiter
 
 // --- Custom Error Type ---
 #[derive(Debug, thiserror::Error)]
@@ -81,6 +115,20 @@ enum ParseError {
     Utf8Error(#[from] std::str::Utf8Error),
 }
 
+impl ParseError {
+    /// Label for the parse error metric.
+    fn kind(&self) -> &'static str {
+        match self {
+            ParseError::ExpectedOpenBrace(..) => "expected_open_brace",
+            ParseError::MissingKeyCloseBrace(..) => "missing_key_close_brace",
+            ParseError::ExpectedValueOpenBrace { .. } => "expected_value_open_brace",
+            ParseError::MissingValueCloseBrace(..) => "missing_value_close_brace",
+            ParseError::UnexpectedEndAfterKey(..) => "unexpected_end_after_key",
+            ParseError::Utf8Error(..) => "utf8",
+        }
+    }
+}
+
 // --- Data Structures ---
 type CharacterDataMap = HashMap<String, Value>;
 
@@ -89,6 +137,35 @@ struct CharacterInfo {
     data: CharacterDataMap,
     #[serde(with = "system_time_serde")]
     timestamp: SystemTime,
+    #[serde(with = "system_time_serde")]
+    first_seen: SystemTime,
+    source: String, // Where the latest update came from, e.g. "http:127.0.0.1"
+}
+
+impl CharacterInfo {
+    fn is_connected(&self) -> bool {
+        self.data.get("CONNECTED").and_then(|v| v.as_str()) == Some("YES")
+    }
+
+    fn meta(&self) -> CharacterMeta {
+        CharacterMeta {
+            last_update: self.timestamp,
+            first_seen: self.first_seen,
+            source: self.source.clone(),
+            connected: self.is_connected(),
+        }
+    }
+}
+
+/// Per-character metadata sent alongside the data map in the v2 message format.
+#[derive(Clone, Debug, Serialize)]
+struct CharacterMeta {
+    #[serde(with = "system_time_serde")]
+    last_update: SystemTime,
+    #[serde(with = "system_time_serde")]
+    first_seen: SystemTime,
+    source: String,
+    connected: bool,
 }
 
 mod system_time_serde {
@@ -111,41 +188,203 @@ mod system_time_serde {
         let secs = u64::deserialize(deserializer)?;
         Ok(UNIX_EPOCH + Duration::from_secs(secs))
     }
+
+    pub fn serialize_option<S>(date: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(date) => serialize(date, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
 struct DeltaUpdate {
     updates: HashMap<String, CharacterDataMap>,
     deletions: Vec<String>,
+    // Only part of the v2 envelope; the legacy format is exactly `updates` + `deletions`.
+    #[serde(skip)]
+    meta: HashMap<String, CharacterMeta>,
+}
+
+impl DeltaUpdate {
+    /// The part of this delta that concerns the given characters.
+    fn filtered(&self, names: &HashSet<String>) -> DeltaUpdate {
+        DeltaUpdate {
+            updates: self.updates.iter()
+                .filter(|(name, _)| names.contains(*name))
+                .map(|(name, data)| (name.clone(), data.clone()))
+                .collect(),
+            deletions: self.deletions.iter().filter(|name| names.contains(*name)).cloned().collect(),
+            meta: self.meta.iter()
+                .filter(|(name, _)| names.contains(*name))
+                .map(|(name, meta)| (name.clone(), meta.clone()))
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.updates.is_empty() && self.deletions.is_empty()
+    }
+}
+
+/// Query parameters shared by the streaming endpoints (`/ws`, `/events`).
+#[derive(Debug, Default, Deserialize)]
+struct StreamQuery {
+    characters: Option<String>, // Comma-separated character names; all characters if absent
+    format: Option<MessageFormat>, // Overrides MESSAGE_FORMAT for this connection
+    group: Option<String>, // Chat, pinned notes and presence scope; "default" if absent
+    name: Option<String>, // Self-reported viewer name shown to the group; not authenticated
+}
+
+impl StreamQuery {
+    fn character_filter(&self) -> Option<HashSet<String>> {
+        let names: HashSet<String> = self.characters.as_deref()?
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        (!names.is_empty()).then_some(names)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct HeartbeatMessage {
+    #[serde(rename = "type")]
+    msg_type: &'static str,
+    #[serde(with = "system_time_serde")]
+    server_time: SystemTime,
+    interval_ms: u64,
+    subscribers: usize,
+}
+
+// --- WebSocket Heartbeat Configuration ---
+// Close codes in the 4000-4999 range are reserved for application use (RFC 6455, 7.4.2).
+const CLOSE_CODE_PONG_TIMEOUT: u16 = 4000;
+
+#[derive(Clone, Debug)]
+struct WsConfig {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    heartbeat_interval: Option<Duration>, // None disables the JSON heartbeat message
+    queue_capacity: usize, // Deltas buffered per subscriber before they are coalesced
+    deflate: DeflateConfig,
+}
+
+// --- Subscriber Tracking ---
+#[derive(Clone, Debug)]
+struct SubscriberInfo {
+    transport: &'static str, // "ws" or "sse"
+    peer_addr: SocketAddr,
+    user_agent: String,
+    name: Option<String>,
+    group: String,
+    filter: Option<HashSet<String>>,
+    format: MessageFormat,
+    connected_at: SystemTime,
+    last_activity: Instant,
+    queue: Arc<SubscriberQueue>,
 }
 
 // --- Shared State ---
 struct AppStateInternal {
-    character_data: DashMap<String, CharacterInfo>,
-    pending_updates: Mutex<HashMap<String, CharacterDataMap>>,
-    pending_deletions: Mutex<HashSet<String>>,
-    delta_tx: broadcast::Sender<DeltaUpdate>,
+    // Effective configuration; settings that can change live are read from here or from the
+    // `Live` fields below, everything else only at startup.
+    config: Live<Config>,
+    store: StateStore,
+    // Only valid while the store version still matches the one it was encoded from.
+    snapshot_cache: StdMutex<Option<Arc<EncodedSnapshot>>>,
+    default_format: MessageFormat,
+    subscribers: DashMap<Uuid, SubscriberInfo>,
+    ws_config: WsConfig,
+    priority: Live<PriorityConfig>,
+    derived: DerivedConfig,
+    computed: Arc<ComputedKeys>,
+    scripts: Arc<Scripts>,
+    plugins: Arc<Plugins>,
+    metrics: Live<MetricsConfig>,
+    mqtt: MqttBridge,
+    rate_limiter: RateLimiter,
+    // The same limits per character, for MQTT updates, which have no peer address of their own.
+    character_rate_limiter: RateLimiter<String>,
+    // Wakes broadcast_loop before its next tick when a priority change arrives.
+    flush_notify: Notify,
+    compression: Arc<CompressionStats>,
+    commands: CommandStore,
+    annotations: AnnotationStore,
+    alerts: AlertEngine,
+    fights: FightTracker,
+    affects: AffectTracker,
+    viewer_events: ViewerEvents,
+    webhooks: Webhooks,
 }
 
 type SharedState = Arc<AppStateInternal>;
 
+impl AppStateInternal {
+    /// Returns the snapshot frame and the store version it reflects. The unfiltered JSON snapshot
+    /// is re-encoded only if the store changed since the last call.
+    fn encoded_snapshot(
+        &self,
+        filter: Option<&HashSet<String>>,
+        group: Option<&GroupSnapshot>,
+        format: MessageFormat,
+        encoding: Encoding,
+    ) -> Result<(u64, Frame), EncodeError> {
+        if encoding != Encoding::Json {
+            return self.store.encode_snapshot(filter, group, format, encoding);
+        }
+        if filter.is_some() {
+            let snapshot = self.store.snapshot(filter)?;
+            return Ok((snapshot.version, snapshot.frame(format, group)?));
+        }
+
+        let current_version = self.store.version();
+        let cached = self.snapshot_cache.lock().unwrap().clone();
+        if let Some(snapshot) = cached.filter(|s| s.version == current_version) {
+            trace!("Snapshot cache hit (version {}).", current_version);
+            return Ok((snapshot.version, snapshot.frame(format, group)?));
+        }
+
+        let snapshot = Arc::new(self.store.snapshot(None)?);
+        let mut cache = self.snapshot_cache.lock().unwrap();
+        // Never replace a newer cached snapshot with one encoded from an older version.
+        if cache.as_ref().is_none_or(|cached| cached.version < snapshot.version) {
+            *cache = Some(Arc::clone(&snapshot));
+            debug!("Snapshot cache rebuilt (version {}).", snapshot.version);
+        }
+        Ok((snapshot.version, snapshot.frame(format, group)?))
+    }
+
+    fn format_for(&self, query: &StreamQuery) -> MessageFormat {
+        query.format.unwrap_or(self.default_format)
+    }
+
+    /// Adds a subscriber and announces it to the viewers of its group.
+    fn register_subscriber(&self, id: Uuid, info: SubscriberInfo) {
+        let (group, viewer) = (info.group.clone(), ViewerSummary::new(&id, &info));
+        self.subscribers.insert(id, info);
+        self.viewer_events.publish(&group, ViewerEvent::ViewerJoined { viewer });
+    }
+
+    /// Removes a subscriber and announces its departure. Returns its info if it was registered.
+    fn unregister_subscriber(&self, id: &Uuid) -> Option<SubscriberInfo> {
+        let (_, info) = self.subscribers.remove(id)?;
+        self.viewer_events.publish(&info.group, ViewerEvent::ViewerLeft { id: id.to_string() });
+        Some(info)
+    }
+}
+
 // --- Parser Logic (REVISED for Rust) ---
 fn parse_final_value(raw_value_block: &str) -> Value {
-    let val = raw_value_block.trim();
-    if val.is_empty() {
+    let inner_val = unbraced(raw_value_block);
+    if inner_val.is_empty() {
         return Value::String("".to_string());
     }
 
-    let inner_val = if val.len() >= 2 && val.starts_with('{') && val.ends_with('}') {
-        val[1..val.len() - 1].trim()
-    } else if val.starts_with('{') {
-        warn!("parse_final_value: Block starts with '{{' but doesn't end with '}}': '{}'", &val[..50.min(val.len())]);
-        val[1..].trim()
-    } else {
-        warn!("parse_final_value: Expected braced value, got: '{}'", &val[..50.min(val.len())]);
-        val
-    };
-
     let cleaned_num_str = inner_val.replace(',', "");
     if let Ok(i) = cleaned_num_str.parse::<i64>() {
         Value::Number(i.into())
@@ -159,6 +398,22 @@ fn parse_final_value(raw_value_block: &str) -> Value {
     }
 }
 
+/// The trimmed text inside a `{value}` block.
+fn unbraced(raw_value_block: &str) -> &str {
+    let val = raw_value_block.trim();
+    if val.is_empty() {
+        val
+    } else if val.len() >= 2 && val.starts_with('{') && val.ends_with('}') {
+        val[1..val.len() - 1].trim()
+    } else if let Some(stripped) = val.strip_prefix('{') {
+        warn!("parse_final_value: Block starts with '{{' but doesn't end with '}}': '{}'", &val[..50.min(val.len())]);
+        stripped.trim()
+    } else {
+        warn!("parse_final_value: Expected braced value, got: '{}'", &val[..50.min(val.len())]);
+        val
+    }
+}
+
 fn parse_strict_key_value_pairs(text: &str) -> Result<CharacterDataMap, ParseError> {
     debug!("Starting STRICT parse. Input len={}", text.len());
     let text = text.trim();
@@ -249,7 +504,7 @@ fn parse_strict_key_value_pairs(text: &str) -> Result<CharacterDataMap, ParseErr
 
         if bytes[i] != b'{' {
             error!("STRICT PARSE: Expected '{{' for value of key '{}' at index {}, but found '{}'", key, i, bytes[i] as char);
-             return Err(ParseError::ExpectedValueOpenBrace{key: key, index: i, found: bytes[i] as char});
+             return Err(ParseError::ExpectedValueOpenBrace{key, index: i, found: bytes[i] as char});
         }
         let value_block_start = i;
         debug!("STRICT PARSE: Value for '{}' starts with '{{' at {}. Scanning for matching brace.", key, value_block_start);
@@ -272,7 +527,12 @@ fn parse_strict_key_value_pairs(text: &str) -> Result<CharacterDataMap, ParseErr
                             &raw_value_block_str[..50.min(raw_value_block_str.len())]
                         );
 
-                        let final_value = parse_final_value(raw_value_block_str);
+                        // Acknowledged command ids are a list; stripping commas as thousands separators would merge them.
+                        let final_value = if key == commands::ACK_KEY {
+                            Value::String(unbraced(raw_value_block_str).to_string())
+                        } else {
+                            parse_final_value(raw_value_block_str)
+                        };
                         debug!(
                             "STRICT PARSE: Stored Key='{}', Value='{}...' (Type: {:?})",
                             key,
@@ -311,15 +571,32 @@ fn parse_strict_key_value_pairs(text: &str) -> Result<CharacterDataMap, ParseErr
 
 
 // --- Rate Limiting Structures and Logic ---
+/// What requests are limited by: the peer address for HTTP, the character for MQTT.
+trait RateLimitKey: Clone + Eq + std::hash::Hash + std::fmt::Display + Send + Sync + 'static {
+    fn ban_event(&self, ban_seconds: u64) -> WebhookEvent;
+}
+
+impl RateLimitKey for SocketAddr {
+    fn ban_event(&self, ban_seconds: u64) -> WebhookEvent {
+        WebhookEvent::IpBanned { ip: self.ip().to_string(), ban_seconds }
+    }
+}
+
+impl RateLimitKey for String {
+    fn ban_event(&self, ban_seconds: u64) -> WebhookEvent {
+        WebhookEvent::CharacterBanned { character: self.clone(), ban_seconds }
+    }
+}
+
 #[derive(Debug)]
-struct RateLimitIpState {
+struct RateLimitState {
     tokens: f64,
     last_refill_time: Instant,
     violations: u32,
     banned_until: Option<Instant>,
 }
 
-impl RateLimitIpState {
+impl RateLimitState {
     fn new(initial_tokens: f64) -> Self {
         Self {
             tokens: initial_tokens,
@@ -340,32 +617,35 @@ struct RateLimiterConfig {
 }
 
 #[derive(Clone)]
-struct RateLimiter {
-    state_map: Arc<DashMap<SocketAddr, StdMutex<RateLimitIpState>>>,
-    config: Arc<RateLimiterConfig>,
+struct RateLimiter<K: RateLimitKey = SocketAddr> {
+    state_map: Arc<DashMap<K, StdMutex<RateLimitState>>>,
+    config: Arc<Live<RateLimiterConfig>>,
+    webhooks: Webhooks,
 }
 
-impl RateLimiter {
-    fn new(config: RateLimiterConfig) -> Self {
+impl<K: RateLimitKey> RateLimiter<K> {
+    fn new(config: RateLimiterConfig, webhooks: Webhooks) -> Self {
         let limiter = Self {
             state_map: Arc::new(DashMap::new()),
-            config: Arc::new(config.clone()),
+            config: Arc::new(Live::new(config.clone())),
+            webhooks,
         };
 
         let state_map_clone = Arc::clone(&limiter.state_map);
-        let cleanup_config = Arc::clone(&limiter.config);
+        let live_config = Arc::clone(&limiter.config);
 
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(cleanup_config.cleanup_interval);
+            let mut interval = tokio::time::interval(config.cleanup_interval);
             interval.tick().await;
-            info!("Rate limiter cleanup task started. Interval: {:?}", cleanup_config.cleanup_interval);
+            info!("Rate limiter cleanup task started. Interval: {:?}", config.cleanup_interval);
             loop {
                 interval.tick().await;
+                let cleanup_config = live_config.get();
                 let now = Instant::now();
                 let initial_size = state_map_clone.len();
 
-                state_map_clone.retain(|_ip, state_mutex| {
-                    let state = state_mutex.lock().unwrap();
+                state_map_clone.retain(|_key, state_mutex| {
+                    let state = state_mutex.get_mut().unwrap();
                     if let Some(banned_until) = state.banned_until {
                         now < banned_until
                     } else {
@@ -376,61 +656,71 @@ impl RateLimiter {
                 });
                 let removed_count = initial_size.saturating_sub(state_map_clone.len());
                 if removed_count > 0 {
-                    debug!("Rate limiter cleanup: Removed {} IP states. Current size: {}", removed_count, state_map_clone.len());
+                    debug!("Rate limiter cleanup: Removed {} states. Current size: {}", removed_count, state_map_clone.len());
                 } else {
-                    trace!("Rate limiter cleanup: No IP states removed. Current size: {}", state_map_clone.len());
+                    trace!("Rate limiter cleanup: No states removed. Current size: {}", state_map_clone.len());
                 }
             }
         });
         limiter
     }
 
-    fn check(&self, ip: SocketAddr) -> Result<(), StatusCode> {
-        let mut ip_state_entry = self.state_map.entry(ip).or_insert_with(|| {
-            StdMutex::new(RateLimitIpState::new(self.config.burst_capacity))
+    /// Applies reloaded limits. Tokens and violations collected so far are kept.
+    fn set_config(&self, config: RateLimiterConfig) {
+        info!("Rate Limiter Config: {:?}", config);
+        self.config.set(config);
+    }
+
+    fn check(&self, key: &K) -> Result<(), StatusCode> {
+        let config = self.config.get();
+        let mut state_entry = self.state_map.entry(key.clone()).or_insert_with(|| {
+            StdMutex::new(RateLimitState::new(config.burst_capacity))
         });
-        let mut ip_state = ip_state_entry.value_mut().lock().unwrap();
+        let key_state = state_entry.value_mut().get_mut().unwrap();
 
         let now = Instant::now();
 
-        if let Some(banned_until) = ip_state.banned_until {
+        if let Some(banned_until) = key_state.banned_until {
             if now < banned_until {
-                warn!("Rate limit: IP {} is banned. Request denied. Until: {:?}", ip, banned_until);
+                warn!("Rate limit: {} is banned. Request denied. Until: {:?}", key, banned_until);
                 return Err(StatusCode::FORBIDDEN);
             } else {
-                ip_state.banned_until = None;
-                ip_state.violations = 0;
-                info!("Rate limit: Ban expired for IP {}. Resetting violations.", ip);
+                key_state.banned_until = None;
+                key_state.violations = 0;
+                info!("Rate limit: Ban expired for {}. Resetting violations.", key);
             }
         }
 
-        let elapsed_seconds = now.duration_since(ip_state.last_refill_time).as_secs_f64();
-        let tokens_to_add = elapsed_seconds * self.config.rps;
-        ip_state.tokens = (ip_state.tokens + tokens_to_add).min(self.config.burst_capacity);
-        ip_state.last_refill_time = now;
+        let elapsed_seconds = now.duration_since(key_state.last_refill_time).as_secs_f64();
+        let tokens_to_add = elapsed_seconds * config.rps;
+        key_state.tokens = (key_state.tokens + tokens_to_add).min(config.burst_capacity);
+        key_state.last_refill_time = now;
 
-        if ip_state.tokens >= 1.0 {
-            ip_state.tokens -= 1.0;
+        if key_state.tokens >= 1.0 {
+            key_state.tokens -= 1.0;
             // Gradually reduce violations on successful requests
-            if ip_state.violations > 0 && ip_state.tokens > self.config.burst_capacity * 0.5 {
-                 ip_state.violations = ip_state.violations.saturating_sub(1);
+            if key_state.violations > 0 && key_state.tokens > config.burst_capacity * 0.5 {
+                 key_state.violations = key_state.violations.saturating_sub(1);
             }
-            trace!("Rate limit: IP {} allowed. Tokens remaining: {:.2}, Violations: {}", ip, ip_state.tokens, ip_state.violations);
+            trace!("Rate limit: {} allowed. Tokens remaining: {:.2}, Violations: {}", key, key_state.tokens, key_state.violations);
             Ok(())
         } else {
-            ip_state.violations += 1;
+            key_state.violations += 1;
+            metrics::RATE_LIMIT_THROTTLED.inc();
             warn!(
-                "Rate limit: IP {} throttled. Tokens: {:.2}, Violations: {}/{}",
-                ip, ip_state.tokens, ip_state.violations, self.config.violation_threshold
+                "Rate limit: {} throttled. Tokens: {:.2}, Violations: {}/{}",
+                key, key_state.tokens, key_state.violations, config.violation_threshold
             );
 
-            if ip_state.violations >= self.config.violation_threshold {
-                let ban_ends_at = now + self.config.ban_duration;
-                ip_state.banned_until = Some(ban_ends_at);
+            if key_state.violations >= config.violation_threshold {
+                let ban_ends_at = now + config.ban_duration;
+                key_state.banned_until = Some(ban_ends_at);
                 error!(
-                    "Rate limit: IP {} BANNED for {:?} due to {} violations. Ban until {:?}. Tokens: {:.2}",
-                    ip, self.config.ban_duration, ip_state.violations, ban_ends_at, ip_state.tokens
+                    "Rate limit: {} BANNED for {:?} due to {} violations. Ban until {:?}. Tokens: {:.2}",
+                    key, config.ban_duration, key_state.violations, ban_ends_at, key_state.tokens
                 );
+                metrics::RATE_LIMIT_BANS.inc();
+                self.webhooks.notify(key.ban_event(config.ban_duration.as_secs()));
                 return Err(StatusCode::FORBIDDEN);
             }
             Err(StatusCode::TOO_MANY_REQUESTS)
@@ -486,7 +776,7 @@ where
 
         match peer_addr_opt {
             Some(addr) => {
-                match self.limiter.check(addr) {
+                match self.limiter.check(&addr) {
                     Ok(()) => {
                         trace!("RateLimitMiddleware: Request from {} allowed.", addr);
                         Box::pin(self.inner.call(req))
@@ -537,24 +827,46 @@ where
 // --- HTTP Handler ---
 async fn handle_http_update(
     State(state): State<SharedState>,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
     body: String,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<(StatusCode, String), StatusCode> {
+    info!("Received HTTP POST data (len={}): {}...", body.len(), body.chars().take(100).collect::<String>());
+    let ingested = ingest_update(&state, &body, format!("http:{}", peer_addr.ip()), None).await?;
+    Ok((StatusCode::OK, ingested.response))
+}
+
+/// A stored update and the commands to send back to the client that sent it.
+struct Ingested {
+    character: String,
+    response: String, // `{COMMAND_ID}{..}{COMMAND}{..}` pairs, empty if nothing is pending
+}
+
+/// Parses and stores one `{key}{value}` update, whichever transport it arrived on. With a
+/// `character_limiter`, updates are also rate limited by their CHARACTER_NAME.
+async fn ingest_update(
+    state: &AppStateInternal,
+    body: &str,
+    source: String,
+    character_limiter: Option<&RateLimiter<String>>,
+) -> Result<Ingested, StatusCode> {
     let start_time = Instant::now();
     let log_msg_snippet = body.chars().take(100).collect::<String>();
-    info!("Received HTTP POST data (len={}): {}...", body.len(), log_msg_snippet);
 
     if body.trim().is_empty() {
-        warn!("HTTP POST processing failed: Received empty or whitespace-only body.");
+        warn!("Update processing failed: Received empty or whitespace-only body.");
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    match parse_strict_key_value_pairs(&body) {
+    let parse_timer = metrics::PARSE_DURATION.start_timer();
+    let parsed = parse_strict_key_value_pairs(body);
+    parse_timer.observe_duration();
+    match parsed {
         Ok(mut parsed_data) => {
             if parsed_data.is_empty() && !body.trim().is_empty() {
-                 error!("HTTP POST processing failed: Parser returned empty data from non-empty input. Input: '{}...'", log_msg_snippet);
+                 error!("Update processing failed: Parser returned empty data from non-empty input. Input: '{}...'", log_msg_snippet);
                  return Err(StatusCode::INTERNAL_SERVER_ERROR);
             } else if parsed_data.is_empty() {
-                 warn!("HTTP POST: Input parsed to empty data, likely whitespace input.");
+                 warn!("Update: Input parsed to empty data, likely whitespace input.");
                  return Err(StatusCode::BAD_REQUEST);
             }
 
@@ -563,30 +875,70 @@ async fn handle_http_update(
                  Some(Value::String(s)) if !s.is_empty() => s.clone(),
                  Some(Value::Number(n)) => n.to_string(),
                  _ => {
-                    warn!("HTTP POST processing failed: Parsed data missing valid 'CHARACTER_NAME'. Keys: {:?}", parsed_data.keys().collect::<Vec<_>>());
+                    warn!("Update processing failed: Parsed data missing valid 'CHARACTER_NAME'. Keys: {:?}", parsed_data.keys().collect::<Vec<_>>());
                     return Err(StatusCode::BAD_REQUEST);
                  }
             };
+            if let Some(limiter) = character_limiter {
+                limiter.check(&char_name)?;
+            }
+
+            // Acknowledgements are addressed to the server, not part of the character's data.
+            if let Some(ack) = parsed_data.remove(commands::ACK_KEY) {
+                let acknowledged = state.commands.acknowledge(&char_name, &commands::ack_ids(&ack), SystemTime::now());
+                debug!("Character '{}' acknowledged {} command(s).", char_name, acknowledged);
+            }
 
-            parsed_data.insert("CONNECTED".to_string(), Value::String("YES".to_string()));
             let now = SystemTime::now();
-            let char_info = CharacterInfo { data: parsed_data.clone(), timestamp: now };
-            let action = if state.character_data.contains_key(&char_name) { "Updated" } else { "Added new" };
-            state.character_data.insert(char_name.clone(), char_info);
-
-            {
-                let mut pending_updates_guard = state.pending_updates.lock().await;
-                let mut pending_deletions_guard = state.pending_deletions.lock().await;
-                pending_updates_guard.insert(char_name.clone(), parsed_data);
-                if pending_deletions_guard.remove(&char_name) {
-                    debug!("'{}' was pending deletion, removed from deletion list.", char_name);
+            // An owned copy, so no store lock is held while scripts and plugins run.
+            let mut previous = state.store.get(&char_name);
+            if state.scripts.handles("on_update") || state.plugins.plugin_count() > 0 {
+                // Scripts and plugins may run up to their time and fuel limits, which must not stall a runtime worker.
+                let (scripts, plugins) = (Arc::clone(&state.scripts), Arc::clone(&state.plugins));
+                let name = char_name.clone();
+                (parsed_data, previous) = tokio::task::spawn_blocking(move || {
+                    let previous_data = previous.as_ref().map(|p| &p.data);
+                    scripts.on_update(&name, &mut parsed_data, previous_data);
+                    plugins.process(&name, &mut parsed_data, previous_data);
+                    (parsed_data, previous)
+                }).await.map_err(|e| {
+                    error!("Update processing failed: scripts or plugins for '{}' panicked: {}", char_name, e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?;
+            }
+            state.derived.apply(&mut parsed_data, previous.as_ref(), now);
+            state.computed.apply(&mut parsed_data, previous.as_ref().map(|p| &p.data));
+            state.affects.observe(&char_name, &parsed_data, now);
+            parsed_data.insert("CONNECTED".to_string(), Value::String("YES".to_string()));
+            let priority = state.priority.get();
+            let current_data = (priority.is_enabled() || state.alerts.is_enabled() || state.fights.is_enabled())
+                .then(|| parsed_data.clone());
+            let previous_data = state.store.upsert(&char_name, parsed_data, now, source);
+            let action = if previous_data.is_none() { "Added new" } else { "Updated" };
+            info!("{} character data for: {}. Processing time: {:?}", action, char_name, start_time.elapsed());
+            let was_connected = previous_data.as_ref().is_some_and(|data| data.get("CONNECTED").and_then(Value::as_str) == Some("YES"));
+            if !was_connected {
+                state.webhooks.notify(WebhookEvent::CharacterConnected { character: char_name.clone() });
+            }
+
+            if let Some(current_data) = current_data {
+                if let Some(key) = priority.triggering_key(previous_data.as_ref(), &current_data) {
+                    debug!("Priority key '{}' changed for '{}'. Requesting immediate broadcast.", key, char_name);
+                    state.flush_notify.notify_one();
                 }
-                 info!("{} character data for: {}. Added to pending updates. Processing time: {:?}", action, char_name, start_time.elapsed());
+                state.alerts.evaluate(&char_name, previous_data.as_ref(), &current_data, SystemTime::now());
+                state.fights.observe(&char_name, previous_data.as_ref(), &current_data, now);
+            }
+
+            let delivered = state.commands.take_pending(&char_name, SystemTime::now());
+            if !delivered.is_empty() {
+                info!("Delivering {} command(s) to '{}': {:?}", delivered.len(), char_name, delivered.iter().map(|c| c.id).collect::<Vec<_>>());
             }
-            Ok(StatusCode::OK)
+            Ok(Ingested { character: char_name, response: commands::response_body(&delivered) })
         }
         Err(e) => {
-            error!("HTTP POST processing failed during parsing: {}. Data: '{}...'", e, log_msg_snippet);
+            error!("Update processing failed during parsing: {}. Data: '{}...'", e, log_msg_snippet);
+            metrics::PARSE_ERRORS.with_label_values(&[e.kind()]).inc();
             Err(StatusCode::BAD_REQUEST)
         }
     }
@@ -598,53 +950,97 @@ async fn ws_handler(
     State(state): State<SharedState>,
     user_agent: Option<TypedHeader<UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StreamQuery>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
     let user_agent_str = user_agent.map_or_else(|| "Unknown".to_string(), |ua| ua.0.to_string());
     debug!("WebSocket connection attempt from User-Agent: {}", user_agent_str);
     debug!("WebSocket Headers: {:?}", headers);
-    ws.on_upgrade(move |socket| handle_socket(socket, state, user_agent_str, addr))
+    let (deflate, compression) = (state.ws_config.deflate.clone(), Arc::clone(&state.compression));
+    ws.on_upgrade(&Encoding::SUBPROTOCOLS, &deflate, compression, move |socket| {
+        handle_socket(socket, state, user_agent_str, addr, query)
+    })
 }
 
 // --- Individual WebSocket Connection Logic ---
-async fn handle_socket(mut socket: WebSocket, state: SharedState, user_agent: String, peer_addr: SocketAddr) {
-    info!("WebSocket client connected: {} (User-Agent: {})", peer_addr, user_agent);
-    let mut delta_rx = state.delta_tx.subscribe();
-    let initial_state: HashMap<String, CharacterDataMap> = state
-         .character_data
-         .iter()
-         .map(|entry| (entry.key().clone(), entry.value().data.clone()))
-         .collect();
-
-     if !initial_state.is_empty() {
-         match serde_json::to_string(&initial_state) {
-             Ok(json_string) => {
-                  info!("Attempting send snapshot string (len={}) to target: {}", json_string.len(), peer_addr);
-                 if let Err(e) = socket.send(Message::Text(json_string)).await {
-                     warn!("Failed to send initial state to {}: {}", peer_addr, e);
-                 } else {
-                     info!("Successfully sent initial state snapshot string to {}", peer_addr);
-                 }
-             }
-             Err(e) => {
-                 error!("Failed to serialize initial state for {}: {}", peer_addr, e);
-                 let _ = socket.close().await; return;
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: SharedState,
+    user_agent: String,
+    peer_addr: SocketAddr,
+    query: StreamQuery,
+) {
+    let filter = query.character_filter();
+    let format = state.format_for(&query);
+    let group = annotations::group_name(query.group.as_deref());
+    let name = presence::viewer_name(query.name.as_deref());
+    let subscriber_id = Uuid::new_v4();
+    // Binary encodings are negotiated via Sec-WebSocket-Protocol; clients asking for none get JSON.
+    let encoding = Encoding::from_subprotocol(socket.protocol());
+    info!("WebSocket client connected: {} (User-Agent: {}, Subscriber: {}, Characters: {:?}, Format: {:?}, Encoding: {:?}, Compressed: {}, Group: {}, Name: {:?})", peer_addr, user_agent, subscriber_id, filter, format, encoding, socket.is_compressed(), group, name);
+    // Register the queue before taking the snapshot so no delta broadcast in between is missed.
+    let queue = Arc::new(SubscriberQueue::new(state.ws_config.queue_capacity));
+    state.register_subscriber(subscriber_id, SubscriberInfo {
+        transport: "ws",
+        peer_addr,
+        user_agent,
+        name,
+        group: group.clone(),
+        filter: filter.clone(),
+        format,
+        connected_at: SystemTime::now(),
+        last_activity: Instant::now(),
+        queue: Arc::clone(&queue),
+    });
+    // Subscribed before the snapshot for the same reason; an event may then repeat what the snapshot shows.
+    let mut viewer_events = state.viewer_events.subscribe();
+    let group_snapshot = (format == MessageFormat::V2).then(|| current_group_snapshot(&state, &group, filter.as_ref()));
+     let mut snapshot_version = match state.encoded_snapshot(filter.as_ref(), group_snapshot.as_ref(), format, encoding) {
+         Ok((version, frame)) => {
+             info!("Attempting send snapshot (len={}) to target: {}", frame.len(), peer_addr);
+             if let Err(e) = socket.send_frame(&frame).await {
+                 warn!("Failed to send initial state to {}: {}", peer_addr, e);
+             } else {
+                 info!("Successfully sent initial state snapshot to {}", peer_addr);
              }
+             version
          }
-     } else {
-         debug!("Initial state is empty, sending empty JSON object {{}} to {}", peer_addr);
-          if let Err(e) = socket.send(Message::Text("{}".to_string())).await {
-              warn!("Failed to send empty initial state to {}: {}", peer_addr, e);
-          }
-     }
+         Err(e) => {
+             error!("Failed to serialize initial state for {}: {}", peer_addr, e);
+             state.unregister_subscriber(&subscriber_id);
+             let _ = socket.close(None).await; return;
+         }
+     };
+
+     let ws_config = state.ws_config.clone();
+     let mut last_activity = Instant::now();
+     let mut ping_interval = time::interval(ws_config.ping_interval);
+     ping_interval.tick().await;
+     // A disabled heartbeat still needs a timer for select!, it just never fires.
+     let mut heartbeat_interval = time::interval(ws_config.heartbeat_interval.unwrap_or(Duration::from_secs(86400)));
+     heartbeat_interval.tick().await;
 
      loop {
          tokio::select! {
              msg_option = socket.recv() => {
+                 if let Some(Ok(_)) = &msg_option {
+                     last_activity = Instant::now();
+                     if let Some(mut subscriber) = state.subscribers.get_mut(&subscriber_id) {
+                         subscriber.last_activity = last_activity;
+                     }
+                 }
                  match msg_option {
                      Some(Ok(msg)) => {
                          match msg {
-                             Message::Text(t) => debug!("Received text message from {}: {}...", peer_addr, t.chars().take(50).collect::<String>()),
+                             Message::Text(t) => {
+                                 debug!("Received text message from {}: {}...", peer_addr, t.chars().take(50).collect::<String>());
+                                 if let Err(e) = handle_viewer_message(&state, &group, &t) {
+                                     debug!("Rejected viewer message from {}: {}", peer_addr, e);
+                                     if format == MessageFormat::V2 && send_event(&mut socket, &state, &ViewerEvent::Error { message: e }, encoding).await.is_err() {
+                                         info!("{} disconnected while sending error event.", peer_addr); break;
+                                     }
+                                 }
+                             }
                              Message::Binary(_) => warn!("Received unexpected binary message from {}", peer_addr),
                              Message::Ping(p) => {
                                  trace!("Received Ping from {}, sending Pong", peer_addr);
@@ -652,65 +1048,140 @@ async fn handle_socket(mut socket: WebSocket, state: SharedState, user_agent: St
                              }
                               Message::Pong(_) => trace!("Received Pong from {}", peer_addr),
                              Message::Close(c) => { info!("Received Close frame from {}: {:?}", peer_addr, c); break; }
+                             Message::Frame(_) => trace!("Received raw frame from {}", peer_addr),
                          }
                      }
                      Some(Err(e)) => { warn!("Error receiving message from {}: {}", peer_addr, e); break; }
                      None => { info!("WebSocket client {} disconnected (recv returned None).", peer_addr); break; }
                  }
              },
-             delta_result = delta_rx.recv() => {
-                 match delta_result {
-                     Ok(delta) => {
-                         match serde_json::to_string(&delta) {
-                             Ok(json_string) => {
-                                 trace!("Sending delta update ({} updates, {} deletions, len={}) to {}", delta.updates.len(), delta.deletions.len(), json_string.len(), peer_addr);
-                                 if let Err(e) = socket.send(Message::Text(json_string)).await {
-                                      warn!("Failed to send delta update to {}: {}. Client likely disconnected.", peer_addr, e); break;
-                                 }
+             _ = ping_interval.tick() => {
+                 let idle = last_activity.elapsed();
+                 if idle > ws_config.pong_timeout {
+                     warn!("WebSocket client {} silent for {:?} (pong timeout {:?}). Closing connection.", peer_addr, idle, ws_config.pong_timeout);
+                     let close_frame = websocket::close_frame(CLOSE_CODE_PONG_TIMEOUT, "pong timeout");
+                     let _ = socket.send(Message::Close(Some(close_frame))).await;
+                     break;
+                 }
+                 trace!("Sending Ping to {} (idle {:?})", peer_addr, idle);
+                 if socket.send(Message::Ping(Vec::new())).await.is_err() { info!("{} disconnected while sending Ping.", peer_addr); break; }
+             },
+             _ = heartbeat_interval.tick(), if ws_config.heartbeat_interval.is_some() => {
+                 let heartbeat = HeartbeatMessage {
+                     msg_type: "heartbeat",
+                     server_time: SystemTime::now(),
+                     interval_ms: ws_config.heartbeat_interval.map_or(0, |d| d.as_millis() as u64),
+                     subscribers: state.subscribers.len(),
+                 };
+                 match protocol::encode(&heartbeat, encoding) {
+                     Ok(frame) => {
+                         trace!("Sending heartbeat to {}", peer_addr);
+                         if socket.send_frame(&frame).await.is_err() { info!("{} disconnected while sending heartbeat.", peer_addr); break; }
+                     }
+                     Err(e) => error!("Failed to serialize heartbeat for {}: {}", peer_addr, e),
+                 }
+             },
+             received = viewer_events.recv() => {
+                 match received {
+                     Ok(event) if event.is_for(&group, filter.as_ref()) && format == MessageFormat::V2 => {
+                         if send_event(&mut socket, &state, &event.event, encoding).await.is_err() {
+                             info!("{} disconnected while sending viewer event.", peer_addr); break;
+                         }
+                     }
+                     Ok(_) => {}
+                     Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                         warn!("WebSocket client {} missed {} viewer events. Resending the snapshot.", peer_addr, skipped);
+                         metrics::LAG_EVENTS.with_label_values(&["viewer_events_missed"]).inc();
+                         if format != MessageFormat::V2 { continue; }
+                         // The snapshot carries the chat, notes, viewers and alerts the missed events would have changed.
+                         let resync = current_group_snapshot(&state, &group, filter.as_ref());
+                         match state.encoded_snapshot(filter.as_ref(), Some(&resync), format, encoding) {
+                             Ok((version, frame)) => {
+                                 if socket.send_frame(&frame).await.is_err() { info!("{} disconnected while resending the snapshot.", peer_addr); break; }
+                                 snapshot_version = version;
                              }
-                             Err(e) => error!("Failed to serialize delta update for {}: {}", peer_addr, e),
+                             Err(e) => error!("Failed to serialize resync snapshot for {}: {}", peer_addr, e),
                          }
-                     },
-                     Err(broadcast::error::RecvError::Lagged(n)) => warn!("WebSocket client {} lagged by {} messages.", peer_addr, n),
-                     Err(broadcast::error::RecvError::Closed) => { error!("Broadcast channel closed for {}.", peer_addr); break; }
+                     }
+                     Err(broadcast::error::RecvError::Closed) => {}
+                 }
+             },
+             queued = queue.pop() => {
+                 // Already covered by the initial snapshot.
+                 if queued.version <= snapshot_version { continue; }
+                 let Some(frame) = queued.frame(format, encoding, filter.as_ref()) else { continue };
+                 trace!("Sending delta update v{} (len={}, queue depth {}) to {}", queued.version, frame.len(), queue.depth(), peer_addr);
+                 if let Err(e) = socket.send_frame(&frame).await {
+                      warn!("Failed to send delta update to {}: {}. Client likely disconnected.", peer_addr, e); break;
                  }
              }
          }
      }
-     info!("WebSocket client {} connection handler finished.", peer_addr);
-     let _ = socket.close().await;
+     if let Some(subscriber) = state.unregister_subscriber(&subscriber_id) {
+         info!(
+             "WebSocket client {} (User-Agent: {}) connection handler finished. Connected since {:?}, last activity {:?} ago, {} deltas coalesced. Remaining subscribers: {}",
+             subscriber.peer_addr, subscriber.user_agent, subscriber.connected_at, subscriber.last_activity.elapsed(), subscriber.queue.coalesced_total(), state.subscribers.len()
+         );
+     } else {
+         info!("WebSocket client {} connection handler finished.", peer_addr);
+     }
+     let _ = socket.close(None).await;
+}
+
+/// Applies a chat/pin/unpin message from a viewer. Returns a reason to show the viewer on failure.
+fn handle_viewer_message(state: &SharedState, group: &str, text: &str) -> Result<(), String> {
+    let message: ViewerMessage = serde_json::from_str(text).map_err(|e| format!("invalid message: {}", e))?;
+    state.annotations
+        .apply(group, message, |name| state.store.get(name).is_some(), SystemTime::now())
+        .map_err(|e| e.to_string())
+}
+
+/// The chat, notes, viewers and alerts a v2 viewer in `group` gets with its snapshot.
+fn current_group_snapshot(state: &SharedState, group: &str, filter: Option<&HashSet<String>>) -> GroupSnapshot {
+    GroupSnapshot {
+        annotations: state.annotations.snapshot(group),
+        viewers: presence::group_viewers(state, Some(group)),
+        alerts: state.alerts.active(filter),
+    }
+}
+
+async fn send_event(socket: &mut WebSocket, state: &SharedState, event: &ViewerEvent, encoding: Encoding) -> Result<(), ()> {
+    match protocol::encode_event(state.store.version(), event, encoding) {
+        Ok(frame) => socket.send_frame(&frame).await.map_err(|_| ()),
+        Err(e) => { error!("Failed to serialize viewer event: {}", e); Ok(()) }
+    }
 }
 
 // --- Background Task: Pruning Old Data ---
-async fn prune_loop(state: SharedState, prune_interval: Duration, data_timeout: Duration) {
-    info!("Starting prune loop. Interval: {:?}, Timeout: {:?}", prune_interval, data_timeout);
+async fn prune_loop(state: SharedState, prune_interval: Duration) {
+    info!("Starting prune loop. Interval: {:?}, Timeout: {:?}", prune_interval, state.config.get().data_timeout());
     let mut interval = time::interval(prune_interval);
     interval.tick().await;
 
     loop {
         interval.tick().await;
-        let now = SystemTime::now();
-        let mut names_to_prune = Vec::new();
-
-        state.character_data.retain(|name, info| {
-            match now.duration_since(info.timestamp) {
-                Ok(age) if age > data_timeout => { names_to_prune.push(name.clone()); false }
-                Ok(_) => true,
-                Err(_) => { warn!("System clock went backwards? Char '{}' timestamp in future.", name); true }
-            }
-        });
+        // Read every time, so a reloaded timeout applies from the next check on.
+        let data_timeout = state.config.get().data_timeout();
+        let names_to_prune = state.store.prune(SystemTime::now(), data_timeout);
 
         if !names_to_prune.is_empty() {
-            let pruned_count = names_to_prune.len();
-            {
-                let mut pending_deletions_guard = state.pending_deletions.lock().await;
-                let mut pending_updates_guard = state.pending_updates.lock().await;
-                for name in &names_to_prune {
-                    pending_deletions_guard.insert(name.clone());
-                    pending_updates_guard.remove(name); // Ensure pruned items are not in pending updates either
-                }
-            }
-             info!("Pruned {} inactive characters: {:?}. Marked for deletion.", pruned_count, names_to_prune);
+             info!("Pruned {} inactive characters: {:?}. Marked for deletion.", names_to_prune.len(), names_to_prune);
+             metrics::PRUNED_CHARACTERS.inc_by(names_to_prune.len() as u64);
+             for name in &names_to_prune {
+                 state.commands.remove(name);
+                 state.annotations.remove_character(name);
+                 state.alerts.remove_character(name, SystemTime::now());
+                 state.fights.remove_character(name);
+                 state.affects.remove_character(name);
+                 state.webhooks.notify(WebhookEvent::CharacterPruned { character: name.clone() });
+             }
+             if state.scripts.handles("on_prune") {
+                 // Like on_update, the hooks may run up to the script time limit, so not on a runtime worker.
+                 let scripts = Arc::clone(&state.scripts);
+                 if let Err(e) = tokio::task::spawn_blocking(move || names_to_prune.iter().for_each(|name| scripts.on_prune(name))).await {
+                     error!("on_prune scripts panicked: {}", e);
+                 }
+             }
         } else {
              trace!("Prune check: No characters timed out.");
         }
@@ -718,111 +1189,105 @@ async fn prune_loop(state: SharedState, prune_interval: Duration, data_timeout:
 }
 
 // --- Background Task: Broadcasting Deltas and Checking Connection Timeouts ---
-async fn broadcast_loop(state: SharedState, broadcast_interval: Duration, connection_timeout: Duration) {
-     info!("Starting broadcast loop. Interval: {:?}, Connection Timeout: {:?}", broadcast_interval, connection_timeout);
+async fn broadcast_loop(state: SharedState, broadcast_interval: Duration) {
+     info!("Starting broadcast loop. Interval: {:?}, Connection Timeout: {:?}", broadcast_interval, state.config.get().connection_timeout());
     let mut interval = time::interval(broadcast_interval);
     interval.tick().await;
 
+    let mut last_broadcast_version = 0u64;
+    let mut last_flush = Instant::now();
+
     loop {
-        interval.tick().await;
-        let now = SystemTime::now();
-        let mut needs_broadcast = false;
-        let mut disconnected_names = Vec::new();
-
-        state.character_data.iter().for_each(|entry| {
-            let name = entry.key();
-            let info = entry.value();
-            if info.data.get("CONNECTED").and_then(|v| v.as_str()) == Some("YES") {
-                if let Ok(age) = now.duration_since(info.timestamp) {
-                    if age > connection_timeout { disconnected_names.push(name.clone()); }
-                } else {
-                    warn!("System clock went backwards? Char '{}' timestamp in future during broadcast check.", name);
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = state.flush_notify.notified() => {
+                // Keep priority flushes at least min_flush_spacing apart; anything arriving meanwhile rides along.
+                let delay = state.priority.get().flush_delay(last_flush.elapsed());
+                if !delay.is_zero() {
+                    time::sleep(delay).await;
                 }
-            }
-        });
-
-        if !disconnected_names.is_empty() {
-            for name in &disconnected_names {
-                 if let Some(mut char_info_entry) = state.character_data.get_mut(name) {
-                      // Double check CONNECTED status before modifying, in case it changed
-                      if char_info_entry.data.get("CONNECTED").and_then(|v| v.as_str()) == Some("YES") {
-                          info!("Marking '{}' as disconnected due to timeout.", name);
-                           char_info_entry.data.insert("CONNECTED".to_string(), Value::String("NO".to_string()));
-                           // Update timestamp for this change as well
-                           char_info_entry.timestamp = SystemTime::now();
-                           {
-                               let mut pending_updates_guard = state.pending_updates.lock().await;
-                               let mut pending_deletions_guard = state.pending_deletions.lock().await;
-                               pending_updates_guard.insert(name.clone(), char_info_entry.data.clone());
-                               pending_deletions_guard.remove(name); // Ensure it's not marked for deletion if it just got updated
-                           }
-                           needs_broadcast = true;
-                      }
-                 }
-            }
+                trace!("Priority flush triggered.");
+            },
         }
-
-        let delta_to_send: Option<DeltaUpdate>;
-        {
-            let mut pending_updates_guard = state.pending_updates.lock().await;
-            let mut pending_deletions_guard = state.pending_deletions.lock().await;
-
-            // If there are any pending changes, set needs_broadcast true
-            if !pending_updates_guard.is_empty() || !pending_deletions_guard.is_empty() {
-                needs_broadcast = true;
-            }
-
-            if needs_broadcast { // Only construct delta if there's something to send or broadcast flag is already set
-                let updates = std::mem::take(&mut *pending_updates_guard);
-                let deletions_set = std::mem::take(&mut *pending_deletions_guard);
-                let deletions: Vec<String> = deletions_set.into_iter().collect();
-
-                // Only create Some(DeltaUpdate) if there are actual updates or deletions
-                if !updates.is_empty() || !deletions.is_empty() {
-                    delta_to_send = Some(DeltaUpdate { updates, deletions });
-                } else {
-                    delta_to_send = None; // No actual changes to send this cycle
-                    if !disconnected_names.is_empty() && needs_broadcast {
-                        // This case implies disconnects happened but resulted in no *net* changes for the delta
-                        // (e.g. already marked as NO, or data didn't change).
-                        // Or, more likely, the pending_updates from disconnects were empty.
-                        // Let's ensure `needs_broadcast` remains true if `delta_to_send` is None but should have been.
-                        // This logic is slightly complex; simpler to just send even if updates/deletions are empty if needs_broadcast was true.
-                        // For now, this is fine: if updates and deletions are empty, `delta_to_send` is None.
-                    }
-                }
-            } else {
-                delta_to_send = None;
+        last_flush = Instant::now();
+        if let Some(delta) = broadcast_pending(&state, &mut last_broadcast_version) {
+            // Off the runtime workers like on_update, once the delta is out. The next broadcast waits
+            // for the hooks, so they see the deltas in order.
+            let scripts = Arc::clone(&state.scripts);
+            if let Err(e) = tokio::task::spawn_blocking(move || scripts.on_broadcast(&delta.updates, &delta.deletions)).await {
+                error!("on_broadcast scripts panicked: {}", e);
             }
         }
+    }
+}
 
-        // Send only if delta_to_send is Some
-        if let Some(delta) = delta_to_send {
-            let num_subscribers = state.delta_tx.receiver_count();
-             if num_subscribers > 0 {
-                info!(
-                    "Broadcasting delta. Updates: {}, Deletions: {}. Subscribers: {}",
-                    delta.updates.len(), delta.deletions.len(), num_subscribers
-                );
-                if state.delta_tx.send(delta).is_err() { // No need for `e` if not logging it.
-                     // This error means there are no active receivers, even though receiver_count > 0.
-                     // This can happen if receivers are dropped between check and send.
-                     debug!("Error broadcasting delta: no active receivers (or all lagged).");
-                }
-             } else {
-                 trace!("Broadcast check: Delta prepared, but no subscribers.");
-             }
-        } else if needs_broadcast {
-             trace!("Broadcast check: Needs broadcast was true, but no concrete delta to send (e.g., only disconnects that didn't change data).");
-        } else {
-             trace!("Broadcast check: No changes or pending updates.");
+/// Sends the changes since the last broadcast. Returns them for the on_broadcast hooks if a script has one.
+fn broadcast_pending(state: &AppStateInternal, last_broadcast_version: &mut u64) -> Option<DeltaUpdate> {
+    let started = Instant::now();
+    let now = SystemTime::now();
+    let config = state.config.get();
+    let disconnected_names = state.store.mark_disconnected(now, config.connection_timeout());
+    for name in &disconnected_names {
+        info!("Marking '{}' as disconnected due to timeout.", name);
+        state.webhooks.notify(WebhookEvent::CharacterDisconnected { character: name.clone() });
+        state.fights.disconnected(name, now);
+        state.affects.remove_character(name);
+        if let Some(info) = state.alerts.is_enabled().then(|| state.store.get(name)).flatten() {
+            let mut previous = info.data.clone();
+            previous.insert("CONNECTED".to_string(), Value::String("YES".to_string()));
+            state.alerts.evaluate(name, Some(&previous), &info.data, now);
         }
     }
+    state.affects.tick(&state.store, now);
+
+    let changes = state.store.changes_since(*last_broadcast_version);
+    if changes.version == *last_broadcast_version {
+        trace!("Broadcast check: No changes or pending updates.");
+        return None;
+    }
+    *last_broadcast_version = changes.version;
+    // Tombstones up to this version are part of this delta; later subscribers get them via the snapshot,
+    // but keep them for a while so SSE clients can resume across a reconnect.
+    state.store.compact_tombstones(changes.version, now, config.tombstone_retention());
+    let delta = changes.delta;
+    if delta.is_empty() {
+        trace!("Broadcast check: Store version advanced but no concrete delta to send.");
+        return None;
+    }
+    let for_scripts = state.scripts.handles("on_broadcast").then(|| delta.clone());
+    state.mqtt.publish_delta(&delta);
+    metrics::BROADCAST_CHARACTERS.observe((delta.updates.len() + delta.deletions.len()) as f64);
+
+    let num_subscribers = state.subscribers.len();
+    if num_subscribers == 0 {
+        trace!("Broadcast check: Delta prepared, but no subscribers.");
+        return for_scripts;
+    }
+
+    // Every subscriber queue shares this delta; each wire format is encoded at most once, on first use.
+    let queued = QueuedDelta::new(changes.version, delta);
+    let mut max_queue_depth = 0;
+    let mut sse_subscribers = 0;
+    for subscriber in state.subscribers.iter() {
+        subscriber.queue.push(queued.clone());
+        let depth = subscriber.queue.depth();
+        metrics::QUEUE_DEPTH.observe(depth as f64);
+        max_queue_depth = max_queue_depth.max(depth);
+        if subscriber.transport == "sse" { sse_subscribers += 1; }
+    }
+    info!(
+        "Broadcasting delta v{}. Updates: {}, Deletions: {}. Subscribers: {} ({} SSE), Max queue depth: {}, Compression ratio: {}",
+        queued.version, queued.delta.updates.len(), queued.delta.deletions.len(),
+        num_subscribers, sse_subscribers, max_queue_depth,
+        state.compression.ratio().map_or_else(|| "n/a".to_string(), |r| format!("{:.2} over {} frames", r, state.compression.messages()))
+    );
+    metrics::BROADCAST_DURATION.observe(started.elapsed().as_secs_f64());
+    for_scripts
 }
 
 // --- Static File Handler for / (subscriber_client.html) ---
-async fn handle_root() -> impl IntoResponse {
-    let html_file_path = PathBuf::from(&*STATIC_DIR_PATH_CONFIG).join("subscriber_client.html");
+async fn handle_root(State(state): State<SharedState>) -> impl IntoResponse {
+    let html_file_path = PathBuf::from(&state.config.get().server.static_dir_path).join("subscriber_client.html");
     info!("Serving root (subscriber_client.html) from: {:?}", html_file_path);
     match File::open(&html_file_path).await {
         Ok(mut file) => {
@@ -846,77 +1311,96 @@ async fn handle_root() -> impl IntoResponse {
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
 
-    let http_host = get_env_var_string("HTTP_HOST", "0.0.0.0");
-    let http_port = get_env_var("HTTP_PORT", 8080u16);
-    let prune_interval_seconds = get_env_var("PRUNE_INTERVAL_SECONDS", 60u64);
-    let data_timeout_minutes = get_env_var("DATA_TIMEOUT_MINUTES", 30u64);
-    let broadcast_interval_seconds = get_env_var("BROADCAST_INTERVAL_SECONDS", 0.2f64); // e.g., 0.2 for 200ms
-    let connection_timeout_seconds = get_env_var("CONNECTION_TIMEOUT_SECONDS", 5u64);
-    let log_level_str = get_env_var_string("LOG_LEVEL", "INFO");
-    let log_level = Level::from_str(&log_level_str.to_lowercase()).unwrap_or(Level::INFO);
-
-    // Rate Limiter Configuration
-    let rate_limit_rps = get_env_var("RATE_LIMIT_RPS", 5.0f64);
-    let rate_limit_burst_capacity = get_env_var("RATE_LIMIT_BURST_CAPACITY", 15.0f64);
-    let rate_limit_violation_threshold = get_env_var("RATE_LIMIT_VIOLATION_THRESHOLD", 20u32);
-    let rate_limit_ban_duration_seconds = get_env_var("RATE_LIMIT_BAN_DURATION_SECONDS", 300u64); // 5 minutes
-    let rate_limit_cleanup_interval_seconds = get_env_var("RATE_LIMIT_CLEANUP_INTERVAL_SECONDS", 600u64); // 10 minutes
+    let args: Vec<String> = env::args().collect();
+    let config_path = config::file_path(&args);
+    let config = Config::load(config_path.as_deref())?;
+    if args.iter().any(|arg| arg == "--print-config") {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    let log_level = Level::from_str(&config.server.log_level)?;
+    let message_format: MessageFormat = config.server.message_format.parse().map_err(anyhow::Error::msg)?;
 
     tracing_subscriber::registry()
         .with(fmt::layer())
         .with(EnvFilter::from_default_env().add_directive(log_level.into()))
         .init();
 
+    let server = &config.server;
     info!("Starting server...");
+    info!("Config File: {:?}", config_path);
     info!("Log level: {:?}", log_level);
-    info!("HTTP Host: {}", http_host);
-    info!("HTTP Port: {}", http_port);
-    info!("Static Directory (for fallback serving): {}", &*STATIC_DIR_PATH_CONFIG);
-
-    let prune_interval_duration = Duration::from_secs(prune_interval_seconds);
-    let data_timeout_duration = Duration::from_secs(data_timeout_minutes * 60);
-    let broadcast_interval_duration = Duration::from_secs_f64(broadcast_interval_seconds);
-    let connection_timeout_duration = Duration::from_secs(connection_timeout_seconds);
-
-    let rl_config = RateLimiterConfig {
-        rps: rate_limit_rps,
-        burst_capacity: rate_limit_burst_capacity,
-        violation_threshold: rate_limit_violation_threshold,
-        ban_duration: Duration::from_secs(rate_limit_ban_duration_seconds),
-        cleanup_interval: Duration::from_secs(rate_limit_cleanup_interval_seconds),
+    info!("HTTP Host: {}", server.http_host);
+    info!("HTTP Port: {}", server.http_port);
+    info!("Static Directory (for fallback serving): {}", server.static_dir_path);
+    info!("Default Message Format: {:?}", message_format);
+
+    let prune_interval_duration = Duration::from_secs(server.prune_interval_seconds);
+    let broadcast_interval_duration = Duration::from_secs_f64(server.broadcast_interval_seconds);
+
+    metrics::init();
+    let (state, mqtt_event_loop) = build_state(&config, message_format)?;
+    let shared_state = Arc::new(state);
+    let rate_limit_layer = RateLimitLayer::new(shared_state.rate_limiter.clone());
+
+    let export = &config.export;
+    let export_config = ExportConfig {
+        sink: ExportConfig::sink_from_env_value(&export.sink, &export.address)?,
+        address: export.address.clone(),
+        influx_token: export.influx_token.clone(),
+        prefix: export.prefix.trim().to_string(),
+        keys: ExportConfig::keys_from_env_value(&export.keys),
+        interval: Duration::from_secs(export.interval_seconds),
+        batch_size: export.batch_size,
+        buffer_lines: export.buffer_lines,
+        max_backoff: Duration::from_secs(export.max_backoff_seconds.max(export.interval_seconds)),
     };
-    info!("Rate Limiter Config: {:?}", rl_config);
-    let rate_limiter = RateLimiter::new(rl_config);
-    let rate_limit_layer = RateLimitLayer::new(rate_limiter);
-
-    let (delta_tx, _) = broadcast::channel::<DeltaUpdate>(100); // Channel capacity
-    let shared_state = Arc::new(AppStateInternal {
-        character_data: DashMap::new(),
-        pending_updates: Mutex::new(HashMap::new()),
-        pending_deletions: Mutex::new(HashSet::new()),
-        delta_tx,
-    });
+    info!("Metrics Export Config: sink {:?}, address '{}', prefix '{}', keys {:?}, interval {:?}",
+        export_config.sink, export_config.address, export_config.prefix, export_config.keys, export_config.interval);
+    let exporter = Exporter::new(export_config).await?;
 
     let prune_state = Arc::clone(&shared_state);
     let prune_handle = tokio::spawn(async move {
-        prune_loop(prune_state, prune_interval_duration, data_timeout_duration).await;
+        prune_loop(prune_state, prune_interval_duration).await;
     });
 
+    tokio::spawn(computed::reload_loop(Arc::clone(&shared_state.computed), Duration::from_secs(config.computed_keys.reload_seconds)));
+    if let Some(exporter) = exporter {
+        tokio::spawn(export::export_loop(Arc::clone(&shared_state), exporter));
+    }
+    if let Some(event_loop) = mqtt_event_loop {
+        tokio::spawn(mqtt::event_loop(Arc::clone(&shared_state), event_loop));
+    }
+    tokio::spawn(scripting::reload_loop(Arc::clone(&shared_state.scripts), Duration::from_secs(config.scripting.reload_seconds)));
+    tokio::spawn(config::reload_loop(Arc::clone(&shared_state), config_path, Duration::from_secs(config.server.config_reload_seconds)));
+
     let broadcast_state = Arc::clone(&shared_state);
     let broadcast_handle = tokio::spawn(async move {
-        broadcast_loop(broadcast_state, broadcast_interval_duration, connection_timeout_duration).await;
+        broadcast_loop(broadcast_state, broadcast_interval_duration).await;
     });
 
     // Configure ServeDir for static files
-    let static_dir_path = PathBuf::from(&*STATIC_DIR_PATH_CONFIG);
+    let static_dir_path = PathBuf::from(&config.server.static_dir_path);
     info!("Configuring static file fallback serving from: {:?}", static_dir_path);
     let static_files_service = ServeDir::new(static_dir_path.clone()) // Clone static_dir_path if needed elsewhere
         .append_index_html_on_directories(false); // Optional: if you don't want /foo/ to serve /foo/index.html
 
     let app = Router::new()
-        .route("/update", post(handle_http_update).layer(rate_limit_layer.clone()))
+        .route(
+            "/update",
+            post(handle_http_update).layer(rate_limit_layer.clone()).layer(axum::middleware::map_response(metrics::record_ingest_status)),
+        )
+        .route("/metrics", get(metrics::metrics_handler))
         .route("/", get(handle_root)) // Specific handler for subscriber_client.html
         .route("/ws", get(ws_handler))
+        .route("/events", get(sse::sse_handler))
+        .route("/api/characters", get(api::list_characters))
+        .route("/api/characters/:name", get(api::get_character))
+        .route("/api/characters/:name/commands", get(commands::list_commands).post(commands::post_command))
+        .route("/api/characters/:name/fights", get(fights::list_fights))
+        .route("/api/characters/:name/:key", get(api::get_character_key))
+        .route("/api/viewers", get(presence::list_viewers))
+        .route("/api/alerts", get(alerts::list_alerts))
         .fallback_service(static_files_service) // <<< MODIFIED: Serve other static files
         .with_state(shared_state)
         .layer(
@@ -927,7 +1411,7 @@ async fn main() -> anyhow::Result<()> {
                 }),
         );
 
-    let addr_str = format!("{}:{}", http_host, http_port);
+    let addr_str = format!("{}:{}", config.server.http_host, config.server.http_port);
     let addr: SocketAddr = addr_str.parse()?;
     info!("HTTP/WebSocket server listening on {}", addr);
 
@@ -940,6 +1424,142 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Builds the shared state from the configuration, with every module loaded and logged. Tasks the
+/// state needs for itself (webhook delivery, rate limiter cleanup) are spawned here; the other
+/// background loops are started by main. Also returns the MQTT event loop, if the bridge is enabled.
+fn build_state(config: &Config, message_format: MessageFormat) -> anyhow::Result<(AppStateInternal, Option<rumqttc::EventLoop>)> {
+    let rl_config = config.rate_limit.limiter_config();
+    info!("Rate Limiter Config: {:?}", rl_config);
+
+    let webhook_section = &config.webhooks;
+    let webhook_config = WebhookConfig {
+        file: webhook_section.file.clone(),
+        max_attempts: webhook_section.max_attempts,
+        initial_backoff: Duration::from_millis(webhook_section.initial_backoff_ms),
+        max_backoff: Duration::from_secs(webhook_section.max_backoff_seconds),
+        timeout: Duration::from_secs(webhook_section.timeout_seconds),
+        queue_capacity: webhook_section.queue_capacity,
+        dead_letter_file: webhook_section.dead_letter_file.clone(),
+    };
+    info!("Webhook Config: {:?}", webhook_config);
+    let webhooks = Webhooks::start(webhook_config)?;
+    info!("Webhooks: {} target(s)", webhooks.target_count());
+
+    let rate_limiter = RateLimiter::new(rl_config.clone(), webhooks.clone());
+    let character_rate_limiter = RateLimiter::new(rl_config, webhooks.clone());
+
+    let ws = &config.websocket;
+    let ws_config = WsConfig {
+        ping_interval: Duration::from_secs(ws.ping_interval_seconds),
+        pong_timeout: Duration::from_secs(ws.pong_timeout_seconds),
+        heartbeat_interval: (ws.heartbeat_interval_seconds > 0).then(|| Duration::from_secs(ws.heartbeat_interval_seconds)),
+        queue_capacity: ws.queue_capacity,
+        deflate: DeflateConfig::new(ws.deflate, ws.deflate_level, ws.deflate_window_bits, ws.deflate_threshold_bytes),
+    };
+    info!("WebSocket Heartbeat Config: {:?}", ws_config);
+
+    let priority_config = config.priority.priority_config();
+    info!("Priority Flush Config: {:?}", priority_config);
+
+    let derived_config = DerivedConfig::parse(
+        &config.derived.metrics,
+        &config.derived.xp_key,
+        Duration::from_secs(config.derived.rate_window_seconds),
+        Duration::from_secs(config.derived.xp_window_seconds),
+    );
+    info!("Derived Metrics Config: {:?}", derived_config);
+    let computed_keys_file = &config.computed_keys.file;
+    let computed_keys = Arc::new(ComputedKeys::load(computed_keys_file)?);
+    info!("Computed Keys: {} key(s) from '{}'", computed_keys.key_count(), computed_keys_file);
+    let scripting = &config.scripting;
+    let script_config = ScriptConfig {
+        dir: scripting.dir.trim().to_string(),
+        timeout: Duration::from_millis(scripting.timeout_ms),
+        max_operations: scripting.max_operations,
+        max_string_size: scripting.max_string_size,
+        max_collection_size: scripting.max_collection_size,
+    };
+    info!("Scripting Config: {:?}", script_config);
+    let scripts = Arc::new(Scripts::load(script_config)?);
+    info!("Scripts: {} script(s) from '{}'", scripts.script_count(), scripting.dir);
+
+    let command_config = config.commands.command_config();
+    info!("Command Channel Config: {:?}", command_config);
+
+    let annotation_config = AnnotationConfig {
+        chat_history: config.annotations.chat_history,
+        notes_per_character: config.annotations.notes_per_character,
+        max_text_len: config.annotations.max_length,
+    };
+    info!("Chat & Notes Config: {:?}", annotation_config);
+
+    let viewer_events = ViewerEvents::new();
+    let alert_section = &config.alerts;
+    let alerts = AlertEngine::load(&alert_section.rules_file, alert_section.history, viewer_events.clone(), webhooks.clone())?;
+    info!("Alert Rules: {} rule(s) from '{}', history {}", alerts.rule_count(), alert_section.rules_file, alert_section.history);
+    let plugin_config = PluginConfig {
+        dir: config.plugins.dir.trim().to_string(),
+        fuel: config.plugins.fuel,
+        max_memory_bytes: config.plugins.max_memory_mb * 1024 * 1024,
+    };
+    info!("Plugin Config: {:?}", plugin_config);
+    let plugins = Arc::new(Plugins::load(plugin_config, viewer_events.clone())?);
+    info!("Plugins: {} plugin(s) from '{}'", plugins.plugin_count(), config.plugins.dir);
+    let metrics_config = config.metrics.metrics_config();
+    info!("Metrics Config: {:?}", metrics_config);
+    let mqtt_section = &config.mqtt;
+    let mqtt_config = MqttConfig {
+        host: mqtt_section.host.trim().to_string(),
+        port: mqtt_section.port,
+        client_id: mqtt_section.client_id.clone(),
+        username: mqtt_section.username.clone(),
+        password: mqtt_section.password.clone(),
+        topic_prefix: mqtt_section.topic_prefix.trim().trim_end_matches('/').to_string(),
+        qos: rumqttc::qos(mqtt_section.qos)?,
+        retain: mqtt_section.retain,
+        ingest_topic: mqtt_section.ingest_topic.trim().to_string(),
+        queue_capacity: mqtt_section.queue_capacity,
+    };
+    info!("MQTT Bridge Config: {:?}", mqtt_config);
+    let (mqtt, mqtt_event_loop) = MqttBridge::new(mqtt_config);
+    let fight_config = FightConfig { history: config.fights.history, curve_points: config.fights.curve_points };
+    info!("Fight Tracking Config: {:?}", fight_config);
+    let affect_config = AffectConfig {
+        key: config.affects.key.trim().to_string(),
+        seconds_per_unit: config.affects.seconds_per_unit,
+        expiring_seconds: config.affects.expiring_seconds,
+        refresh_interval: Duration::from_secs(config.affects.refresh_seconds),
+    };
+    info!("Affect Countdown Config: {:?}", affect_config);
+    let state = AppStateInternal {
+        config: Live::new(config.clone()),
+        store: StateStore::new(),
+        snapshot_cache: StdMutex::new(None),
+        default_format: message_format,
+        subscribers: DashMap::new(),
+        ws_config,
+        priority: Live::new(priority_config),
+        derived: derived_config,
+        computed: computed_keys,
+        scripts,
+        plugins,
+        metrics: Live::new(metrics_config),
+        mqtt,
+        rate_limiter,
+        character_rate_limiter,
+        flush_notify: Notify::new(),
+        compression: Arc::new(CompressionStats::default()),
+        commands: CommandStore::new(command_config),
+        annotations: AnnotationStore::new(annotation_config, viewer_events.clone()),
+        alerts,
+        fights: FightTracker::new(fight_config, viewer_events.clone()),
+        affects: AffectTracker::new(affect_config, viewer_events.clone()),
+        viewer_events,
+        webhooks,
+    };
+    Ok((state, mqtt_event_loop))
+}
+
 // --- Graceful Shutdown Signal Handler ---
 async fn shutdown_signal(
     prune_handle: tokio::task::JoinHandle<()>,
@@ -975,4 +1595,69 @@ async fn shutdown_signal(
     // For simple abort, just signaling is usually enough.
 
     info!("Background tasks cancellation requested. Server will shut down shortly.");
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandStatus;
+    use serde_json::json;
+
+    #[test]
+    fn command_ack_lists_keep_their_commas() {
+        let parsed = parse_strict_key_value_pairs("{CHARACTER_NAME}{Thoric}{COMMAND_ACK}{12,13}{HP}{1,234}").unwrap();
+        assert_eq!(parsed.get(commands::ACK_KEY), Some(&Value::String("12,13".to_string())));
+        assert_eq!(parsed.get("HP"), Some(&json!(1234)));
+    }
+
+    #[tokio::test]
+    async fn update_acknowledges_several_commands_at_once() {
+        let (state, _) = build_state(&Config::default(), MessageFormat::Legacy).unwrap();
+        let source = || "test".to_string();
+        ingest_update(&state, "{CHARACTER_NAME}{Thoric}{COMMAND_ALLOWLIST}{cast *}", source(), None).await.unwrap();
+
+        let data = state.store.get("Thoric").unwrap().data;
+        let now = SystemTime::now();
+        let first = state.commands.enqueue("Thoric", &data, "cast heal", source(), None, now).unwrap();
+        let second = state.commands.enqueue("Thoric", &data, "cast shield", source(), None, now).unwrap();
+
+        let delivered = ingest_update(&state, "{CHARACTER_NAME}{Thoric}", source(), None).await.unwrap();
+        assert_eq!(delivered.response, format!("{{COMMAND_ID}}{{{}}}{{COMMAND}}{{cast heal}}{{COMMAND_ID}}{{{}}}{{COMMAND}}{{cast shield}}", first.id, second.id));
+
+        let ack = format!("{{CHARACTER_NAME}}{{Thoric}}{{COMMAND_ACK}}{{{},{}}}", first.id, second.id);
+        ingest_update(&state, &ack, source(), None).await.unwrap();
+        let statuses: Vec<CommandStatus> = state.commands.list("Thoric", SystemTime::now()).into_iter().map(|c| c.status).collect();
+        assert_eq!(statuses, vec![CommandStatus::Acknowledged, CommandStatus::Acknowledged]);
+        assert!(!state.store.get("Thoric").unwrap().data.contains_key(commands::ACK_KEY));
+    }
+
+    #[tokio::test]
+    async fn update_scripts_rewrite_ingested_data() {
+        let mut config = Config::default();
+        config.scripting.dir = "scripts.example".to_string();
+        let (state, _) = build_state(&config, MessageFormat::Legacy).unwrap();
+        ingest_update(&state, "{CHARACTER_NAME}{Thoric}{HP}{120}{HEALTH_MAX}{150}", "test".to_string(), None).await.unwrap();
+
+        let data = state.store.get("Thoric").unwrap().data;
+        assert_eq!(data.get("HEALTH"), Some(&json!(120)));
+        assert!(!data.contains_key("HP"));
+        // Derived metrics run after the scripts, on their result.
+        assert_eq!(data.get("DERIVED_HEALTH_PCT"), Some(&json!(80)));
+    }
+
+    #[tokio::test]
+    async fn computed_keys_see_derived_metrics_and_the_previous_update() {
+        let path = std::env::temp_dir().join(format!("computed_keys_{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, "[[key]]\nname = \"PCT_CHANGE\"\nexpr = \"DERIVED_HEALTH_PCT - prev(DERIVED_HEALTH_PCT)\"").unwrap();
+        let mut config = Config::default();
+        config.computed_keys.file = path.to_str().unwrap().to_string();
+        let state = build_state(&config, MessageFormat::Legacy).map(|(state, _)| state);
+        std::fs::remove_file(&path).unwrap();
+        let state = state.unwrap();
+
+        ingest_update(&state, "{CHARACTER_NAME}{Thoric}{HEALTH}{200}{HEALTH_MAX}{200}", "test".to_string(), None).await.unwrap();
+        assert!(!state.store.get("Thoric").unwrap().data.contains_key("PCT_CHANGE"));
+        ingest_update(&state, "{CHARACTER_NAME}{Thoric}{HEALTH}{150}{HEALTH_MAX}{200}", "test".to_string(), None).await.unwrap();
+        assert_eq!(state.store.get("Thoric").unwrap().data.get("PCT_CHANGE"), Some(&json!(-25)));
+    }
+
+}
@@ -0,0 +1,268 @@
+// --- Prometheus Metrics ---
+// GET /metrics in the Prometheus text format. Counters and histograms are statics updated where
+// things happen; gauges describing current state (characters, subscribers) are read from the
+// shared state on every scrape.
+//
+// With METRICS_CHARACTER_KEYS set, numeric character keys are also exported as
+// `mud_character_value{character, key}`. Each character/key pair is its own series, so their
+// number is capped at METRICS_MAX_CHARACTER_SERIES; pairs beyond the cap are counted in
+// `mud_character_series_dropped` instead of being exported.
+
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::Lazy;
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tracing::error;
+
+use crate::numbers::as_number;
+use crate::SharedState;
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricsConfig {
+    pub character_keys: Option<HashSet<String>>, // None for no per-character series, empty for every numeric key
+    pub max_character_series: usize,
+}
+
+impl MetricsConfig {
+    /// Parses the `character_keys` list ("HEALTH,MANA", "*" for all numeric keys, empty for none).
+    pub fn parse(character_keys: &str, max_character_series: usize) -> Self {
+        let character_keys = match character_keys.trim() {
+            "" => None,
+            "*" => Some(HashSet::new()),
+            keys => Some(keys.split(',').map(str::trim).filter(|k| !k.is_empty()).map(String::from).collect()),
+        };
+        Self { character_keys, max_character_series }
+    }
+}
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<M: Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).expect("metric names are unique");
+    metric
+}
+
+pub static INGEST_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| register(
+    IntCounterVec::new(Opts::new("mud_ingest_requests_total", "POST /update requests by response status."), &["status"]).unwrap(),
+));
+
+pub static PARSE_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register(
+    IntCounterVec::new(Opts::new("mud_parse_errors_total", "Updates rejected by the {key}{value} parser, by error."), &["error"]).unwrap(),
+));
+
+pub static PARSE_DURATION: Lazy<Histogram> = Lazy::new(|| register(
+    Histogram::with_opts(HistogramOpts::new("mud_parse_duration_seconds", "Time spent parsing one update.")
+        .buckets(exponential_buckets(0.000_01, 4.0, 8).unwrap())).unwrap(),
+));
+
+pub static BROADCAST_CHARACTERS: Lazy<Histogram> = Lazy::new(|| register(
+    Histogram::with_opts(HistogramOpts::new("mud_broadcast_characters", "Updated and deleted characters per broadcast delta.")
+        .buckets(exponential_buckets(1.0, 2.0, 8).unwrap())).unwrap(),
+));
+
+pub static BROADCAST_DURATION: Lazy<Histogram> = Lazy::new(|| register(
+    Histogram::with_opts(HistogramOpts::new("mud_broadcast_duration_seconds", "Time spent preparing and queueing one broadcast.")
+        .buckets(exponential_buckets(0.000_01, 4.0, 8).unwrap())).unwrap(),
+));
+
+pub static LAG_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| register(
+    IntCounterVec::new(
+        Opts::new("mud_subscriber_lag_events_total", "Times a subscriber fell behind: deltas coalesced in a full queue, or viewer events missed."),
+        &["kind"],
+    ).unwrap(),
+));
+
+pub static QUEUE_DEPTH: Lazy<Histogram> = Lazy::new(|| register(
+    Histogram::with_opts(HistogramOpts::new("mud_subscriber_queue_depth", "Deltas waiting in each subscriber's queue, observed per subscriber on every broadcast.")
+        .buckets(exponential_buckets(1.0, 2.0, 8).unwrap())).unwrap(),
+));
+
+pub static DELTAS_COALESCED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("mud_subscriber_deltas_coalesced_total", "Queued deltas merged into a later one because a subscriber's queue was full.").unwrap(),
+));
+
+pub static DEFLATE_INPUT_BYTES: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("mud_ws_deflate_input_bytes_total", "Bytes of /ws frames sent with permessage-deflate, before compression.").unwrap(),
+));
+
+pub static DEFLATE_OUTPUT_BYTES: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("mud_ws_deflate_output_bytes_total", "Bytes of the same frames after compression.").unwrap(),
+));
+
+pub static RATE_LIMIT_THROTTLED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("mud_rate_limit_throttled_total", "Updates rejected because the sender exceeded the rate limit.").unwrap(),
+));
+
+pub static RATE_LIMIT_BANS: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("mud_rate_limit_bans_total", "IPs banned for repeated rate limit violations.").unwrap(),
+));
+
+pub static PRUNED_CHARACTERS: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("mud_pruned_characters_total", "Characters removed after DATA_TIMEOUT_MINUTES without updates.").unwrap(),
+));
+
+static CHARACTERS: Lazy<IntGaugeVec> = Lazy::new(|| register(
+    IntGaugeVec::new(Opts::new("mud_characters", "Tracked characters by connection state."), &["state"]).unwrap(),
+));
+
+static SUBSCRIBERS: Lazy<IntGaugeVec> = Lazy::new(|| register(
+    IntGaugeVec::new(Opts::new("mud_subscribers", "Connected viewers by transport."), &["transport"]).unwrap(),
+));
+
+static CHARACTER_SERIES_DROPPED: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("mud_character_series_dropped", "Character/key pairs left out of mud_character_value by the series cap.").unwrap(),
+));
+
+/// Sum over all labels of a counter.
+pub fn total(counter: &IntCounterVec) -> u64 {
+    counter.collect().iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_counter().get_value() as u64)
+        .sum()
+}
+
+/// Registers every metric, so all of them are exported from the first scrape on.
+pub fn init() {
+    for metric in [&INGEST_REQUESTS, &PARSE_ERRORS, &LAG_EVENTS] {
+        Lazy::force(metric);
+    }
+    for metric in [&PARSE_DURATION, &BROADCAST_CHARACTERS, &BROADCAST_DURATION, &QUEUE_DEPTH] {
+        Lazy::force(metric);
+    }
+    for metric in [&RATE_LIMIT_THROTTLED, &RATE_LIMIT_BANS, &PRUNED_CHARACTERS, &DELTAS_COALESCED, &DEFLATE_INPUT_BYTES, &DEFLATE_OUTPUT_BYTES] {
+        Lazy::force(metric);
+    }
+    Lazy::force(&CHARACTERS);
+    Lazy::force(&SUBSCRIBERS);
+    Lazy::force(&CHARACTER_SERIES_DROPPED);
+}
+
+/// Counts `POST /update` responses, including those of the rate limiter.
+pub async fn record_ingest_status(response: Response) -> Response {
+    INGEST_REQUESTS.with_label_values(&[response.status().as_str()]).inc();
+    response
+}
+
+// GET /metrics
+pub async fn metrics_handler(State(state): State<SharedState>) -> Response {
+    let summaries = state.store.summaries();
+    let connected = summaries.iter().filter(|(_, connected, _)| *connected).count();
+    CHARACTERS.with_label_values(&["connected"]).set(connected as i64);
+    CHARACTERS.with_label_values(&["disconnected"]).set((summaries.len() - connected) as i64);
+    // Counted before any gauge is touched, so a concurrent scrape never sees a half-updated count.
+    let mut subscribers: HashMap<&str, i64> = HashMap::from([("ws", 0), ("sse", 0)]);
+    for subscriber in state.subscribers.iter() {
+        *subscribers.entry(subscriber.transport).or_default() += 1;
+    }
+    for (transport, count) in subscribers {
+        SUBSCRIBERS.with_label_values(&[transport]).set(count);
+    }
+
+    // Before gathering, so mud_character_series_dropped is from this scrape.
+    let character_values = character_values(&state, &summaries);
+    let mut families = REGISTRY.gather();
+    if let Some(character_values) = character_values {
+        families.extend(character_values.collect());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&families, &mut body) {
+        error!("Failed to encode metrics: {}", e);
+        return axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_string())], body).into_response()
+}
+
+/// A fresh gauge per scrape, so pruned characters and removed keys disappear. Characters and keys
+/// are visited in name order, so the cap keeps the same series from one scrape to the next.
+fn character_values(state: &SharedState, summaries: &[(String, bool, SystemTime)]) -> Option<GaugeVec> {
+    let config = state.metrics.get();
+    let keys = config.character_keys.as_ref()?;
+    let gauge = GaugeVec::new(Opts::new("mud_character_value", "Numeric character keys."), &["character", "key"]).unwrap();
+    let mut series = 0;
+    let mut dropped = 0;
+    for (character, _, _) in summaries {
+        let values = state.store.inspect(character, |info| {
+            let mut values: Vec<(String, f64)> = info.into_iter()
+                .flat_map(|info| &info.data)
+                .filter(|(key, _)| keys.is_empty() || keys.contains(*key))
+                .filter_map(|(key, value)| Some((key.clone(), as_number(value)?)))
+                .collect();
+            values.sort_by(|a, b| a.0.cmp(&b.0));
+            values
+        });
+        for (key, value) in values {
+            if series >= config.max_character_series {
+                dropped += 1;
+                continue;
+            }
+            series += 1;
+            gauge.with_label_values(&[character, &key]).set(value);
+        }
+    }
+    CHARACTER_SERIES_DROPPED.set(dropped);
+    Some(gauge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use axum::body::to_bytes;
+    use tokio::time::Instant;
+    use uuid::Uuid;
+
+    use crate::config::Config;
+    use crate::protocol::MessageFormat;
+    use crate::subscriber_queue::SubscriberQueue;
+    use crate::SubscriberInfo;
+
+    fn subscriber(transport: &'static str) -> SubscriberInfo {
+        SubscriberInfo {
+            transport,
+            peer_addr: "127.0.0.1:4000".parse().unwrap(),
+            user_agent: "test".to_string(),
+            name: None,
+            group: "default".to_string(),
+            filter: None,
+            format: MessageFormat::V2,
+            connected_at: SystemTime::now(),
+            last_activity: Instant::now(),
+            queue: Arc::new(SubscriberQueue::new(4)),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrape_counts_subscribers_and_exports_queue_and_compression_metrics() {
+        init();
+        let (state, _) = crate::build_state(&Config::default(), MessageFormat::V2).unwrap();
+        let state = Arc::new(state);
+        for transport in ["ws", "ws", "sse"] {
+            state.subscribers.insert(Uuid::new_v4(), subscriber(transport));
+        }
+
+        let body = to_bytes(metrics_handler(State(Arc::clone(&state))).await.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("mud_subscribers{transport=\"ws\"} 2\n"), "{}", text);
+        assert!(text.contains("mud_subscribers{transport=\"sse\"} 1\n"), "{}", text);
+        for name in ["mud_subscriber_queue_depth_bucket", "mud_subscriber_deltas_coalesced_total", "mud_ws_deflate_input_bytes_total", "mud_ws_deflate_output_bytes_total"] {
+            assert!(text.contains(name), "{} missing", name);
+        }
+
+        state.subscribers.clear();
+        let body = to_bytes(metrics_handler(State(state)).await.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("mud_subscribers{transport=\"ws\"} 0\n"), "{}", text);
+        assert!(text.contains("mud_subscribers{transport=\"sse\"} 0\n"), "{}", text);
+    }
+}
@@ -0,0 +1,443 @@
+// --- MQTT Bridge ---
+// Publishes character data to an MQTT broker for home dashboards, LED strips and other devices
+// that already speak MQTT. Every key gets its own topic, `MQTT_TOPIC_PREFIX/<character>/<key>`,
+// with the value as a plain text payload (strings as-is, anything else as JSON), retained by
+// default so new subscribers get the last values at once. Only changed keys are published, from
+// the same deltas viewers receive. When a key disappears or a character is pruned, its retained
+// topics are cleared with empty payloads.
+//
+// With MQTT_INGEST_TOPIC set, the bridge also accepts updates from that topic, as an alternative
+// to POST /update: the payload is the same `{key}{value}` body. Commands pending for the character
+// are published to `MQTT_INGEST_TOPIC/commands/<character>`, as POST /update would return them.
+// Updates are ingested in order on a task of their own, so slow scripts or plugins do not hold up
+// the event loop, and rate limited per character with the RATE_LIMIT_* settings of /update.
+// The bridge reconnects on its own, resubscribes, and republishes all keys of a character with its
+// next update. An ingest topic the bridge itself publishes to is rejected at startup, since every
+// published value would come straight back as an update.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use rumqttc::{AsyncClient, ClientError, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{ingest_update, DeltaUpdate, SharedState};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct MqttConfig {
+    pub host: String, // Empty disables the bridge
+    pub port: u16,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    pub topic_prefix: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub ingest_topic: String, // Empty disables ingest
+    pub queue_capacity: usize, // Outgoing messages buffered while the broker is slow or away
+}
+
+// Without the password.
+impl std::fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("topic_prefix", &self.topic_prefix)
+            .field("qos", &self.qos)
+            .field("retain", &self.retain)
+            .field("ingest_topic", &self.ingest_topic)
+            .field("queue_capacity", &self.queue_capacity)
+            .finish()
+    }
+}
+
+pub struct MqttBridge {
+    config: MqttConfig,
+    client: Option<AsyncClient>,
+    published: StdMutex<HashMap<String, HashMap<String, String>>>, // Character -> key -> payload
+}
+
+impl MqttBridge {
+    /// Creates the bridge and, unless it is disabled, the event loop to drive with `event_loop`.
+    pub fn new(config: MqttConfig) -> (Self, Option<EventLoop>) {
+        let (client, event_loop) = if config.host.is_empty() {
+            (None, None)
+        } else {
+            let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+            options.set_keep_alive(KEEP_ALIVE);
+            if !config.username.is_empty() {
+                options.set_credentials(&config.username, &config.password);
+            }
+            let (client, event_loop) = AsyncClient::new(options, config.queue_capacity);
+            (Some(client), Some(event_loop))
+        };
+        (Self { config, client, published: StdMutex::new(HashMap::new()) }, event_loop)
+    }
+
+    /// Publishes the keys a broadcast delta changed and clears those that are gone.
+    pub fn publish_delta(&self, delta: &DeltaUpdate) {
+        let Some(client) = &self.client else { return };
+        let mut published = self.published.lock().unwrap();
+        let mut failed = 0;
+        let mut publish = |character: &str, key: &str, payload: String| {
+            let topic = self.topic(character, key);
+            match client.try_publish(topic, self.config.qos, self.config.retain, payload) {
+                Ok(()) => true,
+                Err(ClientError::TryRequest(_)) => { failed += 1; false }
+                Err(e) => { debug!("MQTT publish failed: {}", e); failed += 1; false }
+            }
+        };
+
+        for (character, data) in &delta.updates {
+            let last = published.entry(character.clone()).or_default();
+            for (key, value) in data {
+                let payload = payload(value);
+                if last.get(key) != Some(&payload) && publish(character, key, payload.clone()) {
+                    last.insert(key.clone(), payload);
+                }
+            }
+            last.retain(|key, _| data.contains_key(key) || !publish(character, key, String::new()));
+        }
+        for character in &delta.deletions {
+            if let Some(mut last) = published.remove(character) {
+                last.retain(|key, _| !publish(character, key, String::new()));
+                if !last.is_empty() {
+                    published.insert(character.clone(), last);
+                }
+            }
+        }
+        if failed > 0 {
+            warn!("MQTT queue full, {} message(s) not published. They are retried with the next change.", failed);
+        }
+    }
+
+    fn topic(&self, character: &str, key: &str) -> String {
+        let path = format!("{}/{}", topic_level(character), topic_level(key));
+        if self.config.topic_prefix.is_empty() { path } else { format!("{}/{}", self.config.topic_prefix, path) }
+    }
+}
+
+/// Drives the connection to the broker and ingests updates from MQTT_INGEST_TOPIC.
+pub async fn event_loop(state: SharedState, mut event_loop: EventLoop) {
+    let Some(client) = state.mqtt.client.clone() else { return };
+    let config = &state.mqtt.config;
+    info!("Starting MQTT bridge to {}:{}", config.host, config.port);
+    // Polling also sends keep-alives and queued publishes, so it must not wait for an update to be stored.
+    let (updates, mut received) = mpsc::channel::<Bytes>(config.queue_capacity);
+    tokio::spawn({
+        let (state, client) = (Arc::clone(&state), client.clone());
+        async move {
+            while let Some(payload) = received.recv().await {
+                ingest(&state, &client, &payload).await;
+            }
+        }
+    });
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}:{}.", config.host, config.port);
+                // The broker may have restarted without its retained messages, so every key of a
+                // character is published again with its next update.
+                state.mqtt.published.lock().unwrap().clear();
+                // A reconnect starts a clean session, so the subscription has to be renewed.
+                if !config.ingest_topic.is_empty() {
+                    if let Err(e) = client.try_subscribe(&config.ingest_topic, config.qos) {
+                        warn!("Failed to subscribe to MQTT topic '{}': {}", config.ingest_topic, e);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) if message.topic == config.ingest_topic => {
+                if updates.try_send(message.payload).is_err() {
+                    warn!("MQTT ingest queue full, dropped an update from '{}'.", config.ingest_topic);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection error: {}. Reconnecting in {:?}.", e, RECONNECT_DELAY);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Stores an update received on MQTT_INGEST_TOPIC and publishes the commands pending for its character.
+async fn ingest(state: &SharedState, client: &AsyncClient, payload: &[u8]) {
+    let config = &state.mqtt.config;
+    let body = String::from_utf8_lossy(payload);
+    info!("Received MQTT update (len={}): {}...", body.len(), body.chars().take(100).collect::<String>());
+    match ingest_update(state, &body, format!("mqtt:{}", config.ingest_topic), Some(&state.character_rate_limiter)).await {
+        Ok(ingested) if !ingested.response.is_empty() => {
+            let topic = format!("{}/commands/{}", config.ingest_topic, topic_level(&ingested.character));
+            if let Err(e) = client.try_publish(topic, config.qos, false, ingested.response) {
+                warn!("Failed to publish commands for '{}' over MQTT: {}", ingested.character, e);
+            }
+        }
+        Ok(_) => {}
+        Err(status) => warn!("Rejected MQTT update from '{}' ({}).", config.ingest_topic, status),
+    }
+}
+
+/// Why MQTT_INGEST_TOPIC cannot be used with MQTT_TOPIC_PREFIX, if it cannot.
+pub fn ingest_topic_problem(topic_prefix: &str, ingest_topic: &str) -> Option<&'static str> {
+    let (prefix, topic) = (topic_prefix.trim().trim_end_matches('/'), ingest_topic.trim());
+    if topic.is_empty() {
+        None
+    } else if topic.contains(['+', '#']) {
+        Some("must be a single topic without wildcards")
+    } else if !prefix.is_empty() && topic.starts_with(&format!("{}/", prefix)) {
+        Some("must not be under mqtt.topic_prefix, or the bridge would ingest its own publishes")
+    } else if prefix.is_empty() && topic.split('/').count() == 2 {
+        Some("must not have two levels while mqtt.topic_prefix is empty, as the bridge publishes to <character>/<key>")
+    } else {
+        None
+    }
+}
+
+/// Strings are published as-is, so `95` rather than `"95"`; everything else as JSON.
+fn payload(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Separators and wildcards cannot appear inside a topic level.
+fn topic_level(text: &str) -> String {
+    text.replace(['/', '+', '#'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, Publish, Request, SubAck, SubscribeReasonCode};
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::config::Config;
+    use crate::protocol::MessageFormat;
+    use crate::CharacterDataMap;
+
+    type Requests = flume::Receiver<Request>;
+
+    fn bridge(topic_prefix: &str) -> (MqttBridge, Requests) {
+        let (sender, requests) = flume::unbounded();
+        let config = MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "test".to_string(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: topic_prefix.to_string(),
+            qos: QoS::AtMostOnce,
+            retain: true,
+            ingest_topic: "mud-ingest".to_string(),
+            queue_capacity: 16,
+        };
+        let client = AsyncClient::from_senders(sender);
+        (MqttBridge { config, client: Some(client), published: StdMutex::new(HashMap::new()) }, requests)
+    }
+
+    /// (topic, payload, retain) of every publish sent so far.
+    fn published(requests: &Requests) -> Vec<(String, String, bool)> {
+        requests.try_iter().filter_map(|request| match request {
+            Request::Publish(p) => Some((p.topic, String::from_utf8(p.payload.to_vec()).unwrap(), p.retain)),
+            _ => None,
+        }).collect()
+    }
+
+    fn delta(updates: &[(&str, Value)], deletions: &[&str]) -> DeltaUpdate {
+        DeltaUpdate {
+            updates: updates.iter().map(|(name, data)| (name.to_string(), serde_json::from_value::<CharacterDataMap>(data.clone()).unwrap())).collect(),
+            deletions: deletions.iter().map(|name| name.to_string()).collect(),
+            meta: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn topics_and_payloads() {
+        let (prefixed, _) = bridge("mud/eu");
+        assert_eq!(prefixed.topic("Thoric", "HEALTH"), "mud/eu/Thoric/HEALTH");
+        assert_eq!(prefixed.topic("Sir/Thoric+1", "#TAG"), "mud/eu/Sir_Thoric_1/_TAG");
+        let (bare, _) = bridge("");
+        assert_eq!(bare.topic("Thoric", "HEALTH"), "Thoric/HEALTH");
+
+        assert_eq!(payload(&json!("95")), "95");
+        assert_eq!(payload(&json!("Warrior")), "Warrior");
+        assert_eq!(payload(&json!(95)), "95");
+        assert_eq!(payload(&json!(true)), "true");
+        assert_eq!(payload(&json!(["a", 1])), r#"["a",1]"#);
+    }
+
+    #[test]
+    fn deltas_publish_changed_keys_and_clear_removed_ones() {
+        let (bridge, requests) = bridge("mud");
+        bridge.publish_delta(&delta(&[("Thoric", json!({"HEALTH": 95, "CLASS": "Warrior"}))], &[]));
+        let mut sent = published(&requests);
+        sent.sort();
+        assert_eq!(sent, [
+            ("mud/Thoric/CLASS".to_string(), "Warrior".to_string(), true),
+            ("mud/Thoric/HEALTH".to_string(), "95".to_string(), true),
+        ]);
+
+        // HEALTH is unchanged, MANA is new and CLASS is gone.
+        bridge.publish_delta(&delta(&[("Thoric", json!({"HEALTH": 95, "MANA": 10}))], &[]));
+        let mut sent = published(&requests);
+        sent.sort();
+        assert_eq!(sent, [
+            ("mud/Thoric/CLASS".to_string(), String::new(), true),
+            ("mud/Thoric/MANA".to_string(), "10".to_string(), true),
+        ]);
+
+        bridge.publish_delta(&delta(&[], &["Thoric"]));
+        let mut sent = published(&requests);
+        sent.sort();
+        assert_eq!(sent, [
+            ("mud/Thoric/HEALTH".to_string(), String::new(), true),
+            ("mud/Thoric/MANA".to_string(), String::new(), true),
+        ]);
+        assert!(bridge.published.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ingest_stores_updates_and_publishes_pending_commands() {
+        let (mut state, _) = crate::build_state(&Config::default(), MessageFormat::Legacy).unwrap();
+        let requests;
+        (state.mqtt, requests) = bridge("mud");
+        let state = Arc::new(state);
+        let client = state.mqtt.client.clone().unwrap();
+
+        ingest(&state, &client, b"{CHARACTER_NAME}{Thoric}{HEALTH}{1,200}{COMMAND_ALLOWLIST}{look}").await;
+        let info = state.store.get("Thoric").unwrap();
+        assert_eq!(info.source, "mqtt:mud-ingest");
+        assert_eq!(info.data.get("HEALTH"), Some(&json!(1200)));
+        assert!(published(&requests).is_empty());
+
+        let command = state.commands.enqueue("Thoric", &info.data, "look", "test".to_string(), None, SystemTime::now()).unwrap();
+        ingest(&state, &client, b"{CHARACTER_NAME}{Thoric}{HEALTH}{1,100}").await;
+        assert_eq!(published(&requests), [(
+            "mud-ingest/commands/Thoric".to_string(),
+            format!("{{COMMAND_ID}}{{{}}}{{COMMAND}}{{look}}", command.id),
+            false,
+        )]);
+
+        // Not a `{key}{value}` update, so nothing changes.
+        ingest(&state, &client, b"95").await;
+        assert_eq!(state.store.get("Thoric").unwrap().data.get("HEALTH"), Some(&json!(1100)));
+        assert!(published(&requests).is_empty());
+    }
+
+    /// The broker side of a connection from the bridge, speaking just enough MQTT 3.1.1 for it.
+    struct Broker {
+        stream: TcpStream,
+        buffer: BytesMut,
+    }
+
+    impl Broker {
+        async fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().await.unwrap();
+            Self { stream, buffer: BytesMut::new() }
+        }
+
+        async fn receive(&mut self) -> Packet {
+            loop {
+                match rumqttc::read(&mut self.buffer, 1 << 20) {
+                    Ok(packet) => return packet,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => {
+                        assert!(self.stream.read_buf(&mut self.buffer).await.unwrap() > 0, "the bridge disconnected");
+                    }
+                    Err(e) => panic!("invalid packet from the bridge: {}", e),
+                }
+            }
+        }
+
+        async fn send(&mut self, write: impl FnOnce(&mut BytesMut) -> Result<usize, rumqttc::Error>) {
+            let mut bytes = BytesMut::new();
+            write(&mut bytes).unwrap();
+            self.stream.write_all(&bytes).await.unwrap();
+        }
+
+        async fn publish(&mut self, topic: &str, payload: &str) {
+            self.send(|bytes| Publish::new(topic, QoS::AtMostOnce, payload).write(bytes)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn updates_from_a_broker_are_ingested_and_rate_limited_per_character() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.mqtt.host = "127.0.0.1".to_string();
+        config.mqtt.port = listener.local_addr().unwrap().port();
+        config.mqtt.ingest_topic = "mud-ingest".to_string();
+        // Two updates at once, the third is throttled and the fourth bans the character.
+        config.rate_limit.rps = 0.001;
+        config.rate_limit.burst_capacity = 2.0;
+        config.rate_limit.violation_threshold = 2;
+        let (state, event_loop) = crate::build_state(&config, MessageFormat::Legacy).unwrap();
+        let state = Arc::new(state);
+        tokio::spawn(super::event_loop(Arc::clone(&state), event_loop.unwrap()));
+
+        let mut broker = Broker::accept(&listener).await;
+        assert!(matches!(broker.receive().await, Packet::Connect(_)));
+        broker.send(|bytes| ConnAck::new(ConnectReturnCode::Success, false).write(bytes)).await;
+        let Packet::Subscribe(subscribe) = broker.receive().await else { panic!("expected a subscription") };
+        assert_eq!(subscribe.filters[0].path, "mud-ingest");
+        broker.send(|bytes| SubAck::new(subscribe.pkid, vec![SubscribeReasonCode::Success(QoS::AtMostOnce)]).write(bytes)).await;
+
+        broker.publish("mud-ingest", "{CHARACTER_NAME}{Zed}{COMMAND_ALLOWLIST}{look}").await;
+        broker.publish("mud-elsewhere", "{CHARACTER_NAME}{Mallory}").await;
+        for health in 1..=4 {
+            broker.publish("mud-ingest", &format!("{{CHARACTER_NAME}}{{Thoric}}{{HEALTH}}{{{}}}", health)).await;
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.store.get("Thoric").is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        // Zed has an allowance of its own, and gets its pending command back on the next update.
+        let zed = state.store.get("Zed").unwrap();
+        let command = state.commands.enqueue("Zed", &zed.data, "look", "test".to_string(), None, SystemTime::now()).unwrap();
+        broker.publish("mud-ingest", "{CHARACTER_NAME}{Zed}").await;
+        let commands = loop {
+            match tokio::time::timeout(Duration::from_secs(5), broker.receive()).await.unwrap() {
+                Packet::Publish(publish) => break publish,
+                _ => continue,
+            }
+        };
+        assert_eq!(commands.topic, "mud-ingest/commands/Zed");
+        assert_eq!(commands.payload, format!("{{COMMAND_ID}}{{{}}}{{COMMAND}}{{look}}", command.id).as_bytes());
+
+        // Updates are stored in the order they arrived, so Thoric's last one was seen before Zed's.
+        assert_eq!(state.store.get("Thoric").unwrap().data.get("HEALTH"), Some(&json!(2)));
+        assert_eq!(state.character_rate_limiter.check(&"Thoric".to_string()), Err(axum::http::StatusCode::FORBIDDEN));
+        assert!(state.store.get("Mallory").is_none());
+    }
+
+    #[test]
+    fn ingest_topics_that_would_loop_are_rejected() {
+        assert_eq!(ingest_topic_problem("mud", ""), None);
+        assert_eq!(ingest_topic_problem("mud", "mud-ingest"), None);
+        assert_eq!(ingest_topic_problem("mud", "clients/mud"), None);
+        assert_eq!(ingest_topic_problem("", "mud/ingest/updates"), None);
+        assert!(ingest_topic_problem("mud", "mud/ingest").is_some());
+        assert!(ingest_topic_problem("mud/", " mud/Thoric/HEALTH ").is_some());
+        assert!(ingest_topic_problem("", "ingest/updates").is_some());
+        assert!(ingest_topic_problem("mud", "ingest/+").is_some());
+        assert!(ingest_topic_problem("mud", "#").is_some());
+    }
+}
@@ -0,0 +1,35 @@
+// --- Numeric Values ---
+// MUD clients send most numbers as strings, so everything that reads a character's values as
+// numbers goes through `as_number`.
+
+use serde_json::Value;
+
+/// A JSON number or numeric string as a finite `f64`; `None` for anything else, including `NaN` and `inf`.
+pub(crate) fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok().filter(|n: &f64| n.is_finite()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn numbers_and_numeric_strings() {
+        assert_eq!(as_number(&json!(42)), Some(42.0));
+        assert_eq!(as_number(&json!(-1.5)), Some(-1.5));
+        assert_eq!(as_number(&json!(" 17 ")), Some(17.0));
+        assert_eq!(as_number(&json!("0.25")), Some(0.25));
+    }
+
+    #[test]
+    fn everything_else_is_not_a_number() {
+        for value in [json!("Thoric"), json!(""), json!("NaN"), json!("inf"), json!(true), json!(null), json!([1]), json!({"a": 1})] {
+            assert_eq!(as_number(&value), None, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+// --- WebAssembly Plugins ---
+// Processors shipped as a single .wasm file, for teams that would rather write their MUD's quirks
+// in a compiled language than as scripts. Every `*.wasm` file in PLUGINS_DIR is loaded in file name
+// order at startup and sees each client update after the scripting hooks. The plugin ABI:
+//
+//   memory                        exported linear memory
+//   alloc(len: i32) -> i32        returns a buffer the server writes the input to
+//   process(ptr: i32, len: i32) -> i64
+//                                 reads the input, returns `ptr << 32 | len` of its output, or 0
+//                                 to keep the update as it is
+//   dealloc(ptr: i32, len: i32)   optional, called with the output buffer once it was read
+//   env.log(ptr: i32, len: i32)   optional import, writes a UTF-8 message to the server log
+//
+// The input is `{"character": name, "data": {...}, "previous": {...} or null}` as JSON. The output
+// is `{"data": {...}, "events": [{"name": ..., "message": ...}]}`, where both fields are optional:
+// `data` replaces the update and every event is sent to viewers as a `plugin` event.
+//
+// Plugins get nothing but `env.log` from the host. Every call is limited to PLUGIN_FUEL units of
+// work and memory to PLUGIN_MAX_MEMORY_MB, so a buggy plugin cannot stall ingest. An instance is
+// kept between calls, so a plugin may hold state in its memory; after a trap it starts over from a
+// fresh instance.
+
+use std::path::Path;
+use std::sync::Mutex as StdMutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info, warn};
+use wasmi::core::TrapCode;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::viewer_events::{ViewerEvent, ViewerEvents};
+use crate::CharacterDataMap;
+
+// Events one call may emit; the rest are dropped.
+const MAX_EVENTS_PER_CALL: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct PluginConfig {
+    pub dir: String, // Empty disables plugins
+    pub fuel: u64,   // Per call
+    pub max_memory_bytes: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PluginError {
+    #[error("failed to read '{path}': {error}")]
+    Io { path: String, error: std::io::Error },
+    #[error("plugin '{plugin}': {error}")]
+    Wasm { plugin: String, error: wasmi::Error },
+    #[error("plugin '{plugin}': missing or mistyped export '{export}'")]
+    Export { plugin: String, export: &'static str },
+}
+
+// Why a single call failed; only ever logged.
+#[derive(Debug, thiserror::Error)]
+enum CallError {
+    #[error("ran out of fuel")]
+    OutOfFuel,
+    #[error("trapped: {0}")]
+    Trap(wasmi::Error),
+    #[error("used a buffer outside its memory")]
+    OutOfBounds,
+    #[error("returned invalid output: {0}")]
+    Output(#[from] serde_json::Error),
+    #[error("could not be restarted: {0}")]
+    Restart(Box<PluginError>),
+}
+
+impl From<wasmi::Error> for CallError {
+    fn from(error: wasmi::Error) -> Self {
+        if error.as_trap_code() == Some(TrapCode::OutOfFuel) { CallError::OutOfFuel } else { CallError::Trap(error) }
+    }
+}
+
+struct Host {
+    plugin: String,
+    limits: StoreLimits,
+}
+
+struct Instance {
+    store: Store<Host>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    process: TypedFunc<(i32, i32), i64>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+}
+
+struct Plugin {
+    name: String, // File name
+    module: Module,
+    instance: StdMutex<Option<Instance>>, // None after a trap, until the next call
+}
+
+#[derive(Serialize)]
+struct Input<'a> {
+    character: &'a str,
+    data: &'a CharacterDataMap,
+    previous: Option<&'a CharacterDataMap>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    #[serde(default)]
+    data: Option<CharacterDataMap>,
+    #[serde(default)]
+    events: Vec<OutputEvent>,
+}
+
+#[derive(Deserialize)]
+struct OutputEvent {
+    name: String,
+    #[serde(default)]
+    message: String,
+}
+
+pub struct Plugins {
+    config: PluginConfig,
+    engine: Engine,
+    linker: Linker<Host>,
+    plugins: Vec<Plugin>,
+    events: ViewerEvents,
+}
+
+impl Plugins {
+    /// Compiles and instantiates every plugin in the directory, or creates an empty set if `config.dir` is empty.
+    pub fn load(config: PluginConfig, events: ViewerEvents) -> Result<Self, PluginError> {
+        let mut wasm_config = Config::default();
+        wasm_config.consume_fuel(true);
+        let engine = Engine::new(&wasm_config);
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap("env", "log", host_log).expect("env.log is only defined once");
+        let mut plugins = Self { config, engine, linker, plugins: Vec::new(), events };
+        if plugins.config.dir.is_empty() {
+            return Ok(plugins);
+        }
+
+        let dir = Path::new(&plugins.config.dir);
+        let io_error = |path: &Path, error| PluginError::Io { path: path.display().to_string(), error };
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(|error| io_error(dir, error))? {
+            let path = entry.map_err(|error| io_error(dir, error))?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "wasm") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let bytes = std::fs::read(&path).map_err(|error| io_error(&path, error))?;
+            let module = Module::new(&plugins.engine, &bytes).map_err(|error| PluginError::Wasm { plugin: name.clone(), error })?;
+            // Instantiating up front reports missing exports and failing start functions at startup.
+            let instance = plugins.instantiate(&name, &module)?;
+            plugins.plugins.push(Plugin { name, module, instance: StdMutex::new(Some(instance)) });
+        }
+        Ok(plugins)
+    }
+
+    pub fn plugin_count(&self) -> usize {
+        self.plugins.len()
+    }
+
+    /// Lets every plugin rewrite a client update. The character name cannot be changed.
+    pub fn process(&self, character: &str, data: &mut CharacterDataMap, previous: Option<&CharacterDataMap>) {
+        for plugin in &self.plugins {
+            let input = match serde_json::to_vec(&Input { character, data, previous }) {
+                Ok(input) => input,
+                Err(e) => {
+                    warn!("Could not pass the update of '{}' to plugins: {}", character, e);
+                    return;
+                }
+            };
+            let output = match self.call(plugin, &input) {
+                Ok(Some(output)) => output,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Plugin '{}' {} processing '{}'; keeping the update.", plugin.name, e, character);
+                    continue;
+                }
+            };
+            if let Some(rewritten) = output.data {
+                *data = rewritten;
+                data.insert("CHARACTER_NAME".to_string(), Value::String(character.to_string()));
+            }
+            if output.events.len() > MAX_EVENTS_PER_CALL {
+                warn!("Plugin '{}' emitted {} events; only the first {} are sent.", plugin.name, output.events.len(), MAX_EVENTS_PER_CALL);
+            }
+            for event in output.events.into_iter().take(MAX_EVENTS_PER_CALL) {
+                debug!("Plugin '{}' event '{}' for '{}': {}", plugin.name, event.name, character, event.message);
+                self.events.publish_all(ViewerEvent::Plugin {
+                    character: character.to_string(),
+                    plugin: plugin.name.clone(),
+                    name: event.name,
+                    message: event.message,
+                });
+            }
+        }
+    }
+
+    /// Runs `process` with a fresh fuel budget. None if the plugin kept the update as it is.
+    fn call(&self, plugin: &Plugin, input: &[u8]) -> Result<Option<Output>, CallError> {
+        let mut slot = plugin.instance.lock().unwrap();
+        if slot.is_none() {
+            *slot = Some(self.instantiate(&plugin.name, &plugin.module).map_err(|e| CallError::Restart(Box::new(e)))?);
+        }
+        let instance = slot.as_mut().expect("instantiated above");
+        let result = run(instance, input, self.config.fuel);
+        if matches!(result, Err(CallError::OutOfFuel | CallError::Trap(_))) {
+            // A trap can leave the plugin's memory half updated.
+            *slot = None;
+        }
+        result
+    }
+
+    fn instantiate(&self, name: &str, module: &Module) -> Result<Instance, PluginError> {
+        let wasm_error = |error| PluginError::Wasm { plugin: name.to_string(), error };
+        let limits = StoreLimitsBuilder::new().memory_size(self.config.max_memory_bytes).instances(1).build();
+        let mut store = Store::new(&self.engine, Host { plugin: name.to_string(), limits });
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(self.config.fuel).expect("fuel metering is enabled");
+        let instance = self.linker.instantiate(&mut store, module).and_then(|pre| pre.start(&mut store)).map_err(wasm_error)?;
+
+        let export = |export| PluginError::Export { plugin: name.to_string(), export };
+        let memory = instance.get_memory(&store, "memory").ok_or_else(|| export("memory"))?;
+        let alloc = instance.get_typed_func(&store, "alloc").map_err(|_| export("alloc"))?;
+        let process = instance.get_typed_func(&store, "process").map_err(|_| export("process"))?;
+        let dealloc = match instance.get_export(&store, "dealloc") {
+            Some(_) => Some(instance.get_typed_func(&store, "dealloc").map_err(|_| export("dealloc"))?),
+            None => None,
+        };
+        Ok(Instance { store, memory, alloc, process, dealloc })
+    }
+}
+
+fn run(instance: &mut Instance, input: &[u8], fuel: u64) -> Result<Option<Output>, CallError> {
+    let Instance { store, memory, alloc, process, dealloc } = instance;
+    store.set_fuel(fuel).expect("fuel metering is enabled");
+    let len = i32::try_from(input.len()).map_err(|_| CallError::OutOfBounds)?;
+    let ptr = alloc.call(&mut *store, len)?;
+    memory.write(&mut *store, ptr as u32 as usize, input).map_err(|_| CallError::OutOfBounds)?;
+
+    let packed = process.call(&mut *store, (ptr, len))? as u64;
+    if packed == 0 {
+        return Ok(None);
+    }
+    let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+    let bytes = memory.data(&*store).get(out_ptr..out_ptr + out_len).ok_or(CallError::OutOfBounds)?.to_vec();
+    if let Some(dealloc) = dealloc {
+        dealloc.call(&mut *store, (out_ptr as i32, out_len as i32))?;
+    }
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+/// `env.log(ptr, len)`: a plugin's log message, ignored if it is not valid UTF-8 in bounds.
+fn host_log(caller: Caller<'_, Host>, ptr: i32, len: i32) {
+    let Some(memory) = caller.get_export("memory").and_then(Extern::into_memory) else { return };
+    let (start, len) = (ptr as u32 as usize, len as u32 as usize);
+    let message = memory.data(&caller).get(start..start.saturating_add(len)).and_then(|bytes| std::str::from_utf8(bytes).ok());
+    if let Some(message) = message {
+        info!("Plugin '{}': {}", caller.data().plugin, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    /// A plugins directory in the temp directory, removed again on drop.
+    struct PluginDir(std::path::PathBuf);
+
+    impl PluginDir {
+        /// Compiles each (file name, WAT source) into the directory.
+        fn new(plugins: &[(&str, String)]) -> Self {
+            let dir = Self(std::env::temp_dir().join(format!("plugins_{}", Uuid::new_v4())));
+            std::fs::create_dir(&dir.0).unwrap();
+            for (name, source) in plugins {
+                std::fs::write(dir.0.join(name), wat::parse_str(source).unwrap()).unwrap();
+            }
+            dir
+        }
+
+        fn load(&self, events: ViewerEvents) -> Plugins {
+            let config = PluginConfig { dir: self.0.to_str().unwrap().to_string(), fuel: 100_000, max_memory_bytes: 1 << 20 };
+            Plugins::load(config, events).unwrap()
+        }
+    }
+
+    impl Drop for PluginDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A plugin whose `process` body is `process`, with the input buffer at 1024 and `data` at 0.
+    fn plugin(data: &str, process: &str) -> String {
+        format!(r#"
+            (module
+              (import "env" "log" (func $log (param i32 i32)))
+              (memory (export "memory") 1)
+              (data (i32.const 0) "{}")
+              (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+              (func (export "process") (param $ptr i32) (param $len i32) (result i64) {}))
+        "#, data.replace('"', "\\\""), process)
+    }
+
+    /// Returns the JSON output from its data segment after logging it.
+    fn rewriting(output: &str) -> String {
+        plugin(output, &format!("(call $log (i32.const 0) (i32.const {len})) (i64.const {len})", len = output.len()))
+    }
+
+    fn echoing() -> String {
+        plugin("", "(i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32)) (i64.extend_i32_u (local.get $len)))")
+    }
+
+    fn looping() -> String {
+        plugin("", "(loop $forever (br $forever)) (i64.const 0)")
+    }
+
+    fn update() -> CharacterDataMap {
+        CharacterDataMap::from([("CHARACTER_NAME".to_string(), json!("Thoric")), ("HEALTH".to_string(), json!(120))])
+    }
+
+    #[test]
+    fn plugins_rewrite_updates_and_emit_events() {
+        let output = r#"{"data": {"CHARACTER_NAME": "Mallory", "HEALTH": 99}, "events": [{"name": "checked", "message": "hello"}]}"#;
+        let dir = PluginDir::new(&[("10_rewrite.wasm", rewriting(output)), ("20_echo.wasm", echoing())]);
+        let events = ViewerEvents::new();
+        let mut receiver = events.subscribe();
+        let plugins = dir.load(events);
+        assert_eq!(plugins.plugin_count(), 2);
+
+        let mut data = update();
+        plugins.process("Thoric", &mut data, None);
+        // The echo plugin hands back the rewritten update; the character name cannot be changed.
+        assert_eq!(data, CharacterDataMap::from([("CHARACTER_NAME".to_string(), json!("Thoric")), ("HEALTH".to_string(), json!(99))]));
+        let event = receiver.try_recv().unwrap();
+        assert!(matches!(&event.event, ViewerEvent::Plugin { character, plugin, name, message }
+            if character == "Thoric" && plugin == "10_rewrite.wasm" && name == "checked" && message == "hello"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn plugins_can_keep_the_update() {
+        let dir = PluginDir::new(&[("keep.wasm", plugin("", "(i64.const 0)"))]);
+        let mut data = update();
+        dir.load(ViewerEvents::new()).process("Thoric", &mut data, None);
+        assert_eq!(data, update());
+    }
+
+    #[test]
+    fn runaway_plugins_run_out_of_fuel_without_changing_the_update() {
+        let dir = PluginDir::new(&[("loop.wasm", looping()), ("rewrite.wasm", rewriting(r#"{"data": {"HEALTH": 1}}"#))]);
+        let plugins = dir.load(ViewerEvents::new());
+        let error = plugins.call(&plugins.plugins[0], b"{}").err().unwrap();
+        assert_eq!(error.to_string(), "ran out of fuel");
+        assert!(plugins.plugins[0].instance.lock().unwrap().is_none());
+
+        // The failed plugin is skipped and later ones still run; the next call starts a fresh instance.
+        for _ in 0..2 {
+            let mut data = update();
+            plugins.process("Thoric", &mut data, None);
+            assert_eq!(data.get("HEALTH"), Some(&json!(1)));
+        }
+    }
+
+    #[test]
+    fn plugins_without_the_abi_are_rejected() {
+        let dir = PluginDir::new(&[("empty.wasm", "(module (memory (export \"memory\") 1))".to_string())]);
+        let config = PluginConfig { dir: dir.0.to_str().unwrap().to_string(), fuel: 1000, max_memory_bytes: 1 << 20 };
+        let error = Plugins::load(config, ViewerEvents::new()).err().unwrap();
+        assert_eq!(error.to_string(), "plugin 'empty.wasm': missing or mistyped export 'alloc'");
+    }
+}
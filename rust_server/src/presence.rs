@@ -0,0 +1,154 @@
+// --- Viewer Presence ---
+// Who is watching: every /ws and /events subscriber with its group, self-reported name
+// (`?name=`, not authenticated), subscriptions and activity. Joins and leaves are announced to
+// the viewer's group as `viewer_joined`/`viewer_left` events, v2 snapshots list the group's
+// current viewers, and GET /api/viewers exposes the same list. Peer addresses and User-Agents
+// stay in the server log.
+
+use std::time::SystemTime;
+
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::protocol::MessageFormat;
+use crate::{system_time_serde, SharedState, SubscriberInfo};
+
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ViewerSummary {
+    pub id: String,
+    pub name: Option<String>,
+    pub group: String,
+    pub transport: &'static str,
+    pub format: MessageFormat,
+    pub characters: Option<Vec<String>>, // None means all characters
+    #[serde(with = "system_time_serde")]
+    pub connected_since: SystemTime,
+    pub idle_seconds: u64,
+}
+
+impl ViewerSummary {
+    pub fn new(id: &Uuid, info: &SubscriberInfo) -> Self {
+        let characters = info.filter.as_ref().map(|names| {
+            let mut names: Vec<String> = names.iter().cloned().collect();
+            names.sort();
+            names
+        });
+        Self {
+            id: id.to_string(),
+            name: info.name.clone(),
+            group: info.group.clone(),
+            transport: info.transport,
+            format: info.format,
+            characters,
+            connected_since: info.connected_at,
+            idle_seconds: info.last_activity.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Normalizes the self-reported `?name=`; blank names are treated as anonymous.
+pub fn viewer_name(requested: Option<&str>) -> Option<String> {
+    requested.map(|name| name.trim().chars().filter(|c| !c.is_control()).take(MAX_NAME_LEN).collect::<String>())
+        .filter(|name| !name.is_empty())
+}
+
+/// The viewers of one group, or of all groups, longest connected first.
+pub fn group_viewers(state: &SharedState, group: Option<&str>) -> Vec<ViewerSummary> {
+    let mut viewers: Vec<ViewerSummary> = state.subscribers.iter()
+        .filter(|entry| group.is_none_or(|group| entry.group == group))
+        .map(|entry| ViewerSummary::new(entry.key(), entry.value()))
+        .collect();
+    viewers.sort_by_key(|v| v.connected_since);
+    viewers
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ViewersQuery {
+    group: Option<String>,
+}
+
+// GET /api/viewers
+pub async fn list_viewers(State(state): State<SharedState>, Query(query): Query<ViewersQuery>) -> impl IntoResponse {
+    Json(group_viewers(&state, query.group.as_deref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::config::Config;
+    use crate::subscriber_queue::SubscriberQueue;
+    use crate::viewer_events::ViewerEvent;
+    use crate::build_state;
+
+    fn subscriber(name: Option<&str>, group: &str, connected_secs_ago: u64) -> SubscriberInfo {
+        SubscriberInfo {
+            transport: "ws",
+            peer_addr: "127.0.0.1:4000".parse().unwrap(),
+            user_agent: "test".to_string(),
+            name: viewer_name(name),
+            group: group.to_string(),
+            filter: Some(HashSet::from(["Thoric".to_string(), "Alice".to_string()])),
+            format: MessageFormat::V2,
+            connected_at: SystemTime::now() - Duration::from_secs(connected_secs_ago),
+            last_activity: Instant::now(),
+            queue: Arc::new(SubscriberQueue::new(4)),
+        }
+    }
+
+    fn state() -> SharedState {
+        let (state, _) = build_state(&Config::default(), MessageFormat::Legacy).unwrap();
+        Arc::new(state)
+    }
+
+    #[test]
+    fn names_are_trimmed_and_blank_names_are_anonymous() {
+        assert_eq!(viewer_name(Some("  Bob ")), Some("Bob".to_string()));
+        assert_eq!(viewer_name(Some("B\u{7}ob")), Some("Bob".to_string()));
+        assert_eq!(viewer_name(Some(&"x".repeat(40))), Some("x".repeat(MAX_NAME_LEN)));
+        assert_eq!(viewer_name(Some("   ")), None);
+        assert_eq!(viewer_name(None), None);
+    }
+
+    #[tokio::test]
+    async fn viewers_are_listed_per_group_longest_connected_first() {
+        let state = state();
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        state.register_subscriber(ids[0], subscriber(Some("Bob"), "raid", 10));
+        state.register_subscriber(ids[1], subscriber(None, "other", 60));
+        state.register_subscriber(ids[2], subscriber(Some("Eve"), "raid", 30));
+
+        let raid = group_viewers(&state, Some("raid"));
+        assert_eq!(raid.iter().map(|v| v.name.as_deref()).collect::<Vec<_>>(), vec![Some("Eve"), Some("Bob")]);
+        assert_eq!(raid[0].characters, Some(vec!["Alice".to_string(), "Thoric".to_string()]));
+        assert_eq!(group_viewers(&state, None).iter().map(|v| v.id.clone()).collect::<Vec<_>>(),
+            vec![ids[1].to_string(), ids[2].to_string(), ids[0].to_string()]);
+    }
+
+    #[tokio::test]
+    async fn joins_and_leaves_are_announced_to_the_group() {
+        let state = state();
+        let mut events = state.viewer_events.subscribe();
+        let id = Uuid::new_v4();
+        state.register_subscriber(id, subscriber(Some("Bob"), "raid", 0));
+        assert!(state.unregister_subscriber(&id).is_some());
+        assert!(state.unregister_subscriber(&id).is_none());
+
+        let joined = events.try_recv().unwrap();
+        assert_eq!(joined.group.as_deref(), Some("raid"));
+        assert!(matches!(&joined.event, ViewerEvent::ViewerJoined { viewer } if viewer.id == id.to_string() && viewer.name.as_deref() == Some("Bob")));
+        assert!(matches!(&events.try_recv().unwrap().event, ViewerEvent::ViewerLeft { id: left } if *left == id.to_string()));
+        assert!(events.try_recv().is_err());
+        assert!(group_viewers(&state, None).is_empty());
+    }
+}
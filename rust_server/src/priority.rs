@@ -0,0 +1,150 @@
+// --- Priority Flush Rules ---
+// Most changes wait for the next broadcast_loop tick. Changes to a priority key, or a numeric
+// drop larger than a configured percentage, wake broadcast_loop immediately instead so that
+// e.g. a health drop mid-combat reaches viewers without raising the global broadcast rate.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::numbers::as_number;
+use crate::CharacterDataMap;
+
+#[derive(Clone, Debug, Default)]
+pub struct PriorityConfig {
+    pub keys: HashSet<String>,
+    // Key -> percentage drop (relative to the previous value) that triggers a flush.
+    pub drop_thresholds: HashMap<String, f64>,
+    pub min_flush_spacing: Duration,
+}
+
+impl PriorityConfig {
+    /// Parses the `keys` ("HEALTH,WAIT_TIME") and `drop_thresholds` ("HEALTH:10,MANA:25") lists.
+    pub fn parse(keys: &str, drop_thresholds: &str, min_flush_spacing: Duration) -> Self {
+        let keys = keys.split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        let drop_thresholds = drop_thresholds.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let parsed = entry.split_once(':')
+                    .and_then(|(key, pct)| Some((key.trim().to_string(), pct.trim().parse::<f64>().ok()?)))
+                    .filter(|(key, pct)| !key.is_empty() && *pct > 0.0);
+                if parsed.is_none() {
+                    warn!("Ignoring invalid PRIORITY_DROP_THRESHOLDS entry '{}'. Expected KEY:PERCENT.", entry);
+                }
+                parsed
+            })
+            .collect();
+
+        Self { keys, drop_thresholds, min_flush_spacing }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty() || !self.drop_thresholds.is_empty()
+    }
+
+    /// How long a priority flush has to wait so that flushes stay `min_flush_spacing` apart.
+    pub fn flush_delay(&self, since_last_flush: Duration) -> Duration {
+        self.min_flush_spacing.saturating_sub(since_last_flush)
+    }
+
+    /// Returns the first key whose change warrants an immediate broadcast, if any.
+    pub fn triggering_key<'a>(&'a self, previous: Option<&CharacterDataMap>, current: &CharacterDataMap) -> Option<&'a str> {
+        // A brand new character is already worth showing right away if it carries any priority key.
+        let Some(previous) = previous else {
+            return self.keys.iter().find(|k| current.contains_key(k.as_str())).map(String::as_str);
+        };
+
+        if let Some(key) = self.keys.iter().find(|k| previous.get(k.as_str()) != current.get(k.as_str())) {
+            return Some(key);
+        }
+
+        self.drop_thresholds.iter()
+            .find(|(key, pct)| {
+                let old = previous.get(key.as_str()).and_then(as_number);
+                let new = current.get(key.as_str()).and_then(as_number);
+                match (old, new) {
+                    (Some(old), Some(new)) if old > 0.0 => (old - new) / old * 100.0 > **pct,
+                    _ => false,
+                }
+            })
+            .map(|(key, _)| key.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn data(values: &[(&str, serde_json::Value)]) -> CharacterDataMap {
+        values.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+    }
+
+    fn health_drop(pct: f64) -> PriorityConfig {
+        PriorityConfig::parse("", &format!("HEALTH:{}", pct), Duration::ZERO)
+    }
+
+    #[test]
+    fn parses_keys_and_skips_invalid_thresholds() {
+        let config = PriorityConfig::parse(" HEALTH, WAIT_TIME ,", "HEALTH:10, MANA:x, :5, MOVES:-3, MANA:25", Duration::ZERO);
+        assert_eq!(config.keys, HashSet::from(["HEALTH".to_string(), "WAIT_TIME".to_string()]));
+        assert_eq!(config.drop_thresholds, HashMap::from([("HEALTH".to_string(), 10.0), ("MANA".to_string(), 25.0)]));
+        assert!(config.is_enabled());
+        assert!(!PriorityConfig::parse("", "", Duration::ZERO).is_enabled());
+    }
+
+    #[test]
+    fn drops_past_the_threshold_trigger() {
+        let config = health_drop(10.0);
+        let previous = data(&[("HEALTH", json!("100"))]);
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("HEALTH", json!("89"))])), Some("HEALTH"));
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("HEALTH", json!(85))])), Some("HEALTH"));
+        // Exactly the threshold, smaller drops and rises don't.
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("HEALTH", json!("90"))])), None);
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("HEALTH", json!("95"))])), None);
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("HEALTH", json!("150"))])), None);
+    }
+
+    #[test]
+    fn drops_need_a_positive_numeric_previous_value() {
+        let config = health_drop(10.0);
+        let current = data(&[("HEALTH", json!("-50"))]);
+        for previous in [json!("0"), json!("-10"), json!("dead"), json!(null)] {
+            assert_eq!(config.triggering_key(Some(&data(&[("HEALTH", previous.clone())])), &current), None, "{}", previous);
+        }
+        assert_eq!(config.triggering_key(Some(&data(&[])), &current), None);
+        assert_eq!(config.triggering_key(Some(&data(&[("HEALTH", json!("100"))])), &data(&[])), None);
+    }
+
+    #[test]
+    fn priority_keys_trigger_on_any_change() {
+        let config = PriorityConfig::parse("WAIT_TIME", "", Duration::ZERO);
+        let previous = data(&[("WAIT_TIME", json!("0")), ("HEALTH", json!("100"))]);
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("WAIT_TIME", json!("2")), ("HEALTH", json!("100"))])), Some("WAIT_TIME"));
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("WAIT_TIME", json!("0")), ("HEALTH", json!("10"))])), None);
+        assert_eq!(config.triggering_key(Some(&previous), &data(&[("HEALTH", json!("100"))])), Some("WAIT_TIME"));
+    }
+
+    #[test]
+    fn new_characters_trigger_only_with_a_priority_key() {
+        let config = PriorityConfig::parse("WAIT_TIME", "HEALTH:10", Duration::ZERO);
+        assert_eq!(config.triggering_key(None, &data(&[("WAIT_TIME", json!("0"))])), Some("WAIT_TIME"));
+        assert_eq!(config.triggering_key(None, &data(&[("HEALTH", json!("1"))])), None);
+    }
+
+    #[test]
+    fn flushes_wait_out_the_minimum_spacing() {
+        let config = PriorityConfig::parse("HEALTH", "", Duration::from_millis(250));
+        assert_eq!(config.flush_delay(Duration::ZERO), Duration::from_millis(250));
+        assert_eq!(config.flush_delay(Duration::from_millis(100)), Duration::from_millis(150));
+        assert_eq!(config.flush_delay(Duration::from_millis(250)), Duration::ZERO);
+        assert_eq!(config.flush_delay(Duration::from_secs(5)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,358 @@
+// --- Wire Protocol ---
+// Two message formats are served to viewers:
+//   * legacy: the original bare snapshot map and `{"updates", "deletions"}` deltas.
+//   * v2: a typed envelope `{"type": "snapshot" | "delta" | "event", "v", "seq", "server_time", ...}`
+//     that also carries per-character metadata (last update, first seen, ingest source, connection state).
+// `seq` is the state store version, so it is monotonic across snapshots and deltas and matches
+// the SSE event id. The default comes from MESSAGE_FORMAT and clients can pick per connection
+// with `?format=v2` or `?format=legacy`.
+//
+// Independently of the format, /ws clients can ask for a binary encoding through
+// Sec-WebSocket-Protocol: "msgpack" (MessagePack) or "cbor". JSON text frames remain the default.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::viewer_events::{GroupSnapshot, ViewerEvent};
+use crate::{system_time_serde, CharacterDataMap, CharacterMeta, DeltaUpdate};
+
+pub const PROTOCOL_VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Legacy,
+    V2,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "legacy" | "v1" => Ok(Self::Legacy),
+            "v2" => Ok(Self::V2),
+            other => Err(format!("unknown message format '{}'", other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Subprotocols offered on /ws, in order of server preference.
+    pub const SUBPROTOCOLS: [&'static str; 3] = ["msgpack", "cbor", "json"];
+
+    pub fn from_subprotocol(protocol: Option<&str>) -> Self {
+        match protocol {
+            Some("msgpack") => Self::MessagePack,
+            Some("cbor") => Self::Cbor,
+            _ => Self::Json,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error("JSON encoding failed: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("MessagePack encoding failed: {0}")]
+    MessagePack(#[from] rmp_serde::encode::Error),
+    #[error("CBOR encoding failed: {0}")]
+    Cbor(String),
+}
+
+/// An encoded message. Clones share the buffer, which /ws writes out as is for every subscriber.
+#[derive(Clone, Debug)]
+pub enum Frame {
+    Text(Arc<str>),
+    Binary(Arc<[u8]>),
+}
+
+impl Frame {
+    pub fn len(&self) -> usize {
+        match self {
+            Frame::Text(text) => text.len(),
+            Frame::Binary(bytes) => bytes.len(),
+        }
+    }
+
+    /// The text of a JSON frame; `None` for binary encodings.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Frame::Text(text) => Some(text),
+            Frame::Binary(_) => None,
+        }
+    }
+}
+
+pub fn encode<T: Serialize + ?Sized>(value: &T, encoding: Encoding) -> Result<Frame, EncodeError> {
+    match encoding {
+        Encoding::Json => Ok(Frame::Text(serde_json::to_string(value)?.into())),
+        Encoding::MessagePack => Ok(Frame::Binary(rmp_serde::to_vec_named(value)?.into())),
+        Encoding::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(value, &mut bytes).map_err(|e| EncodeError::Cbor(e.to_string()))?;
+            Ok(Frame::Binary(bytes.into()))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SnapshotEnvelope<'a, C, M> {
+    #[serde(rename = "type")]
+    msg_type: &'static str,
+    v: u32,
+    seq: u64,
+    #[serde(with = "system_time_serde")]
+    server_time: SystemTime,
+    characters: C,
+    meta: M,
+    #[serde(flatten)]
+    group: Option<&'a GroupSnapshot>, // Chat, pinned notes and viewers of the viewer's group
+}
+
+#[derive(Serialize)]
+struct DeltaEnvelope<'a> {
+    #[serde(rename = "type")]
+    msg_type: &'static str,
+    v: u32,
+    seq: u64,
+    #[serde(with = "system_time_serde")]
+    server_time: SystemTime,
+    updates: &'a HashMap<String, CharacterDataMap>,
+    deletions: &'a [String],
+    meta: &'a HashMap<String, CharacterMeta>,
+}
+
+#[derive(Serialize)]
+struct EventEnvelope<'a> {
+    #[serde(rename = "type")]
+    msg_type: &'static str,
+    v: u32,
+    seq: u64,
+    #[serde(with = "system_time_serde")]
+    server_time: SystemTime,
+    #[serde(flatten)]
+    event: &'a ViewerEvent,
+}
+
+/// Encodes a snapshot from serializable views of the character and metadata maps. Group data
+/// only exists in the v2 format.
+pub fn encode_snapshot<C: Serialize, M: Serialize>(
+    version: u64,
+    characters: C,
+    meta: M,
+    group: Option<&GroupSnapshot>,
+    format: MessageFormat,
+    encoding: Encoding,
+) -> Result<Frame, EncodeError> {
+    match format {
+        MessageFormat::Legacy => encode(&characters, encoding),
+        MessageFormat::V2 => encode(&SnapshotEnvelope {
+            msg_type: "snapshot",
+            v: PROTOCOL_VERSION,
+            seq: version,
+            server_time: SystemTime::now(),
+            characters,
+            meta,
+            group,
+        }, encoding),
+    }
+}
+
+/// The JSON character map and metadata map of a snapshot, each encoded once and then wrapped per
+/// format. Only used for JSON; binary encodings are built straight from the store.
+#[derive(Debug)]
+pub struct EncodedSnapshot {
+    pub version: u64,
+    characters: Box<RawValue>,
+    meta: Box<RawValue>,
+}
+
+impl EncodedSnapshot {
+    pub fn new(version: u64, characters_json: String, meta_json: String) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            version,
+            characters: RawValue::from_string(characters_json)?,
+            meta: RawValue::from_string(meta_json)?,
+        })
+    }
+
+    pub fn frame(&self, format: MessageFormat, group: Option<&GroupSnapshot>) -> Result<Frame, EncodeError> {
+        match format {
+            MessageFormat::Legacy => Ok(Frame::Text(Arc::from(self.characters.get()))),
+            MessageFormat::V2 => encode_snapshot(self.version, &self.characters, &self.meta, group, format, Encoding::Json),
+        }
+    }
+}
+
+pub fn encode_delta(
+    delta: &DeltaUpdate,
+    seq: u64,
+    server_time: SystemTime,
+    format: MessageFormat,
+    encoding: Encoding,
+) -> Result<Frame, EncodeError> {
+    match format {
+        MessageFormat::Legacy => encode(delta, encoding),
+        MessageFormat::V2 => encode(&DeltaEnvelope {
+            msg_type: "delta",
+            v: PROTOCOL_VERSION,
+            seq,
+            server_time,
+            updates: &delta.updates,
+            deletions: &delta.deletions,
+            meta: &delta.meta,
+        }, encoding),
+    }
+}
+
+/// A v2 `event` message. `seq` is the store version at the time of the event.
+pub fn encode_event(seq: u64, event: &ViewerEvent, encoding: Encoding) -> Result<Frame, EncodeError> {
+    encode(&EventEnvelope { msg_type: "event", v: PROTOCOL_VERSION, seq, server_time: SystemTime::now(), event }, encoding)
+}
+
+/// Frames for one broadcast delta, encoded at most once per format and encoding no matter how many subscribers ask.
+#[derive(Debug, Default)]
+pub struct FrameCache {
+    frames: StdMutex<HashMap<(MessageFormat, Encoding), Frame>>,
+}
+
+impl FrameCache {
+    pub fn get_or_encode(
+        &self,
+        format: MessageFormat,
+        encoding: Encoding,
+        encode: impl FnOnce() -> Result<Frame, EncodeError>,
+    ) -> Result<Frame, EncodeError> {
+        let mut frames = self.frames.lock().unwrap();
+        if let Some(frame) = frames.get(&(format, encoding)) {
+            return Ok(frame.clone());
+        }
+        let frame = encode()?;
+        frames.insert((format, encoding), frame.clone());
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_json::{json, Value};
+
+    use crate::viewer_events::GroupSnapshot;
+
+    fn server_time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn delta() -> DeltaUpdate {
+        let data = CharacterDataMap::from([("HEALTH".to_string(), json!("812"))]);
+        let meta = CharacterMeta { last_update: server_time(), first_seen: UNIX_EPOCH, source: "http".to_string(), connected: true };
+        DeltaUpdate {
+            updates: HashMap::from([("Thoric".to_string(), data)]),
+            deletions: vec!["Alice".to_string()],
+            meta: HashMap::from([("Thoric".to_string(), meta)]),
+        }
+    }
+
+    fn json_of(frame: &Frame) -> Value {
+        serde_json::from_str(frame.as_text().expect("JSON frames are text")).unwrap()
+    }
+
+    fn binary(frame: &Frame) -> &[u8] {
+        match frame {
+            Frame::Binary(bytes) => bytes,
+            Frame::Text(_) => panic!("binary encodings produce binary frames"),
+        }
+    }
+
+    #[test]
+    fn formats_parse_case_insensitively() {
+        assert_eq!(" V2 ".parse::<MessageFormat>(), Ok(MessageFormat::V2));
+        assert_eq!("v1".parse::<MessageFormat>(), Ok(MessageFormat::Legacy));
+        assert_eq!("Legacy".parse::<MessageFormat>(), Ok(MessageFormat::Legacy));
+        assert_eq!("v3".parse::<MessageFormat>(), Err("unknown message format 'v3'".to_string()));
+        assert_eq!(Encoding::from_subprotocol(Some("cbor")), Encoding::Cbor);
+        assert_eq!(Encoding::from_subprotocol(Some("msgpack")), Encoding::MessagePack);
+        assert_eq!(Encoding::from_subprotocol(Some("json")), Encoding::Json);
+        assert_eq!(Encoding::from_subprotocol(None), Encoding::Json);
+    }
+
+    #[test]
+    fn legacy_deltas_are_exactly_updates_and_deletions() {
+        let frame = encode_delta(&delta(), 7, server_time(), MessageFormat::Legacy, Encoding::Json).unwrap();
+        assert_eq!(json_of(&frame), json!({"updates": {"Thoric": {"HEALTH": "812"}}, "deletions": ["Alice"]}));
+    }
+
+    #[test]
+    fn v2_deltas_carry_the_envelope_and_metadata() {
+        let frame = encode_delta(&delta(), 7, server_time(), MessageFormat::V2, Encoding::Json).unwrap();
+        assert_eq!(json_of(&frame), json!({
+            "type": "delta",
+            "v": PROTOCOL_VERSION,
+            "seq": 7,
+            "server_time": 1_700_000_000,
+            "updates": {"Thoric": {"HEALTH": "812"}},
+            "deletions": ["Alice"],
+            "meta": {"Thoric": {"last_update": 1_700_000_000, "first_seen": 0, "source": "http", "connected": true}},
+        }));
+    }
+
+    #[test]
+    fn snapshots_wrap_the_shared_encoding_per_format() {
+        let snapshot = EncodedSnapshot::new(3, r#"{"Thoric":{"HEALTH":"812"}}"#.to_string(), r#"{"Thoric":{}}"#.to_string()).unwrap();
+        let legacy = snapshot.frame(MessageFormat::Legacy, None).unwrap();
+        assert_eq!(legacy.as_text(), Some(r#"{"Thoric":{"HEALTH":"812"}}"#));
+
+        let group = GroupSnapshot { annotations: None, viewers: Vec::new(), alerts: Vec::new() };
+        let v2 = json_of(&snapshot.frame(MessageFormat::V2, Some(&group)).unwrap());
+        assert_eq!(v2["type"], "snapshot");
+        assert_eq!(v2["seq"], 3);
+        assert_eq!(v2["characters"], json!({"Thoric": {"HEALTH": "812"}}));
+        assert_eq!(v2["meta"], json!({"Thoric": {}}));
+        assert_eq!(v2["viewers"], json!([]));
+        assert!(v2.get("annotations").is_none());
+    }
+
+    #[test]
+    fn binary_encodings_round_trip_to_the_json_message() {
+        for format in [MessageFormat::Legacy, MessageFormat::V2] {
+            let expected = json_of(&encode_delta(&delta(), 7, server_time(), format, Encoding::Json).unwrap());
+
+            let msgpack = encode_delta(&delta(), 7, server_time(), format, Encoding::MessagePack).unwrap();
+            assert_eq!(rmp_serde::from_slice::<Value>(binary(&msgpack)).unwrap(), expected, "{:?} msgpack", format);
+
+            let cbor = encode_delta(&delta(), 7, server_time(), format, Encoding::Cbor).unwrap();
+            assert_eq!(ciborium::from_reader::<Value, _>(binary(&cbor)).unwrap(), expected, "{:?} cbor", format);
+        }
+    }
+
+    #[test]
+    fn frame_cache_encodes_once_per_format_and_encoding() {
+        let cache = FrameCache::default();
+        let mut encodes = 0;
+        for (format, encoding) in [(MessageFormat::V2, Encoding::Json), (MessageFormat::V2, Encoding::Json), (MessageFormat::V2, Encoding::Cbor)] {
+            cache.get_or_encode(format, encoding, || {
+                encodes += 1;
+                encode_delta(&delta(), 7, server_time(), format, encoding)
+            }).unwrap();
+        }
+        assert_eq!(encodes, 2);
+    }
+}
@@ -0,0 +1,469 @@
+// --- Scripting Hooks ---
+// Rhai scripts for MUD-specific quirks that computed keys cannot express, e.g. parsing a prompt
+// string into separate keys or renaming keys a client script sends under another name. Every
+// `*.rhai` file in SCRIPTS_DIR is loaded in file name order and may define any of these functions:
+//
+//   fn on_update(name, data, previous) // Return a map to replace `data`, or () to keep it
+//   fn on_prune(name)
+//   fn on_broadcast(updates, deletions)
+//
+// on_update runs on every client update before derived metrics and computed keys, so their inputs
+// can come from a script; each script gets the previous script's output. `previous` is the stored
+// data or () for a new character. The other hooks are notifications and their results are ignored;
+// on_broadcast runs once the delta has been sent.
+// Each script has a persistent `this` map for state that has to survive between calls.
+//
+// Scripts are sandboxed: no file, module or eval access, and every call is limited in operations,
+// wall-clock time and string/array/map size. A call that fails or hits a limit is logged and its
+// result dropped. The directory is compiled at startup, where errors are fatal, and recompiled
+// whenever a script is added, removed or changed; a broken edit is logged and the previous scripts
+// stay active.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+use serde_json::Value;
+use tracing::{debug, error, info, warn};
+
+use crate::CharacterDataMap;
+
+// Hooks and the number of parameters each must take.
+const HOOKS: [(&str, usize); 3] = [("on_update", 3), ("on_prune", 1), ("on_broadcast", 2)];
+
+// Operations between two wall-clock checks.
+const CLOCK_CHECK_OPERATIONS: u64 = 1024;
+
+thread_local! {
+    // Deadline of the call running on this thread.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+#[derive(Clone, Debug)]
+pub struct ScriptConfig {
+    pub dir: String, // Empty disables scripting
+    pub timeout: Duration,
+    pub max_operations: u64,
+    pub max_string_size: usize,
+    pub max_collection_size: usize, // Per array or map
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScriptError {
+    #[error("failed to read '{path}': {error}")]
+    Io { path: String, error: std::io::Error },
+    #[error("script '{script}': {error}")]
+    Compile { script: String, error: rhai::ParseError },
+    #[error("script '{script}': {hook} must take {expected} parameter(s)")]
+    Signature { script: String, hook: &'static str, expected: usize },
+}
+
+struct Script {
+    name: String, // File name
+    ast: AST,
+    state: StdMutex<Dynamic>, // `this`
+}
+
+impl Script {
+    fn defines(&self, hook: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == hook)
+    }
+}
+
+// File names and modification times the current scripts were compiled from.
+type Fingerprint = Vec<(PathBuf, SystemTime)>;
+
+pub struct Scripts {
+    config: ScriptConfig,
+    engine: Engine,
+    scripts: RwLock<Arc<Vec<Arc<Script>>>>,
+    fingerprint: RwLock<Option<Fingerprint>>,
+}
+
+impl Scripts {
+    /// Compiles every script in the directory, or creates an empty set if scripting is disabled.
+    pub fn load(config: ScriptConfig) -> Result<Self, ScriptError> {
+        let scripts = Self {
+            engine: sandboxed_engine(&config),
+            config,
+            scripts: RwLock::new(Arc::new(Vec::new())),
+            fingerprint: RwLock::new(None),
+        };
+        if !scripts.config.dir.is_empty() {
+            scripts.reload()?;
+        }
+        Ok(scripts)
+    }
+
+    pub fn script_count(&self) -> usize {
+        self.scripts.read().unwrap().len()
+    }
+
+    /// Whether any script defines `hook`.
+    pub fn handles(&self, hook: &str) -> bool {
+        self.scripts.read().unwrap().iter().any(|script| script.defines(hook))
+    }
+
+    /// Lets every on_update hook rewrite a client update. The character name cannot be changed.
+    pub fn on_update(&self, name: &str, data: &mut CharacterDataMap, previous: Option<&CharacterDataMap>) {
+        let scripts = self.with_hook("on_update");
+        if scripts.is_empty() {
+            return;
+        }
+        let previous = previous.map_or(Ok(Dynamic::UNIT), rhai::serde::to_dynamic);
+        let (Ok(mut current), Ok(previous)) = (rhai::serde::to_dynamic(&*data), previous) else {
+            warn!("Could not pass the update of '{}' to scripts.", name);
+            return;
+        };
+        for script in scripts {
+            let Some(result) = self.call(&script, "on_update", (name.to_string(), current.clone(), previous.clone())) else {
+                continue;
+            };
+            if result.is_unit() {
+                continue;
+            }
+            if !result.is_map() {
+                warn!("Script '{}' on_update returned a {} instead of a map or (); ignoring it.", script.name, result.type_name());
+            } else if !self.within_limits(&result) {
+                warn!("Script '{}' on_update returned data over the string or collection size limit; ignoring it.", script.name);
+            } else {
+                current = result;
+            }
+        }
+        match rhai::serde::from_dynamic::<CharacterDataMap>(&current) {
+            Ok(rewritten) => {
+                *data = rewritten;
+                data.insert("CHARACTER_NAME".to_string(), Value::String(name.to_string()));
+            }
+            Err(e) => warn!("Scripts produced invalid data for '{}', keeping the update as sent: {}", name, e),
+        }
+    }
+
+    pub fn on_prune(&self, name: &str) {
+        for script in self.with_hook("on_prune") {
+            self.call(&script, "on_prune", (name.to_string(),));
+        }
+    }
+
+    /// Announces a broadcast delta: a map of character name to data, and the deleted names.
+    pub fn on_broadcast(&self, updates: &HashMap<String, CharacterDataMap>, deletions: &[String]) {
+        let scripts = self.with_hook("on_broadcast");
+        if scripts.is_empty() {
+            return;
+        }
+        let (Ok(updates), Ok(deletions)) = (rhai::serde::to_dynamic(updates), rhai::serde::to_dynamic(deletions)) else {
+            warn!("Could not pass the broadcast delta to scripts.");
+            return;
+        };
+        for script in scripts {
+            self.call(&script, "on_broadcast", (updates.clone(), deletions.clone()));
+        }
+    }
+
+    /// Recompiles the directory if a script was added, removed or changed. Returns whether it did.
+    pub fn reload_if_changed(&self) -> Result<bool, ScriptError> {
+        if self.config.dir.is_empty() {
+            return Ok(false);
+        }
+        let fingerprint = self.fingerprint()?;
+        if self.fingerprint.read().unwrap().as_ref() == Some(&fingerprint) {
+            return Ok(false);
+        }
+        self.reload().map(|()| true)
+    }
+
+    fn reload(&self) -> Result<(), ScriptError> {
+        // Taken before reading, so an edit during the read is picked up by the next check.
+        let fingerprint = self.fingerprint()?;
+        let compiled = self.compile(&fingerprint);
+        // A failed reload is not retried until the directory changes again.
+        *self.fingerprint.write().unwrap() = Some(fingerprint);
+        let compiled = compiled?;
+
+        // A script keeps its state across reloads, so an edit does not reset e.g. running counters.
+        let mut scripts = self.scripts.write().unwrap();
+        for script in &compiled {
+            if let Some(old) = scripts.iter().find(|old| old.name == script.name) {
+                *script.state.lock().unwrap() = old.state.lock().unwrap().clone();
+            }
+        }
+        *scripts = Arc::new(compiled);
+        Ok(())
+    }
+
+    fn compile(&self, fingerprint: &Fingerprint) -> Result<Vec<Arc<Script>>, ScriptError> {
+        fingerprint.iter().map(|(path, _)| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let text = std::fs::read_to_string(path).map_err(|error| io_error(path, error))?;
+            let mut ast = self.engine.compile(&text).map_err(|error| ScriptError::Compile { script: name.clone(), error })?;
+            ast.set_source(name.as_str());
+            for (hook, expected) in HOOKS {
+                if ast.iter_functions().any(|f| f.name == hook && f.params.len() != expected) {
+                    return Err(ScriptError::Signature { script: name, hook, expected });
+                }
+            }
+            Ok(Arc::new(Script { name, ast, state: StdMutex::new(Dynamic::from_map(Map::new())) }))
+        }).collect()
+    }
+
+    /// The `*.rhai` files in the directory, sorted by name.
+    fn fingerprint(&self) -> Result<Fingerprint, ScriptError> {
+        let dir = Path::new(&self.config.dir);
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(|error| io_error(dir, error))? {
+            let path = entry.map_err(|error| io_error(dir, error))?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "rhai") {
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).map_err(|error| io_error(&path, error))?;
+                files.push((path, modified));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Whether every string, array and map in `value` is within the configured sizes. Rhai does not
+    /// check every way a script can grow a map, e.g. assigning new keys by index.
+    fn within_limits(&self, value: &Dynamic) -> bool {
+        if let Some(text) = value.read_lock::<rhai::ImmutableString>() {
+            return text.len() <= self.config.max_string_size;
+        }
+        if let Some(array) = value.read_lock::<rhai::Array>() {
+            return array.len() <= self.config.max_collection_size && array.iter().all(|item| self.within_limits(item));
+        }
+        if let Some(map) = value.read_lock::<Map>() {
+            return map.len() <= self.config.max_collection_size && map.values().all(|item| self.within_limits(item));
+        }
+        true
+    }
+
+    fn with_hook(&self, hook: &str) -> Vec<Arc<Script>> {
+        let scripts = Arc::clone(&self.scripts.read().unwrap());
+        scripts.iter().filter(|script| script.defines(hook)).cloned().collect()
+    }
+
+    /// Calls a hook with the script's `this` bound, within the time limit. None if it failed.
+    fn call(&self, script: &Script, hook: &str, args: impl FuncArgs) -> Option<Dynamic> {
+        let mut state = script.state.lock().unwrap();
+        let options = CallFnOptions::new().eval_ast(false).rewind_scope(true).bind_this_ptr(&mut state);
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.config.timeout)));
+        let result = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &script.ast, hook, args);
+        DEADLINE.with(|deadline| deadline.set(None));
+        match result {
+            Ok(value) => Some(value),
+            Err(e) if matches!(*e, EvalAltResult::ErrorTerminated(..)) => {
+                warn!("Script '{}' {} exceeded the time limit of {:?}.", script.name, hook, self.config.timeout);
+                None
+            }
+            Err(e) => {
+                warn!("Script '{}' {} failed: {}", script.name, hook, e);
+                None
+            }
+        }
+    }
+}
+
+fn sandboxed_engine(config: &ScriptConfig) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.set_max_operations(config.max_operations);
+    engine.set_max_string_size(config.max_string_size);
+    engine.set_max_array_size(config.max_collection_size);
+    engine.set_max_map_size(config.max_collection_size);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.on_progress(|operations| {
+        if operations % CLOCK_CHECK_OPERATIONS != 0 {
+            return None;
+        }
+        let expired = DEADLINE.with(|deadline| deadline.get().is_some_and(|d| Instant::now() >= d));
+        expired.then(|| Dynamic::from("time limit exceeded"))
+    });
+    engine.on_print(|text| info!("Script: {}", text));
+    engine.on_debug(|text, source, position| debug!("Script '{}' at {}: {}", source.unwrap_or("?"), position, text));
+    engine
+}
+
+fn io_error(path: &Path, error: std::io::Error) -> ScriptError {
+    ScriptError::Io { path: path.display().to_string(), error }
+}
+
+/// Checks the scripts directory for changes every `interval`.
+pub async fn reload_loop(scripts: Arc<Scripts>, interval: Duration) {
+    if scripts.config.dir.is_empty() {
+        return;
+    }
+    info!("Watching '{}' for script changes every {:?}", scripts.config.dir, interval);
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match scripts.reload_if_changed() {
+            Ok(true) => info!("Reloaded {} script(s) from '{}'.", scripts.script_count(), scripts.config.dir),
+            Ok(false) => {}
+            Err(e) => error!("Failed to reload scripts, keeping the previous ones: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    /// A scripts directory in the temp directory, removed again on drop.
+    struct ScriptDir(PathBuf);
+
+    impl ScriptDir {
+        fn new(scripts: &[(&str, &str)]) -> Self {
+            let dir = Self(std::env::temp_dir().join(format!("scripts_{}", Uuid::new_v4())));
+            std::fs::create_dir(&dir.0).unwrap();
+            for (name, source) in scripts {
+                dir.write(name, source, SystemTime::now());
+            }
+            dir
+        }
+
+        /// Writes a script with an explicit modification time so the change is always seen.
+        fn write(&self, name: &str, source: &str, modified: SystemTime) {
+            let path = self.0.join(name);
+            std::fs::write(&path, source).unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        }
+
+        fn config(&self) -> ScriptConfig {
+            ScriptConfig {
+                dir: self.0.to_str().unwrap().to_string(),
+                timeout: Duration::from_secs(5),
+                max_operations: 100_000,
+                max_string_size: 100,
+                max_collection_size: 10,
+            }
+        }
+
+        fn load(&self) -> Scripts {
+            Scripts::load(self.config()).unwrap()
+        }
+    }
+
+    impl Drop for ScriptDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn update(scripts: &Scripts) -> CharacterDataMap {
+        let mut data = CharacterDataMap::from([("CHARACTER_NAME".to_string(), json!("Thoric")), ("HP".to_string(), json!(120))]);
+        scripts.on_update("Thoric", &mut data, None);
+        data
+    }
+
+    fn unchanged() -> CharacterDataMap {
+        CharacterDataMap::from([("CHARACTER_NAME".to_string(), json!("Thoric")), ("HP".to_string(), json!(120))])
+    }
+
+    const RENAME_HP: &str = "fn on_update(name, data, previous) { data.HEALTH = data.HP; data.remove(\"HP\"); data }";
+
+    #[test]
+    fn scripts_rewrite_updates_in_file_order() {
+        let dir = ScriptDir::new(&[
+            ("20_double.rhai", "fn on_update(name, data, previous) { data.HEALTH *= 2; data.CHARACTER_NAME = \"Mallory\"; data }"),
+            ("10_rename.rhai", RENAME_HP),
+            ("30_keep.rhai", "fn on_update(name, data, previous) { () }"),
+            ("notes.txt", "not a script"),
+        ]);
+        let scripts = dir.load();
+        assert_eq!(scripts.script_count(), 3);
+        assert!(scripts.handles("on_update") && !scripts.handles("on_prune"));
+        // The character name cannot be changed.
+        assert_eq!(update(&scripts), CharacterDataMap::from([("CHARACTER_NAME".to_string(), json!("Thoric")), ("HEALTH".to_string(), json!(240))]));
+    }
+
+    #[test]
+    fn failing_scripts_leave_the_update_unchanged() {
+        let dir = ScriptDir::new(&[
+            ("10_throw.rhai", "fn on_update(name, data, previous) { data.HP = 1; throw \"broken\"; }"),
+            ("20_number.rhai", "fn on_update(name, data, previous) { 5 }"),
+        ]);
+        assert_eq!(update(&dir.load()), unchanged());
+    }
+
+    #[test]
+    fn later_scripts_still_run_after_a_failure() {
+        let dir = ScriptDir::new(&[("10_throw.rhai", "fn on_update(name, data, previous) { throw \"broken\"; }"), ("20_rename.rhai", RENAME_HP)]);
+        assert_eq!(update(&dir.load()).get("HEALTH"), Some(&json!(120)));
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped_by_the_operation_limit() {
+        let dir = ScriptDir::new(&[("loop.rhai", "fn on_update(name, data, previous) { loop { data.HP += 1; } }")]);
+        assert_eq!(update(&dir.load()), unchanged());
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped_by_the_time_limit() {
+        let dir = ScriptDir::new(&[("loop.rhai", "fn on_update(name, data, previous) { loop { data.HP += 1; } }")]);
+        let config = ScriptConfig { timeout: Duration::from_millis(50), max_operations: 0, ..dir.config() };
+        let scripts = Scripts::load(config).unwrap();
+        let started = Instant::now();
+        assert_eq!(update(&scripts), unchanged());
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+    }
+
+    #[test]
+    fn strings_and_collections_are_capped() {
+        let dir = ScriptDir::new(&[
+            ("10_string.rhai", "fn on_update(name, data, previous) { let s = \"\"; for i in 0..50 { s += \"abc\"; } data.TEXT = s; data }"),
+            ("20_array.rhai", "fn on_update(name, data, previous) { let a = []; for i in 0..20 { a.push(i); } data.LIST = a; data }"),
+            ("30_map.rhai", "fn on_update(name, data, previous) { for i in 0..20 { data[`K${i}`] = i; } data }"),
+        ]);
+        assert_eq!(update(&dir.load()), unchanged());
+    }
+
+    #[test]
+    fn this_keeps_state_between_calls_and_across_reloads() {
+        let count = "fn on_update(name, data, previous) { this.calls = (this.calls ?? 0) + STEP; data.CALLS = this.calls; data }";
+        let dir = ScriptDir::new(&[("count.rhai", &count.replace("STEP", "1"))]);
+        let scripts = dir.load();
+        update(&scripts);
+        assert_eq!(update(&scripts).get("CALLS"), Some(&json!(2)));
+
+        dir.write("count.rhai", &count.replace("STEP", "10"), SystemTime::now() + Duration::from_secs(10));
+        assert!(scripts.reload_if_changed().unwrap());
+        assert_eq!(update(&scripts).get("CALLS"), Some(&json!(12)));
+    }
+
+    #[test]
+    fn reload_picks_up_changes_and_keeps_scripts_after_a_broken_edit() {
+        let dir = ScriptDir::new(&[("10_rename.rhai", RENAME_HP)]);
+        let scripts = dir.load();
+        assert!(!scripts.reload_if_changed().unwrap());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        dir.write("20_double.rhai", "fn on_update(name, data, previous) { data.HEALTH *= 2; data }", later);
+        assert!(scripts.reload_if_changed().unwrap());
+        assert_eq!(scripts.script_count(), 2);
+        assert_eq!(update(&scripts).get("HEALTH"), Some(&json!(240)));
+
+        dir.write("20_double.rhai", "fn on_update(name, data, previous) { data.HEALTH *= ; }", later + Duration::from_secs(10));
+        assert!(matches!(scripts.reload_if_changed(), Err(ScriptError::Compile { script, .. }) if script == "20_double.rhai"));
+        assert!(!scripts.reload_if_changed().unwrap());
+        assert_eq!(update(&scripts).get("HEALTH"), Some(&json!(240)));
+
+        std::fs::remove_file(dir.0.join("20_double.rhai")).unwrap();
+        assert!(scripts.reload_if_changed().unwrap());
+        assert_eq!(update(&scripts).get("HEALTH"), Some(&json!(120)));
+    }
+
+    #[test]
+    fn hooks_with_the_wrong_parameters_are_rejected() {
+        let dir = ScriptDir::new(&[("prune.rhai", "fn on_prune() { }")]);
+        let error = Scripts::load(dir.config()).err().unwrap();
+        assert!(error.to_string().ends_with("on_prune must take 1 parameter(s)"), "{}", error);
+    }
+}
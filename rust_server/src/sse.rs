@@ -0,0 +1,275 @@
+// --- Server-Sent Events Handler ---
+// Read-only alternative to /ws for consumers that cannot do WebSockets (OBS browser sources behind
+// proxies, simple dashboards, curl). Registers a subscriber queue exactly like handle_socket, so
+// broadcast_loop feeds both transports. Event ids are store versions: a client reconnecting with
+// Last-Event-ID gets an exact catch-up delta when the server can still compute one, and a fresh
+// snapshot otherwise.
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::connect_info::ConnectInfo;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures::stream::{self, Stream, StreamExt};
+use tokio::time::Instant;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::{annotations, presence};
+use crate::subscriber_queue::SubscriberQueue;
+use crate::protocol::{encode_delta, EncodeError, Encoding, Frame, MessageFormat};
+use crate::{SharedState, StreamQuery, SubscriberInfo};
+
+/// Removes the subscriber from shared state when the SSE stream is dropped (client went away).
+struct SubscriberGuard {
+    state: SharedState,
+    id: Uuid,
+    peer_addr: SocketAddr,
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.state.unregister_subscriber(&self.id);
+        info!("SSE client {} disconnected. Remaining subscribers: {}", self.peer_addr, self.state.subscribers.len());
+    }
+}
+
+pub async fn sse_handler(
+    State(state): State<SharedState>,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let filter = query.character_filter();
+    let format = state.format_for(&query);
+    let user_agent = headers.get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("Unknown")
+        .to_string();
+    let last_event_id = headers.get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    let subscriber_id = Uuid::new_v4();
+    info!("SSE client connected: {} (User-Agent: {}, Subscriber: {}, Characters: {:?}, Last-Event-ID: {:?})",
+        peer_addr, user_agent, subscriber_id, filter, last_event_id);
+
+    // Register the queue before reading state so no delta broadcast in between is missed.
+    let queue = Arc::new(SubscriberQueue::new(state.ws_config.queue_capacity));
+    state.register_subscriber(subscriber_id, SubscriberInfo {
+        transport: "sse",
+        peer_addr,
+        user_agent,
+        name: presence::viewer_name(query.name.as_deref()),
+        group: annotations::group_name(query.group.as_deref()),
+        filter: filter.clone(),
+        format,
+        connected_at: SystemTime::now(),
+        last_activity: Instant::now(),
+        queue: Arc::clone(&queue),
+    });
+    let guard = SubscriberGuard { state: Arc::clone(&state), id: subscriber_id, peer_addr };
+
+    let (start_version, first_event) = match initial_event(&state, last_event_id, filter.as_ref(), format) {
+        Ok(initial) => initial,
+        Err(e) => {
+            error!("Failed to serialize initial SSE state for {}: {}", peer_addr, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let deltas = delta_stream(queue, filter, format, guard, start_version);
+    let events = stream::iter(first_event).chain(deltas).map(Ok::<Event, Infallible>);
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// A catch-up delta if the client can resume from `last_event_id`, otherwise a full snapshot,
+/// together with the store version the client is brought up to.
+fn initial_event(
+    state: &SharedState,
+    last_event_id: Option<u64>,
+    filter: Option<&HashSet<String>>,
+    format: MessageFormat,
+) -> Result<(u64, Option<Event>), EncodeError> {
+    if let Some(since) = last_event_id.filter(|v| state.store.can_resume_from(*v)) {
+        let changes = state.store.changes_since(since);
+        let delta = match filter {
+            Some(names) => changes.delta.filtered(names),
+            None => changes.delta,
+        };
+        debug!("SSE resume from version {} to {}: {} updates, {} deletions.", since, changes.version, delta.updates.len(), delta.deletions.len());
+        if delta.is_empty() {
+            return Ok((changes.version, None));
+        }
+        let frame = encode_delta(&delta, changes.version, SystemTime::now(), format, Encoding::Json)?;
+        let event = Event::default().event("delta").id(changes.version.to_string()).data(text(&frame));
+        return Ok((changes.version, Some(event)));
+    }
+
+    let (version, frame) = state.encoded_snapshot(filter, None, format, Encoding::Json)?;
+    Ok((version, Some(Event::default().event("snapshot").id(version.to_string()).data(text(&frame)))))
+}
+
+fn delta_stream(
+    queue: Arc<SubscriberQueue>,
+    filter: Option<HashSet<String>>,
+    format: MessageFormat,
+    guard: SubscriberGuard,
+    start_version: u64,
+) -> impl Stream<Item = Event> {
+    stream::unfold((queue, filter, guard), move |(queue, filter, guard)| async move {
+        loop {
+            let queued = queue.pop().await;
+            // Already covered by the initial snapshot or catch-up delta.
+            if queued.version <= start_version {
+                continue;
+            }
+            if let Some(frame) = queued.frame(format, Encoding::Json, filter.as_ref()) {
+                let event = Event::default().event("delta").id(queued.version.to_string()).data(text(&frame));
+                return Some((event, (queue, filter, guard)));
+            }
+        }
+    })
+}
+
+/// SSE is text-only, so frames are always requested as JSON.
+fn text(frame: &Frame) -> &str {
+    frame.as_text().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+    use futures::stream::BoxStream;
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::subscriber_queue::QueuedDelta;
+    use crate::{build_state, CharacterDataMap, DeltaUpdate, StreamQuery};
+
+    type Body = BoxStream<'static, Result<Bytes, axum::Error>>;
+
+    fn state_with(names: &[&str]) -> SharedState {
+        let (state, _) = build_state(&Config::default(), MessageFormat::Legacy).unwrap();
+        for name in names {
+            let data = CharacterDataMap::from([("CHARACTER_NAME".to_string(), json!(name))]);
+            state.store.upsert(name, data, SystemTime::now(), "test".to_string());
+        }
+        Arc::new(state)
+    }
+
+    async fn connect(state: &SharedState, last_event_id: Option<&str>) -> Body {
+        let mut headers = HeaderMap::new();
+        if let Some(id) = last_event_id {
+            headers.insert("last-event-id", id.parse().unwrap());
+        }
+        let response = sse_handler(
+            State(Arc::clone(state)),
+            ConnectInfo("127.0.0.1:4000".parse().unwrap()),
+            Query(StreamQuery::default()),
+            headers,
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.into_body().into_data_stream().boxed()
+    }
+
+    /// The next event as (event type, id, data), skipping keep-alive comments.
+    async fn next_event(body: &mut Body) -> (String, String, String) {
+        let mut text = String::new();
+        while !text.contains("\n\n") {
+            let chunk = tokio::time::timeout(Duration::from_secs(2), body.next()).await
+                .expect("no SSE event within 2s")
+                .expect("SSE stream ended")
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let field = |name: &str| text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+            .unwrap_or_default()
+            .to_string();
+        (field("event"), field("id"), field("data"))
+    }
+
+    fn queued(version: u64, name: &str) -> QueuedDelta {
+        let data = CharacterDataMap::from([("CHARACTER_NAME".to_string(), json!(name))]);
+        QueuedDelta::new(version, DeltaUpdate { updates: HashMap::from([(name.to_string(), data)]), deletions: Vec::new(), meta: HashMap::new() })
+    }
+
+    fn subscriber_queue(state: &SharedState) -> Arc<SubscriberQueue> {
+        Arc::clone(&state.subscribers.iter().next().expect("SSE client not registered").queue)
+    }
+
+    #[tokio::test]
+    async fn fresh_clients_get_a_snapshot() {
+        let state = state_with(&["Alice", "Bob"]);
+        let mut body = connect(&state, None).await;
+        let (event, id, data) = next_event(&mut body).await;
+        assert_eq!((event.as_str(), id.as_str()), ("snapshot", "2"));
+        assert!(data.contains("Alice") && data.contains("Bob"), "{}", data);
+    }
+
+    #[tokio::test]
+    async fn last_event_id_resumes_with_a_catch_up_delta() {
+        let state = state_with(&["Alice", "Bob"]);
+        state.store.prune(SystemTime::now() + Duration::from_secs(60), Duration::from_secs(30));
+        state.store.upsert("Carol", CharacterDataMap::new(), SystemTime::now(), "test".to_string());
+
+        // Alice (1) and Bob (2) were pruned at 3 and 4, Carol arrived at 5.
+        let mut body = connect(&state, Some("2")).await;
+        let (event, id, data) = next_event(&mut body).await;
+        assert_eq!((event.as_str(), id.as_str()), ("delta", "5"));
+        assert!(data.contains("Carol") && data.contains("Alice") && data.contains("Bob"), "{}", data);
+    }
+
+    #[tokio::test]
+    async fn unresumable_ids_fall_back_to_a_snapshot() {
+        let state = state_with(&["Alice", "Bob"]);
+        let later = SystemTime::now() + Duration::from_secs(60);
+        state.store.prune(later, Duration::from_secs(30));
+        // The tombstones are gone, so nothing before version 4 can be caught up exactly.
+        state.store.compact_tombstones(4, later + Duration::from_secs(60), Duration::from_secs(1));
+        state.store.upsert("Carol", CharacterDataMap::new(), SystemTime::now(), "test".to_string());
+
+        for last_event_id in ["2", "99", "not-a-version"] {
+            let mut body = connect(&state, Some(last_event_id)).await;
+            let (event, id, data) = next_event(&mut body).await;
+            assert_eq!((event.as_str(), id.as_str()), ("snapshot", "5"), "Last-Event-ID {}", last_event_id);
+            assert!(data.contains("Carol") && !data.contains("Alice"), "{}", data);
+        }
+    }
+
+    #[tokio::test]
+    async fn deltas_covered_by_the_initial_event_are_skipped() {
+        let state = state_with(&["Alice", "Bob"]);
+        let mut body = connect(&state, None).await;
+        assert_eq!(next_event(&mut body).await.1, "2");
+
+        // broadcast_loop may still deliver deltas up to the snapshot version after registration.
+        let queue = subscriber_queue(&state);
+        queue.push(queued(1, "Alice"));
+        queue.push(queued(2, "Bob"));
+        queue.push(queued(3, "Carol"));
+        let (event, id, data) = next_event(&mut body).await;
+        assert_eq!((event.as_str(), id.as_str()), ("delta", "3"));
+        assert!(data.contains("Carol") && !data.contains("Bob"), "{}", data);
+    }
+
+    #[tokio::test]
+    async fn resuming_without_changes_waits_for_the_next_delta() {
+        let state = state_with(&["Alice"]);
+        let mut body = connect(&state, Some("1")).await;
+        subscriber_queue(&state).push(queued(2, "Bob"));
+        let (event, id, _) = next_event(&mut body).await;
+        assert_eq!((event.as_str(), id.as_str()), ("delta", "2"));
+    }
+}
@@ -0,0 +1,372 @@
+// --- Versioned Character State Store ---
+// Single owner of all character state. Every mutation bumps a global version counter and stamps
+// the touched character (or its tombstone, for deletions) with it. broadcast_loop then asks for
+// "everything changed since version N" instead of draining separate pending-update and
+// pending-deletion sets. All access goes through one RwLock that is never held across an .await,
+// so there is no lock ordering to get wrong and a prune can never interleave with an update to
+// the same character.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::viewer_events::GroupSnapshot;
+use crate::protocol::{self, EncodeError, EncodedSnapshot, Encoding, Frame, MessageFormat};
+use crate::{CharacterDataMap, CharacterInfo, DeltaUpdate};
+
+#[derive(Debug)]
+struct VersionedCharacter {
+    info: CharacterInfo,
+    version: u64,
+}
+
+#[derive(Debug, Default)]
+struct StoreInner {
+    version: u64,
+    characters: HashMap<String, VersionedCharacter>,
+    // Deleted names with the version and time they were deleted at. Kept for a retention window
+    // after broadcast so reconnecting consumers can still resume with an exact delta.
+    tombstones: HashMap<String, (u64, SystemTime)>,
+    // Highest tombstone version ever compacted away. Resuming from an older version would miss deletions.
+    resume_horizon: u64,
+}
+
+impl StoreInner {
+    fn next_version(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
+}
+
+/// Changes between two store versions.
+#[derive(Debug)]
+pub struct Changes {
+    pub delta: DeltaUpdate,
+    pub version: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct StateStore {
+    inner: RwLock<StoreInner>,
+}
+
+impl StateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.inner.read().unwrap().version
+    }
+
+    pub fn get(&self, name: &str) -> Option<CharacterInfo> {
+        self.inner.read().unwrap().characters.get(name).map(|c| c.info.clone())
+    }
+
+    /// Runs `f` on a character's current info without cloning it. `f` runs under the store's read
+    /// lock, which blocks every update, so it must be short and must not block; take a copy with
+    /// `get` for anything else.
+    pub fn inspect<R>(&self, name: &str, f: impl FnOnce(Option<&CharacterInfo>) -> R) -> R {
+        f(self.inner.read().unwrap().characters.get(name).map(|c| &c.info))
+    }
+
+    /// Name, `CONNECTED == "YES"` and last update time of every character, sorted by name.
+    pub fn summaries(&self) -> Vec<(String, bool, SystemTime)> {
+        let inner = self.inner.read().unwrap();
+        let mut summaries: Vec<_> = inner.characters.iter()
+            .map(|(name, c)| (name.clone(), c.info.is_connected(), c.info.timestamp))
+            .collect();
+        summaries.sort_by(|a, b| a.0.cmp(&b.0));
+        summaries
+    }
+
+    /// Stores the latest data for a character. Returns the data it replaced, or `None` if the character is new.
+    pub fn upsert(&self, name: &str, data: CharacterDataMap, timestamp: SystemTime, source: String) -> Option<CharacterDataMap> {
+        let mut inner = self.inner.write().unwrap();
+        let version = inner.next_version();
+        if inner.tombstones.remove(name).is_some() {
+            debug!("'{}' was pending deletion, removed from deletion list.", name);
+        }
+        let first_seen = inner.characters.get(name).map_or(timestamp, |c| c.info.first_seen);
+        let previous = inner.characters.insert(name.to_string(), VersionedCharacter {
+            info: CharacterInfo { data, timestamp, first_seen, source },
+            version,
+        });
+        previous.map(|c| c.info.data)
+    }
+
+    /// Replaces one key of a known character on the server's behalf. Unlike `upsert`, this does not
+    /// count as an update from the character, so its timestamp and timeouts are unaffected.
+    pub fn set_key(&self, name: &str, key: &str, value: Value) {
+        let mut inner = self.inner.write().unwrap();
+        // Without a change, a new version would only make broadcast_loop send an empty delta.
+        if !inner.characters.contains_key(name) {
+            return;
+        }
+        let version = inner.next_version();
+        if let Some(character) = inner.characters.get_mut(name) {
+            character.info.data.insert(key.to_string(), value);
+            character.version = version;
+        }
+    }
+
+    /// Marks every connected character not updated within `timeout` as `CONNECTED: NO`.
+    pub fn mark_disconnected(&self, now: SystemTime, timeout: Duration) -> Vec<String> {
+        let mut inner = self.inner.write().unwrap();
+        let timed_out: Vec<String> = inner.characters.iter()
+            .filter(|(_, c)| c.info.is_connected())
+            .filter(|(name, c)| match now.duration_since(c.info.timestamp) {
+                Ok(age) => age > timeout,
+                Err(_) => { warn!("System clock went backwards? Char '{}' timestamp in future during broadcast check.", name); false }
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in &timed_out {
+            let version = inner.next_version();
+            if let Some(character) = inner.characters.get_mut(name) {
+                character.info.data.insert("CONNECTED".to_string(), Value::String("NO".to_string()));
+                // Update timestamp for this change as well
+                character.info.timestamp = now;
+                character.version = version;
+            }
+        }
+        timed_out
+    }
+
+    /// Removes every character not updated within `timeout`, leaving a tombstone for the next broadcast.
+    pub fn prune(&self, now: SystemTime, timeout: Duration) -> Vec<String> {
+        let mut inner = self.inner.write().unwrap();
+        let expired: Vec<String> = inner.characters.iter()
+            .filter(|(name, c)| match now.duration_since(c.info.timestamp) {
+                Ok(age) => age > timeout,
+                Err(_) => { warn!("System clock went backwards? Char '{}' timestamp in future.", name); false }
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in &expired {
+            let version = inner.next_version();
+            inner.characters.remove(name);
+            inner.tombstones.insert(name.clone(), (version, now));
+        }
+        expired
+    }
+
+    /// Everything that changed after `since`, as a delta carrying the full current data of each changed character.
+    pub fn changes_since(&self, since: u64) -> Changes {
+        let inner = self.inner.read().unwrap();
+        let mut updates = HashMap::new();
+        let mut meta = HashMap::new();
+        for (name, character) in inner.characters.iter().filter(|(_, c)| c.version > since) {
+            updates.insert(name.clone(), character.info.data.clone());
+            meta.insert(name.clone(), character.info.meta());
+        }
+        let deletions = inner.tombstones.iter()
+            .filter(|(_, (version, _))| *version > since)
+            .map(|(name, _)| name.clone())
+            .collect();
+        Changes { delta: DeltaUpdate { updates, deletions, meta }, version: inner.version }
+    }
+
+    /// Whether `changes_since(version)` is still exact, i.e. no deletion after `version` has been compacted away.
+    pub fn can_resume_from(&self, version: u64) -> bool {
+        let inner = self.inner.read().unwrap();
+        version >= inner.resume_horizon && version <= inner.version
+    }
+
+    /// Drops tombstones that have already been broadcast (`version <= upto`) and are older than `retention`.
+    pub fn compact_tombstones(&self, upto: u64, now: SystemTime, retention: Duration) {
+        let mut inner = self.inner.write().unwrap();
+        let mut horizon = inner.resume_horizon;
+        inner.tombstones.retain(|_, (version, deleted_at)| {
+            let expired = now.duration_since(*deleted_at).is_ok_and(|age| age > retention);
+            if *version <= upto && expired {
+                horizon = horizon.max(*version);
+                false
+            } else {
+                true
+            }
+        });
+        inner.resume_horizon = horizon;
+    }
+
+    /// Encodes characters (name -> data) and their metadata under a single read lock, together with
+    /// the version they reflect. With a filter, only the named characters are included.
+    pub fn snapshot(&self, filter: Option<&HashSet<String>>) -> Result<EncodedSnapshot, serde_json::Error> {
+        let inner = self.inner.read().unwrap();
+        let characters = serde_json::to_string(&SnapshotView { characters: &inner.characters, filter, meta: false })?;
+        let meta = serde_json::to_string(&SnapshotView { characters: &inner.characters, filter, meta: true })?;
+        EncodedSnapshot::new(inner.version, characters, meta)
+    }
+
+    /// Encodes a complete snapshot message in any format and encoding under a single read lock.
+    pub fn encode_snapshot(
+        &self,
+        filter: Option<&HashSet<String>>,
+        group: Option<&GroupSnapshot>,
+        format: MessageFormat,
+        encoding: Encoding,
+    ) -> Result<(u64, Frame), EncodeError> {
+        let inner = self.inner.read().unwrap();
+        let characters = SnapshotView { characters: &inner.characters, filter, meta: false };
+        let meta = SnapshotView { characters: &inner.characters, filter, meta: true };
+        let frame = protocol::encode_snapshot(inner.version, characters, meta, group, format, encoding)?;
+        Ok((inner.version, frame))
+    }
+}
+
+/// Serializes the stored characters as name -> data (or name -> metadata) without cloning them first.
+struct SnapshotView<'a> {
+    characters: &'a HashMap<String, VersionedCharacter>,
+    filter: Option<&'a HashSet<String>>,
+    meta: bool,
+}
+
+impl Serialize for SnapshotView<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(None)?;
+        for (name, character) in self.characters {
+            if self.filter.is_none_or(|filter| filter.contains(name)) {
+                if self.meta {
+                    map.serialize_entry(name, &character.info.meta())?;
+                } else {
+                    map.serialize_entry(name, &character.info.data)?;
+                }
+            }
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    fn data(health: u32) -> CharacterDataMap {
+        CharacterDataMap::from([("HEALTH".to_string(), Value::String(health.to_string()))])
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn interleaved_upserts_and_prunes_stay_consistent() {
+        const ROUNDS: u32 = 2000;
+        let store = Arc::new(StateStore::new());
+
+        let upserts = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                for i in 0..ROUNDS {
+                    store.upsert("Alice", data(i), at(1), "test".to_string());
+                }
+            })
+        };
+        // Everything is older than a zero timeout, so every prune removes Alice if she is there.
+        let prunes = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || (0..ROUNDS).map(|_| store.prune(SystemTime::now(), Duration::ZERO).len() as u64).sum::<u64>())
+        };
+        let broadcasts = {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                let mut last = 0;
+                for _ in 0..ROUNDS {
+                    let changes = store.changes_since(last);
+                    assert!(changes.version >= last);
+                    let deleted = changes.delta.deletions.iter().any(|name| name == "Alice");
+                    assert!(!(deleted && changes.delta.updates.contains_key("Alice")), "Alice both updated and deleted in one delta");
+                    last = changes.version;
+                }
+            })
+        };
+
+        upserts.join().unwrap();
+        let pruned = prunes.join().unwrap();
+        broadcasts.join().unwrap();
+
+        // Every upsert and every removal got its own version, and nothing else did.
+        assert_eq!(store.version(), ROUNDS as u64 + pruned);
+        let changes = store.changes_since(0);
+        let present = store.get("Alice").is_some();
+        assert_eq!(changes.delta.updates.contains_key("Alice"), present);
+        assert_eq!(changes.delta.deletions.contains(&"Alice".to_string()), !present);
+    }
+
+    #[test]
+    fn changes_since_reports_tombstones() {
+        let store = StateStore::new();
+        store.upsert("Alice", data(1), at(100), "test".to_string());
+        store.upsert("Bob", data(2), at(1000), "test".to_string());
+        let before_prune = store.version();
+
+        assert_eq!(store.prune(at(1000), Duration::from_secs(60)), vec!["Alice".to_string()]);
+
+        let changes = store.changes_since(before_prune);
+        assert_eq!(changes.delta.deletions, vec!["Alice".to_string()]);
+        assert!(changes.delta.updates.is_empty());
+        assert_eq!(changes.version, before_prune + 1);
+
+        let changes = store.changes_since(0);
+        assert_eq!(changes.delta.deletions, vec!["Alice".to_string()]);
+        assert_eq!(changes.delta.updates.keys().collect::<Vec<_>>(), vec!["Bob"]);
+
+        // Coming back clears the tombstone.
+        store.upsert("Alice", data(3), at(1001), "test".to_string());
+        let changes = store.changes_since(before_prune);
+        assert!(changes.delta.deletions.is_empty());
+        assert!(changes.delta.updates.contains_key("Alice"));
+    }
+
+    #[test]
+    fn compaction_moves_the_resume_horizon() {
+        let store = StateStore::new();
+        store.upsert("Alice", data(1), at(100), "test".to_string());
+        store.upsert("Bob", data(2), at(1000), "test".to_string());
+        store.prune(at(1000), Duration::from_secs(60));
+        let version = store.version();
+        assert!(store.can_resume_from(1));
+
+        // Within the retention window the tombstone stays.
+        store.compact_tombstones(version, at(1030), Duration::from_secs(60));
+        assert!(store.can_resume_from(1));
+        assert_eq!(store.changes_since(1).delta.deletions, vec!["Alice".to_string()]);
+
+        // Not yet broadcast, so it stays regardless of age.
+        store.compact_tombstones(version - 1, at(5000), Duration::from_secs(60));
+        assert!(store.can_resume_from(1));
+
+        store.compact_tombstones(version, at(5000), Duration::from_secs(60));
+        assert!(!store.can_resume_from(1));
+        assert!(!store.can_resume_from(version - 1));
+        assert!(store.can_resume_from(version));
+        assert!(!store.can_resume_from(version + 1));
+        assert!(store.changes_since(0).delta.deletions.is_empty());
+    }
+
+    #[test]
+    fn set_key_on_unknown_character_keeps_the_version() {
+        let store = StateStore::new();
+        store.set_key("Nobody", "AFFECTS", Value::String(String::new()));
+        assert_eq!(store.version(), 0);
+
+        store.upsert("Alice", data(1), at(100), "test".to_string());
+        store.set_key("Alice", "AFFECTS", Value::String("{haste}{3}".to_string()));
+        assert_eq!(store.version(), 2);
+        assert_eq!(store.get("Alice").unwrap().timestamp, at(100));
+    }
+}
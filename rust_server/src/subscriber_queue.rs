@@ -0,0 +1,205 @@
+// --- Per-Subscriber Delta Queues ---
+// Every WebSocket subscriber gets its own bounded queue instead of sharing a broadcast channel.
+// Fast subscribers receive the shared pre-encoded frames from broadcast_loop unchanged. When a
+// subscriber falls behind and its queue is full, everything queued is merged into one delta that
+// carries the latest data per character, so a slow viewer still converges to the current state
+// and never misses a deletion.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::SystemTime;
+
+use tokio::sync::Notify;
+use tracing::{debug, error};
+
+use crate::metrics;
+use crate::protocol::{encode_delta, Encoding, Frame, FrameCache, MessageFormat};
+use crate::{CharacterDataMap, CharacterMeta, DeltaUpdate};
+
+#[derive(Clone, Debug)]
+pub struct QueuedDelta {
+    pub version: u64, // Store version the delta brings a subscriber up to; doubles as `seq` and the SSE event id
+    pub server_time: SystemTime,
+    pub delta: Arc<DeltaUpdate>,
+    frames: Arc<FrameCache>,
+}
+
+impl QueuedDelta {
+    pub fn new(version: u64, delta: DeltaUpdate) -> Self {
+        Self {
+            version,
+            server_time: SystemTime::now(),
+            delta: Arc::new(delta),
+            frames: Arc::new(FrameCache::default()),
+        }
+    }
+
+    /// The frame to send to a subscriber. Shared frames are encoded once per format and encoding; subscribers
+    /// filtering characters get their own encoding, or `None` if nothing in the delta concerns them.
+    pub fn frame(&self, format: MessageFormat, encoding: Encoding, filter: Option<&HashSet<String>>) -> Option<Frame> {
+        let encoded = match filter {
+            None => self.frames.get_or_encode(format, encoding, || {
+                encode_delta(&self.delta, self.version, self.server_time, format, encoding)
+            }),
+            Some(names) => {
+                let delta = self.delta.filtered(names);
+                if delta.is_empty() {
+                    return None;
+                }
+                encode_delta(&delta, self.version, self.server_time, format, encoding)
+            }
+        };
+        match encoded {
+            Ok(frame) => Some(frame),
+            Err(e) => { error!("Failed to serialize delta update: {}", e); None }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SubscriberQueue {
+    frames: StdMutex<VecDeque<QueuedDelta>>,
+    notify: Notify,
+    capacity: usize,
+    coalesced_total: AtomicU64,
+}
+
+impl SubscriberQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: StdMutex::new(VecDeque::with_capacity(capacity)),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+            coalesced_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of deltas waiting to be sent.
+    pub fn depth(&self) -> usize {
+        self.frames.lock().unwrap().len()
+    }
+
+    /// Total number of queued deltas that were merged away because the subscriber was slow.
+    pub fn coalesced_total(&self) -> u64 {
+        self.coalesced_total.load(Ordering::Relaxed)
+    }
+
+    pub fn push(&self, item: QueuedDelta) {
+        {
+            let mut frames = self.frames.lock().unwrap();
+            if frames.len() >= self.capacity {
+                let merged_count = frames.len() + 1;
+                let pending = frames.drain(..).chain(std::iter::once(item));
+                let merged = coalesce(pending);
+                debug!(
+                    "Subscriber queue full ({}). Coalesced {} deltas into one ({} updates, {} deletions).",
+                    self.capacity, merged_count, merged.delta.updates.len(), merged.delta.deletions.len()
+                );
+                frames.push_back(merged);
+                self.coalesced_total.fetch_add(merged_count as u64 - 1, Ordering::Relaxed);
+                metrics::DELTAS_COALESCED.inc_by(merged_count as u64 - 1);
+                metrics::LAG_EVENTS.with_label_values(&["queue_coalesced"]).inc();
+            } else {
+                frames.push_back(item);
+            }
+        }
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next delta. Cancel-safe, so it can be used directly in `tokio::select!`.
+    pub async fn pop(&self) -> QueuedDelta {
+        loop {
+            if let Some(item) = self.frames.lock().unwrap().pop_front() {
+                return item;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Replays deltas in order so that the result reflects the final state of every character they touch.
+fn coalesce(items: impl Iterator<Item = QueuedDelta>) -> QueuedDelta {
+    let mut updates: HashMap<String, CharacterDataMap> = HashMap::new();
+    let mut meta: HashMap<String, CharacterMeta> = HashMap::new();
+    let mut deletions: HashSet<String> = HashSet::new();
+    let mut version = 0;
+    for item in items {
+        version = version.max(item.version);
+        for (name, data) in &item.delta.updates {
+            deletions.remove(name);
+            updates.insert(name.clone(), data.clone());
+        }
+        for (name, character_meta) in &item.delta.meta {
+            meta.insert(name.clone(), character_meta.clone());
+        }
+        for name in &item.delta.deletions {
+            updates.remove(name);
+            meta.remove(name);
+            deletions.insert(name.clone());
+        }
+    }
+    QueuedDelta::new(version, DeltaUpdate { updates, deletions: deletions.into_iter().collect(), meta })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn character(name: &str) -> CharacterDataMap {
+        let mut data = CharacterDataMap::new();
+        data.insert("CHARACTER_NAME".to_string(), json!(name));
+        data.insert("HEALTH".to_string(), json!("812"));
+        data.insert("HEALTH_MAX".to_string(), json!("1024"));
+        data.insert("OPPONENT_NAME".to_string(), json!("a cave troll"));
+        data.insert("AFFECTS".to_string(), json!("{sanctuary}{24}{haste}{12}{bless}{6}{armor}{30}{stone skin}{18}".repeat(4)));
+        for i in 0..20 {
+            data.insert(format!("KEY_{}", i), json!(i.to_string()));
+        }
+        data
+    }
+
+    fn delta(characters: usize) -> DeltaUpdate {
+        let updates = (0..characters).map(|i| (format!("Char{}", i), character(&format!("Char{}", i)))).collect();
+        DeltaUpdate { updates, deletions: vec!["Gone".to_string()], meta: HashMap::new() }
+    }
+
+    #[test]
+    fn subscribers_share_one_encoded_buffer() {
+        let queued = QueuedDelta::new(7, delta(3));
+        let first = queued.frame(MessageFormat::Legacy, Encoding::Json, None).unwrap();
+        let second = queued.clone().frame(MessageFormat::Legacy, Encoding::Json, None).unwrap();
+        match (first, second) {
+            (Frame::Text(a), Frame::Text(b)) => assert!(Arc::ptr_eq(&a, &b)),
+            other => panic!("expected text frames, got {:?}", other),
+        }
+
+        let binary = queued.frame(MessageFormat::V2, Encoding::MessagePack, None).unwrap();
+        match (binary, queued.frame(MessageFormat::V2, Encoding::MessagePack, None).unwrap()) {
+            (Frame::Binary(a), Frame::Binary(b)) => assert!(Arc::ptr_eq(&a, &b)),
+            other => panic!("expected binary frames, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn full_queue_coalesces_without_losing_deletions() {
+        let queue = SubscriberQueue::new(2);
+        let update = |version, name: &str| QueuedDelta::new(version, DeltaUpdate {
+            updates: HashMap::from([(name.to_string(), character(name))]),
+            deletions: Vec::new(),
+            meta: HashMap::new(),
+        });
+        queue.push(update(1, "Alice"));
+        queue.push(QueuedDelta::new(2, DeltaUpdate { updates: HashMap::new(), deletions: vec!["Alice".to_string()], meta: HashMap::new() }));
+        queue.push(update(3, "Bob"));
+
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(queue.coalesced_total(), 2);
+        let merged = queue.frames.lock().unwrap().pop_front().unwrap();
+        assert_eq!(merged.version, 3);
+        assert_eq!(merged.delta.deletions, vec!["Alice".to_string()]);
+        assert!(merged.delta.updates.contains_key("Bob") && !merged.delta.updates.contains_key("Alice"));
+    }
+}
@@ -0,0 +1,97 @@
+// --- Viewer Events ---
+// Events for viewers that are not character data: chat, pinned notes, presence, alerts, fights
+// and expiring affects. Chat, notes and presence are scoped to a viewer group; the rest go to
+// every group. They fan out through one broadcast
+// channel; every /ws connection subscribes and forwards the events meant for it as v2 `event`
+// messages.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::alerts::AlertRecord;
+use crate::annotations::{ChatMessage, GroupAnnotations, PinnedNote};
+use crate::fights::FightRecord;
+use crate::presence::ViewerSummary;
+
+const CHANNEL_CAPACITY: usize = 256;
+
+/// What a group's viewers see in their v2 snapshot, next to the characters.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GroupSnapshot {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<GroupAnnotations>, // Chat and pinned notes
+    pub viewers: Vec<ViewerSummary>,
+    pub alerts: Vec<AlertRecord>, // Active alerts for the viewer's characters
+}
+
+/// Payload of a v2 `event` message, tagged by its `event` field.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ViewerEvent {
+    Chat { message: ChatMessage },
+    NotePinned { note: PinnedNote },
+    NoteUnpinned { character: String, id: u64 },
+    ViewerJoined { viewer: ViewerSummary },
+    ViewerLeft { id: String },
+    Alert { alert: AlertRecord },
+    AlertCleared { id: u64, rule: String, character: String },
+    FightEnded { fight: FightRecord },
+    AffectExpiring { character: String, affect: String, remaining_seconds: u64 },
+    AffectExpired { character: String, affect: String },
+    Plugin { character: String, plugin: String, name: String, message: String },
+    Error { message: String }, // Only ever sent to the viewer whose request failed
+}
+
+#[derive(Clone, Debug)]
+pub struct GroupEvent {
+    pub group: Option<String>, // None for events every group receives
+    pub event: ViewerEvent,
+}
+
+impl GroupEvent {
+    /// Whether a viewer in `group` subscribed to `filter` (None for all characters) should get this event.
+    pub fn is_for(&self, group: &str, filter: Option<&HashSet<String>>) -> bool {
+        let character = match &self.event {
+            ViewerEvent::Alert { alert } => Some(&alert.character),
+            ViewerEvent::AlertCleared { character, .. } => Some(character),
+            ViewerEvent::FightEnded { fight } => Some(&fight.character),
+            ViewerEvent::AffectExpiring { character, .. } | ViewerEvent::AffectExpired { character, .. } => Some(character),
+            ViewerEvent::Plugin { character, .. } => Some(character),
+            _ => None,
+        };
+        self.group.as_deref().is_none_or(|g| g == group)
+            && character.is_none_or(|name| filter.is_none_or(|names| names.contains(name)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ViewerEvents {
+    sender: broadcast::Sender<Arc<GroupEvent>>,
+}
+
+impl ViewerEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<GroupEvent>> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, group: &str, event: ViewerEvent) {
+        self.send(GroupEvent { group: Some(group.to_string()), event });
+    }
+
+    pub fn publish_all(&self, event: ViewerEvent) {
+        self.send(GroupEvent { group: None, event });
+    }
+
+    fn send(&self, event: GroupEvent) {
+        // Sending only fails when no viewer is connected, in which case nobody needs the event.
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
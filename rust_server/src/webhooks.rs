@@ -0,0 +1,412 @@
+// --- Outbound Webhooks ---
+// POSTs a JSON payload to local services (a chat bot, a home automation box) when something
+// happens server-side. Targets come from a TOML file (WEBHOOKS_FILE):
+//
+//   [[webhook]]
+//   url = "http://127.0.0.1:9000/mud"
+//   events = ["alert_fired", "character_disconnected"]   # optional, every event if absent
+//   secret = "change-me"                                 # optional HMAC-SHA256 signing key
+//
+// Each target has its own queue and worker, so a slow or failing target never delays the others
+// and receives its events in order. Failed deliveries are retried with exponential backoff on
+// network errors, 5xx, 408 and 429; anything that still fails is appended to the dead-letter file.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::alerts::AlertRecord;
+use crate::system_time_serde;
+
+pub const EVENT_NAMES: [&str; 7] = [
+    "character_connected",
+    "character_disconnected",
+    "character_pruned",
+    "alert_fired",
+    "alert_cleared",
+    "ip_banned",
+    "character_banned",
+];
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub file: String,
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub timeout: Duration,
+    pub queue_capacity: usize, // Undelivered events buffered per target
+    pub dead_letter_file: String, // Empty only logs failed deliveries
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhooksFile {
+    #[serde(default, rename = "webhook")]
+    webhooks: Vec<TargetDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetDefinition {
+    url: String,
+    #[serde(default)]
+    events: Vec<String>,
+    secret: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("failed to read '{path}': {error}")]
+    Io { path: String, error: std::io::Error },
+    #[error("invalid webhooks file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("webhook '{url}': unknown event '{event}'")]
+    UnknownEvent { url: String, event: String },
+    #[error("webhook '{0}': not an http:// or https:// URL")]
+    InvalidUrl(String),
+    #[error("failed to create HTTP client: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    CharacterConnected { character: String },
+    CharacterDisconnected { character: String },
+    CharacterPruned { character: String },
+    AlertFired { alert: AlertRecord },
+    AlertCleared { id: u64, rule: String, character: String },
+    IpBanned { ip: String, ban_seconds: u64 },
+    CharacterBanned { character: String, ban_seconds: u64 },
+}
+
+impl WebhookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::CharacterConnected { .. } => "character_connected",
+            WebhookEvent::CharacterDisconnected { .. } => "character_disconnected",
+            WebhookEvent::CharacterPruned { .. } => "character_pruned",
+            WebhookEvent::AlertFired { .. } => "alert_fired",
+            WebhookEvent::AlertCleared { .. } => "alert_cleared",
+            WebhookEvent::IpBanned { .. } => "ip_banned",
+            WebhookEvent::CharacterBanned { .. } => "character_banned",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: &'a str,
+    #[serde(with = "system_time_serde")]
+    time: SystemTime,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+/// One event, encoded once and shared by every target that wants it.
+#[derive(Debug)]
+struct Delivery {
+    id: String,
+    event: &'static str,
+    timestamp: u64,
+    body: String,
+}
+
+#[derive(Debug)]
+struct Target {
+    url: String,
+    events: HashSet<String>, // Empty means every event
+    sender: mpsc::Sender<Arc<Delivery>>,
+}
+
+#[derive(Debug)]
+struct Failure {
+    reason: String,
+    retryable: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Webhooks {
+    targets: Arc<Vec<Target>>,
+    config: Option<Arc<WebhookConfig>>,
+}
+
+impl Webhooks {
+    /// Loads the targets and starts one delivery worker per target. No file means no webhooks.
+    pub fn start(config: WebhookConfig) -> Result<Self, WebhookError> {
+        if config.file.is_empty() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(&config.file).map_err(|error| WebhookError::Io { path: config.file.clone(), error })?;
+        let definitions = toml::from_str::<WebhooksFile>(&text)?.webhooks;
+        let client = reqwest::Client::builder().timeout(config.timeout).build()?;
+        let config = Arc::new(config);
+
+        let mut targets = Vec::with_capacity(definitions.len());
+        for definition in definitions {
+            if !(definition.url.starts_with("http://") || definition.url.starts_with("https://")) {
+                return Err(WebhookError::InvalidUrl(definition.url));
+            }
+            if let Some(event) = definition.events.iter().find(|e| !EVENT_NAMES.contains(&e.as_str())) {
+                return Err(WebhookError::UnknownEvent { url: definition.url.clone(), event: event.clone() });
+            }
+            let (sender, receiver) = mpsc::channel(config.queue_capacity.max(1));
+            let secret = definition.secret.filter(|s| !s.is_empty());
+            tokio::spawn(deliver_loop(definition.url.clone(), secret, client.clone(), Arc::clone(&config), receiver));
+            targets.push(Target { url: definition.url, events: definition.events.into_iter().collect(), sender });
+        }
+        Ok(Self { targets: Arc::new(targets), config: Some(config) })
+    }
+
+    pub fn target_count(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Queues an event for every interested target without waiting for delivery.
+    pub fn notify(&self, event: WebhookEvent) {
+        let name = event.name();
+        let interested: Vec<&Target> = self.targets.iter().filter(|t| t.events.is_empty() || t.events.contains(name)).collect();
+        if interested.is_empty() {
+            return;
+        }
+        let id = Uuid::new_v4().to_string();
+        let time = SystemTime::now();
+        let body = match serde_json::to_string(&Payload { id: &id, time, event: &event }) {
+            Ok(body) => body,
+            Err(e) => { error!("Failed to serialize webhook event '{}': {}", name, e); return; }
+        };
+        let timestamp = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let delivery = Arc::new(Delivery { id, event: name, timestamp, body });
+
+        for target in interested {
+            if let Err(mpsc::error::TrySendError::Full(delivery)) = target.sender.try_send(Arc::clone(&delivery)) {
+                warn!("Webhook queue for {} is full. Dropping '{}' event {}.", target.url, delivery.event, delivery.id);
+                if let Some(config) = &self.config {
+                    let (path, url) = (config.dead_letter_file.clone(), target.url.clone());
+                    tokio::spawn(async move { dead_letter(&path, &url, &delivery, 0, "queue full").await });
+                }
+            }
+        }
+    }
+}
+
+async fn deliver_loop(
+    url: String,
+    secret: Option<String>,
+    client: reqwest::Client,
+    config: Arc<WebhookConfig>,
+    mut receiver: mpsc::Receiver<Arc<Delivery>>,
+) {
+    info!("Webhook worker started for {}", url);
+    while let Some(delivery) = receiver.recv().await {
+        let mut backoff = config.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match send(&client, &url, secret.as_deref(), &delivery).await {
+                Ok(()) => {
+                    debug!("Delivered '{}' event {} to {} (attempt {}).", delivery.event, delivery.id, url, attempt);
+                    break;
+                }
+                Err(failure) if failure.retryable && attempt < config.max_attempts => {
+                    warn!("Webhook delivery of {} to {} failed (attempt {}/{}): {}. Retrying in {:?}.",
+                        delivery.id, url, attempt, config.max_attempts, failure.reason, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(config.max_backoff);
+                    attempt += 1;
+                }
+                Err(failure) => {
+                    error!("Webhook delivery of '{}' event {} to {} failed after {} attempt(s): {}",
+                        delivery.event, delivery.id, url, attempt, failure.reason);
+                    dead_letter(&config.dead_letter_file, &url, &delivery, attempt, &failure.reason).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, url: &str, secret: Option<&str>, delivery: &Delivery) -> Result<(), Failure> {
+    let mut request = client.post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Id", &delivery.id)
+        .header("X-Webhook-Event", delivery.event)
+        .header("X-Webhook-Timestamp", delivery.timestamp.to_string());
+    if let Some(secret) = secret {
+        request = request.header("X-Webhook-Signature", signature(secret, delivery.timestamp, &delivery.body));
+    }
+    let response = request.body(delivery.body.clone()).send().await
+        .map_err(|e| Failure { reason: e.to_string(), retryable: true })?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let retryable = status.is_server_error()
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+    Err(Failure { reason: format!("HTTP {}", status), retryable })
+}
+
+/// `sha256=<hex HMAC-SHA256 of "{timestamp}.{body}">`. Including the timestamp lets receivers reject replays.
+fn signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    #[serde(with = "system_time_serde")]
+    failed_at: SystemTime,
+    url: &'a str,
+    attempts: u32,
+    error: &'a str,
+    payload: &'a RawValue,
+}
+
+/// Appends a failed delivery to the dead-letter file as one JSON line.
+async fn dead_letter(path: &str, url: &str, delivery: &Delivery, attempts: u32, reason: &str) {
+    if path.is_empty() {
+        return;
+    }
+    let Ok(payload) = serde_json::from_str::<&RawValue>(&delivery.body) else { return };
+    let entry = DeadLetter { failed_at: SystemTime::now(), url, attempts, error: reason, payload };
+    let Ok(mut line) = serde_json::to_string(&entry) else { return };
+    line.push('\n');
+    let result = async {
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        file.write_all(line.as_bytes()).await
+    }.await;
+    if let Err(e) = result {
+        error!("Failed to write webhook dead letter to '{}': {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex as StdMutex;
+
+    use axum::http::{HeaderMap, StatusCode};
+    use serde_json::Value;
+
+    type Received = mpsc::UnboundedReceiver<(HeaderMap, String)>;
+
+    /// A local receiver answering with `statuses` in turn (the last one repeats) and reporting every request.
+    async fn receiver(statuses: &[StatusCode]) -> (String, Received) {
+        let statuses = Arc::new(StdMutex::new(statuses.iter().copied().collect::<VecDeque<_>>()));
+        let (sender, received) = mpsc::unbounded_channel();
+        let app = axum::Router::new().route("/hook", axum::routing::post(move |headers: HeaderMap, body: String| async move {
+            let _ = sender.send((headers, body));
+            let mut statuses = statuses.lock().unwrap();
+            if statuses.len() > 1 { statuses.pop_front().unwrap() } else { statuses[0] }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    fn start(url: &str, dead_letter_file: &str) -> Webhooks {
+        let file = std::env::temp_dir().join(format!("webhooks-test-{}.toml", Uuid::new_v4()));
+        std::fs::write(&file, format!("[[webhook]]\nurl = \"{}\"\nsecret = \"change-me\"\n", url)).unwrap();
+        let webhooks = Webhooks::start(WebhookConfig {
+            file: file.to_string_lossy().into_owned(),
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            timeout: Duration::from_secs(5),
+            queue_capacity: 8,
+            dead_letter_file: dead_letter_file.to_string(),
+        }).unwrap();
+        std::fs::remove_file(file).unwrap();
+        webhooks
+    }
+
+    async fn next(received: &mut Received) -> (HeaderMap, String) {
+        tokio::time::timeout(Duration::from_secs(5), received.recv()).await.expect("no request within 5s").unwrap()
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn signature_matches_a_known_hmac() {
+        assert_eq!(
+            signature("change-me", 1700000000, r#"{"event":"character_connected","character":"Thoric"}"#),
+            "sha256=9c061476cef7b0efba3ab5247d2aca31ea6cae33ece77926871e2d03cd11f370",
+        );
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let (url, mut received) = receiver(&[StatusCode::OK]).await;
+        let webhooks = start(&url, "");
+        webhooks.notify(WebhookEvent::CharacterConnected { character: "Thoric".to_string() });
+
+        let (headers, body) = next(&mut received).await;
+        let timestamp: u64 = header(&headers, "X-Webhook-Timestamp").parse().unwrap();
+        assert_eq!(header(&headers, "X-Webhook-Signature"), signature("change-me", timestamp, &body));
+        assert_eq!(header(&headers, "X-Webhook-Event"), "character_connected");
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["id"], header(&headers, "X-Webhook-Id"));
+        assert_eq!(payload["event"], "character_connected");
+        assert_eq!(payload["character"], "Thoric");
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, mut received) = receiver(&[StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK]).await;
+        let dead_letters = std::env::temp_dir().join(format!("webhooks-test-{}.jsonl", Uuid::new_v4()));
+        let webhooks = start(&url, &dead_letters.to_string_lossy());
+        webhooks.notify(WebhookEvent::CharacterPruned { character: "Thoric".to_string() });
+
+        let (first, first_body) = next(&mut received).await;
+        let (second, second_body) = next(&mut received).await;
+        assert_eq!(header(&first, "X-Webhook-Id"), header(&second, "X-Webhook-Id"));
+        assert_eq!(first_body, second_body);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(received.try_recv().is_err(), "delivered after success");
+        assert!(!dead_letters.exists());
+    }
+
+    #[tokio::test]
+    async fn exhausted_retries_are_dead_lettered() {
+        let (url, mut received) = receiver(&[StatusCode::INTERNAL_SERVER_ERROR]).await;
+        let dead_letters = std::env::temp_dir().join(format!("webhooks-test-{}.jsonl", Uuid::new_v4()));
+        let webhooks = start(&url, &dead_letters.to_string_lossy());
+        webhooks.notify(WebhookEvent::IpBanned { ip: "192.0.2.1".to_string(), ban_seconds: 60 });
+
+        for _ in 0..3 {
+            next(&mut received).await;
+        }
+        let mut text = String::new();
+        for _ in 0..50 {
+            text = std::fs::read_to_string(&dead_letters).unwrap_or_default();
+            if !text.is_empty() { break; }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::remove_file(&dead_letters).unwrap();
+        let lines: Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["url"], url);
+        assert_eq!(lines[0]["attempts"], 3);
+        assert_eq!(lines[0]["error"], "HTTP 500 Internal Server Error");
+        assert_eq!(lines[0]["payload"]["event"], "ip_banned");
+        assert_eq!(lines[0]["payload"]["ip"], "192.0.2.1");
+        assert!(received.try_recv().is_err(), "retried past max_attempts");
+    }
+}
@@ -0,0 +1,858 @@
+// --- WebSocket Transport with permessage-deflate ---
+// tungstenite (and therefore axum's WebSocket) has no permessage-deflate support (RFC 7692) and
+// rejects frames with the RSV1 bit set, so /ws performs the upgrade handshake itself:
+//   * The extension is negotiated from the client's Sec-WebSocket-Extensions offers, honouring the
+//     window size and context takeover parameters the client asks for.
+//   * Inbound compressed messages are inflated by InflateStream, which sits between the upgraded
+//     connection and tungstenite and rewrites them into plain frames before tungstenite parses them.
+//   * Outbound frames at or above WS_DEFLATE_THRESHOLD_BYTES are deflated here and written as raw
+//     frames with RSV1 set. Smaller frames are sent uncompressed, which the extension allows per message.
+// Clients that do not offer the extension get a plain tungstenite connection with no shim in between.
+//
+// Data frames bypass tungstenite on the way out: the header is written followed by the payload
+// straight from the shared, once-encoded frame, so a broadcast is not copied per subscriber.
+// tungstenite still handles everything inbound and the control frames it sends itself.
+
+use std::borrow::Cow;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::Response;
+use bytes::Buf;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use futures::{SinkExt, StreamExt};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, error};
+
+use crate::metrics;
+use crate::protocol::Frame;
+
+const EXTENSION_NAME: &str = "permessage-deflate";
+// Every sync-flushed deflate block ends with this empty stored block; RFC 7692 strips it on the wire.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// Same limits tungstenite applies by default, enforced here before a compressed message is inflated.
+const MAX_FRAME_SIZE: usize = 16 << 20;
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+// zlib cannot produce raw deflate streams with an 8 bit window.
+const MIN_WINDOW_BITS: u8 = 9;
+const MAX_WINDOW_BITS: u8 = 15;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+
+// --- Configuration & Negotiation ---
+#[derive(Clone, Debug)]
+pub struct DeflateConfig {
+    pub enabled: bool,
+    pub level: u32,       // zlib compression level, 0-9
+    pub window_bits: u8,  // Largest LZ77 window (2^bits bytes) the server compresses with, 9-15
+    pub threshold: usize, // Frames smaller than this many bytes are sent uncompressed
+}
+
+impl DeflateConfig {
+    pub fn new(enabled: bool, level: u32, window_bits: u8, threshold: usize) -> Self {
+        Self {
+            enabled,
+            level: level.min(9),
+            window_bits: window_bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS),
+            threshold,
+        }
+    }
+
+    /// Accepts the first usable permessage-deflate offer and returns the response header value
+    /// together with the parameters both sides agreed on.
+    fn negotiate(&self, offers: &str) -> Option<(String, DeflateParams)> {
+        if !self.enabled {
+            return None;
+        }
+        offers.split(',').find_map(|offer| self.accept_offer(offer))
+    }
+
+    fn accept_offer(&self, offer: &str) -> Option<(String, DeflateParams)> {
+        let mut parts = offer.split(';').map(str::trim);
+        if !parts.next()?.eq_ignore_ascii_case(EXTENSION_NAME) {
+            return None;
+        }
+
+        let mut server_no_context_takeover = false;
+        let mut client_no_context_takeover = false;
+        let mut server_max_window_bits: Option<u8> = None;
+        let mut client_max_window_bits: Option<Option<u8>> = None;
+        for param in parts.filter(|p| !p.is_empty()) {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            // Unknown, duplicate or malformed parameters make the whole offer unusable (RFC 7692, 7.1).
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("server_no_context_takeover", None) if !server_no_context_takeover => server_no_context_takeover = true,
+                ("client_no_context_takeover", None) if !client_no_context_takeover => client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) if server_max_window_bits.is_none() => {
+                    server_max_window_bits = Some(parse_window_bits(bits)?);
+                }
+                ("client_max_window_bits", None) if client_max_window_bits.is_none() => client_max_window_bits = Some(None),
+                ("client_max_window_bits", Some(bits)) if client_max_window_bits.is_none() => {
+                    client_max_window_bits = Some(Some(parse_window_bits(bits)?));
+                }
+                _ => return None,
+            }
+        }
+
+        let window_bits = self.window_bits.min(server_max_window_bits.unwrap_or(MAX_WINDOW_BITS));
+        if window_bits < MIN_WINDOW_BITS {
+            debug!("Declining permessage-deflate offer with server_max_window_bits={:?}.", server_max_window_bits);
+            return None;
+        }
+
+        let mut response = EXTENSION_NAME.to_string();
+        if server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if server_max_window_bits.is_some() || window_bits < MAX_WINDOW_BITS {
+            response.push_str(&format!("; server_max_window_bits={}", window_bits));
+        }
+        // The client's window may only be limited if it said it supports that.
+        if let Some(offered) = client_max_window_bits {
+            let client_bits = self.window_bits.min(offered.unwrap_or(MAX_WINDOW_BITS));
+            if client_bits < MAX_WINDOW_BITS || offered.is_some() {
+                response.push_str(&format!("; client_max_window_bits={}", client_bits));
+            }
+        }
+
+        Some((response, DeflateParams { window_bits, server_no_context_takeover, client_no_context_takeover }))
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    value.parse::<u8>().ok().filter(|bits| (8..=MAX_WINDOW_BITS).contains(bits))
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DeflateParams {
+    window_bits: u8,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+// --- Compression Statistics ---
+/// Totals over every compressed frame sent, for the compression ratio reported by broadcast_loop.
+/// The byte counts are also exported as mud_ws_deflate_input_bytes_total and mud_ws_deflate_output_bytes_total.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    messages: AtomicU64,
+    raw_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    fn record(&self, raw: usize, compressed: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.raw_bytes.fetch_add(raw as u64, Ordering::Relaxed);
+        self.compressed_bytes.fetch_add(compressed as u64, Ordering::Relaxed);
+        metrics::DEFLATE_INPUT_BYTES.inc_by(raw as u64);
+        metrics::DEFLATE_OUTPUT_BYTES.inc_by(compressed as u64);
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    /// Compressed size over raw size of all compressed frames so far; `None` before the first one.
+    pub fn ratio(&self) -> Option<f64> {
+        let raw = self.raw_bytes.load(Ordering::Relaxed);
+        (raw > 0).then(|| self.compressed_bytes.load(Ordering::Relaxed) as f64 / raw as f64)
+    }
+}
+
+// --- Upgrade Extractor ---
+/// Drop-in replacement for axum's WebSocketUpgrade that can negotiate permessage-deflate.
+pub struct WebSocketUpgrade {
+    key: HeaderValue,
+    on_upgrade: OnUpgrade,
+    requested_protocols: Option<String>,
+    requested_extensions: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for WebSocketUpgrade {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if parts.method != Method::GET {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, "WebSocket upgrades must use GET"));
+        }
+        if !header_contains(&parts.headers, header::CONNECTION, "upgrade") || !header_contains(&parts.headers, header::UPGRADE, "websocket") {
+            return Err((StatusCode::BAD_REQUEST, "Connection header did not include 'upgrade' to 'websocket'"));
+        }
+        if parts.headers.get(header::SEC_WEBSOCKET_VERSION).is_none_or(|v| v != "13") {
+            return Err((StatusCode::BAD_REQUEST, "Sec-WebSocket-Version must be 13"));
+        }
+        let key = parts.headers.get(header::SEC_WEBSOCKET_KEY).cloned()
+            .ok_or((StatusCode::BAD_REQUEST, "Missing Sec-WebSocket-Key header"))?;
+        let on_upgrade = parts.extensions.remove::<OnUpgrade>()
+            .ok_or((StatusCode::UPGRADE_REQUIRED, "Connection is not upgradable"))?;
+
+        Ok(Self {
+            key,
+            on_upgrade,
+            requested_protocols: joined_header(&parts.headers, header::SEC_WEBSOCKET_PROTOCOL),
+            requested_extensions: joined_header(&parts.headers, header::SEC_WEBSOCKET_EXTENSIONS),
+        })
+    }
+}
+
+impl WebSocketUpgrade {
+    /// Finishes the handshake: selects the first of `protocols` (in server preference order) that the
+    /// client asked for, negotiates compression, and runs `callback` once the connection is upgraded.
+    pub fn on_upgrade<F, Fut>(
+        self,
+        protocols: &[&'static str],
+        deflate: &DeflateConfig,
+        stats: Arc<CompressionStats>,
+        callback: F,
+    ) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let protocol = self.requested_protocols.as_deref().and_then(|requested| {
+            protocols.iter().copied().find(|p| requested.split(',').any(|r| r.trim().eq_ignore_ascii_case(p)))
+        });
+        let negotiated = self.requested_extensions.as_deref().and_then(|offers| deflate.negotiate(offers));
+
+        let mut builder = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_ACCEPT, derive_accept_key(self.key.as_bytes()));
+        if let Some(protocol) = protocol {
+            builder = builder.header(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+        if let Some((extension, _)) = &negotiated {
+            builder = builder.header(header::SEC_WEBSOCKET_EXTENSIONS, extension.as_str());
+        }
+
+        let level = deflate.level;
+        let threshold = deflate.threshold;
+        let on_upgrade = self.on_upgrade;
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(e) => { error!("WebSocket upgrade failed: {}", e); return; }
+            };
+            let params = negotiated.map(|(_, params)| params);
+            let io = InflateStream::new(TokioIo::new(upgraded), params.map(Inflater::new));
+            let stream = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+            let deflater = params.map(|params| Deflater::new(params, level, threshold));
+            callback(WebSocket { stream, protocol, deflater, stats, closed: false }).await;
+        });
+
+        builder.body(Body::empty()).unwrap_or_else(|e| {
+            error!("Failed to build WebSocket upgrade response: {}", e);
+            Response::new(Body::empty())
+        })
+    }
+}
+
+fn header_contains(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+fn joined_header(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+// --- Upgraded Connection ---
+pub struct WebSocket {
+    stream: WebSocketStream<InflateStream<TokioIo<Upgraded>>>,
+    protocol: Option<&'static str>,
+    deflater: Option<Deflater>,
+    stats: Arc<CompressionStats>,
+    closed: bool, // Set once a close frame was received or sent, so no data frame follows it
+}
+
+impl WebSocket {
+    /// The negotiated subprotocol, if any.
+    pub fn protocol(&self) -> Option<&'static str> {
+        self.protocol
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.deflater.is_some()
+    }
+
+    pub async fn recv(&mut self) -> Option<Result<Message, WsError>> {
+        let received = self.stream.next().await;
+        if matches!(received, None | Some(Err(_)) | Some(Ok(Message::Close(_)))) {
+            self.closed = true;
+        }
+        received
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), WsError> {
+        self.stream.send(message).await
+    }
+
+    /// Sends an encoded frame, compressing it when the extension was negotiated and the frame is large enough.
+    pub async fn send_frame(&mut self, frame: &Frame) -> Result<(), WsError> {
+        let (opcode, payload) = match frame {
+            Frame::Text(text) => (OPCODE_TEXT, text.as_bytes()),
+            Frame::Binary(bytes) => (OPCODE_BINARY, &bytes[..]),
+        };
+        match self.deflater.as_mut().filter(|d| payload.len() >= d.threshold) {
+            Some(deflater) => {
+                let compressed = deflater.compress(payload).map_err(WsError::Io)?;
+                self.stats.record(payload.len(), compressed.len());
+                self.write_data_frame(opcode, true, &compressed).await
+            }
+            None => self.write_data_frame(opcode, false, payload).await,
+        }
+    }
+
+    /// Writes a final, unmasked server frame without copying `payload`.
+    async fn write_data_frame(&mut self, opcode: u8, rsv1: bool, payload: &[u8]) -> Result<(), WsError> {
+        if self.closed {
+            return Err(WsError::AlreadyClosed);
+        }
+        // Whatever tungstenite has queued (a pong, say) has to go out before our frame.
+        self.stream.flush().await?;
+        let mut header = Vec::with_capacity(10);
+        write_frame_header(&mut header, opcode | if rsv1 { 0x40 } else { 0 }, payload.len(), false);
+        let io = self.stream.get_mut();
+        io.write_all_buf(&mut header.as_slice().chain(payload)).await?;
+        io.flush().await?;
+        Ok(())
+    }
+
+    pub async fn close(&mut self, frame: Option<CloseFrame<'static>>) -> Result<(), WsError> {
+        self.closed = true;
+        self.stream.close(frame).await
+    }
+}
+
+/// Convenience for application close codes (4000-4999).
+pub fn close_frame(code: u16, reason: &'static str) -> CloseFrame<'static> {
+    CloseFrame { code: code.into(), reason: Cow::Borrowed(reason) }
+}
+
+// --- Outbound Compression ---
+struct Deflater {
+    compress: Compress,
+    reset_after_message: bool,
+    threshold: usize,
+}
+
+impl Deflater {
+    fn new(params: DeflateParams, level: u32, threshold: usize) -> Self {
+        Self {
+            compress: Compress::new_with_window_bits(Compression::new(level), false, params.window_bits),
+            reset_after_message: params.server_no_context_takeover,
+            threshold,
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity().max(1024));
+            }
+            let before = self.compress.total_in();
+            self.compress.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            consumed += (self.compress.total_in() - before) as usize;
+            // A sync flush is complete once all input is consumed and zlib stopped short of filling the buffer.
+            if consumed >= data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.reset_after_message {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+}
+
+// --- Inbound Decompression ---
+struct Inflater {
+    decompress: Decompress,
+    reset_after_message: bool,
+    pending: Vec<u8>,  // Raw bytes read from the connection, not yet a complete frame
+    ready: Vec<u8>,    // Rewritten bytes waiting to be read by tungstenite
+    ready_pos: usize,
+    message: Option<CompressedMessage>,
+}
+
+struct CompressedMessage {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl Inflater {
+    fn new(params: DeflateParams) -> Self {
+        Self {
+            // A 15 bit window can decode data compressed with any smaller window.
+            decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+            reset_after_message: params.client_no_context_takeover,
+            pending: Vec::new(),
+            ready: Vec::new(),
+            ready_pos: 0,
+            message: None,
+        }
+    }
+
+    /// Moves every complete frame in `pending` to `ready`, inflating compressed messages on the way.
+    fn process_pending(&mut self) -> io::Result<()> {
+        let mut offset = 0;
+        while let Some(frame) = parse_frame_header(&self.pending[offset..])? {
+            let end = offset + frame.header_len + frame.payload_len;
+            if self.pending.len() < end {
+                break;
+            }
+            let is_control = frame.opcode & 0x08 != 0;
+            let continues_compressed = frame.opcode == 0 && self.message.is_some();
+            let starts_compressed = frame.rsv1 && matches!(frame.opcode, 1 | 2);
+
+            if is_control || !(starts_compressed || continues_compressed) {
+                // Control frames, uncompressed messages and protocol errors go to tungstenite untouched.
+                self.ready.extend_from_slice(&self.pending[offset..end]);
+            } else {
+                if starts_compressed && self.message.is_some() {
+                    return Err(invalid_data("new compressed message started before the previous one finished"));
+                }
+                if continues_compressed && frame.rsv1 {
+                    return Err(invalid_data("RSV1 set on a continuation frame"));
+                }
+                let message = self.message.get_or_insert_with(|| CompressedMessage { opcode: frame.opcode, payload: Vec::new() });
+                let start = message.payload.len();
+                message.payload.extend_from_slice(&self.pending[offset + frame.header_len..end]);
+                if let Some(mask) = frame.mask {
+                    for (i, byte) in message.payload[start..].iter_mut().enumerate() {
+                        *byte ^= mask[i % 4];
+                    }
+                }
+                if message.payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(invalid_data("compressed message too large"));
+                }
+                if frame.fin {
+                    let message = self.message.take().expect("compressed message in progress");
+                    let inflated = self.inflate(message.payload)?;
+                    write_frame(&mut self.ready, message.opcode, &inflated);
+                }
+            }
+            offset = end;
+        }
+        self.pending.drain(..offset);
+        Ok(())
+    }
+
+    fn inflate(&mut self, mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        payload.extend_from_slice(&DEFLATE_TAIL);
+        let mut out = Vec::with_capacity(payload.len() * 4);
+        let mut consumed = 0;
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity().max(4096));
+            }
+            let (before_in, before_out) = (self.decompress.total_in(), self.decompress.total_out());
+            self.decompress.decompress_vec(&payload[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| invalid_data(&e.to_string()))?;
+            consumed += (self.decompress.total_in() - before_in) as usize;
+            if out.len() > MAX_MESSAGE_SIZE {
+                return Err(invalid_data("inflated message too large"));
+            }
+            let progressed = self.decompress.total_in() != before_in || self.decompress.total_out() != before_out;
+            if (consumed >= payload.len() && out.len() < out.capacity()) || !progressed {
+                break;
+            }
+        }
+        if self.reset_after_message {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("permessage-deflate: {}", reason))
+}
+
+fn parse_frame_header(buf: &[u8]) -> io::Result<Option<FrameHeader>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (payload_len, mut header_len) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().expect("8 bytes")), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if payload_len > MAX_FRAME_SIZE as u64 {
+        return Err(invalid_data("frame too large"));
+    }
+    let mask = if masked {
+        if buf.len() < header_len + 4 {
+            return Ok(None);
+        }
+        let key = [buf[header_len], buf[header_len + 1], buf[header_len + 2], buf[header_len + 3]];
+        header_len += 4;
+        Some(key)
+    } else {
+        None
+    };
+    Ok(Some(FrameHeader {
+        fin: buf[0] & 0x80 != 0,
+        rsv1: buf[0] & 0x40 != 0,
+        opcode: buf[0] & 0x0f,
+        mask,
+        header_len,
+        payload_len: payload_len as usize,
+    }))
+}
+
+/// Writes a single final client frame. The all-zero mask key leaves the payload as is while still
+/// satisfying tungstenite's requirement that client frames are masked.
+fn write_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    write_frame_header(out, opcode, payload.len(), true);
+    out.extend_from_slice(payload);
+}
+
+/// Writes the header of a final frame. `first_byte` holds the opcode and any RSV bits.
+fn write_frame_header(out: &mut Vec<u8>, first_byte: u8, len: usize, masked: bool) {
+    let mask_bit = if masked { 0x80 } else { 0 };
+    out.push(0x80 | first_byte);
+    match len {
+        len if len < 126 => out.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if masked {
+        out.extend_from_slice(&[0; 4]);
+    }
+}
+
+/// The upgraded connection as tungstenite sees it: a pass-through, except that compressed inbound
+/// messages are replaced by their inflated equivalent when the extension is in use.
+pub struct InflateStream<S> {
+    inner: S,
+    inflater: Option<Inflater>,
+}
+
+impl<S> InflateStream<S> {
+    fn new(inner: S, inflater: Option<Inflater>) -> Self {
+        Self { inner, inflater }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for InflateStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(inflater) = this.inflater.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if inflater.ready_pos < inflater.ready.len() {
+                let available = &inflater.ready[inflater.ready_pos..];
+                let n = available.len().min(buf.remaining());
+                buf.put_slice(&available[..n]);
+                inflater.ready_pos += n;
+                if inflater.ready_pos == inflater.ready.len() {
+                    inflater.ready.clear();
+                    inflater.ready_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                // EOF. Hand over any partial frame so tungstenite reports the truncation itself.
+                if inflater.pending.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                inflater.ready.append(&mut inflater.pending);
+                continue;
+            }
+            inflater.pending.extend_from_slice(read_buf.filled());
+            inflater.process_pending()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for InflateStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(window_bits: u8) -> DeflateConfig {
+        DeflateConfig::new(true, 6, window_bits, 0)
+    }
+
+    fn params(server_no_context_takeover: bool, client_no_context_takeover: bool) -> DeflateParams {
+        DeflateParams { window_bits: MAX_WINDOW_BITS, server_no_context_takeover, client_no_context_takeover }
+    }
+
+    /// A masked client frame, as a browser would send it.
+    fn client_frame(fin: bool, rsv1: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut out = vec![if fin { 0x80 } else { 0 } | if rsv1 { 0x40 } else { 0 } | opcode];
+        match payload.len() {
+            len if len < 126 => out.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                out.push(0x80 | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(0x80 | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        out
+    }
+
+    /// What a client's deflater produces for one message, tail stripped.
+    fn client_compress(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 1024);
+        compress.compress_vec(data, &mut out, FlushCompress::Sync).unwrap();
+        assert!(out.ends_with(&DEFLATE_TAIL));
+        out.truncate(out.len() - DEFLATE_TAIL.len());
+        out
+    }
+
+    /// Opcode and payload of every frame tungstenite would read from the inflater.
+    fn ready_frames(inflater: &Inflater) -> Vec<(u8, bool, Vec<u8>)> {
+        let mut frames = Vec::new();
+        let mut buf = &inflater.ready[inflater.ready_pos..];
+        while let Some(header) = parse_frame_header(buf).unwrap() {
+            let mut payload = buf[header.header_len..header.header_len + header.payload_len].to_vec();
+            if let Some(mask) = header.mask {
+                payload.iter_mut().enumerate().for_each(|(i, byte)| *byte ^= mask[i % 4]);
+            }
+            frames.push((header.opcode, header.rsv1, payload));
+            buf = &buf[header.header_len + header.payload_len..];
+        }
+        assert!(buf.is_empty(), "trailing partial frame");
+        frames
+    }
+
+    fn feed(inflater: &mut Inflater, bytes: &[u8]) -> io::Result<()> {
+        inflater.pending.extend_from_slice(bytes);
+        inflater.process_pending()
+    }
+
+    #[test]
+    fn accepts_a_plain_offer() {
+        let (response, params) = config(15).negotiate("permessage-deflate").unwrap();
+        assert_eq!(response, "permessage-deflate");
+        assert_eq!(params.window_bits, 15);
+        assert!(!params.server_no_context_takeover && !params.client_no_context_takeover);
+    }
+
+    #[test]
+    fn negotiates_window_bits() {
+        // A smaller configured window has to be announced.
+        let (response, params) = config(12).negotiate("permessage-deflate").unwrap();
+        assert_eq!(response, "permessage-deflate; server_max_window_bits=12");
+        assert_eq!(params.window_bits, 12);
+
+        let (response, params) = config(15).negotiate("permessage-deflate; server_max_window_bits=10").unwrap();
+        assert_eq!(response, "permessage-deflate; server_max_window_bits=10");
+        assert_eq!(params.window_bits, 10);
+
+        // An 8 bit window cannot be produced with zlib, so that offer is declined.
+        assert!(config(15).negotiate("permessage-deflate; server_max_window_bits=8").is_none());
+    }
+
+    #[test]
+    fn negotiates_client_max_window_bits() {
+        // Supported but not requested: nothing to say unless we want a smaller window.
+        let (response, _) = config(15).negotiate("permessage-deflate; client_max_window_bits").unwrap();
+        assert_eq!(response, "permessage-deflate");
+        let (response, _) = config(10).negotiate("permessage-deflate; client_max_window_bits").unwrap();
+        assert_eq!(response, "permessage-deflate; server_max_window_bits=10; client_max_window_bits=10");
+
+        let (response, _) = config(15).negotiate("permessage-deflate; client_max_window_bits=11").unwrap();
+        assert_eq!(response, "permessage-deflate; client_max_window_bits=11");
+
+        // Without the parameter the client's window must not be limited.
+        let (response, _) = config(10).negotiate("permessage-deflate").unwrap();
+        assert_eq!(response, "permessage-deflate; server_max_window_bits=10");
+    }
+
+    #[test]
+    fn negotiates_context_takeover() {
+        let (response, params) = config(15)
+            .negotiate("permessage-deflate; server_no_context_takeover; client_no_context_takeover")
+            .unwrap();
+        assert_eq!(response, "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+        assert!(params.server_no_context_takeover && params.client_no_context_takeover);
+    }
+
+    #[test]
+    fn skips_unusable_offers() {
+        let offers = "permessage-deflate; unknown_param, permessage-deflate; server_no_context_takeover; server_no_context_takeover, \
+                      x-webkit-deflate-frame, permessage-deflate; client_max_window_bits=12";
+        let (response, _) = config(15).negotiate(offers).unwrap();
+        assert_eq!(response, "permessage-deflate; client_max_window_bits=12");
+
+        assert!(config(15).negotiate("permessage-deflate; client_max_window_bits=16").is_none());
+        assert!(DeflateConfig::new(false, 6, 15, 0).negotiate("permessage-deflate").is_none());
+    }
+
+    #[test]
+    fn outbound_frames_inflate_back() {
+        let message = br#"{"type":"delta","updates":{"Alice":{"AFFECTS":"{sanctuary}{24}{haste}{12}"}}}"#.repeat(20);
+        for server_no_context_takeover in [false, true] {
+            let mut deflater = Deflater::new(params(server_no_context_takeover, false), 6, 0);
+            let mut decompress = Decompress::new(false);
+            for _ in 0..3 {
+                let mut compressed = deflater.compress(&message).unwrap();
+                assert!(compressed.len() < message.len() / 4);
+                if server_no_context_takeover {
+                    decompress = Decompress::new(false);
+                }
+                compressed.extend_from_slice(&DEFLATE_TAIL);
+                let mut inflated = Vec::with_capacity(message.len() + 64);
+                decompress.decompress_vec(&compressed, &mut inflated, FlushDecompress::Sync).unwrap();
+                assert_eq!(inflated, message);
+            }
+        }
+
+        let mut header = Vec::new();
+        write_frame_header(&mut header, OPCODE_TEXT | 0x40, 300, false);
+        header.resize(header.len() + 300, b'x');
+        let parsed = parse_frame_header(&header).unwrap().unwrap();
+        assert!(parsed.fin && parsed.rsv1 && parsed.mask.is_none());
+        assert_eq!((parsed.opcode, parsed.header_len, parsed.payload_len), (OPCODE_TEXT, 4, 300));
+    }
+
+    #[test]
+    fn inbound_compressed_frames_are_inflated() {
+        let mut inflater = Inflater::new(params(false, false));
+        let mut compress = Compress::new(Compression::default(), false);
+        let first = br#"{"type":"chat","text":"heal Thoric please"}"#;
+        let second = br#"{"type":"chat","text":"heal Thoric please, again"}"#;
+
+        // Context takeover: the second message refers back to the first.
+        let mut bytes = client_frame(true, true, OPCODE_TEXT, &client_compress(&mut compress, first));
+        bytes.extend(client_frame(true, false, OPCODE_TEXT, b"plain"));
+        bytes.extend(client_frame(true, true, OPCODE_BINARY, &client_compress(&mut compress, second)));
+        // Arriving a few bytes at a time must not matter.
+        for chunk in bytes.chunks(3) {
+            feed(&mut inflater, chunk).unwrap();
+        }
+
+        assert_eq!(ready_frames(&inflater), vec![
+            (OPCODE_TEXT, false, first.to_vec()),
+            (OPCODE_TEXT, false, b"plain".to_vec()),
+            (OPCODE_BINARY, false, second.to_vec()),
+        ]);
+        assert!(inflater.pending.is_empty());
+    }
+
+    #[test]
+    fn fragmented_compressed_messages_are_reassembled() {
+        let mut inflater = Inflater::new(params(false, true));
+        let mut compress = Compress::new(Compression::default(), false);
+        let message = b"cast 'heal' Thoric; cast 'sanctuary' Thoric; cast 'bless' Thoric".repeat(10);
+        let compressed = client_compress(&mut compress, &message);
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+
+        let mut bytes = client_frame(false, true, OPCODE_TEXT, head);
+        bytes.extend(client_frame(true, false, 0x9, b"ping")); // Control frames may sit between fragments
+        bytes.extend(client_frame(true, false, 0x0, tail));
+        feed(&mut inflater, &bytes).unwrap();
+        assert_eq!(ready_frames(&inflater), vec![(0x9, false, b"ping".to_vec()), (OPCODE_TEXT, false, message.clone())]);
+
+        // RSV1 belongs on the first fragment only.
+        let mut inflater = Inflater::new(params(false, true));
+        let mut bytes = client_frame(false, true, OPCODE_TEXT, head);
+        bytes.extend(client_frame(true, true, 0x0, tail));
+        assert!(feed(&mut inflater, &bytes).is_err());
+
+        // A new message cannot start before the previous one finished.
+        let mut inflater = Inflater::new(params(false, true));
+        let mut bytes = client_frame(false, true, OPCODE_TEXT, head);
+        bytes.extend(client_frame(true, true, OPCODE_TEXT, tail));
+        assert!(feed(&mut inflater, &bytes).is_err());
+    }
+
+    #[test]
+    fn oversized_inflated_messages_are_rejected() {
+        let mut inflater = Inflater::new(params(false, false));
+        let mut compress = Compress::new(Compression::fast(), false);
+        let bomb = client_compress(&mut compress, &vec![0; MAX_MESSAGE_SIZE + 1024]);
+        assert!(bomb.len() < MAX_FRAME_SIZE);
+        let error = feed(&mut inflater, &client_frame(true, true, OPCODE_BINARY, &bomb)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("inflated message too large"), "{}", error);
+
+        let mut header = vec![0x80 | 0x40 | OPCODE_BINARY, 0x80 | 127];
+        header.extend_from_slice(&(MAX_FRAME_SIZE as u64 + 1).to_be_bytes());
+        assert!(parse_frame_header(&header).is_err());
+    }
+}